@@ -1,20 +1,81 @@
 #![allow(non_snake_case)]
 
-use std::{env, path::PathBuf};
+use std::{env, fs, io, path::Path, path::PathBuf};
+
+/// Sample programs from the `userprogs` crate, copied to /bin of the ramdisk.
+const PROGRAMS: [&str; 2] = ["hello", "threads"];
 
 fn main() {
     // set by cargo for the kernel artifact dependency
     let kernelPath = PathBuf::from(env::var("CARGO_BIN_FILE_ROSKERNEL").unwrap());
     let outDir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
+    // a disk image (SFS, FAT, ...) the bootloader loads as the ramdisk, the kernel mounts it
+    // as the root file system
+    println!("cargo:rerun-if-env-changed=ROS_RAMDISK");
+    println!("cargo:rerun-if-env-changed=ROS_RAMDISK_DIR");
+    let mut ramdisk = env::var_os("ROS_RAMDISK").map(PathBuf::from);
+    if let Some(ramdisk) = &ramdisk {
+        assert!(
+            ramdisk.is_file(),
+            "ROS_RAMDISK={} is not a file",
+            ramdisk.display()
+        );
+        println!("cargo:rerun-if-changed={}", ramdisk.display());
+    }
+    // or an SFS image made from a host directory, like `sfstool create` does, with the
+    // sample programs added. Without either it holds only the programs
+    let dir = env::var_os("ROS_RAMDISK_DIR").map(PathBuf::from);
+    if let Some(dir) = &dir {
+        assert!(
+            ramdisk.is_none(),
+            "ROS_RAMDISK and ROS_RAMDISK_DIR can't both be set"
+        );
+        assert!(
+            dir.is_dir(),
+            "ROS_RAMDISK_DIR={} is not a directory",
+            dir.display()
+        );
+        println!("cargo:rerun-if-changed={}", dir.display());
+    }
+    if ramdisk.is_some() {
+        println!("cargo:warning=ROS_RAMDISK is used as is, the sample programs aren't on it");
+    } else {
+        let staging = outDir.join("ramdisk");
+        if staging.exists() {
+            fs::remove_dir_all(&staging).expect("Failed to clear the ramdisk staging directory");
+        }
+        fs::create_dir_all(staging.join("bin")).expect("Failed to create the ramdisk staging directory");
+        if let Some(dir) = &dir {
+            copyDir(dir, &staging).expect("Failed to copy ROS_RAMDISK_DIR");
+        }
+        for program in PROGRAMS {
+            // set by cargo for the userprogs artifact dependency
+            let binary = env::var(format!("CARGO_BIN_FILE_USERPROGS_{}", program)).unwrap();
+            fs::copy(&binary, staging.join("bin").join(program))
+                .expect("Failed to copy a sample program");
+        }
+
+        let image = outDir.join("ramdisk.sfs");
+        sfs::host::createImage(&image, &staging, &[], |_| {})
+            .expect("Failed to create the ramdisk image");
+        ramdisk = Some(image);
+    }
+
     let biosPath = outDir.join("bios.img");
-    bootloader::BiosBoot::new(&kernelPath)
-        .create_disk_image(&biosPath)
+    let mut bios = bootloader::BiosBoot::new(&kernelPath);
+    if let Some(ramdisk) = &ramdisk {
+        bios.set_ramdisk(ramdisk);
+    }
+    bios.create_disk_image(&biosPath)
         .expect("Failed to create BIOS disk image");
 
     let uefiPath = outDir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernelPath)
-        .create_disk_image(&uefiPath)
+    let mut uefi = bootloader::UefiBoot::new(&kernelPath);
+    if let Some(ramdisk) = &ramdisk {
+        uefi.set_ramdisk(ramdisk);
+    }
+    uefi.create_disk_image(&uefiPath)
         .expect("Failed to create UEFI disk image");
 
     // pass the disk image paths via environment variables
@@ -22,3 +83,18 @@ fn main() {
     println!("cargo:rustc-env=BIOS_IMAGE={}", biosPath.display());
 }
 
+/// Copies the files and directories under `from` into `to`, which must exist.
+fn copyDir(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.path().is_dir() {
+            fs::create_dir_all(&target)?;
+            copyDir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
@@ -21,6 +21,9 @@ impl TscInfo {
         if execute(0, 0).eax < 0x15 { return None; }
 
         let leaf15 = execute(0x15, 0);
+        // a zero ratio means the TSC/crystal relationship is not enumerated
+        if leaf15.eax == 0 || leaf15.ebx == 0 { return None; }
+
         if leaf15.ecx == 0 {
             let procFreq = ProcessorFrequency::read()?;
             let coreCrystalHz = (procFreq.baseMHz as u32)
@@ -0,0 +1,611 @@
+//! The on-disk format of FAT12, FAT16 and FAT32 volumes with long file names, shared by the
+//! kernel driver and its host tests.
+//!
+//! `Geometry` reads the boot sector, `parseDir` turns a directory's raw bytes into its entries
+//! and the name functions make the short and long entries for new ones. Nothing here does I/O.
+
+#![allow(non_snake_case)]
+#![no_std]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No FAT boot sector.
+    NotFat,
+    /// On-disk structures that don't make sense.
+    Corrupted,
+    NameTooLong,
+    /// A name FAT can't store.
+    InvalidName,
+    /// Every numbered short name for a long name is taken.
+    NoShortName,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::NotFat => "not a FAT volume",
+            Error::Corrupted => "volume is corrupted",
+            Error::NameTooLong => "name too long",
+            Error::InvalidName => "invalid name",
+            Error::NoShortName => "no short name left",
+        })
+    }
+}
+
+pub const ENTRY_SIZE: usize = 32;
+/// First name byte of a deleted entry.
+pub const DELETED: u8 = 0xE5;
+
+// attributes
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Sequence number flag of the long name entry holding the end of the name.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// UTF-16 units per long name entry.
+const LONG_NAME_CHARS: usize = 13;
+pub const MAX_NAME_LEN: usize = 255;
+// where the characters are in a long name entry
+const LONG_NAME_PARTS: [(usize, usize); 3] = [(1, 5), (14, 6), (28, 2)];
+
+// case flags for short names shown in lowercase
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// Characters a short name may have besides uppercase letters and digits.
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+pub fn u16At(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn u32At(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Where everything is, from the boot sector. Offsets and sizes are in bytes.
+pub struct Geometry {
+    pub kind: FatKind,
+    pub clusterSize: u64,
+    pub fatStart: u64,
+    pub fatSize: u64,
+    pub fats: u64,
+    /// The fixed root directory of FAT12 and FAT16.
+    pub rootDirStart: u64,
+    pub rootDirSize: u64,
+    /// The first cluster of the root directory on FAT32, 0 otherwise.
+    pub rootCluster: u32,
+    pub dataStart: u64,
+    /// Clusters are numbered from 2 to `clusterCount + 1`.
+    pub clusterCount: u32,
+    /// The FAT32 FSInfo sector.
+    pub fsInfo: Option<u64>,
+    pub volumeSize: u64,
+}
+
+impl Geometry {
+    /// `NotFat` if `boot` isn't a FAT boot sector, `Corrupted` if its numbers don't add up.
+    pub fn parse(boot: &[u8]) -> Result<Self> {
+        let bytesPerSector = u16At(boot, 11) as u64;
+        let sectorsPerCluster = boot[13] as u64;
+        let reservedSectors = u16At(boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let rootEntries = u16At(boot, 17) as u64;
+        let totalSectors = match u16At(boot, 19) {
+            0 => u32At(boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fatSectors = match u16At(boot, 22) {
+            0 => u32At(boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if !matches!(boot[0], 0xEB | 0xE9)
+            || boot[510..512] != [0x55, 0xAA]
+            || !matches!(bytesPerSector, 512 | 1024 | 2048 | 4096)
+            || !sectorsPerCluster.is_power_of_two()
+            || reservedSectors == 0
+            || fats == 0
+            || fatSectors == 0
+        {
+            return Err(Error::NotFat);
+        }
+
+        let rootDirSectors = (rootEntries * ENTRY_SIZE as u64).div_ceil(bytesPerSector);
+        let dataSector = reservedSectors + fats * fatSectors + rootDirSectors;
+        let clusterCount = totalSectors.checked_sub(dataSector).ok_or(Error::Corrupted)? / sectorsPerCluster;
+        // the cluster count alone decides the FAT type
+        let kind = match clusterCount {
+            0..4085 => FatKind::Fat12,
+            4085..65525 => FatKind::Fat16,
+            _ => FatKind::Fat32,
+        };
+        let entryBits = match kind {
+            FatKind::Fat12 => 12,
+            FatKind::Fat16 => 16,
+            FatKind::Fat32 => 32,
+        };
+        if (kind == FatKind::Fat32) != (rootEntries == 0)
+            || clusterCount > 0x0FFF_FFF5
+            || (clusterCount + 2) * entryBits > fatSectors * bytesPerSector * 8
+        {
+            return Err(Error::Corrupted);
+        }
+
+        let (rootCluster, fsInfo) = match kind {
+            FatKind::Fat32 => {
+                let fsInfo = match u16At(boot, 48) {
+                    0 | 0xFFFF => None,
+                    sector => Some(sector as u64 * bytesPerSector),
+                };
+                (u32At(boot, 44), fsInfo)
+            }
+            _ => (0, None),
+        };
+        if kind == FatKind::Fat32 && !(2..clusterCount as u32 + 2).contains(&rootCluster) {
+            return Err(Error::Corrupted);
+        }
+
+        Ok(Self {
+            kind,
+            clusterSize: sectorsPerCluster * bytesPerSector,
+            fatStart: reservedSectors * bytesPerSector,
+            fatSize: fatSectors * bytesPerSector,
+            fats,
+            rootDirStart: (reservedSectors + fats * fatSectors) * bytesPerSector,
+            rootDirSize: rootDirSectors * bytesPerSector,
+            rootCluster,
+            dataStart: dataSector * bytesPerSector,
+            clusterCount: clusterCount as u32,
+            fsInfo,
+            volumeSize: totalSectors * bytesPerSector,
+        })
+    }
+
+    pub fn clusterOffset(&self, cluster: u32) -> u64 {
+        self.dataStart + (cluster as u64 - 2) * self.clusterSize
+    }
+
+    /// What the last cluster of a chain points to.
+    pub fn endOfChain(&self) -> u32 {
+        match self.kind {
+            FatKind::Fat12 => 0xFFF,
+            FatKind::Fat16 => 0xFFFF,
+            FatKind::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    pub fn isEndOfChain(&self, value: u32) -> bool {
+        value >= self.endOfChain() - 7
+    }
+}
+
+/// A file or directory as its directory lists it.
+pub struct RawEntry {
+    pub name: String,
+    pub shortName: [u8; 11],
+    pub attr: u8,
+    pub firstCluster: u32,
+    pub size: u32,
+    /// Byte offset of the short entry in the directory.
+    pub offset: u64,
+    /// Byte offset of the first long name entry, `offset` if there are none.
+    pub first: u64,
+}
+
+impl RawEntry {
+    pub fn isDirectory(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Long name entries seen so far, waiting for their short entry.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    first: u64,
+    /// Sequence number of the entry that comes next, they count down to 1.
+    next: u8,
+}
+
+pub fn shortNameChecksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// `name` as `ls` shows it, "README.TXT" or "readme.txt" with the case flags.
+pub fn displayShortName(name: &[u8; 11], flags: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut part = String::new();
+        for (i, &byte) in bytes.iter().enumerate() {
+            // 0x05 stands in for a name starting with 0xE5
+            let byte = if i == 0 && byte == 0x05 { DELETED } else { byte };
+            let c = byte as char;
+            part.push(if lower { c.to_ascii_lowercase() } else { c });
+        }
+        String::from(part.trim_end_matches(' '))
+    };
+    let mut shown = part(&name[..8], flags & LOWER_BASE != 0);
+    let ext = part(&name[8..], flags & LOWER_EXT != 0);
+    if !ext.is_empty() {
+        shown.push('.');
+        shown.push_str(&ext);
+    }
+    shown
+}
+
+/// The files and directories in a directory's raw bytes, without `.`, `..` and volume labels.
+pub fn parseDir(data: &[u8]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (i, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = (i * ENTRY_SIZE) as u64;
+        match raw[0] {
+            0 => break,
+            DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attr = raw[11];
+        if attr & 0x3F == ATTR_LONG_NAME {
+            let sequence = raw[0] & 0x1F;
+            if raw[0] & LAST_LONG_ENTRY != 0 {
+                long = Some(LongName {
+                    units: vec![0xFFFF; sequence as usize * LONG_NAME_CHARS],
+                    checksum: raw[13],
+                    first: offset,
+                    next: sequence,
+                });
+            }
+            // entries out of order or for another name throw the whole name away
+            long = long.filter(|long| sequence != 0 && sequence == long.next && raw[13] == long.checksum);
+            if let Some(long) = &mut long {
+                let mut at = (sequence as usize - 1) * LONG_NAME_CHARS;
+                for (start, count) in LONG_NAME_PARTS {
+                    for unit in 0..count {
+                        long.units[at] = u16At(raw, start + unit * 2);
+                        at += 1;
+                    }
+                }
+                long.next -= 1;
+            }
+            continue;
+        }
+
+        let long = long.take();
+        if attr & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            continue;
+        }
+        let shortName: [u8; 11] = raw[..11].try_into().unwrap();
+        let long = long.filter(|long| long.next == 0 && long.checksum == shortNameChecksum(&shortName));
+        let longName = long.as_ref().and_then(|long| {
+            let end = long.units.iter().position(|&unit| unit == 0).unwrap_or(long.units.len());
+            char::decode_utf16(long.units[..end].iter().copied()).collect::<core::result::Result<String, _>>().ok()
+        });
+        entries.push(RawEntry {
+            name: longName.unwrap_or_else(|| displayShortName(&shortName, raw[12])),
+            shortName,
+            attr,
+            firstCluster: (u16At(raw, 20) as u32) << 16 | u16At(raw, 26) as u32,
+            size: u32At(raw, 28),
+            offset,
+            first: long.map_or(offset, |long| long.first),
+        });
+    }
+    entries
+}
+
+/// FAT names compare without regard to case.
+pub fn sameName(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+pub fn checkName(name: &str) -> Result<()> {
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(Error::NameTooLong);
+    }
+    let invalid = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    if name.is_empty() || name == "." || name == ".." || name.contains(invalid) || name.ends_with(['.', ' ']) {
+        return Err(Error::InvalidName);
+    }
+    Ok(())
+}
+
+/// The 8.3 name and case flags `name` can be stored under without a long name.
+pub fn exactShortName(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid = |part: &str| {
+        part.bytes().all(|c| c.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&c))
+            && (!part.bytes().any(|c| c.is_ascii_lowercase()) || !part.bytes().any(|c| c.is_ascii_uppercase()))
+    };
+    if !(1..=8).contains(&base.len()) || ext.len() > 3 || !valid(base) || !valid(ext) {
+        return None;
+    }
+
+    let mut shortName = [b' '; 11];
+    shortName[..base.len()].copy_from_slice(base.as_bytes());
+    shortName[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    shortName.make_ascii_uppercase();
+    let mut flags = 0;
+    if base.bytes().any(|c| c.is_ascii_lowercase()) {
+        flags |= LOWER_BASE;
+    }
+    if ext.bytes().any(|c| c.is_ascii_lowercase()) {
+        flags |= LOWER_EXT;
+    }
+    Some((shortName, flags))
+}
+
+/// A "BASIS~N.EXT" short name for `name` that `taken` says nobody has yet.
+pub fn numberedShortName(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Result<[u8; 11]> {
+    let shortChars = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.to_ascii_uppercase() {
+                c if c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_SPECIAL.contains(&(c as u8))) => c as u8,
+                _ => b'_',
+            })
+            .take(max)
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = trimmed.rsplit_once('.').unwrap_or((trimmed, ""));
+    let base = shortChars(base, 8);
+    let ext = shortChars(ext, 3);
+
+    for n in 1..1_000_000 {
+        let suffix = format!("~{}", n);
+        let keep = base.len().min(8 - suffix.len());
+        let mut shortName = [b' '; 11];
+        shortName[..keep].copy_from_slice(&base[..keep]);
+        shortName[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+        shortName[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken(&shortName) {
+            return Ok(shortName);
+        }
+    }
+    Err(Error::NoShortName)
+}
+
+/// The long name entries for `name`, in the order they go on disk.
+pub fn longNameEntries(name: &str, checksum: u8) -> Vec<u8> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_CHARS);
+    let mut raw = Vec::with_capacity(count * ENTRY_SIZE);
+    for sequence in (1..=count).rev() {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = sequence as u8 | if sequence == count { LAST_LONG_ENTRY } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        // the name ends with a NUL if there's room, the rest is 0xFFFF
+        let mut chars = units.iter().copied().skip((sequence - 1) * LONG_NAME_CHARS).chain([0]).chain([0xFFFF; LONG_NAME_CHARS]);
+        for (start, count) in LONG_NAME_PARTS {
+            for unit in 0..count {
+                let at = start + unit * 2;
+                entry[at..at + 2].copy_from_slice(&chars.next().unwrap().to_le_bytes());
+            }
+        }
+        raw.extend_from_slice(&entry);
+    }
+    raw
+}
+
+/// A short entry for a new file or directory, without its name.
+pub fn newEntry(attr: u8, firstCluster: u32, (date, time): (u16, u16)) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[11] = attr;
+    raw[14..16].copy_from_slice(&time.to_le_bytes());
+    raw[16..18].copy_from_slice(&date.to_le_bytes());
+    raw[18..20].copy_from_slice(&date.to_le_bytes());
+    raw[20..22].copy_from_slice(&((firstCluster >> 16) as u16).to_le_bytes());
+    raw[22..24].copy_from_slice(&time.to_le_bytes());
+    raw[24..26].copy_from_slice(&date.to_le_bytes());
+    raw[26..28].copy_from_slice(&(firstCluster as u16).to_le_bytes());
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+    use std::io::{Cursor, Write};
+
+    const NAMES: [&str; 5] = ["README.TXT", "lower.txt", "A long file name with spaces.text", "Ünïcode näme.bin", "x.y"];
+
+    /// A `size` byte volume formatted and filled by fatfs, `NAMES` as files and "EFI" as a directory.
+    fn image(kind: FatType, size: usize) -> Vec<u8> {
+        let mut image = Cursor::new(vec![0u8; size]);
+        fatfs::format_volume(&mut image, FormatVolumeOptions::new().fat_type(kind)).unwrap();
+        {
+            let fs = FileSystem::new(&mut image, FsOptions::new()).unwrap();
+            let root = fs.root_dir();
+            for (i, name) in NAMES.iter().enumerate() {
+                root.create_file(name).unwrap().write_all(&vec![b'x'; i * 100]).unwrap();
+            }
+            root.create_dir("EFI").unwrap();
+        }
+        image.into_inner()
+    }
+
+    /// The first cluster of the root directory, or all of it on FAT12 and FAT16.
+    fn rootDir<'a>(geometry: &Geometry, image: &'a [u8]) -> &'a [u8] {
+        let (start, size) = match geometry.kind {
+            FatKind::Fat32 => (geometry.clusterOffset(geometry.rootCluster), geometry.clusterSize),
+            _ => (geometry.rootDirStart, geometry.rootDirSize),
+        };
+        &image[start as usize..(start + size) as usize]
+    }
+
+    fn images() -> [(FatKind, Vec<u8>); 3] {
+        [
+            (FatKind::Fat12, image(FatType::Fat12, 1 << 20)),
+            (FatKind::Fat16, image(FatType::Fat16, 16 << 20)),
+            (FatKind::Fat32, image(FatType::Fat32, 64 << 20)),
+        ]
+    }
+
+    #[test]
+    fn geometryOfFatfsImages() {
+        for (kind, image) in images() {
+            let geometry = Geometry::parse(&image[..512]).unwrap();
+            assert_eq!(geometry.kind, kind);
+            assert_eq!(geometry.volumeSize, image.len() as u64);
+            assert!(geometry.dataStart + geometry.clusterCount as u64 * geometry.clusterSize <= geometry.volumeSize);
+            assert_eq!(geometry.fatStart + geometry.fats * geometry.fatSize, geometry.rootDirStart);
+            assert_eq!(geometry.rootDirStart + geometry.rootDirSize, geometry.dataStart);
+            match kind {
+                FatKind::Fat32 => {
+                    assert_eq!(geometry.rootDirSize, 0);
+                    assert!(geometry.fsInfo.is_some());
+                }
+                _ => {
+                    assert_eq!(geometry.rootCluster, 0);
+                    assert!(geometry.rootDirSize > 0);
+                }
+            }
+            // the FAT's first entry holds the media byte, the second the end of chain mark
+            let fat = &image[geometry.fatStart as usize..];
+            let second = match kind {
+                FatKind::Fat12 => (u16At(fat, 1) >> 4) as u32,
+                FatKind::Fat16 => u16At(fat, 2) as u32,
+                FatKind::Fat32 => u32At(fat, 4) & 0x0FFF_FFFF,
+            };
+            assert!(geometry.isEndOfChain(second));
+        }
+    }
+
+    #[test]
+    fn geometryRejectsOtherBootSectors() {
+        let mut image = images().into_iter().next().unwrap().1;
+        image[510] = 0;
+        assert_eq!(Geometry::parse(&image[..512]).err(), Some(Error::NotFat));
+        image[510] = 0x55;
+        // more root entries than FAT12 has room for data
+        image[17..19].copy_from_slice(&0xFFF0u16.to_le_bytes());
+        assert_eq!(Geometry::parse(&image[..512]).err(), Some(Error::Corrupted));
+    }
+
+    #[test]
+    fn parseDirReadsFatfsEntries() {
+        for (_, image) in images() {
+            let geometry = Geometry::parse(&image[..512]).unwrap();
+            let dir = rootDir(&geometry, &image);
+            let entries = parseDir(dir);
+            let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, [&NAMES[..], &["EFI"]].concat());
+
+            for (i, entry) in entries.iter().enumerate() {
+                assert_eq!(entry.isDirectory(), entry.name == "EFI");
+                if !entry.isDirectory() {
+                    assert_eq!(entry.size as usize, i * 100);
+                }
+                assert_eq!(dir[entry.offset as usize..][..11], entry.shortName);
+                // every long name entry carries the checksum of the short name
+                for at in (entry.first..entry.offset).step_by(ENTRY_SIZE) {
+                    assert_eq!(dir[at as usize + 13], shortNameChecksum(&entry.shortName));
+                }
+            }
+            // fatfs gives every name a long name, even those that fit 8.3
+            assert!(entries.iter().all(|entry| entry.first < entry.offset));
+        }
+    }
+
+    #[test]
+    fn wrongChecksumDropsTheLongName() {
+        let (_, image) = images().into_iter().next().unwrap();
+        let geometry = Geometry::parse(&image[..512]).unwrap();
+        let mut dir = rootDir(&geometry, &image).to_vec();
+        let entry = parseDir(&dir).into_iter().find(|entry| entry.name == NAMES[2]).unwrap();
+        dir[entry.first as usize + 13] ^= 1;
+
+        let parsed = parseDir(&dir);
+        assert!(!parsed.iter().any(|parsed| parsed.name == NAMES[2]));
+        let damaged = parsed.iter().find(|parsed| parsed.shortName == entry.shortName).unwrap();
+        assert_eq!(damaged.name, displayShortName(&entry.shortName, 0));
+    }
+
+    #[test]
+    fn exactShortNames() {
+        assert_eq!(exactShortName("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(exactShortName("readme.txt"), Some((*b"README  TXT", LOWER_BASE | LOWER_EXT)));
+        assert_eq!(exactShortName("Makefile"), None);
+        assert_eq!(exactShortName("boot.EFI"), Some((*b"BOOT    EFI", LOWER_BASE)));
+        assert_eq!(exactShortName("~$A-1"), Some((*b"~$A-1      ", 0)));
+        for name in ["toolongname.txt", "a.text", "a+b", "a b", ".hidden", "a.b.c", "é"] {
+            assert_eq!(exactShortName(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn numberedShortNames() {
+        let none = |_: &[u8; 11]| false;
+        assert_eq!(numberedShortName("A long file name.text", none), Ok(*b"ALONGF~1TEX"));
+        assert_eq!(numberedShortName(".bashrc", none), Ok(*b"BASHRC~1   "));
+        assert_eq!(numberedShortName("a+b é.c d", none), Ok(*b"A_B_~1  CD "));
+        // taken names count up, the basis gets shorter to make room
+        let free = *b"ANOTH~12   ";
+        assert_eq!(numberedShortName("another long name", |name| *name != free), Ok(free));
+        assert_eq!(numberedShortName("x", |_| true), Err(Error::NoShortName));
+    }
+
+    #[test]
+    fn fatfsUsesTheFirstNumberedShortName() {
+        let (_, image) = images().into_iter().next().unwrap();
+        let geometry = Geometry::parse(&image[..512]).unwrap();
+        for entry in parseDir(rootDir(&geometry, &image)) {
+            if exactShortName(&entry.name).is_none() {
+                assert_eq!(numberedShortName(&entry.name, |_| false), Ok(entry.shortName), "{}", entry.name);
+            }
+        }
+    }
+
+    #[test]
+    fn longNamesRoundTrip() {
+        let names = ["a".repeat(12), "b".repeat(13), "c".repeat(14), "d".repeat(26), "e".repeat(MAX_NAME_LEN)];
+        for name in names.iter().map(String::as_str).chain(["Ünïcode näme.bin", "emoji 🦀.rs"]) {
+            checkName(name).unwrap();
+            let shortName = numberedShortName(name, |_| false).unwrap();
+            let mut dir = longNameEntries(name, shortNameChecksum(&shortName));
+            let mut entry = newEntry(ATTR_ARCHIVE, 5, (0x1234, 0x5678));
+            entry[..11].copy_from_slice(&shortName);
+            dir.extend_from_slice(&entry);
+            dir.extend_from_slice(&[0; ENTRY_SIZE]);
+
+            let parsed = parseDir(&dir);
+            assert_eq!(parsed.len(), 1);
+            assert_eq!(parsed[0].name, name);
+            assert_eq!(parsed[0].firstCluster, 5);
+            assert_eq!(parsed[0].first, 0);
+            assert_eq!(parsed[0].offset as usize, dir.len() - 2 * ENTRY_SIZE);
+        }
+    }
+
+    #[test]
+    fn checkedNames() {
+        assert_eq!(checkName(&"n".repeat(MAX_NAME_LEN + 1)), Err(Error::NameTooLong));
+        for name in ["", ".", "..", "a/b", "a:b", "trailing.", "trailing ", "tab\t"] {
+            assert_eq!(checkName(name), Err(Error::InvalidName), "{:?}", name);
+        }
+    }
+}
@@ -23,6 +23,18 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Prints even if the port is locked, for reports from contexts where the holder may be
+/// the interrupted code that will never release it. Output can interleave with the holder's.
+#[doc(hidden)]
+pub fn _print_emergency(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    let _ = SERIAL1.lock().write_fmt(args);
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -0,0 +1,226 @@
+//! Devices, the file system mounted on `/dev`: the fixed character devices, then the block
+//! devices in the disk registry.
+
+use super::disk::{readBytes, registry, writeBytes, BlockDevice};
+use super::vfs::{not_a_directory, DirEntry, FileStat, FileType, FsError, FsResult, INode};
+use crate::debug::serial::SERIAL1;
+use crate::multitasking::preemptive;
+use alloc::string::String;
+use alloc::sync::Arc;
+use lazy_static::lazy_static;
+
+/// How long a console read waits between polls of the serial port.
+const CONSOLE_POLL_MS: u64 = 10;
+
+lazy_static! {
+    static ref CONSOLE: Arc<dyn INode> = Arc::new(Console);
+    static ref NULL: Arc<dyn INode> = Arc::new(Null);
+    static ref ROOT: Arc<dyn INode> = Arc::new(DevDir);
+}
+
+/// The console, where standard input, output and error of new processes go.
+pub fn console() -> Arc<dyn INode> {
+    CONSOLE.clone()
+}
+
+/// Names of the character devices under `/dev`.
+const NAMES: [&str; 2] = ["console", "null"];
+
+/// The directory holding every device, to be mounted on `/dev`.
+pub fn root() -> Arc<dyn INode> {
+    ROOT.clone()
+}
+
+/// Nothing can be created or removed in it, block devices come and go with the registry.
+struct DevDir;
+
+impl INode for DevDir {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(FileStat {
+            fileType: FileType::Directory,
+            size: (NAMES.len() + registry::devices().len()) as u64,
+            blockSize: 0,
+            blocks: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn INode>> {
+        match name {
+            "console" => Ok(CONSOLE.clone()),
+            "null" => Ok(NULL.clone()),
+            _ => {
+                let device = registry::get(name).ok_or(FsError::NotFound)?;
+                Ok(Arc::new(Disk { device }))
+            }
+        }
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> FsResult<Arc<dyn INode>> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn mkdir(&self, _name: &str) -> FsResult<Arc<dyn INode>> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rmdir(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rename(&self, _oldname: &str, _newname: &str) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn link(&self, _name: &str, _target: &dyn INode) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> FsResult<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn readdir(&self, cookie: u64) -> FsResult<Option<DirEntry>> {
+        let index = cookie as usize;
+        if let Some(name) = NAMES.get(index) {
+            return Ok(Some(DirEntry {
+                name: String::from(*name),
+                fileType: FileType::CharDevice,
+                cookie: cookie + 1,
+            }));
+        }
+        let devices = registry::devices();
+        Ok(devices.into_iter().nth(index - NAMES.len()).map(|(name, _)| DirEntry {
+            name,
+            fileType: FileType::BlockDevice,
+            cookie: cookie + 1,
+        }))
+    }
+}
+
+fn charDeviceStat() -> FileStat {
+    FileStat {
+        fileType: FileType::CharDevice,
+        size: 0,
+        blockSize: 0,
+        blocks: 0,
+    }
+}
+
+/// Reads from and writes to the first serial port.
+struct Console;
+
+impl INode for Console {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(charDeviceStat())
+    }
+
+    not_a_directory!();
+
+    /// Blocks until at least one byte arrived, then returns what's there up to the end of the
+    /// line. Terminals send carriage returns for Enter, those come back as newlines.
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let received = x86_64::instructions::interrupts::without_interrupts(|| {
+                let mut port = SERIAL1.lock();
+                let mut n = 0;
+                while n < buf.len() {
+                    let Ok(byte) = port.try_receive() else {
+                        break;
+                    };
+                    buf[n] = if byte == b'\r' { b'\n' } else { byte };
+                    n += 1;
+                    if buf[n - 1] == b'\n' {
+                        break;
+                    }
+                }
+                n
+            });
+
+            if received > 0 {
+                return Ok(received);
+            }
+            preemptive::sleep_ms(CONSOLE_POLL_MS);
+        }
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> FsResult<usize> {
+        for chunk in buf.utf8_chunks() {
+            crate::serial_print!("{}", chunk.valid());
+            if !chunk.invalid().is_empty() {
+                crate::serial_print!("\u{FFFD}");
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Reads nothing, swallows everything.
+struct Null;
+
+impl INode for Null {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(charDeviceStat())
+    }
+
+    not_a_directory!();
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> FsResult<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> FsResult<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// A registered block device, read and written byte-wise.
+struct Disk {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl Disk {
+    fn size(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+}
+
+impl INode for Disk {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(FileStat {
+            fileType: FileType::BlockDevice,
+            size: self.size(),
+            blockSize: self.device.block_size() as u32,
+            blocks: self.device.block_count(),
+        })
+    }
+
+    not_a_directory!();
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let n = buf.len().min(self.size().saturating_sub(offset) as usize);
+        readBytes(&*self.device, offset, &mut buf[..n])?;
+        Ok(n)
+    }
+
+    /// Writes stop at the end of the device.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let n = buf.len().min(self.size().saturating_sub(offset) as usize);
+        if n == 0 && !buf.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        writeBytes(&*self.device, offset, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.device.flush()
+    }
+}
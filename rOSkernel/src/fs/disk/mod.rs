@@ -1 +1,109 @@
+//! Disks and other block storage.
+//!
+//! Drivers hand their devices to `registry::register`, which also looks for partitions on them
+//! and registers those as devices of their own.
+
 pub mod floppy;
+pub mod partition;
+pub mod ramdisk;
+pub mod registry;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::future::{self, Future};
+use core::pin::Pin;
+use crate::fs::vfs::{FsError, FsResult};
+
+/// A transfer started by `read_blocks_async` or `write_blocks_async`.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = FsResult<()>> + Send + 'a>>;
+
+/// Storage addressed in fixed-size blocks.
+pub trait BlockDevice: Send + Sync {
+    /// Bytes per block, a power of two.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Fills `buf`, a whole number of blocks long, from block `start` on.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> FsResult<()>;
+
+    /// Writes `buf`, a whole number of blocks long, from block `start` on.
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> FsResult<()>;
+
+    /// Makes sure everything written so far is on the medium.
+    fn flush(&self) -> FsResult<()> {
+        Ok(())
+    }
+
+    /// `read_blocks` for the cooperative executor. Drivers that wait on interrupts override
+    /// this, the default transfers right away.
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(future::ready(self.read_blocks(start, buf)))
+    }
+
+    /// `write_blocks` for the cooperative executor.
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(future::ready(self.write_blocks(start, buf)))
+    }
+}
+
+/// Checks that `len` bytes from block `start` on are whole blocks inside `device`, for drivers
+/// to call before a transfer.
+pub fn checkRange(device: &dyn BlockDevice, start: u64, len: usize) -> FsResult<()> {
+    let blockSize = device.block_size();
+    if !len.is_multiple_of(blockSize) {
+        return Err(FsError::InvalidArgument);
+    }
+    let end = start.checked_add((len / blockSize) as u64).ok_or(FsError::InvalidArgument)?;
+    if end > device.block_count() {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes from byte `offset` on, which needn't line up with blocks.
+pub fn readBytes(device: &dyn BlockDevice, mut offset: u64, mut buf: &mut [u8]) -> FsResult<()> {
+    let blockSize = device.block_size();
+    let mut scratch = vec![0u8; blockSize];
+    while !buf.is_empty() {
+        let block = offset / blockSize as u64;
+        let inBlock = (offset % blockSize as u64) as usize;
+        let n = if inBlock == 0 && buf.len() >= blockSize {
+            let whole = buf.len() / blockSize * blockSize;
+            device.read_blocks(block, &mut buf[..whole])?;
+            whole
+        } else {
+            let n = buf.len().min(blockSize - inBlock);
+            device.read_blocks(block, &mut scratch)?;
+            buf[..n].copy_from_slice(&scratch[inBlock..inBlock + n]);
+            n
+        };
+        buf = &mut core::mem::take(&mut buf)[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// Writes `buf` from byte `offset` on, reading back the blocks it only covers partly.
+pub fn writeBytes(device: &dyn BlockDevice, mut offset: u64, mut buf: &[u8]) -> FsResult<()> {
+    let blockSize = device.block_size();
+    let mut scratch = vec![0u8; blockSize];
+    while !buf.is_empty() {
+        let block = offset / blockSize as u64;
+        let inBlock = (offset % blockSize as u64) as usize;
+        let n = if inBlock == 0 && buf.len() >= blockSize {
+            let whole = buf.len() / blockSize * blockSize;
+            device.write_blocks(block, &buf[..whole])?;
+            whole
+        } else {
+            let n = buf.len().min(blockSize - inBlock);
+            device.read_blocks(block, &mut scratch)?;
+            scratch[inBlock..inBlock + n].copy_from_slice(&buf[..n]);
+            device.write_blocks(block, &scratch)?;
+            n
+        };
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
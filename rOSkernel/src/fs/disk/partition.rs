@@ -0,0 +1,288 @@
+//! MBR and GPT partition tables, every partition being a block device of its own.
+//!
+//! Partitions are numbered like Linux does: the four MBR slots are 1 to 4, logical partitions
+//! in an extended one count on from 5, GPT entries from 1 in table order.
+
+use super::{checkRange, BlockDevice, BlockFuture};
+use crate::fs::vfs::{FsError, FsResult};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE: usize = 0x1BE;
+const MBR_ENTRY_SIZE: usize = 16;
+
+// MBR partition types
+const MBR_EXTENDED_CHS: u8 = 0x05;
+const MBR_EXTENDED_LBA: u8 = 0x0F;
+const MBR_EXTENDED_LINUX: u8 = 0x85;
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Logical partitions followed in an extended partition before giving up on a looping chain.
+const MAX_LOGICAL: u32 = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN: usize = 92;
+const GPT_ENTRY_MIN: usize = 128;
+/// Entries read from a GPT, the spec asks for room for 128.
+const GPT_MAX_ENTRIES: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// System ID byte of an MBR entry.
+    Mbr(u8),
+    /// Type GUID of a GPT entry, as stored.
+    Gpt([u8; 16]),
+}
+
+/// A run of blocks on another device.
+pub struct Partition {
+    parent: Arc<dyn BlockDevice>,
+    number: u32,
+    first: u64,
+    count: u64,
+    kind: PartitionKind,
+}
+
+impl Partition {
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// First block on the parent device.
+    pub fn first(&self) -> u64 {
+        self.first
+    }
+
+    pub fn kind(&self) -> PartitionKind {
+        self.kind
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.parent.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> FsResult<()> {
+        checkRange(self, start, buf.len())?;
+        self.parent.read_blocks(self.first + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> FsResult<()> {
+        checkRange(self, start, buf.len())?;
+        self.parent.write_blocks(self.first + start, buf)
+    }
+
+    fn flush(&self) -> FsResult<()> {
+        self.parent.flush()
+    }
+
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        match checkRange(self, start, buf.len()) {
+            Ok(()) => self.parent.read_blocks_async(self.first + start, buf),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
+    }
+
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        match checkRange(self, start, buf.len()) {
+            Ok(()) => self.parent.write_blocks_async(self.first + start, buf),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
+    }
+}
+
+/// The partitions on `device`, none if it has no partition table it makes sense of.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> FsResult<Vec<Partition>> {
+    let blockSize = device.block_size();
+    if blockSize < 512 || device.block_count() < 2 {
+        return Ok(Vec::new());
+    }
+
+    let mut mbr = vec![0u8; blockSize];
+    device.read_blocks(0, &mut mbr)?;
+    let Some(entries) = mbrEntries(&mbr) else {
+        return Ok(Vec::new());
+    };
+    if entries.iter().any(|entry| entry.kind == MBR_GPT_PROTECTIVE) {
+        return scanGpt(device);
+    }
+
+    let mut found = Vec::new();
+    for (slot, entry) in entries.iter().enumerate() {
+        match entry.kind {
+            0 => {}
+            MBR_EXTENDED_CHS | MBR_EXTENDED_LBA | MBR_EXTENDED_LINUX => {
+                scanExtended(device, entry.first, entry.count, &mut found)?;
+            }
+            kind => found.push((slot as u32 + 1, entry.first, entry.count, PartitionKind::Mbr(kind))),
+        }
+    }
+    Ok(found
+        .into_iter()
+        .filter(|&(number, first, count, _)| inside(device, number, first, count))
+        .map(|(number, first, count, kind)| Partition {
+            parent: device.clone(),
+            number,
+            first,
+            count,
+            kind,
+        })
+        .collect())
+}
+
+struct MbrEntry {
+    kind: u8,
+    first: u64,
+    count: u64,
+}
+
+/// The four entries of an MBR or EBR, None if `block` isn't one.
+fn mbrEntries(block: &[u8]) -> Option<[MbrEntry; 4]> {
+    if block[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+    // FAT volumes without a partition table end the same way
+    if &block[0x36..0x39] == b"FAT" || &block[0x52..0x57] == b"FAT32" {
+        return None;
+    }
+
+    let entries: [MbrEntry; 4] = core::array::from_fn(|i| {
+        let raw = &block[MBR_TABLE + i * MBR_ENTRY_SIZE..MBR_TABLE + (i + 1) * MBR_ENTRY_SIZE];
+        MbrEntry {
+            kind: raw[4],
+            first: u32::from_le_bytes(raw[8..12].try_into().unwrap()) as u64,
+            count: u32::from_le_bytes(raw[12..16].try_into().unwrap()) as u64,
+        }
+    });
+    let statusOk = (0..4).all(|i| matches!(block[MBR_TABLE + i * MBR_ENTRY_SIZE], 0x00 | 0x80));
+    statusOk.then_some(entries)
+}
+
+/// Follows the chain of extended boot records in the extended partition at `first`.
+fn scanExtended(
+    device: &Arc<dyn BlockDevice>,
+    first: u64,
+    count: u64,
+    found: &mut Vec<(u32, u64, u64, PartitionKind)>,
+) -> FsResult<()> {
+    let mut block = vec![0u8; device.block_size()];
+    let mut ebr = first;
+    for number in 5..5 + MAX_LOGICAL {
+        if ebr >= first + count || ebr >= device.block_count() {
+            break;
+        }
+        device.read_blocks(ebr, &mut block)?;
+        let Some([logical, next, ..]) = mbrEntries(&block) else {
+            break;
+        };
+        if logical.kind != 0 {
+            // relative to this EBR, the link to the next one to the extended partition
+            found.push((number, ebr + logical.first, logical.count, PartitionKind::Mbr(logical.kind)));
+        }
+        if next.kind == 0 || next.first == 0 {
+            break;
+        }
+        ebr = first + next.first;
+    }
+    Ok(())
+}
+
+fn scanGpt(device: &Arc<dyn BlockDevice>) -> FsResult<Vec<Partition>> {
+    let blockSize = device.block_size();
+    let mut header = vec![0u8; blockSize];
+    device.read_blocks(1, &mut header)?;
+    if !gptHeaderValid(&header) {
+        log::warn!("GPT: primary header is damaged, trying the backup");
+        device.read_blocks(device.block_count() - 1, &mut header)?;
+        if !gptHeaderValid(&header) {
+            return Err(FsError::Corrupted);
+        }
+    }
+
+    let entriesLba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entryCount = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let entrySize = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    let entriesCrc = u32::from_le_bytes(header[88..92].try_into().unwrap());
+    // 128 times a power of two, and entries don't straddle blocks
+    let entrySizeValid =
+        entrySize >= GPT_ENTRY_MIN && entrySize.is_power_of_two() && entrySize <= blockSize;
+    if !entrySizeValid || entryCount > GPT_MAX_ENTRIES {
+        return Err(FsError::Corrupted);
+    }
+
+    let tableLen = entryCount as usize * entrySize;
+    let mut table = vec![0u8; tableLen.div_ceil(blockSize) * blockSize];
+    let tableEnd = entriesLba.checked_add((table.len() / blockSize) as u64);
+    if tableEnd.is_none_or(|end| end > device.block_count()) {
+        return Err(FsError::Corrupted);
+    }
+    device.read_blocks(entriesLba, &mut table)?;
+    if crc32(&table[..tableLen]) != entriesCrc {
+        log::warn!("GPT: partition entry CRC mismatch");
+        return Err(FsError::Corrupted);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, raw) in table[..tableLen].chunks_exact(entrySize).enumerate() {
+        let kind: [u8; 16] = raw[0..16].try_into().unwrap();
+        if kind == [0; 16] {
+            continue;
+        }
+        let first = u64::from_le_bytes(raw[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(raw[40..48].try_into().unwrap());
+        let number = i as u32 + 1;
+        if last < first || !inside(device, number, first, last - first + 1) {
+            continue;
+        }
+        partitions.push(Partition {
+            parent: device.clone(),
+            number,
+            first,
+            count: last - first + 1,
+            kind: PartitionKind::Gpt(kind),
+        });
+    }
+    Ok(partitions)
+}
+
+fn gptHeaderValid(header: &[u8]) -> bool {
+    if &header[0..8] != GPT_SIGNATURE {
+        return false;
+    }
+    let size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    if size < GPT_HEADER_MIN || size > header.len() {
+        return false;
+    }
+    // the CRC covers the header with its own field zeroed
+    let stored = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    let mut copy = Vec::from(&header[..size]);
+    copy[16..20].fill(0);
+    crc32(&copy) == stored
+}
+
+/// Whether blocks `first..first + count` are on `device`, warning about partitions that aren't.
+fn inside(device: &Arc<dyn BlockDevice>, number: u32, first: u64, count: u64) -> bool {
+    let fits = count > 0 && first.checked_add(count).is_some_and(|end| end <= device.block_count());
+    if !fits {
+        log::warn!("Partition {} ({} blocks at {}) doesn't fit on its disk", number, count, first);
+    }
+    fits
+}
+
+/// CRC-32 as GPT uses it (IEEE 802.3, reflected).
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 }
+        })
+    })
+}
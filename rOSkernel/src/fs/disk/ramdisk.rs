@@ -0,0 +1,53 @@
+//! Disk image the bootloader loaded into memory next to the kernel.
+
+use super::{checkRange, BlockDevice};
+use crate::fs::vfs::FsResult;
+use spin::Mutex;
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct RamDisk {
+    data: Mutex<&'static mut [u8]>,
+    blocks: u64,
+}
+
+impl RamDisk {
+    /// The `len` bytes mapped at `addr`, as `BootInfo::ramdisk_addr` and `ramdisk_len` give
+    /// them. A partial block at the end is left out.
+    ///
+    /// # Safety
+    /// The memory has to be mapped writable and used by nothing else for as long as the kernel
+    /// runs.
+    pub unsafe fn new(addr: u64, len: u64) -> Self {
+        let len = len as usize / BLOCK_SIZE * BLOCK_SIZE;
+        let data = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+        Self {
+            data: Mutex::new(data),
+            blocks: (len / BLOCK_SIZE) as u64,
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> FsResult<()> {
+        checkRange(self, start, buf.len())?;
+        let offset = start as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data.lock()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> FsResult<()> {
+        checkRange(self, start, buf.len())?;
+        let offset = start as usize * BLOCK_SIZE;
+        self.data.lock()[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+//! Block devices by name, as `/dev` lists them.
+//!
+//! Partitions get their disk's name and their number, with a `p` in between if the disk's name
+//! ends in a digit: `sda` has `sda1`, `ram0` has `ram0p1`.
+
+use super::partition;
+use super::BlockDevice;
+use crate::fs::vfs::{FsError, FsResult};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Registered devices, partitions right after their disk.
+static DEVICES: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+struct Registered {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    // the disk a partition is on
+    parent: Option<String>,
+}
+
+/// Adds `device` under `name`, and any partitions found on it.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> FsResult<()> {
+    // a disk without a readable table is still usable as a whole
+    let partitions = partition::scan(&device).unwrap_or_else(|e| {
+        log::warn!("{}: unreadable partition table: {:?}", name, e);
+        Vec::new()
+    });
+
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|registered| registered.name == name) {
+        return Err(FsError::AlreadyExists);
+    }
+    log::info!("{}: {} blocks of {} bytes", name, device.block_count(), device.block_size());
+    devices.push(Registered {
+        name: String::from(name),
+        device,
+        parent: None,
+    });
+
+    let separator = if name.ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+    for partition in partitions {
+        let partitionName = alloc::format!("{}{}{}", name, separator, partition.number());
+        log::info!(
+            "{}: {:?}, {} blocks from {}",
+            partitionName,
+            partition.kind(),
+            partition.block_count(),
+            partition.first()
+        );
+        devices.push(Registered {
+            name: partitionName,
+            device: Arc::new(partition),
+            parent: Some(String::from(name)),
+        });
+    }
+    Ok(())
+}
+
+/// Removes the device `name` and its partitions. File systems on them keep their device until
+/// they're unmounted.
+pub fn unregister(name: &str) -> FsResult<()> {
+    let mut devices = DEVICES.lock();
+    let count = devices.len();
+    devices.retain(|registered| registered.name != name && registered.parent.as_deref() != Some(name));
+    if devices.len() == count {
+        return Err(FsError::NotFound);
+    }
+    Ok(())
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|registered| registered.name == name)
+        .map(|registered| registered.device.clone())
+}
+
+/// Names and devices of everything registered, in registration order.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|registered| (registered.name.clone(), registered.device.clone()))
+        .collect()
+}
@@ -0,0 +1,765 @@
+//! FAT12, FAT16 and FAT32 volumes with long file names, like the UEFI system partition.
+//!
+//! The on-disk format lives in the `fat-format` crate, where it's tested on the host against
+//! images fatfs builds. The driver keeps the FAT in memory and writes every change straight
+//! through to all its copies. Directories are read whole whenever they're looked through, except
+//! by `readdir`, which reads on from its cookie. Files and directories are known by where their
+//! short entry is, every lookup of one gets the same node so its size and first cluster stay in
+//! one place.
+
+use super::disk::{readBytes, writeBytes, BlockDevice};
+use super::vfs::{DirEntry, FileStat, FileType, FsError, FsResult, INode};
+use crate::kernel::RTC;
+use crate::multitasking::preemptive::signal::SleepMutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use fat_format::{
+    checkName, exactShortName, longNameEntries, newEntry, numberedShortName, parseDir, sameName, shortNameChecksum, u16At,
+    u32At, FatKind, Geometry, RawEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, DELETED, ENTRY_SIZE,
+};
+
+impl From<fat_format::Error> for FsError {
+    fn from(e: fat_format::Error) -> Self {
+        match e {
+            fat_format::Error::NotFat => FsError::NotSupported,
+            fat_format::Error::Corrupted => FsError::Corrupted,
+            fat_format::Error::NameTooLong => FsError::NameTooLong,
+            fat_format::Error::InvalidName => FsError::InvalidPath,
+            fat_format::Error::NoShortName => FsError::NoSpace,
+        }
+    }
+}
+
+fn fileType(entry: &RawEntry) -> FileType {
+    match entry.isDirectory() {
+        true => FileType::Directory,
+        false => FileType::RegularFile,
+    }
+}
+
+/// The current FAT date and time, the time in 2-second steps.
+fn timeStamp() -> (u16, u16) {
+    let (year, month, day, hour, minute, second) = RTC::now().parts();
+    let date = (year.saturating_sub(1980) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((hour as u16) << 11) | ((minute as u16) << 5) | (second as u16 / 2);
+    (date, time)
+}
+
+struct State {
+    /// The first FAT as on disk.
+    fat: Vec<u8>,
+    /// Where to look for a free cluster first.
+    nextFree: u32,
+    freeCount: u32,
+    /// Nodes in use, by directory and offset of their short entry.
+    nodes: BTreeMap<(u32, u64), Weak<FatNode>>,
+}
+
+pub struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    state: SleepMutex<State>,
+}
+
+impl Volume {
+    /// Reads the boot sector and FAT of the volume on `device`. `NotSupported` if it isn't FAT.
+    pub fn open(device: Arc<dyn BlockDevice>) -> FsResult<Arc<Self>> {
+        let mut boot = [0u8; 512];
+        readBytes(&*device, 0, &mut boot)?;
+        let geometry = Geometry::parse(&boot)?;
+        if geometry.volumeSize > device.block_count() * device.block_size() as u64 {
+            log::warn!("FAT: volume is larger than its device");
+            return Err(FsError::Corrupted);
+        }
+
+        let mut fat = vec![0u8; geometry.fatSize as usize];
+        readBytes(&*device, geometry.fatStart, &mut fat)?;
+        let volume = Self {
+            device,
+            geometry,
+            state: SleepMutex::new(State {
+                fat,
+                nextFree: 2,
+                freeCount: 0,
+                nodes: BTreeMap::new(),
+            }),
+        };
+        {
+            let mut state = volume.state.lock();
+            let clusters = 2..volume.geometry.clusterCount + 2;
+            state.freeCount = clusters.filter(|&cluster| volume.fatEntry(&state, cluster) == 0).count() as u32;
+            log::info!(
+                "FAT: {:?} volume, {} clusters of {} bytes, {} free",
+                volume.geometry.kind,
+                volume.geometry.clusterCount,
+                volume.geometry.clusterSize,
+                state.freeCount
+            );
+        }
+        Ok(Arc::new(volume))
+    }
+
+    /// The root directory, what gets mounted.
+    pub fn root(self: &Arc<Self>) -> Arc<dyn INode> {
+        Arc::new(FatNode {
+            volume: self.clone(),
+            fileType: FileType::Directory,
+            state: SleepMutex::new(NodeState {
+                entry: None,
+                firstCluster: self.geometry.rootCluster,
+                size: 0,
+                removed: false,
+            }),
+        })
+    }
+
+    /// The node for the entry at `offset` in directory `dir`, the one already in use if there is.
+    fn node(self: &Arc<Self>, state: &mut State, dir: u32, entry: &RawEntry) -> Arc<FatNode> {
+        let key = (dir, entry.offset);
+        if let Some(node) = state.nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+        let node = Arc::new(FatNode {
+            volume: self.clone(),
+            fileType: fileType(entry),
+            state: SleepMutex::new(NodeState {
+                entry: Some(key),
+                firstCluster: entry.firstCluster,
+                size: entry.size,
+                removed: false,
+            }),
+        });
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+
+    fn fatEntry(&self, state: &State, cluster: u32) -> u32 {
+        let n = cluster as usize;
+        match self.geometry.kind {
+            FatKind::Fat12 => {
+                let pair = u16At(&state.fat, n + n / 2);
+                (if n % 2 == 1 { pair >> 4 } else { pair & 0xFFF }) as u32
+            }
+            FatKind::Fat16 => u16At(&state.fat, n * 2) as u32,
+            FatKind::Fat32 => u32At(&state.fat, n * 4) & 0x0FFF_FFFF,
+        }
+    }
+
+    /// Sets the FAT entry of `cluster` in every copy of the FAT.
+    fn setFatEntry(&self, state: &mut State, cluster: u32, value: u32) -> FsResult<()> {
+        let n = cluster as usize;
+        let (at, len) = match self.geometry.kind {
+            FatKind::Fat12 => {
+                let at = n + n / 2;
+                let pair = u16At(&state.fat, at);
+                let pair = match n % 2 {
+                    1 => (pair & 0x000F) | (value as u16) << 4,
+                    _ => (pair & 0xF000) | (value as u16 & 0xFFF),
+                };
+                state.fat[at..at + 2].copy_from_slice(&pair.to_le_bytes());
+                (at, 2)
+            }
+            FatKind::Fat16 => {
+                state.fat[n * 2..n * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                (n * 2, 2)
+            }
+            FatKind::Fat32 => {
+                // the top 4 bits are reserved and stay as they are
+                let value = (u32At(&state.fat, n * 4) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                state.fat[n * 4..n * 4 + 4].copy_from_slice(&value.to_le_bytes());
+                (n * 4, 4)
+            }
+        };
+        for copy in 0..self.geometry.fats {
+            let offset = self.geometry.fatStart + copy * self.geometry.fatSize + at as u64;
+            writeBytes(&*self.device, offset, &state.fat[at..at + len])?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`.
+    fn chain(&self, state: &State, first: u32) -> FsResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        loop {
+            // out of range, or going round in circles
+            if !(2..self.geometry.clusterCount + 2).contains(&cluster) || chain.len() > self.geometry.clusterCount as usize {
+                return Err(FsError::Corrupted);
+            }
+            chain.push(cluster);
+            let next = self.fatEntry(state, cluster);
+            if self.geometry.isEndOfChain(next) {
+                return Ok(chain);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Takes `count` free clusters and chains them together.
+    fn allocate(&self, state: &mut State, count: usize) -> FsResult<Vec<u32>> {
+        if count > state.freeCount as usize {
+            return Err(FsError::NoSpace);
+        }
+        let clusterCount = self.geometry.clusterCount;
+        let mut clusters = Vec::with_capacity(count);
+        let mut cluster = state.nextFree;
+        let mut scanned = 0;
+        while clusters.len() < count {
+            if scanned > clusterCount {
+                return Err(FsError::Corrupted);
+            }
+            scanned += 1;
+            if !(2..clusterCount + 2).contains(&cluster) {
+                cluster = 2;
+            }
+            if self.fatEntry(state, cluster) == 0 {
+                clusters.push(cluster);
+            }
+            cluster += 1;
+        }
+
+        for (i, &cluster) in clusters.iter().enumerate().rev() {
+            let next = clusters.get(i + 1).copied().unwrap_or(self.geometry.endOfChain());
+            self.setFatEntry(state, cluster, next)?;
+        }
+        state.nextFree = cluster;
+        state.freeCount -= count as u32;
+        self.writeFsInfo(state)?;
+        Ok(clusters)
+    }
+
+    fn free(&self, state: &mut State, clusters: &[u32]) -> FsResult<()> {
+        for &cluster in clusters {
+            self.setFatEntry(state, cluster, 0)?;
+        }
+        state.freeCount += clusters.len() as u32;
+        self.writeFsInfo(state)
+    }
+
+    /// Keeps the free cluster count and hint of FAT32 up to date.
+    fn writeFsInfo(&self, state: &State) -> FsResult<()> {
+        let Some(offset) = self.geometry.fsInfo else {
+            return Ok(());
+        };
+        let mut sector = [0u8; 512];
+        readBytes(&*self.device, offset, &mut sector)?;
+        if u32At(&sector, 0) != 0x4161_5252 || u32At(&sector, 484) != 0x6141_7272 {
+            return Ok(());
+        }
+        sector[488..492].copy_from_slice(&state.freeCount.to_le_bytes());
+        sector[492..496].copy_from_slice(&state.nextFree.to_le_bytes());
+        writeBytes(&*self.device, offset, &sector)
+    }
+
+    fn zeroCluster(&self, cluster: u32) -> FsResult<()> {
+        let zeroes = vec![0u8; self.geometry.clusterSize as usize];
+        writeBytes(&*self.device, self.geometry.clusterOffset(cluster), &zeroes)
+    }
+
+    /// Reads `buf.len()` bytes from byte `offset` of the file in `chain`.
+    fn readChain(&self, chain: &[u32], mut offset: u64, mut buf: &mut [u8]) -> FsResult<()> {
+        let clusterSize = self.geometry.clusterSize;
+        while !buf.is_empty() {
+            let cluster = chain[(offset / clusterSize) as usize];
+            let inCluster = offset % clusterSize;
+            let n = buf.len().min((clusterSize - inCluster) as usize);
+            readBytes(&*self.device, self.geometry.clusterOffset(cluster) + inCluster, &mut buf[..n])?;
+            buf = &mut core::mem::take(&mut buf)[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at byte `offset` of the file in `chain`, which has to be long enough.
+    fn writeChain(&self, chain: &[u32], mut offset: u64, mut buf: &[u8]) -> FsResult<()> {
+        let clusterSize = self.geometry.clusterSize;
+        while !buf.is_empty() {
+            let cluster = chain[(offset / clusterSize) as usize];
+            let inCluster = offset % clusterSize;
+            let n = buf.len().min((clusterSize - inCluster) as usize);
+            writeBytes(&*self.device, self.geometry.clusterOffset(cluster) + inCluster, &buf[..n])?;
+            buf = &buf[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    fn zeroChain(&self, chain: &[u32], from: u64, to: u64) -> FsResult<()> {
+        let zeroes = vec![0u8; self.geometry.clusterSize as usize];
+        let mut offset = from;
+        while offset < to {
+            let n = (to - offset).min(zeroes.len() as u64);
+            self.writeChain(chain, offset, &zeroes[..n as usize])?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// The clusters of directory `dir`, the fixed root directory being 0 and having none.
+    fn dirChain(&self, state: &State, dir: u32) -> FsResult<Vec<u32>> {
+        match dir {
+            0 => Ok(Vec::new()),
+            dir => self.chain(state, dir),
+        }
+    }
+
+    fn readDir(&self, state: &State, dir: u32) -> FsResult<Vec<u8>> {
+        if dir == 0 {
+            let mut data = vec![0u8; self.geometry.rootDirSize as usize];
+            readBytes(&*self.device, self.geometry.rootDirStart, &mut data)?;
+            return Ok(data);
+        }
+        let chain = self.chain(state, dir)?;
+        let mut data = vec![0u8; chain.len() * self.geometry.clusterSize as usize];
+        self.readChain(&chain, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// The first entry at or after byte `from` of directory `dir`, reading a cluster at a time
+    /// and no further than that entry.
+    fn entryFrom(&self, state: &State, dir: u32, from: u64) -> FsResult<Option<RawEntry>> {
+        let chain = self.dirChain(state, dir)?;
+        let clusterSize = self.geometry.clusterSize;
+        let size = match dir {
+            0 => self.geometry.rootDirSize,
+            _ => chain.len() as u64 * clusterSize,
+        };
+        let start = from / ENTRY_SIZE as u64 * ENTRY_SIZE as u64;
+        let mut data = Vec::new();
+        let mut offset = start;
+        while offset < size {
+            let n = (size - offset).min(clusterSize - offset % clusterSize);
+            let read = data.len();
+            data.resize(read + n as usize, 0);
+            match dir {
+                0 => readBytes(&*self.device, self.geometry.rootDirStart + offset, &mut data[read..])?,
+                _ => self.readChain(&chain, offset, &mut data[read..])?,
+            }
+            offset += n;
+
+            // an entry only shows up once its short entry is read
+            if let Some(mut entry) = parseDir(&data).into_iter().next() {
+                entry.offset += start;
+                entry.first += start;
+                return Ok(Some(entry));
+            }
+            if data[read..].chunks_exact(ENTRY_SIZE).any(|raw| raw[0] == 0) {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    fn writeDir(&self, state: &State, dir: u32, offset: u64, bytes: &[u8]) -> FsResult<()> {
+        match dir {
+            0 => writeBytes(&*self.device, self.geometry.rootDirStart + offset, bytes),
+            dir => self.writeChain(&self.chain(state, dir)?, offset, bytes),
+        }
+    }
+
+    /// Finds the entry named `name` in directory `dir`.
+    fn find(&self, state: &State, dir: u32, name: &str) -> FsResult<Option<RawEntry>> {
+        let data = self.readDir(state, dir)?;
+        Ok(parseDir(&data).into_iter().find(|entry| sameName(&entry.name, name)))
+    }
+
+    /// Adds `name` to directory `dir` with the rest of its short entry from `short`, growing the
+    /// directory if it's full. Returns the offset of the short entry.
+    fn addEntry(&self, state: &mut State, dir: u32, name: &str, mut short: [u8; ENTRY_SIZE]) -> FsResult<u64> {
+        let data = self.readDir(state, dir)?;
+        let entries = parseDir(&data);
+        let taken = |shortName: &[u8; 11]| entries.iter().any(|entry| &entry.shortName == shortName);
+        let mut raw = Vec::new();
+        match exactShortName(name) {
+            Some((shortName, flags)) if !taken(&shortName) => {
+                short[..11].copy_from_slice(&shortName);
+                short[12] = flags;
+            }
+            _ => {
+                let shortName = numberedShortName(name, taken)?;
+                short[..11].copy_from_slice(&shortName);
+                short[12] = 0;
+                raw = longNameEntries(name, shortNameChecksum(&shortName));
+            }
+        }
+        raw.extend_from_slice(&short);
+
+        let slots = raw.len() / ENTRY_SIZE;
+        let count = data.len() / ENTRY_SIZE;
+        let mut run = 0;
+        let mut at = None;
+        for slot in 0..count {
+            if matches!(data[slot * ENTRY_SIZE], 0 | DELETED) {
+                run += 1;
+                if run == slots {
+                    at = Some(slot + 1 - slots);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+        let at = match at {
+            Some(at) => at,
+            None if dir == 0 => return Err(FsError::NoSpace),
+            None => {
+                // the free entries at the end go on into new clusters
+                let needed = ((slots - run) * ENTRY_SIZE) as u64;
+                let clusters = self.allocate(state, needed.div_ceil(self.geometry.clusterSize) as usize)?;
+                for &cluster in &clusters {
+                    self.zeroCluster(cluster)?;
+                }
+                let last = *self.chain(state, dir)?.last().unwrap();
+                self.setFatEntry(state, last, clusters[0])?;
+                count - run
+            }
+        };
+        self.writeDir(state, dir, (at * ENTRY_SIZE) as u64, &raw)?;
+        Ok(((at + slots - 1) * ENTRY_SIZE) as u64)
+    }
+
+    /// Marks `entry` and its long name entries in directory `dir` deleted.
+    fn removeEntry(&self, state: &State, dir: u32, entry: &RawEntry) -> FsResult<()> {
+        for offset in (entry.first..=entry.offset).step_by(ENTRY_SIZE) {
+            self.writeDir(state, dir, offset, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the first cluster and size of a node to its short entry, with a new modification
+    /// time.
+    fn updateEntry(&self, state: &State, node: &NodeState) -> FsResult<()> {
+        let Some((dir, offset)) = node.entry else {
+            return Ok(());
+        };
+        let mut raw = [0u8; ENTRY_SIZE];
+        match dir {
+            0 => readBytes(&*self.device, self.geometry.rootDirStart + offset, &mut raw)?,
+            dir => self.readChain(&self.chain(state, dir)?, offset, &mut raw)?,
+        }
+        let (date, time) = timeStamp();
+        raw[18..20].copy_from_slice(&date.to_le_bytes());
+        raw[20..22].copy_from_slice(&((node.firstCluster >> 16) as u16).to_le_bytes());
+        raw[22..24].copy_from_slice(&time.to_le_bytes());
+        raw[24..26].copy_from_slice(&date.to_le_bytes());
+        raw[26..28].copy_from_slice(&(node.firstCluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&node.size.to_le_bytes());
+        self.writeDir(state, dir, offset, &raw)
+    }
+}
+
+struct NodeState {
+    /// Directory and offset of the short entry, `None` for the root.
+    entry: Option<(u32, u64)>,
+    /// 0 for an empty file and the fixed root directory.
+    firstCluster: u32,
+    size: u32,
+    /// Deleted while still open.
+    removed: bool,
+}
+
+/// A file or directory. Its state is only touched with the volume locked.
+pub struct FatNode {
+    volume: Arc<Volume>,
+    fileType: FileType,
+    state: SleepMutex<NodeState>,
+}
+
+impl FatNode {
+    /// The directory's first cluster, 0 for the fixed root directory.
+    fn dir(&self) -> FsResult<u32> {
+        if self.fileType != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let node = self.state.lock();
+        match node.removed {
+            true => Err(FsError::NotFound),
+            false => Ok(node.firstCluster),
+        }
+    }
+
+    fn isFile(&self) -> FsResult<()> {
+        match self.fileType {
+            FileType::Directory => Err(FsError::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    /// What `..` in a new directory here points to, 0 for the root.
+    fn parentCluster(&self, dir: u32) -> u32 {
+        if dir == self.volume.geometry.rootCluster { 0 } else { dir }
+    }
+
+    /// The clusters of the file, as many as `length` bytes need at least.
+    fn reserve(&self, state: &mut State, node: &mut NodeState, length: u64) -> FsResult<Vec<u32>> {
+        let volume = &self.volume;
+        let needed = length.div_ceil(volume.geometry.clusterSize) as usize;
+        let mut chain = match node.firstCluster {
+            0 => Vec::new(),
+            first => volume.chain(state, first)?,
+        };
+        if chain.len() < needed {
+            let clusters = volume.allocate(state, needed - chain.len())?;
+            match chain.last() {
+                Some(&last) => volume.setFatEntry(state, last, clusters[0])?,
+                None => node.firstCluster = clusters[0],
+            }
+            chain.extend(clusters);
+        }
+        Ok(chain)
+    }
+
+    /// Forgets the node of a deleted entry, it reads as empty from now on.
+    fn forget(state: &mut State, dir: u32, entry: &RawEntry) {
+        if let Some(node) = state.nodes.remove(&(dir, entry.offset)).and_then(|node| node.upgrade()) {
+            let mut node = node.state.lock();
+            node.removed = true;
+            node.entry = None;
+            node.firstCluster = 0;
+            node.size = 0;
+        }
+    }
+}
+
+impl INode for FatNode {
+    fn stat(&self) -> FsResult<FileStat> {
+        let clusterSize = self.volume.geometry.clusterSize;
+        let size = match self.fileType {
+            FileType::Directory => 0,
+            _ => self.state.lock().size as u64,
+        };
+        Ok(FileStat {
+            fileType: self.fileType,
+            size,
+            blockSize: clusterSize as u32,
+            blocks: size.div_ceil(clusterSize),
+        })
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn INode>> {
+        let dir = self.dir()?;
+        let mut state = self.volume.state.lock();
+        let entry = self.volume.find(&state, dir, name)?.ok_or(FsError::NotFound)?;
+        if fileType(&entry) == FileType::Directory && entry.firstCluster == 0 {
+            return Err(FsError::Corrupted);
+        }
+        Ok(self.volume.node(&mut state, dir, &entry))
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> FsResult<Arc<dyn INode>> {
+        let dir = self.dir()?;
+        checkName(name)?;
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        if volume.find(&state, dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (attr, firstCluster) = match kind {
+            FileType::RegularFile => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                let cluster = volume.allocate(&mut state, 1)?[0];
+                volume.zeroCluster(cluster)?;
+                let mut dots = [0u8; 2 * ENTRY_SIZE];
+                dots[..ENTRY_SIZE].copy_from_slice(&newEntry(ATTR_DIRECTORY, cluster, timeStamp()));
+                dots[ENTRY_SIZE..].copy_from_slice(&newEntry(ATTR_DIRECTORY, self.parentCluster(dir), timeStamp()));
+                dots[..11].copy_from_slice(b".          ");
+                dots[ENTRY_SIZE..ENTRY_SIZE + 11].copy_from_slice(b"..         ");
+                writeBytes(&*volume.device, volume.geometry.clusterOffset(cluster), &dots)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+            _ => return Err(FsError::NotSupported),
+        };
+        let offset = match volume.addEntry(&mut state, dir, name, newEntry(attr, firstCluster, timeStamp())) {
+            Ok(offset) => offset,
+            Err(e) => {
+                if firstCluster != 0 {
+                    volume.free(&mut state, &[firstCluster])?;
+                }
+                return Err(e);
+            }
+        };
+
+        let entry = RawEntry {
+            name: String::from(name),
+            shortName: [0; 11],
+            attr,
+            firstCluster,
+            size: 0,
+            offset,
+            first: offset,
+        };
+        Ok(volume.node(&mut state, dir, &entry))
+    }
+
+    fn mkdir(&self, name: &str) -> FsResult<Arc<dyn INode>> {
+        self.create(name, FileType::Directory)
+    }
+
+    fn rmdir(&self, name: &str) -> FsResult<()> {
+        let dir = self.dir()?;
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let entry = volume.find(&state, dir, name)?.ok_or(FsError::NotFound)?;
+        if fileType(&entry) != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if !parseDir(&volume.readDir(&state, entry.firstCluster)?).is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+
+        let chain = volume.chain(&state, entry.firstCluster)?;
+        FatNode::forget(&mut state, dir, &entry);
+        volume.removeEntry(&state, dir, &entry)?;
+        volume.free(&mut state, &chain)
+    }
+
+    fn rename(&self, oldname: &str, newname: &str) -> FsResult<()> {
+        let dir = self.dir()?;
+        checkName(newname)?;
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let data = volume.readDir(&state, dir)?;
+        let entries = parseDir(&data);
+        let old = entries.iter().find(|entry| sameName(&entry.name, oldname)).ok_or(FsError::NotFound)?;
+        // changing only the case of a name is fine
+        if entries.iter().any(|entry| sameName(&entry.name, newname) && entry.offset != old.offset) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let short: [u8; ENTRY_SIZE] = data[old.offset as usize..old.offset as usize + ENTRY_SIZE].try_into().unwrap();
+        let offset = volume.addEntry(&mut state, dir, newname, short)?;
+        volume.removeEntry(&state, dir, old)?;
+        if let Some(node) = state.nodes.remove(&(dir, old.offset)) {
+            if let Some(live) = node.upgrade() {
+                live.state.lock().entry = Some((dir, offset));
+            }
+            state.nodes.insert((dir, offset), node);
+        }
+        Ok(())
+    }
+
+    fn link(&self, _name: &str, _target: &dyn INode) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let dir = self.dir()?;
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let entry = volume.find(&state, dir, name)?.ok_or(FsError::NotFound)?;
+        if fileType(&entry) == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        // an open node knows better than the entry where the file is
+        let firstCluster = match state.nodes.get(&(dir, entry.offset)).and_then(Weak::upgrade) {
+            Some(node) => node.state.lock().firstCluster,
+            None => entry.firstCluster,
+        };
+        let chain = match firstCluster {
+            0 => Vec::new(),
+            first => volume.chain(&state, first)?,
+        };
+        FatNode::forget(&mut state, dir, &entry);
+        volume.removeEntry(&state, dir, &entry)?;
+        volume.free(&mut state, &chain)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Cookies are byte offsets in the directory.
+    fn readdir(&self, cookie: u64) -> FsResult<Option<DirEntry>> {
+        let dir = self.dir()?;
+        let state = self.volume.state.lock();
+        Ok(self.volume.entryFrom(&state, dir, cookie)?.map(|entry| DirEntry {
+            fileType: fileType(&entry),
+            cookie: entry.offset + ENTRY_SIZE as u64,
+            name: entry.name,
+        }))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        self.isFile()?;
+        let state = self.volume.state.lock();
+        let node = self.state.lock();
+        let size = node.size as u64;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min((size - offset) as usize);
+        let chain = self.volume.chain(&state, node.firstCluster)?;
+        if (chain.len() as u64) < size.div_ceil(self.volume.geometry.clusterSize) {
+            return Err(FsError::Corrupted);
+        }
+        self.volume.readChain(&chain, offset, &mut buf[..n])?;
+        Ok(n)
+    }
+
+    /// Writing past the end fills the gap with zeroes.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        self.isFile()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64).filter(|&end| end <= u32::MAX as u64).ok_or(FsError::FileTooLarge)?;
+
+        let mut state = self.volume.state.lock();
+        let mut node = self.state.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        let chain = self.reserve(&mut state, &mut node, end)?;
+        let size = node.size as u64;
+        if offset > size {
+            self.volume.zeroChain(&chain, size, offset)?;
+        }
+        self.volume.writeChain(&chain, offset, buf)?;
+        node.size = node.size.max(end as u32);
+        self.volume.updateEntry(&state, &node)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.isFile()?;
+        if size > u32::MAX as u64 {
+            return Err(FsError::FileTooLarge);
+        }
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let mut node = self.state.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+
+        let oldSize = node.size as u64;
+        if size > oldSize {
+            let chain = self.reserve(&mut state, &mut node, size)?;
+            volume.zeroChain(&chain, oldSize, size)?;
+        } else if node.firstCluster != 0 {
+            let chain = volume.chain(&state, node.firstCluster)?;
+            let keep = size.div_ceil(volume.geometry.clusterSize) as usize;
+            if keep == 0 {
+                node.firstCluster = 0;
+            } else if keep < chain.len() {
+                volume.setFatEntry(&mut state, chain[keep - 1], volume.geometry.endOfChain())?;
+            }
+            if keep < chain.len() {
+                volume.free(&mut state, &chain[keep..])?;
+            }
+        }
+        node.size = size as u32;
+        volume.updateEntry(&state, &node)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.volume.device.flush()
+    }
+}
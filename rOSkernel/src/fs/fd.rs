@@ -1,100 +1,234 @@
-// use super::vfs::{OpenFlags, SeekFrom, FsResult, FsError, INode};
-// use alloc::{sync::Arc, vec::Vec};
-// use spin::Mutex;
-
-// /// An open file handle
-// pub struct OpenFile {
-//     pub inode: Arc<dyn INode>,
-//     pub flags: OpenFlags,
-//     pub offset: Mutex<u64>,
-// }
-
-// impl OpenFile {
-//     pub fn new(inode: Arc<dyn INode>, flags: OpenFlags) -> Self {
-//         Self {
-//             inode,
-//             flags,
-//             offset: Mutex::new(0),
-//         }
-//     }
-    
-//     pub fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
-//         if !self.flags.contains(OpenFlags::READ) {
-//             return Err(FsError::PermissionDenied);
-//         }
-//         let mut offset = self.offset.lock();
-//         let bytes_read = self.vnode.read_at(*offset, buf)?;
-//         *offset += bytes_read as u64;
-//         Ok(bytes_read)
-//     }
-    
-//     pub fn write(&self, buf: &[u8]) -> FsResult<usize> {
-//         if !self.flags.contains(OpenFlags::WRITE) {
-//             return Err(FsError::PermissionDenied);
-//         }
-//         let mut offset = self.offset.lock();
-//         if self.flags.contains(OpenFlags::APPEND) {
-//             *offset = self.vnode.stat()?.size;
-//         }
-//         let bytes_written = self.vnode.write_at(*offset, buf)?;
-//         *offset += bytes_written as u64;
-//         Ok(bytes_written)
-//     }
-    
-//     pub fn seek(&self, pos: SeekFrom) -> FsResult<u64> {
-//         let mut offset = self.offset.lock();
-//         let size = self.vnode.stat()?.size;
-        
-//         let new_offset = match pos {
-//             SeekFrom::Start(n) => n,
-//             SeekFrom::Current(n) => (*offset as i64 + n) as u64,
-//             SeekFrom::End(n) => (size as i64 + n) as u64,
-//         };
-        
-//         *offset = new_offset;
-//         Ok(new_offset)
-//     }
-// }
-
-// /// File descriptor table (one per process)
-// pub struct FdTable {
-//     files: Vec<Option<Arc<OpenFile>>>,
-// }
-
-// impl FdTable {
-//     pub fn new() -> Self {
-//         // Reserve fd 0, 1, 2 for stdin, stdout, stderr
-//         Self {
-//             files: Vec::new(),
-//         }
-//     }
-    
-//     /// Allocate a new file descriptor
-//     pub fn insert(&mut self, file: Arc<OpenFile>) -> usize {
-//         // Find first empty slot
-//         for (i, slot) in self.files.iter_mut().enumerate() {
-//             if slot.is_none() {
-//                 *slot = Some(file);
-//                 return i;
-//             }
-//         }
-//         // No empty slot, push new
-//         let fd = self.files.len();
-//         self.files.push(Some(file));
-//         fd
-//     }
-    
-//     pub fn get(&self, fd: usize) -> Option<Arc<OpenFile>> {
-//         self.files.get(fd)?.clone()
-//     }
-    
-//     pub fn close(&mut self, fd: usize) -> FsResult<()> {
-//         match self.files.get_mut(fd) {
-//             Some(slot @ Some(_)) => {
-//                 *slot = None;
-//                 Ok(())
-//             }
-//             _ => Err(FsError::NotFound),
-//         }
-//     }
-// }
+use super::vfs::{DirEntry, FileType, FsError, FsResult, INode, OpenFlags, SeekFrom, TNode};
+use super::dev;
+use super::mount::MountUse;
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+use crate::multitasking::preemptive::signal::SleepMutex;
+
+/// Highest number of descriptors a process can have open.
+pub const MAX_FDS: usize = 256;
+
+/// An open file description, shared by every descriptor duplicated or inherited from the
+/// one `open` returned, together with its offset.
+pub struct OpenFile {
+    inode: Arc<dyn INode>,
+    flags: OpenFlags,
+    fileType: FileType,
+    // devices and pipes ignore the offset and don't lock it
+    seekable: bool,
+    // held across the driver call, which may block on a disk, so waiters sleep
+    offset: SleepMutex<u64>,
+    // keeps the file system from being unmounted, None for pipes and the boot console
+    _mount: Option<MountUse>,
+}
+
+impl OpenFile {
+    pub fn new(inode: Arc<dyn INode>, flags: OpenFlags) -> FsResult<Self> {
+        let inode = inode.open(flags)?.unwrap_or(inode);
+        let fileType = inode.stat()?.fileType;
+        let seekable = fileType != FileType::CharDevice && fileType != FileType::Fifo;
+        Ok(Self {
+            inode,
+            flags,
+            fileType,
+            seekable,
+            offset: SleepMutex::new(0),
+            _mount: None,
+        })
+    }
+
+    /// Opens the file a path resolved to.
+    pub fn fromNode(node: &Arc<TNode>, flags: OpenFlags) -> FsResult<Self> {
+        let mut file = Self::new(node.driver().clone(), flags)?;
+        file._mount = node.mount().map(MountUse::new);
+        Ok(file)
+    }
+
+    pub fn inode(&self) -> &Arc<dyn INode> {
+        &self.inode
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadDescriptor);
+        }
+        if self.fileType == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if !self.seekable {
+            return self.inode.read_at(0, buf);
+        }
+
+        let mut offset = self.offset.lock();
+        let bytesRead = self.inode.read_at(*offset, buf)?;
+        *offset += bytesRead as u64;
+        Ok(bytesRead)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadDescriptor);
+        }
+        if !self.seekable {
+            return self.inode.write_at(0, buf);
+        }
+
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.inode.stat()?.size;
+        }
+        let bytesWritten = self.inode.write_at(*offset, buf)?;
+        *offset += bytesWritten as u64;
+        Ok(bytesWritten)
+    }
+
+    /// Lists directory entries from the offset on, which is the cookie of the last entry
+    /// listed. Stops after the last one or when `fill` returns false, the entry it refused is
+    /// listed again next time.
+    pub fn readdir(&self, mut fill: impl FnMut(&DirEntry) -> bool) -> FsResult<()> {
+        if self.fileType != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut cookie = self.offset.lock();
+        loop {
+            match self.inode.readdir(*cookie)? {
+                Some(entry) if fill(&entry) => *cookie = entry.cookie,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Sets the file size, the offset stays where it is.
+    pub fn truncate(&self, size: u64) -> FsResult<()> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadDescriptor);
+        }
+        match self.fileType {
+            FileType::RegularFile => self.inode.truncate(size),
+            FileType::Directory => Err(FsError::IsADirectory),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    pub fn sync(&self) -> FsResult<()> {
+        self.inode.sync()
+    }
+
+    /// Moves the offset and returns the new one, which may lie past the end of the file.
+    pub fn seek(&self, pos: SeekFrom) -> FsResult<u64> {
+        if !self.seekable {
+            return Err(FsError::NotSeekable);
+        }
+
+        let mut offset = self.offset.lock();
+        let newOffset = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => offset.checked_add_signed(n),
+            SeekFrom::End(n) => self.inode.stat()?.size.checked_add_signed(n),
+        };
+
+        *offset = newOffset.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+}
+
+/// File descriptor table (one per process)
+///
+/// Cloning shares the open file descriptions, which is how a new process inherits its
+/// parent's descriptors.
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self {
+            files: Vec::new(),
+        }
+    }
+
+    /// A table with standard input, output and error open on the console.
+    pub fn withStdio() -> Self {
+        let mut table = Self::new();
+        let console = dev::console();
+        for flags in [OpenFlags::READ, OpenFlags::WRITE, OpenFlags::WRITE] {
+            let file = OpenFile::new(console.clone(), flags).expect("Console has no metadata");
+            let _ = table.insert(Arc::new(file));
+        }
+        table
+    }
+
+    /// Allocates the lowest free descriptor for `file`.
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> FsResult<u32> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd as u32);
+        }
+        if self.files.len() >= MAX_FDS {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok((self.files.len() - 1) as u32)
+    }
+
+    pub fn get(&self, fd: u32) -> FsResult<Arc<OpenFile>> {
+        self.files
+            .get(fd as usize)
+            .cloned()
+            .flatten()
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    /// Releases `fd`, the file is closed once no descriptor refers to it anymore.
+    pub fn close(&mut self, fd: u32) -> FsResult<()> {
+        match self.files.get_mut(fd as usize) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                while let Some(None) = self.files.last() {
+                    self.files.pop();
+                }
+                Ok(())
+            }
+            _ => Err(FsError::BadDescriptor),
+        }
+    }
+
+    /// Opens the lowest free descriptor on the same file as `fd`.
+    pub fn dup(&mut self, fd: u32) -> FsResult<u32> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    /// Makes `newFd` refer to the same file as `oldFd`, closing what it referred to before.
+    pub fn dup2(&mut self, oldFd: u32, newFd: u32) -> FsResult<u32> {
+        let file = self.get(oldFd)?;
+        let index = newFd as usize;
+        if index >= MAX_FDS {
+            return Err(FsError::BadDescriptor);
+        }
+        if oldFd != newFd {
+            if self.files.len() <= index {
+                self.files.resize(index + 1, None);
+            }
+            self.files[index] = Some(file);
+        }
+        Ok(newFd)
+    }
+
+    /// Number of open descriptors.
+    pub fn count(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+}
+
+impl fmt::Debug for FdTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FdTable").field("open", &self.count()).finish()
+    }
+}
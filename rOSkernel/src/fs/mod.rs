@@ -1,4 +1,46 @@
+pub mod dev;
 pub mod disk;
+pub mod fat;
 pub mod vfs;
 pub mod fd;
-pub mod simplefs;
\ No newline at end of file
+pub mod mount;
+pub mod path;
+pub mod pipe;
+pub mod ramfs;
+pub mod simplefs;
+
+use alloc::sync::Arc;
+use disk::{registry, BlockDevice};
+use vfs::{FileType, FsError, FsResult, INode};
+
+/// Finds out which file system is on `device`, returning its name and root directory.
+/// `NotSupported` if it's none the kernel knows.
+pub fn probe(device: Arc<dyn BlockDevice>) -> FsResult<(&'static str, Arc<dyn INode>)> {
+    match simplefs::Volume::open(device.clone()) {
+        Err(FsError::NotSupported) => {}
+        result => return result.map(|volume| ("sfs", volume.root())),
+    }
+    let volume = fat::Volume::open(device)?;
+    Ok(("fat", volume.root()))
+}
+
+/// Mounts the file system on the registered block device `rootDevice` as the root, an empty
+/// ramfs if there's none or it can't be mounted, and the devices on `/dev`.
+pub fn init(rootDevice: Option<&str>) {
+    let root = rootDevice.and_then(|name| {
+        let result = registry::get(name).ok_or(FsError::NotFound).and_then(probe);
+        result
+            .inspect_err(|e| log::warn!("Can't mount {} as the root: {:?}", name, e))
+            .ok()
+    });
+    let device = root.as_ref().and(rootDevice);
+    let (fsName, rootINode) = root.unwrap_or_else(|| ("ramfs", ramfs::RamDir::new()));
+    mount::mountRoot(fsName, device, rootINode).expect("Failed to mount the root file system");
+    log::info!("Root file system: {}", fsName);
+
+    match mount::root().create("dev", FileType::Directory) {
+        Ok(_) | Err(FsError::AlreadyExists) => {}
+        Err(e) => panic!("Failed to create /dev: {:?}", e),
+    }
+    mount::mount("/dev", "devfs", None, dev::root()).expect("Failed to mount /dev");
+}
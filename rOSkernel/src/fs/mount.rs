@@ -0,0 +1,197 @@
+//! Mount table: which file system is attached where.
+//!
+//! The first mount is the root file system, every other one hides a directory of a file system
+//! mounted before it. `path::resolve` steps from a mount point onto the root of what's mounted
+//! there and back up again on `..`.
+
+use super::path;
+use super::vfs::{FsError, FsResult, INode, TNode, VINode};
+use crate::multitasking::preemptive;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Mounted file systems, the root first.
+static MOUNTS: Mutex<Vec<Arc<Mount>>> = Mutex::new(Vec::new());
+
+pub struct Mount {
+    path: String,
+    fsName: String,
+    // registered name of the block device the file system is on, None for memory ones
+    device: Option<String>,
+    root: Arc<TNode>,
+    // None for the root file system
+    mountpoint: Option<Arc<TNode>>,
+    // open files on this file system
+    users: AtomicUsize,
+}
+
+impl Mount {
+    fn new(
+        path: String,
+        fsName: &str,
+        device: Option<&str>,
+        rootINode: Arc<dyn INode>,
+        mountpoint: Option<Arc<TNode>>,
+    ) -> FsResult<Arc<Self>> {
+        let vinode = VINode::new(rootINode)?;
+        if !matches!(vinode, VINode::Folder(_)) {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(Arc::new_cyclic(|mount| Self {
+            path,
+            fsName: String::from(fsName),
+            device: device.map(String::from),
+            root: TNode::root(vinode, mount.clone()),
+            mountpoint,
+            users: AtomicUsize::new(0),
+        }))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn fsName(&self) -> &str {
+        &self.fsName
+    }
+
+    /// The block device the file system is on.
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    pub fn root(&self) -> &Arc<TNode> {
+        &self.root
+    }
+
+    /// The directory this file system hides, None for the root file system.
+    pub fn mountpoint(&self) -> Option<Arc<TNode>> {
+        self.mountpoint.clone()
+    }
+
+    /// Number of open files on this file system.
+    pub fn users(&self) -> usize {
+        self.users.load(Ordering::Relaxed)
+    }
+}
+
+/// Keeps a file system from being unmounted while it's alive, held by open files.
+pub struct MountUse(Arc<Mount>);
+
+impl MountUse {
+    pub fn new(mount: Arc<Mount>) -> Self {
+        mount.users.fetch_add(1, Ordering::Relaxed);
+        Self(mount)
+    }
+}
+
+impl Drop for MountUse {
+    fn drop(&mut self) {
+        self.0.users.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Root of the root file system.
+///
+/// Panics before `mountRoot`.
+pub fn root() -> Arc<TNode> {
+    MOUNTS.lock().first().expect("No root file system mounted").root.clone()
+}
+
+/// Makes `rootINode` the root directory, once at boot.
+pub fn mountRoot(fsName: &str, device: Option<&str>, rootINode: Arc<dyn INode>) -> FsResult<()> {
+    let mut mounts = MOUNTS.lock();
+    if !mounts.is_empty() {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount::new(String::from("/"), fsName, device, rootINode, None)?);
+    Ok(())
+}
+
+/// Whether a file system on the block device `device` is mounted.
+pub fn isDeviceMounted(device: &str) -> bool {
+    MOUNTS.lock().iter().any(|mount| mount.device() == Some(device))
+}
+
+/// Attaches the file system whose root directory is `rootINode` at the directory `target`.
+/// `device` is the block device it's on, which can only be mounted once.
+pub fn mount(target: &str, fsName: &str, device: Option<&str>, rootINode: Arc<dyn INode>) -> FsResult<()> {
+    let mountpoint = path::resolve("/", target, true)?;
+    if !mountpoint.isDirectory() {
+        return Err(FsError::NotADirectory);
+    }
+    // resolving crossed any mount on the target already, only its root comes back
+    if mountpoint.parent().is_none() {
+        return Err(FsError::Busy);
+    }
+
+    let mut mounts = MOUNTS.lock();
+    // two volumes caching the same blocks would overwrite each other
+    if device.is_some() && mounts.iter().any(|mount| mount.device() == device) {
+        return Err(FsError::Busy);
+    }
+    let mut mounted = mountpoint.mounted().lock();
+    if mounted.is_some() {
+        return Err(FsError::Busy);
+    }
+    let mount = Mount::new(mountpoint.path(), fsName, device, rootINode, Some(mountpoint.clone()))?;
+    *mounted = Some(mount.clone());
+    mounts.push(mount);
+    Ok(())
+}
+
+/// Detaches the file system mounted at `target`. Fails with `Busy` while files on it are open,
+/// a process works in a directory on it or other file systems are mounted inside it.
+pub fn umount(target: &str) -> FsResult<()> {
+    let root = path::resolve("/", target, true)?;
+    let mount = root.mount().ok_or(FsError::InvalidArgument)?;
+    if !Arc::ptr_eq(&root, &mount.root) {
+        return Err(FsError::InvalidArgument);
+    }
+    let Some(mountpoint) = mount.mountpoint() else {
+        // the root file system stays
+        return Err(FsError::Busy);
+    };
+
+    let rootPath = root.path();
+    let processes = preemptive::processes();
+    let mut mounts = MOUNTS.lock();
+    let nested = mounts.iter().any(|other| {
+        other
+            .mountpoint
+            .as_ref()
+            .and_then(|node| node.mount())
+            .is_some_and(|parent| Arc::ptr_eq(&parent, &mount))
+    });
+    let inCwd = processes.iter().any(|process| isWithin(&process.cwd().lock(), &rootPath));
+    if nested || inCwd || mount.users() > 0 {
+        return Err(FsError::Busy);
+    }
+
+    *mountpoint.mounted().lock() = None;
+    mounts.retain(|other| !Arc::ptr_eq(other, &mount));
+    Ok(())
+}
+
+/// Whether the absolute path `path` is `dir` or below it.
+fn isWithin(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Evicts the cached nodes nothing holds from every mounted file system.
+pub(super) fn pruneDentries() {
+    let mounts = mounts();
+    let mut evicted = Vec::new();
+    for mount in &mounts {
+        mount.root.prune(&mut evicted);
+    }
+}
+
+/// Everything mounted, the root first.
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.lock().clone()
+}
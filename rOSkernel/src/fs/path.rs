@@ -0,0 +1,178 @@
+//! Path resolution through the `TNode` tree.
+//!
+//! Paths are walked one name at a time from the root or the working directory, looking names
+//! up in the directory cache first. `.` is skipped, `..` goes to the parent and from the root of
+//! a mounted file system on to the parent of its mount point, symlinks are followed up to
+//! `MAX_SYMLINKS` deep.
+
+use super::fd::OpenFile;
+use super::mount;
+use super::vfs::{FileType, FsError, FsResult, OpenFlags, TNode};
+use alloc::string::String;
+use alloc::sync::Arc;
+use rOSuser::abi::NAME_MAX;
+
+/// Most symlinks followed while resolving one path.
+const MAX_SYMLINKS: usize = 8;
+
+/// Finds the node at `path`, relative to the absolute path `cwd` unless it starts with `/`.
+/// A symlink at the end is only followed if `followLast` is set.
+pub fn resolve(cwd: &str, path: &str, followLast: bool) -> FsResult<Arc<TNode>> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    let mut links = 0;
+    let start = if path.starts_with('/') {
+        mount::root()
+    } else {
+        walk(mount::root(), cwd, true, &mut links)?
+    };
+    walk(start, path, followLast, &mut links)
+}
+
+/// Finds the directory `path` would be created in, and the name it would get there.
+pub fn resolveParent<'a>(cwd: &str, path: &'a str) -> FsResult<(Arc<TNode>, &'a str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = match trimmed.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => (".", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+
+    let parent = resolve(cwd, dir, true)?;
+    if !parent.isDirectory() {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((parent, name))
+}
+
+fn walk(start: Arc<TNode>, path: &str, followLast: bool, links: &mut usize) -> FsResult<Arc<TNode>> {
+    let mut node = if path.starts_with('/') { mount::root() } else { start };
+    let mustBeDirectory = path.ends_with('/');
+    let mut names = path.split('/').filter(|name| !name.is_empty() && *name != ".").peekable();
+
+    while let Some(name) = names.next() {
+        if !node.isDirectory() {
+            return Err(FsError::NotADirectory);
+        }
+        if name == ".." {
+            node = parentOf(node);
+            continue;
+        }
+
+        let child = crossMounts(node.lookup(name)?);
+        let last = names.peek().is_none();
+        node = if child.fileType() == FileType::Symlink && (!last || followLast || mustBeDirectory) {
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            // relative targets start in the directory holding the link
+            let target = child.driver().readlink()?;
+            walk(node, &target, true, links)?
+        } else {
+            child
+        };
+    }
+
+    if mustBeDirectory && !node.isDirectory() {
+        return Err(FsError::NotADirectory);
+    }
+    Ok(node)
+}
+
+/// Where `..` leads, the root is its own parent.
+fn parentOf(mut node: Arc<TNode>) -> Arc<TNode> {
+    loop {
+        if let Some(parent) = node.parent() {
+            return parent;
+        }
+        match node.mount().and_then(|mount| mount.mountpoint()) {
+            Some(mountpoint) => node = mountpoint,
+            None => return node,
+        }
+    }
+}
+
+/// The root of whatever is mounted on `node`, or `node` itself.
+fn crossMounts(mut node: Arc<TNode>) -> Arc<TNode> {
+    while let Some(root) = node.mountedRoot() {
+        node = root;
+    }
+    node
+}
+
+/// Opens `path`, creating a regular file there first if `flags` ask for it.
+pub fn open(cwd: &str, path: &str, flags: OpenFlags) -> FsResult<OpenFile> {
+    let exclusive = flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE);
+    let node = match resolve(cwd, path, !exclusive) {
+        Ok(_) if exclusive => return Err(FsError::AlreadyExists),
+        Ok(node) => node,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (dir, name) = resolveParent(cwd, path)?;
+            match dir.create(name, FileType::RegularFile) {
+                // someone else created it in the meantime
+                Err(FsError::AlreadyExists) if !exclusive => dir.lookup(name)?,
+                result => result?,
+            }
+        }
+        Err(e) => return Err(e),
+    };
+
+    if node.isDirectory() && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::IsADirectory);
+    }
+    if flags.contains(OpenFlags::TRUNCATE)
+        && flags.contains(OpenFlags::WRITE)
+        && node.fileType() == FileType::RegularFile
+        && node.driver().stat()?.size > 0
+    {
+        node.driver().truncate(0)?;
+    }
+    OpenFile::fromNode(&node, flags)
+}
+
+/// Makes a directory at `path`.
+pub fn mkdir(cwd: &str, path: &str) -> FsResult<()> {
+    let (dir, name) = resolveParent(cwd, path)?;
+    dir.create(name, FileType::Directory).map(|_| ())
+}
+
+/// Removes the file at `path`, a symlink itself rather than its target.
+pub fn unlink(cwd: &str, path: &str) -> FsResult<()> {
+    let (dir, name) = resolveParent(cwd, path)?;
+    dir.remove(name, false)
+}
+
+/// Removes the empty directory at `path`.
+pub fn rmdir(cwd: &str, path: &str) -> FsResult<()> {
+    let (dir, name) = resolveParent(cwd, path)?;
+    dir.remove(name, true)
+}
+
+/// Renames `from` to `to`, both in the same directory since drivers rename within one.
+pub fn rename(cwd: &str, from: &str, to: &str) -> FsResult<()> {
+    let (dir, oldname) = resolveParent(cwd, from)?;
+    let (newDir, newname) = resolveParent(cwd, to)?;
+    if !Arc::ptr_eq(&dir, &newDir) {
+        return Err(FsError::NotSupported);
+    }
+    dir.rename(oldname, newname)
+}
+
+/// Makes a symlink at `path` pointing to `target`, which isn't checked.
+pub fn symlink(cwd: &str, target: &str, path: &str) -> FsResult<()> {
+    let (dir, name) = resolveParent(cwd, path)?;
+    dir.symlink(name, target)
+}
+
+/// The canonical absolute path of `path`, symlinks resolved.
+pub fn canonicalize(cwd: &str, path: &str) -> FsResult<String> {
+    Ok(resolve(cwd, path, true)?.path())
+}
@@ -0,0 +1,277 @@
+//! Pipes: a bounded byte buffer with a read end and a write end.
+//!
+//! Readers block while the buffer is empty and see end of file once every write end is
+//! closed, writers block while it's full and get `BrokenPipe` once every read end is closed.
+//! An end counts as closed when the last descriptor on its open file goes away.
+
+use super::vfs::{not_a_directory, FileStat, FileType, FsError, FsResult, INode, OpenFlags};
+use crate::multitasking::preemptive::signal::WaitQueue;
+use crate::multitasking::preemptive::yield_now;
+use alloc::{boxed::Box, sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Bytes a pipe buffers before writers block.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buf: Box<[u8; PIPE_CAPACITY]>,
+    // ring buffer: `len` bytes starting at `head`
+    head: usize,
+    len: usize,
+    readers: usize,
+    writers: usize,
+}
+
+impl PipeState {
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        for byte in &mut out[..n] {
+            *byte = self.buf[self.head];
+            self.head = (self.head + 1) % PIPE_CAPACITY;
+        }
+        self.len -= n;
+        n
+    }
+
+    fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(PIPE_CAPACITY - self.len);
+        for &byte in &data[..n] {
+            self.buf[(self.head + self.len) % PIPE_CAPACITY] = byte;
+            self.len += 1;
+        }
+        n
+    }
+}
+
+pub struct Pipe {
+    // only locked with interrupts off, a holder preempted with it would leave any other user on
+    // its CPU spinning with interrupts off for good
+    state: Mutex<PipeState>,
+    // readers waiting for data or a writer
+    readable: WaitQueue,
+    // writers waiting for space or a reader
+    writable: WaitQueue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum End {
+    Read,
+    Write,
+    /// A FIFO opened for reading and writing, it counts as both.
+    Both,
+}
+
+impl End {
+    fn reads(self) -> bool {
+        self != End::Write
+    }
+
+    fn writes(self) -> bool {
+        self != End::Read
+    }
+}
+
+impl Pipe {
+    pub fn new() -> Arc<Pipe> {
+        Arc::new(Pipe {
+            state: Mutex::new(PipeState {
+                buf: Box::new([0; PIPE_CAPACITY]),
+                head: 0,
+                len: 0,
+                readers: 0,
+                writers: 0,
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        })
+    }
+
+    /// Creates a read end and a write end for an anonymous pipe.
+    pub fn ends(self: &Arc<Self>) -> (Arc<dyn INode>, Arc<dyn INode>) {
+        (PipeEnd::new(self.clone(), End::Read), PipeEnd::new(self.clone(), End::Write))
+    }
+
+    fn withState<R>(&self, f: impl FnOnce(&mut PipeState) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Blocks on `queue` until `ready` holds, then runs `then` on the state while still holding
+    /// the lock.
+    fn waitUntil<R>(
+        &self,
+        queue: &WaitQueue,
+        ready: impl Fn(&PipeState) -> bool,
+        then: impl FnOnce(&mut PipeState) -> R,
+    ) -> R {
+        let mut then = Some(then);
+        loop {
+            let slept = interrupts::without_interrupts(|| {
+                let mut state = self.state.lock();
+                if ready(&state) {
+                    return Ok(then.take().unwrap()(&mut state));
+                }
+                // the state stays locked until we're asleep, so a waker can't slip in between
+                Err(queue.sleep())
+            });
+
+            match slept {
+                Ok(result) => return result,
+                Err(true) => yield_now(),
+                Err(false) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // empty with no writer left is end of file
+        let n = self.waitUntil(&self.readable, |s| s.len > 0 || s.writers == 0, |s| s.pop(buf));
+
+        if n > 0 {
+            self.writable.wake_all();
+        }
+        Ok(n)
+    }
+
+    /// Writes all of `buf`, blocking for space as often as needed. Stops early only when the
+    /// last reader goes away, reporting what was written up to then.
+    fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let pushed = self.waitUntil(
+                &self.writable,
+                |s| s.len < PIPE_CAPACITY || s.readers == 0,
+                |s| (s.readers > 0).then(|| s.push(&buf[written..])),
+            );
+            let Some(pushed) = pushed else {
+                return if written > 0 { Ok(written) } else { Err(FsError::BrokenPipe) };
+            };
+            written += pushed;
+
+            self.readable.wake_all();
+        }
+        Ok(written)
+    }
+
+    fn buffered(&self) -> usize {
+        self.withState(|s| s.len)
+    }
+
+    /// Blocks until the other side of a FIFO has been opened at least once. An end that
+    /// reads and writes is its own peer.
+    fn waitForPeer(&self, end: End) {
+        match end {
+            End::Read => self.waitUntil(&self.readable, |s| s.writers > 0, |_| ()),
+            End::Write => self.waitUntil(&self.writable, |s| s.readers > 0, |_| ()),
+            End::Both => {}
+        }
+    }
+}
+
+fn fifoStat(size: usize) -> FileStat {
+    FileStat {
+        fileType: FileType::Fifo,
+        size: size as u64,
+        blockSize: 0,
+        blocks: 0,
+    }
+}
+
+/// One end of a pipe, the node behind an open file on it.
+struct PipeEnd {
+    pipe: Arc<Pipe>,
+    end: End,
+}
+
+impl PipeEnd {
+    fn new(pipe: Arc<Pipe>, end: End) -> Arc<dyn INode> {
+        pipe.withState(|state| {
+            state.readers += end.reads() as usize;
+            state.writers += end.writes() as usize;
+        });
+        // a FIFO opener may be waiting for this end
+        if end.reads() {
+            pipe.writable.wake_all();
+        }
+        if end.writes() {
+            pipe.readable.wake_all();
+        }
+        Arc::new(PipeEnd { pipe, end })
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        self.pipe.withState(|state| {
+            state.readers -= self.end.reads() as usize;
+            state.writers -= self.end.writes() as usize;
+        });
+        // the other side sees end of file or a broken pipe
+        if self.end.reads() {
+            self.pipe.writable.wake_all();
+        }
+        if self.end.writes() {
+            self.pipe.readable.wake_all();
+        }
+    }
+}
+
+impl INode for PipeEnd {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(fifoStat(self.pipe.buffered()))
+    }
+
+    not_a_directory!();
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        match self.end.reads() {
+            true => self.pipe.read(buf),
+            false => Err(FsError::BadDescriptor),
+        }
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> FsResult<usize> {
+        match self.end.writes() {
+            true => self.pipe.write(buf),
+            false => Err(FsError::BadDescriptor),
+        }
+    }
+}
+
+/// Named pipe, the node a file system keeps for a `FileType::Fifo` entry. Everyone opening
+/// it shares one pipe, opening blocks until both a reader and a writer are there, unless it
+/// is opened for both.
+pub struct Fifo {
+    pipe: Arc<Pipe>,
+}
+
+impl Fifo {
+    pub fn new() -> Self {
+        Self { pipe: Pipe::new() }
+    }
+}
+
+impl INode for Fifo {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(fifoStat(self.pipe.buffered()))
+    }
+
+    not_a_directory!();
+
+    fn open(&self, flags: OpenFlags) -> FsResult<Option<Arc<dyn INode>>> {
+        let end = match (flags.contains(OpenFlags::READ), flags.contains(OpenFlags::WRITE)) {
+            (true, false) => End::Read,
+            (false, true) => End::Write,
+            (true, true) => End::Both,
+            (false, false) => return Err(FsError::InvalidArgument),
+        };
+
+        let node = PipeEnd::new(self.pipe.clone(), end);
+        self.pipe.waitForPeer(end);
+        Ok(Some(node))
+    }
+}
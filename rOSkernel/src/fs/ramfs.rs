@@ -0,0 +1,199 @@
+//! File system kept entirely in memory, the root until a disk is mounted over it.
+
+use super::pipe::Fifo;
+use super::vfs::{not_a_directory, DirEntry, FileStat, FileType, FsError, FsResult, INode};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Sizes in `stat` are counted in blocks of this many bytes.
+const BLOCK_SIZE: u32 = 512;
+
+fn stat(fileType: FileType, size: u64) -> FileStat {
+    FileStat {
+        fileType,
+        size,
+        blockSize: BLOCK_SIZE,
+        blocks: size.div_ceil(BLOCK_SIZE as u64),
+    }
+}
+
+/// A directory, an empty one is the root of a new ramfs.
+pub struct RamDir {
+    entries: Mutex<BTreeMap<String, Arc<dyn INode>>>,
+}
+
+impl RamDir {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            entries: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn insert(&self, name: &str, node: Arc<dyn INode>) -> FsResult<Arc<dyn INode>> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+}
+
+impl INode for RamDir {
+    /// The size of a directory is its number of entries.
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(FileStat {
+            fileType: FileType::Directory,
+            size: self.entries.lock().len() as u64,
+            blockSize: BLOCK_SIZE,
+            blocks: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn INode>> {
+        self.entries.lock().get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> FsResult<Arc<dyn INode>> {
+        let node: Arc<dyn INode> = match kind {
+            FileType::RegularFile => Arc::new(RamFile { data: Mutex::new(Vec::new()) }),
+            FileType::Directory => RamDir::new(),
+            FileType::Fifo => Arc::new(Fifo::new()),
+            _ => return Err(FsError::NotSupported),
+        };
+        self.insert(name, node)
+    }
+
+    fn mkdir(&self, name: &str) -> FsResult<Arc<dyn INode>> {
+        self.insert(name, RamDir::new())
+    }
+
+    fn rmdir(&self, name: &str) -> FsResult<()> {
+        let mut entries = self.entries.lock();
+        let stat = entries.get(name).ok_or(FsError::NotFound)?.stat()?;
+        if stat.fileType != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if stat.size > 0 {
+            return Err(FsError::NotEmpty);
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, oldname: &str, newname: &str) -> FsResult<()> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(newname) {
+            return Err(FsError::AlreadyExists);
+        }
+        let node = entries.remove(oldname).ok_or(FsError::NotFound)?;
+        entries.insert(String::from(newname), node);
+        Ok(())
+    }
+
+    /// Hard links need the target's own `Arc`, which a `&dyn INode` doesn't give.
+    fn link(&self, _name: &str, _target: &dyn INode) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let mut entries = self.entries.lock();
+        let node = entries.get(name).ok_or(FsError::NotFound)?;
+        if node.stat()?.fileType == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<()> {
+        self.insert(name, Arc::new(RamSymlink { target: String::from(target) }))
+            .map(|_| ())
+    }
+
+    /// Cookies are indices in name order, entries added or removed meanwhile can shift an
+    /// ongoing listing by one.
+    fn readdir(&self, cookie: u64) -> FsResult<Option<DirEntry>> {
+        let entries = self.entries.lock();
+        let Some((name, node)) = usize::try_from(cookie).ok().and_then(|index| entries.iter().nth(index)) else {
+            return Ok(None);
+        };
+        Ok(Some(DirEntry {
+            name: name.clone(),
+            fileType: node.stat()?.fileType,
+            cookie: cookie + 1,
+        }))
+    }
+}
+
+struct RamFile {
+    data: Mutex<Vec<u8>>,
+}
+
+impl INode for RamFile {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(stat(FileType::RegularFile, self.data.lock().len() as u64))
+    }
+
+    not_a_directory!();
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let data = self.data.lock();
+        let Some(rest) = usize::try_from(offset).ok().and_then(|offset| data.get(offset..)) else {
+            return Ok(0);
+        };
+        let n = buf.len().min(rest.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+
+    /// Writing past the end fills the gap with zeroes.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let offset = usize::try_from(offset).map_err(|_| FsError::FileTooLarge)?;
+        let end = offset.checked_add(buf.len()).ok_or(FsError::FileTooLarge)?;
+        let mut data = self.data.lock();
+        if data.len() < end {
+            grow(&mut data, end)?;
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        let size = usize::try_from(size).map_err(|_| FsError::FileTooLarge)?;
+        let mut data = self.data.lock();
+        if size > data.len() {
+            grow(&mut data, size)?;
+        } else {
+            data.truncate(size);
+            data.shrink_to_fit();
+        }
+        Ok(())
+    }
+}
+
+/// Zero-fills `data` up to `size` bytes, failing instead of panicking when the heap is full.
+fn grow(data: &mut Vec<u8>, size: usize) -> FsResult<()> {
+    data.try_reserve(size - data.len()).map_err(|_| FsError::NoSpace)?;
+    data.resize(size, 0);
+    Ok(())
+}
+
+struct RamSymlink {
+    target: String,
+}
+
+impl INode for RamSymlink {
+    fn stat(&self) -> FsResult<FileStat> {
+        Ok(stat(FileType::Symlink, self.target.len() as u64))
+    }
+
+    not_a_directory!();
+
+    fn readlink(&self) -> FsResult<String> {
+        Ok(self.target.clone())
+    }
+}
@@ -1,5 +1,11 @@
-use alloc::{boxed::Box, string::String, sync::Arc};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use hashbrown::HashMap;
+use rOSuser::abi::Errno;
+use spin::Mutex;
+use super::mount::Mount;
 
 /// Errors that can occur during filesystem operations
 #[derive(Debug, Clone, Copy)]
@@ -14,20 +20,67 @@ pub enum FsError {
     NoSpace,
     IoError,
     NotSupported,
+    /// Not an open descriptor, or not open for the requested access.
+    BadDescriptor,
+    TooManyOpenFiles,
+    /// Pipes and devices have no file offset.
+    NotSeekable,
+    InvalidArgument,
+    /// Writing to a pipe nobody reads from anymore.
+    BrokenPipe,
+    /// Still in use, like a mount point or a file system with open files.
+    Busy,
+    /// Too many symlinks while resolving a path.
+    TooManyLinks,
+    /// Past the largest size the file system can store.
+    FileTooLarge,
+    /// The file system can't be written to.
+    ReadOnly,
+    NameTooLong,
+    /// On-disk structures that don't make sense.
+    Corrupted,
 }
 
 pub type FsResult<T> = Result<T, FsError>;
 
+impl From<FsError> for Errno {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::PermissionDenied => Errno::EACCES,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::NotEmpty => Errno::ENOTEMPTY,
+            FsError::InvalidPath => Errno::EINVAL,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::IoError => Errno::EIO,
+            FsError::NotSupported => Errno::EINVAL,
+            FsError::BadDescriptor => Errno::EBADF,
+            FsError::TooManyOpenFiles => Errno::EMFILE,
+            FsError::NotSeekable => Errno::ESPIPE,
+            FsError::InvalidArgument => Errno::EINVAL,
+            FsError::BrokenPipe => Errno::EPIPE,
+            FsError::Busy => Errno::EBUSY,
+            FsError::TooManyLinks => Errno::ELOOP,
+            FsError::FileTooLarge => Errno::EFBIG,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::NameTooLong => Errno::ENAMETOOLONG,
+            FsError::Corrupted => Errno::EIO,
+        }
+    }
+}
+
 /// File types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileType {
     RegularFile,
     Directory,
-    // CharDevice,
-    // BlockDevice,
-    // Symlink,
+    CharDevice,
+    BlockDevice,
+    Symlink,
     // Socket,
-    // Fifo,
+    Fifo,
 }
 
 /// File metadata
@@ -52,6 +105,15 @@ bitflags::bitflags! {
     }
 }
 
+/// One entry of a directory listing.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub fileType: FileType,
+    /// Where `readdir` continues after this entry.
+    pub cookie: u64,
+}
+
 /// Seek origin for lseek
 #[derive(Debug, Clone, Copy)]
 pub enum SeekFrom {
@@ -60,9 +122,22 @@ pub enum SeekFrom {
     End(i64),
 }
 
+/// Once more nodes than this are alive, `TNode::lookup` evicts the unused ones.
+const DENTRY_CACHE_LIMIT: usize = 1024;
+/// Number of `TNode`s alive, nearly all of them held by the dentry cache.
+static LIVE_NODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory entry: a name in the tree the VFS walks, the dentry. Directories cache the
+/// children looked up through them so far, so a path only reaches the drivers once.
 pub struct TNode {
     pub name: String,
-    pub vinode: Arc<VINode>,
+    // None for the root of a mounted file system
+    parent: Option<Weak<TNode>>,
+    pub vinode: VINode,
+    // the mount this node belongs to
+    mount: Weak<Mount>,
+    // file system mounted on top of this node, which then hides it
+    mounted: Mutex<Option<Arc<Mount>>>,
 }
 
 pub enum VINode {
@@ -71,26 +146,335 @@ pub enum VINode {
 }
 
 pub struct VFolderINode {
-    pub entries: HashMap<String, Arc<TNode>>,
-    pub driverINode: Box<dyn INode>,
+    pub entries: Mutex<HashMap<String, Arc<TNode>>>,
+    pub driverINode: Arc<dyn INode>,
 }
 
+/// Anything that isn't a directory: files, devices, pipes and symlinks.
 pub struct VFileINode {
-    //TODO: other metadata
-    pub created: u64,
-    pub modified: u64,
-    pub size: u64,
-    pub driverINode: Box<dyn INode>,
+    pub fileType: FileType,
+    pub driverINode: Arc<dyn INode>,
+}
+
+impl VINode {
+    pub(super) fn new(driverINode: Arc<dyn INode>) -> FsResult<Self> {
+        Ok(match driverINode.stat()?.fileType {
+            FileType::Directory => VINode::Folder(VFolderINode {
+                entries: Mutex::new(HashMap::new()),
+                driverINode,
+            }),
+            fileType => VINode::File(VFileINode { fileType, driverINode }),
+        })
+    }
+}
+
+impl TNode {
+    /// Root of a mounted file system.
+    pub(super) fn root(vinode: VINode, mount: Weak<Mount>) -> Arc<Self> {
+        LIVE_NODES.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Self {
+            name: String::from("/"),
+            parent: None,
+            vinode,
+            mount,
+            mounted: Mutex::new(None),
+        })
+    }
+
+    fn child(self: &Arc<Self>, name: &str, driverINode: Arc<dyn INode>) -> FsResult<Arc<Self>> {
+        let vinode = VINode::new(driverINode)?;
+        LIVE_NODES.fetch_add(1, Ordering::Relaxed);
+        Ok(Arc::new(Self {
+            name: String::from(name),
+            parent: Some(Arc::downgrade(self)),
+            vinode,
+            mount: self.mount.clone(),
+            mounted: Mutex::new(None),
+        }))
+    }
+
+    pub fn driver(&self) -> &Arc<dyn INode> {
+        match &self.vinode {
+            VINode::File(file) => &file.driverINode,
+            VINode::Folder(folder) => &folder.driverINode,
+        }
+    }
+
+    pub fn fileType(&self) -> FileType {
+        match &self.vinode {
+            VINode::File(file) => file.fileType,
+            VINode::Folder(_) => FileType::Directory,
+        }
+    }
+
+    pub fn isDirectory(&self) -> bool {
+        matches!(self.vinode, VINode::Folder(_))
+    }
+
+    pub fn mount(&self) -> Option<Arc<Mount>> {
+        self.mount.upgrade()
+    }
+
+    /// The parent directory within the same file system, None at its root.
+    pub fn parent(&self) -> Option<Arc<TNode>> {
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+
+    /// The root of the file system mounted here, if any.
+    pub fn mountedRoot(&self) -> Option<Arc<TNode>> {
+        self.mounted.lock().as_ref().map(|mount| mount.root().clone())
+    }
+
+    pub(super) fn mounted(&self) -> &Mutex<Option<Arc<Mount>>> {
+        &self.mounted
+    }
+
+    fn hasCachedChildren(&self) -> bool {
+        self.entries().is_ok_and(|entries| !entries.lock().is_empty())
+    }
+
+    fn entries(&self) -> FsResult<&Mutex<HashMap<String, Arc<TNode>>>> {
+        match &self.vinode {
+            VINode::Folder(folder) => Ok(&folder.entries),
+            VINode::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// Finds `name` in this directory, from the cache or else the driver. Doesn't cross mounts.
+    pub fn lookup(self: &Arc<Self>, name: &str) -> FsResult<Arc<TNode>> {
+        let entries = self.entries()?;
+        if let Some(cached) = entries.lock().get(name) {
+            return Ok(cached.clone());
+        }
+
+        // the driver may block on a disk, so the cache isn't locked meanwhile
+        let node = self.child(name, self.driver().lookup(name)?)?;
+        let node = entries.lock().entry(String::from(name)).or_insert(node).clone();
+        if LIVE_NODES.load(Ordering::Relaxed) > DENTRY_CACHE_LIMIT {
+            super::mount::pruneDentries();
+        }
+        Ok(node)
+    }
+
+    /// Creates `name` in this directory through the driver and caches it.
+    pub fn create(self: &Arc<Self>, name: &str, kind: FileType) -> FsResult<Arc<TNode>> {
+        let entries = self.entries()?;
+        let driverINode = match kind {
+            FileType::Directory => self.driver().mkdir(name)?,
+            kind => self.driver().create(name, kind)?,
+        };
+        let node = self.child(name, driverINode)?;
+        entries.lock().insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    pub fn symlink(self: &Arc<Self>, name: &str, target: &str) -> FsResult<()> {
+        self.entries()?;
+        self.driver().symlink(name, target)
+    }
+
+    /// Removes `name`, a directory only if `directory` is set. Mount points are busy.
+    pub fn remove(self: &Arc<Self>, name: &str, directory: bool) -> FsResult<()> {
+        let entries = self.entries()?;
+        let node = self.lookup(name)?;
+        if node.mounted.lock().is_some() {
+            return Err(FsError::Busy);
+        }
+        match (directory, node.isDirectory()) {
+            (true, true) => self.driver().rmdir(name)?,
+            (false, false) => self.driver().unlink(name)?,
+            (true, false) => return Err(FsError::NotADirectory),
+            (false, true) => return Err(FsError::IsADirectory),
+        }
+        entries.lock().remove(name);
+        Ok(())
+    }
+
+    /// Renames `oldname` to `newname` in this directory, replacing `newname` if it exists.
+    /// Directories with mount points or nodes in use below them are busy.
+    pub fn rename(self: &Arc<Self>, oldname: &str, newname: &str) -> FsResult<()> {
+        let entries = self.entries()?;
+        let node = self.lookup(oldname)?;
+        let replaced = match self.lookup(newname) {
+            Ok(replaced) => Some(replaced),
+            Err(FsError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        for node in core::iter::once(&node).chain(&replaced) {
+            let mut evicted = Vec::new();
+            node.prune(&mut evicted);
+            drop(evicted);
+            // whatever is left below is in use and would keep the old path
+            if node.mounted.lock().is_some() || Arc::weak_count(node) > 0 {
+                return Err(FsError::Busy);
+            }
+        }
+
+        self.driver().rename(oldname, newname)?;
+        // the nodes carry their names, the next lookups make new ones
+        let mut entries = entries.lock();
+        let old = (entries.remove(oldname), entries.remove(newname));
+        drop(entries);
+        drop(old);
+        Ok(())
+    }
+
+    /// Evicts the cached nodes below this one that nothing else holds, into `evicted` so they
+    /// are dropped once no cache is locked anymore.
+    pub(super) fn prune(&self, evicted: &mut Vec<Arc<TNode>>) {
+        let VINode::Folder(folder) = &self.vinode else {
+            return;
+        };
+        folder.entries.lock().retain(|_, node| {
+            node.prune(evicted);
+            // lookups clone from the locked cache, and nodes in use below hold a weak parent
+            let unused = Arc::strong_count(node) == 1
+                && Arc::weak_count(node) == 0
+                && !node.hasCachedChildren();
+            if unused {
+                evicted.push(node.clone());
+            }
+            !unused
+        });
+    }
+
+    /// Absolute path of this node, across mounts.
+    pub fn path(self: &Arc<Self>) -> String {
+        let mut names = Vec::new();
+        let mut node = self.clone();
+        loop {
+            if let Some(parent) = node.parent() {
+                names.push(node.name.clone());
+                node = parent;
+                continue;
+            }
+            match node.mount().and_then(|mount| mount.mountpoint()) {
+                Some(mountpoint) => node = mountpoint,
+                None => break,
+            }
+        }
+
+        if names.is_empty() {
+            return String::from("/");
+        }
+        names.iter().rev().fold(String::new(), |mut path, name| {
+            path.push('/');
+            path.push_str(name);
+            path
+        })
+    }
+}
+
+impl Drop for TNode {
+    fn drop(&mut self) {
+        LIVE_NODES.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub trait INode: Send + Sync {
     fn stat(&self) -> FsResult<FileStat>;
-    fn lookup(&self, name: &str) -> FsResult<Box<dyn INode>>;
-    fn create(&self, name: &str, kind: FileType) -> FsResult<Box<dyn INode>>;
-    fn mkdir(&self, name: &str) -> FsResult<Box<dyn INode>>;
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn INode>>;
+    fn create(&self, name: &str, kind: FileType) -> FsResult<Arc<dyn INode>>;
+    fn mkdir(&self, name: &str) -> FsResult<Arc<dyn INode>>;
     fn rmdir(&self, name: &str) -> FsResult<()>;
     fn rename(&self, oldname: &str, newname: &str) -> FsResult<()>;
     fn link(&self, name: &str, target: &dyn INode) -> FsResult<()>;
     fn unlink(&self, name: &str) -> FsResult<()>;
     fn symlink(&self, name: &str, target: &str) -> FsResult<()>;
+
+    /// The entry at `cookie`, None past the last one. Listing starts at cookie 0 and goes on
+    /// with the cookie of the previous entry, otherwise cookies mean whatever the driver likes,
+    /// an index or an offset on disk. `.` and `..` aren't listed.
+    fn readdir(&self, cookie: u64) -> FsResult<Option<DirEntry>>;
+
+    /// Target of a symlink.
+    fn readlink(&self) -> FsResult<String> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Called when the node is opened, special files like FIFOs return the node the open
+    /// file should use instead of this one.
+    fn open(&self, _flags: OpenFlags) -> FsResult<Option<Arc<dyn INode>>> {
+        Ok(None)
+    }
+
+    /// Reads from byte `offset`, returns how much was read, 0 at the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Writes at byte `offset`, returns how much was written.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Sets the size, dropping what's past it or growing with zeroes.
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Writes anything the driver holds back for this node to the device.
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+/// Implements the directory operations of `INode` for a node that isn't one, like a device
+/// or a pipe.
+macro_rules! not_a_directory {
+    () => {
+        fn lookup(
+            &self,
+            _name: &str,
+        ) -> $crate::fs::vfs::FsResult<alloc::sync::Arc<dyn $crate::fs::vfs::INode>> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn create(
+            &self,
+            _name: &str,
+            _kind: $crate::fs::vfs::FileType,
+        ) -> $crate::fs::vfs::FsResult<alloc::sync::Arc<dyn $crate::fs::vfs::INode>> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn mkdir(
+            &self,
+            _name: &str,
+        ) -> $crate::fs::vfs::FsResult<alloc::sync::Arc<dyn $crate::fs::vfs::INode>> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn rmdir(&self, _name: &str) -> $crate::fs::vfs::FsResult<()> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn rename(&self, _oldname: &str, _newname: &str) -> $crate::fs::vfs::FsResult<()> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn link(
+            &self,
+            _name: &str,
+            _target: &dyn $crate::fs::vfs::INode,
+        ) -> $crate::fs::vfs::FsResult<()> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn unlink(&self, _name: &str) -> $crate::fs::vfs::FsResult<()> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn symlink(&self, _name: &str, _target: &str) -> $crate::fs::vfs::FsResult<()> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+
+        fn readdir(
+            &self,
+            _cookie: u64,
+        ) -> $crate::fs::vfs::FsResult<Option<$crate::fs::vfs::DirEntry>> {
+            Err($crate::fs::vfs::FsError::NotADirectory)
+        }
+    };
 }
+pub(crate) use not_a_directory;
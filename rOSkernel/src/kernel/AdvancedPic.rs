@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use core::alloc::Allocator;
 use core::ops::Add;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use crate::kernel::kernelContext;
 use crate::mem::memory;
 use acpi::InterruptModel;
@@ -11,21 +12,39 @@ use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, P
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::mapper::MapToError;
-use crate::kernel::binIO;
+use crate::kernel::{binIO, timer};
 use crate::kernel::interrupts::InterruptIndex;
+use crate::util::wrappers::rdtsc;
+use cpuid::CPUID;
 
 
 const LAPIC_ID_REG: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
 const LAPIC_TIMER_REG: usize = 0x320;
 const LAPIC_TIMER_INIT_COUNT: usize = 0x380;
 const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
 const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+const LAPIC_LVT_PERF_COUNTER: usize = 0x340;
+const LAPIC_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const LAPIC_TIMER_MODE_ONESHOT: u32 = 0b00 << 17;
+const LAPIC_TIMER_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
 
 /// AdvancedPIC provides APIC management similar to the ChainedPics from pic8259.
 #[derive(Debug)]
 pub struct AdvancedPic {
     lapicBase: VirtAddr,
     ioApicBases: Vec<VirtAddr>,
+    timerTicksPerMs: AtomicU32,
+    tscDeadline: AtomicBool,
 }
 
 impl AdvancedPic {
@@ -47,6 +66,8 @@ impl AdvancedPic {
         let mut advancedPic = AdvancedPic {
             lapicBase: VirtAddr::zero(),
             ioApicBases: Vec::new(),
+            timerTicksPerMs: AtomicU32::new(0),
+            tscDeadline: AtomicBool::new(false),
         };
 
         match apic {
@@ -65,10 +86,9 @@ impl AdvancedPic {
                 binIO::out8(0x21, 0xFF);
                 binIO::out8(0xA1, 0xFF);
                 // set lapic TPR=0
-                advancedPic.lapicWrite(0x80, 0);
+                advancedPic.lapicWrite(LAPIC_TPR, 0);
                 info!("Local APIC enabled (IA32_APIC_BASE=0x{:X})", value);
 
-                const LAPIC_SVR: usize = 0xF0;
                 advancedPic.lapicWrite(LAPIC_SVR, 0x100 | 0xFF);
 
                 let mut ioApicIdx = 0;
@@ -153,7 +173,8 @@ impl AdvancedPic {
         }
     }
 
-    unsafe fn calibrateApicTimer(&self) -> u32 { unsafe {
+    /// Measures the APIC timer and TSC rates against the PIT, returns (APIC ticks, TSC cycles) per ms.
+    unsafe fn calibrateApicTimer(&self) -> (u32, u64) { unsafe {
         const PIT_CH2_GATE: u16 = 0x61;
         const PIT_CH2_DATA: u16 = 0x42;
         const PIT_CMD: u16 = 0x43;
@@ -176,12 +197,14 @@ impl AdvancedPic {
 
         self.lapicWrite(LAPIC_TIMER_DIVIDE, 0x3); // divide by 16
         self.lapicWrite(LAPIC_TIMER_INIT_COUNT, 0xFFFFFFFF);
+        let tscStart = rdtsc();
 
         // wait for PIT to expire
         while (binIO::in8(PIT_CH2_GATE) & 0x20) == 0 {
             core::hint::spin_loop();
         }
 
+        let tscElapsed = rdtsc() - tscStart;
         self.lapicWrite(LAPIC_TIMER_REG, 1 << 16); // mask timer
         let elapsed = 0xFFFFFFFF - self.lapicRead(LAPIC_TIMER_CURRENT_COUNT);
 
@@ -189,26 +212,85 @@ impl AdvancedPic {
         binIO::out8(PIT_CH2_GATE, initial);
 
         let ticksPerMs = elapsed / CALIBRATION_MS;
-        log::trace!("APIC Timer calibrated: {} ticks/ms", ticksPerMs);
-        ticksPerMs
+        let tscPerMs = tscElapsed / CALIBRATION_MS as u64;
+        log::trace!("APIC Timer calibrated: {} ticks/ms, TSC {} cycles/ms", ticksPerMs, tscPerMs);
+        (ticksPerMs, tscPerMs)
     }}
 
+    /// Sets up the timer for tickless operation.
+    ///
+    /// The timer is left disarmed, the scheduler programs each event with `armTimer`.
+    /// TSC-deadline mode is used when the CPU supports it, one-shot mode otherwise.
     pub fn initAPICTimer(&self) {
-        let ticksPerMs = unsafe { self.calibrateApicTimer() };
+        let (ticksPerMs, calibratedTscPerMs) = unsafe { self.calibrateApicTimer() };
+        self.timerTicksPerMs.store(ticksPerMs, Ordering::Relaxed);
+
+        // prefer the enumerated TSC frequency over the 1ms PIT measurement
+        let tscPerMs = CPUID::tscInfo()
+            .map(|info| info.tscHz() / 1000)
+            .filter(|&perMs| perMs != 0)
+            .unwrap_or(calibratedTscPerMs);
+        timer::setTscPerMs(tscPerMs);
+
+        let tscDeadline = CPUID::featureInfo().tsc_deadline();
+        self.tscDeadline.store(tscDeadline, Ordering::Relaxed);
+        self.configureTimer();
+
+        log::trace!(
+            "APIC timer configured in {} mode ({} ticks/ms, TSC {} cycles/ms)",
+            if tscDeadline { "TSC-deadline" } else { "one-shot" },
+            ticksPerMs,
+            tscPerMs
+        );
+    }
 
+    /// Puts the calling CPU's timer into the mode picked by `initAPICTimer`, disarmed.
+    fn configureTimer(&self) {
         unsafe {
-            log::trace!("initializing APICTimer divide");
             self.lapicWrite(LAPIC_TIMER_DIVIDE, 0x3);
-            log::trace!("initializing APICTimer init count");
-            // Set initial count for 10ms
-            self.lapicWrite(LAPIC_TIMER_INIT_COUNT, ticksPerMs * 10);
-            log::trace!("initializing APICTimer reg");
-            // Set timer to Periodic mode (bit 17) and unmask (clear bit 16)
-            self.lapicWrite(LAPIC_TIMER_REG, (1 << 17) | (InterruptIndex::LApicTimer as u32));
-
-            log::trace!("APIC timer configured for 10ms interval with {} ticks.", 10 * ticksPerMs);
+            if self.tscDeadline.load(Ordering::Relaxed) {
+                self.lapicWrite(LAPIC_TIMER_REG, LAPIC_TIMER_MODE_TSC_DEADLINE | (InterruptIndex::LApicTimer as u32));
+                Msr::new(IA32_TSC_DEADLINE).write(0);
+            } else {
+                self.lapicWrite(LAPIC_TIMER_REG, LAPIC_TIMER_MODE_ONESHOT | (InterruptIndex::LApicTimer as u32));
+                self.lapicWrite(LAPIC_TIMER_INIT_COUNT, 0);
+            }
         }
+    }
 
+    /// Enables the local APIC of a CPU started after the boot CPU, with its timer set up like
+    /// the boot CPU's. The I/O APICs keep delivering to the boot CPU.
+    pub fn initLocalApic(&self) {
+        unsafe {
+            let mut apicMsr = Msr::new(0x1B); // IA32_APIC_BASE
+            let value = apicMsr.read();
+            apicMsr.write(value | 1u64 << 11);
+            self.lapicWrite(LAPIC_TPR, 0);
+            self.lapicWrite(LAPIC_SVR, 0x100 | 0xFF);
+        }
+        self.configureTimer();
+    }
+
+    /// Programs the next timer interrupt for TSC time `deadline`, or stops the timer on None.
+    /// Deadlines in the past fire as soon as possible.
+    pub fn armTimer(&self, deadline: Option<u64>) {
+        let tscDeadline = self.tscDeadline.load(Ordering::Relaxed);
+
+        unsafe {
+            match deadline {
+                None if tscDeadline => Msr::new(IA32_TSC_DEADLINE).write(0),
+                None => self.lapicWrite(LAPIC_TIMER_INIT_COUNT, 0),
+                // a zero deadline disarms the timer, so never write one
+                Some(deadline) if tscDeadline => Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1)),
+                Some(deadline) => {
+                    let delta = deadline.saturating_sub(rdtsc()) as u128;
+                    let ticksPerMs = self.timerTicksPerMs.load(Ordering::Relaxed) as u128;
+                    let tscPerMs = timer::tscPerMs().max(1) as u128;
+                    let count = (delta * ticksPerMs / tscPerMs).clamp(1, u32::MAX as u128);
+                    self.lapicWrite(LAPIC_TIMER_INIT_COUNT, count as u32);
+                }
+            }
+        }
     }
 
     fn isaIRQtoGSI<A: Allocator>(apic: &Apic<A>, isaIRQ: u8) -> u32 {
@@ -222,6 +304,17 @@ impl AdvancedPic {
         }
     }
 
+    /// Delivers performance counter overflows as NMIs, used by the lockup watchdog.
+    ///
+    /// The CPU masks the entry on every overflow, so the NMI handler calls this again to unmask it.
+    pub fn enablePerfCounterNmi(&self) {
+        unsafe { self.lapicWrite(LAPIC_LVT_PERF_COUNTER, LAPIC_DELIVERY_MODE_NMI) };
+    }
+
+    pub fn lapicID(&self) -> u8 {
+        unsafe { (self.lapicRead(LAPIC_ID_REG) >> 24) as u8 }
+    }
+
     pub fn notifyEOI(&self) {
         unsafe { self.lapicWrite(0xB0, 0) };
     }
@@ -241,7 +334,25 @@ impl AdvancedPic {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Puts the CPU with local APIC ID `apicID` into its wait-for-startup state.
+    pub fn sendInit(&self, apicID: u8) {
+        unsafe { self.sendIcr(apicID, ICR_DELIVERY_MODE_INIT | ICR_LEVEL_ASSERT) };
+    }
+
+    /// Starts a CPU waiting after `sendInit` in real mode at physical address `page << 12`.
+    pub fn sendStartup(&self, apicID: u8, page: u8) {
+        unsafe { self.sendIcr(apicID, ICR_DELIVERY_MODE_STARTUP | ICR_LEVEL_ASSERT | page as u32) };
+    }
+
+    /// Sends one IPI, waiting for the previous one to be accepted first.
+    unsafe fn sendIcr(&self, apicID: u8, low: u32) { unsafe {
+        while self.lapicRead(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        self.lapicWrite(LAPIC_ICR_HIGH, (apicID as u32) << 24);
+        self.lapicWrite(LAPIC_ICR_LOW, low);
+    }}
+
     pub fn sendIPI(&self, apic_id: u8, vector: u8) {
         // The Interrupt Command Register (ICR) is split into two 32-bit registers.
         // Typically, one writes to the high and low parts separately.
@@ -256,10 +367,6 @@ impl AdvancedPic {
             // For a fixed delivery mode, assert level, edge-triggered.
             let icr_value = (vector as u32) | (0 << 8) | (1 << 14) | (0 << 15);
             core::ptr::write_volatile(icr_low, icr_value);
-            info!(
-                "IPI sent to APIC ID {} with vector 0x{:X} (ICR=0x{:X})",
-                apic_id, vector, icr_value
-            );
         }
     }
 }
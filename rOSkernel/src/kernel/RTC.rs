@@ -1,17 +1,17 @@
 use core::ops::Add;
 
 use core::cmp::Ordering;
-use core::hint::spin_loop;
-use core::sync::atomic::Ordering as AtomicOrdering;
+use core::fmt;
 use spin::Mutex;
 use crate::kernel::binIO;
-use crate::kernel::interrupts::TICK_COUNTER;
+use crate::kernel::timer::{self, TICK_MS};
 
 static TIME: Mutex<Time> = Mutex::new(Time::new());
 static MONTHS: [u8; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
 
 //#[derive(Eq, PartialEq, PartialOrd)]
-struct DateTime {
+#[derive(Debug, Clone, Copy)]
+pub struct DateTime {
     second: u8,
     minute: u8,
     hour: u8,
@@ -44,6 +44,27 @@ impl DateTime {
             century: 0,
         }
     }
+
+    /// Seconds since 1970-01-01 00:00:00, taking the clock for UTC in the 2000s.
+    pub fn unixSeconds(&self) -> u64 {
+        // days from the civil calendar, with years starting in March so leap days come last
+        let (year, month) = match self.month {
+            1 | 2 => (2000 + self.year as i64 - 1, self.month as i64 + 9),
+            month => (2000 + self.year as i64, month as i64 - 3),
+        };
+        let era = year / 400;
+        let yearOfEra = year - era * 400;
+        let dayOfYear = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let dayOfEra = yearOfEra * 365 + yearOfEra / 4 - yearOfEra / 100 + dayOfYear;
+        let days = era * 146097 + dayOfEra - 719468;
+
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64) as u64
+    }
+
+    /// Year, month, day, hour, minute and second, the year in the 2000s like `unixSeconds`.
+    pub fn parts(&self) -> (u16, u8, u8, u8, u8, u8) {
+        (2000 + self.year as u16, self.month, self.day, self.hour, self.minute, self.second)
+    }
 }
 
 impl Add<u16> for DateTime {
@@ -205,21 +226,15 @@ impl Time {
 }
 
 pub async fn waitSeconds(seconds: u32) {
-    log::info!("{} ticks", seconds * 20);
-    waitTicks(seconds * 20).await;
+    let ticks = seconds * (1000 / TICK_MS as u32);
+    log::info!("{} ticks", ticks);
+    waitTicks(ticks).await;
 }
 
+/// Waits `ticks` scheduler ticks (`TICK_MS` each) on the kernel timer queue, leaving the CPU
+/// to other tasks and threads in the meantime.
 pub async fn waitTicks(ticks: u32) {
-    let now = TICK_COUNTER.load(AtomicOrdering::Relaxed);
-    let target = now + ticks;
-
-    loop {
-        let now = TICK_COUNTER.load(AtomicOrdering::Relaxed);
-        spin_loop();
-        if now >= target {
-            break;
-        }
-    }
+    timer::sleepFor(ticks as u64 * TICK_MS).await;
 }
 
 unsafe fn updateInProgress() -> bool {
@@ -230,6 +245,48 @@ unsafe fn updateInProgress() -> bool {
     }
 }
 
+/// Set in status register A while the clock updates its time registers.
+const STATUS_A_UPDATING: u8 = 0x80;
+
+unsafe fn readDateTime() -> DateTime {
+    unsafe {
+        loop {
+            binIO::out8(0x70, (0x1 << 7) | 0x0A);
+            if binIO::in8(0x71) & STATUS_A_UPDATING == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        DateTime::new(getRTC(0x00), getRTC(0x02), getRTC(0x04), getRTC(0x07), getRTC(0x08), getRTC(0x09))
+    }
+}
+
+/// Reads the current date and time from the CMOS clock.
+pub fn now() -> DateTime {
+    // an update can still start halfway through, read until two reads agree
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut last = readDateTime();
+        loop {
+            let current = readDateTime();
+            if current == last {
+                return current;
+            }
+            last = current;
+        }
+    })
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the century register isn't read yet, see `handleInterrupt`
+        write!(
+            f,
+            "20{:02}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
 unsafe fn getRTC(port: u8) -> u8 {
     unsafe {
         // check format
@@ -1,4 +1,5 @@
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use core::fmt;
 use core::ops::Add;
 use noto_sans_mono_bitmap::{
     FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width,
@@ -9,6 +10,7 @@ const LETTER_SPACING: usize = 0;
 const BORDER_PADDING: usize = 1;
 const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
 const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+const LINE_HEIGHT: usize = CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
 const BACKUP_CHAR: char = '�';
 const FONT_WEIGHT: FontWeight = FontWeight::Regular;
 
@@ -132,68 +134,74 @@ impl FrameBufferEditor {
         }
     }
 
-    /*
-        fn newline(&mut self) {
-            self.carriageReturn();
-            self.lineFeed();
-        }
+    fn newline(&mut self) {
+        self.carriageReturn();
+        self.lineFeed();
+    }
 
-        fn lineFeed(&mut self) {
-            self.position.y += CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
-        }
+    fn lineFeed(&mut self) {
+        self.position.y += LINE_HEIGHT;
+    }
 
-        fn carriageReturn(&mut self) {
-            self.position.x = BORDER_PADDING;
+    fn carriageReturn(&mut self) {
+        self.position.x = BORDER_PADDING;
+    }
+
+    /// Moves everything up by one line and clears the bottom one.
+    fn scroll(&mut self) {
+        let lineBytes = LINE_HEIGHT * self.info.stride * self.info.bytes_per_pixel;
+        let buffer = self.framebuffer.buffer_mut();
+        let kept = buffer.len().saturating_sub(lineBytes);
+        buffer.copy_within(lineBytes.., 0);
+        buffer[kept..].fill(0);
+        self.position.y -= LINE_HEIGHT;
+    }
+
+    /// Steps back one character on the current line and blanks it.
+    fn backspace(&mut self) {
+        let step = CHAR_RASTER_WIDTH + LETTER_SPACING;
+        if self.position.x < BORDER_PADDING + step {
+            return;
         }
+        self.position.x -= step;
+        for y in 0..CHAR_RASTER_HEIGHT.val() {
+            for x in 0..step {
+                self.writePixel(self.position + Position { x, y }, PixelColour { r: 0, g: 0, b: 0 });
+            }
+        }
+    }
 
-        fn writeChar(&mut self, c: char) {
-            match c {
-                '\n' => self.lineFeed(),
-                '\r' => self.carriageReturn(),
-                c => {
-                    if self.position.x + CHAR_RASTER_WIDTH >= self.width() {
-                        self.newline();
-                    }
-
-                    if self.position.y + CHAR_RASTER_HEIGHT.val() + BORDER_PADDING >= self.height() {
-                        // Scroll up
-                        let scroll_offset = CHAR_RASTER_HEIGHT.val() + LINE_SPACING + BORDER_PADDING;
-                        let scroll_size = self.info.stride * (self.height() - scroll_offset);
-                        let copy_size =
-                            self.info.stride * (self.height() - scroll_offset - BORDER_PADDING);
-                        let copy_src = unsafe {
-                            self.framebuffer.buffer_mut()
-                                .as_ptr()
-                                .add(scroll_offset * self.info.stride)
-                        };
-                        let copy_dst = self.framebuffer.buffer_mut().as_mut_ptr();
-                        unsafe {
-                            ptr::copy(copy_src, copy_dst, copy_size);
-                            ptr::write_bytes(copy_dst.add(copy_size), 0, scroll_size - copy_size);
-                        }
-                        self.position.y -= scroll_offset;
-                    }
-                    self.renderChar(getRaster(c));
+    fn writeChar(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.carriageReturn(),
+            '\x08' => self.backspace(),
+            c => {
+                if self.position.x + CHAR_RASTER_WIDTH >= self.width() {
+                    self.newline();
                 }
+                while self.position.y + CHAR_RASTER_HEIGHT.val() + BORDER_PADDING >= self.height() {
+                    self.scroll();
+                }
+                self.renderChar(getRaster(c));
             }
         }
+    }
 
-        fn renderChar(&mut self, renderedChar: RasterizedChar) {
-            for (y, row) in renderedChar.raster().iter().enumerate() {
-                for (x, byte) in row.iter().enumerate() {
-                    self.writePixel(self.position + Position {x, y}, PixelColour{r: *byte, g: *byte, b: *byte});
-                }
+    fn renderChar(&mut self, renderedChar: RasterizedChar) {
+        for (y, row) in renderedChar.raster().iter().enumerate() {
+            for (x, byte) in row.iter().enumerate() {
+                self.writePixel(self.position + Position { x, y }, PixelColour { r: *byte, g: *byte, b: *byte });
             }
-            self.position.x += renderedChar.width() + LETTER_SPACING;
         }
-    */
+        self.position.x += renderedChar.width() + LETTER_SPACING;
+    }
 }
 
-/*
 unsafe impl Send for FrameBufferEditor {}
 unsafe impl Sync for FrameBufferEditor {}
 
-impl core::fmt::Write for FrameBufferEditor {
+impl fmt::Write for FrameBufferEditor {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
             self.writeChar(c);
@@ -201,4 +209,3 @@ impl core::fmt::Write for FrameBufferEditor {
         Ok(())
     }
 }
-*/
@@ -3,12 +3,22 @@ use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::gdt::{DescriptorFlags, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+use crate::kernel::{syscall, MAX_CPUS};
+use crate::util::wrappers::CPUID;
 
 const STACK_SIZE: usize = 4096 * 5;
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
 pub const TIMER_INTERRUPT_IST_INDEX: usize = 1;
 pub const PAGE_FAULT_IST_INDEX: usize = 2;
 pub const GENERAL_FAULT_IST_INDEX: usize = 3;
+pub const NMI_IST_INDEX: usize = 4;
+pub const DEBUG_IST_INDEX: usize = 5;
+pub const MACHINE_CHECK_IST_INDEX: usize = 6;
+// all seven slots the TSS has
+const IST_STACK_COUNT: usize = 7;
+
+// null, kernel code/data, user code32/data/code, then a two-entry TSS descriptor per CPU
+const GDT_ENTRIES: usize = 6 + 2 * MAX_CPUS;
 
 // Wrapper to ensure 16-byte stack alignment (required by x86_64 ABI)
 #[repr(C, align(16))]
@@ -20,42 +30,27 @@ impl AlignedStack {
     }
 }
 
-// Use static mut to ensure these go into writable memory (.bss), not .rodata
-pub static mut DOUBLE_FAULT_STACK: AlignedStack = AlignedStack::new();
-pub static mut TIMER_INTERRUPT_STACK: AlignedStack = AlignedStack::new();
-pub static mut PAGE_FAULT_STACK: AlignedStack = AlignedStack::new();
-pub static mut GENERAL_FAULT_STACK: AlignedStack = AlignedStack::new();
+// Use static mut to ensure these go into writable memory (.bss), not .rodata. Each CPU gets
+// its own set, indexed like `TSS`, so the kernel image maps them in every address space
+static mut IST_STACKS: [[AlignedStack; IST_STACK_COUNT]; MAX_CPUS] =
+    [const { [const { AlignedStack::new() }; IST_STACK_COUNT] }; MAX_CPUS];
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = {
-            let stackStart = VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK);
-            let stackEnd = stackStart + STACK_SIZE as u64;
-            stackEnd
-        };
-        tss.interrupt_stack_table[TIMER_INTERRUPT_IST_INDEX] = {
-            let stackStart = VirtAddr::from_ptr(&raw const TIMER_INTERRUPT_STACK);
-            let stackEnd = stackStart + STACK_SIZE as u64;
-            stackEnd
-        };
-        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = {
-            let stackStart = VirtAddr::from_ptr(&raw const PAGE_FAULT_STACK);
-            let stackEnd = stackStart + STACK_SIZE as u64;
-            stackEnd
-        };
-        tss.interrupt_stack_table[GENERAL_FAULT_IST_INDEX] = {
-            let stackStart = VirtAddr::from_ptr(&raw const GENERAL_FAULT_STACK);
-            let stackEnd = stackStart + STACK_SIZE as u64;
-            stackEnd
-        };
-        tss
-    };
+// Filled in by each CPU before it loads its task register, afterwards only that CPU
+// writes its slot, and only `rsp0`
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::new() }; MAX_CPUS];
+
+fn istStacks(tss: &mut TaskStateSegment, cpu: usize) {
+    for index in 0..IST_STACK_COUNT {
+        let stackStart = VirtAddr::from_ptr(unsafe { &raw const IST_STACKS[cpu][index] });
+        tss.interrupt_stack_table[index] = stackStart + STACK_SIZE as u64;
+    }
 }
 
 lazy_static! {
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
+    // The user segments are laid out for SYSRET, which takes SS from STAR[63:48] + 8
+    // and the 64-bit CS from STAR[63:48] + 16
+    pub static ref GDT: (GlobalDescriptorTable<GDT_ENTRIES>, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::empty();
 
         let kernelCodeSelector = gdt.append(Descriptor::kernel_code_segment());
         let kernelDataSelector = gdt.append(Descriptor::kernel_data_segment());
@@ -63,7 +58,9 @@ lazy_static! {
             gdt.append(Descriptor::UserSegment(DescriptorFlags::USER_CODE32.bits()));
         let userDataSelector = gdt.append(Descriptor::user_data_segment());
         let userCodeSelector = gdt.append(Descriptor::user_code_segment());
-        let tssSelector = gdt.append(Descriptor::tss_segment(&TSS));
+        let tssSelectors = core::array::from_fn(|cpu| {
+            gdt.append(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS[cpu]) })
+        });
         (
             gdt,
             Selectors {
@@ -72,7 +69,7 @@ lazy_static! {
                 userSegmentSelector,
                 userDataSelector,
                 userCodeSelector,
-                tssSelector,
+                tssSelectors,
             },
         )
     };
@@ -84,13 +81,21 @@ pub struct Selectors {
     pub userSegmentSelector: SegmentSelector,
     pub userDataSelector: SegmentSelector,
     pub userCodeSelector: SegmentSelector,
-    pub tssSelector: SegmentSelector,
+    pub tssSelectors: [SegmentSelector; MAX_CPUS],
 }
 
+/// Loads the GDT and the calling CPU's TSS.
+///
+/// Runs before the local APIC is mapped, so the CPU is identified by its initial APIC ID.
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::registers::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
 
+    let cpu = unsafe { CPUID(1, 0).1 >> 24 } as usize;
+    // `smp` doesn't start APs past the table, so only the boot CPU can get here with one
+    assert!(cpu < MAX_CPUS, "CPU {} is past the {} CPUs the kernel supports", cpu, MAX_CPUS);
+    unsafe { istStacks(&mut *(&raw mut TSS[cpu]), cpu) };
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.kernelCodeSelector);
@@ -99,6 +104,32 @@ pub fn init() {
         FS::set_reg(GDT.1.kernelDataSelector);
         GS::set_reg(GDT.1.kernelDataSelector);
         SS::set_reg(GDT.1.kernelDataSelector);
-        load_tss(GDT.1.tssSelector);
+        load_tss(GDT.1.tssSelectors[cpu]);
+    }
+}
+
+/// The TSS `init` loaded on the calling CPU, found through the task register so it is the
+/// same slot however the CPU is numbered elsewhere.
+fn localTss() -> *mut TaskStateSegment {
+    let selector: u16;
+    unsafe {
+        core::arch::asm!("str {0:x}", out(reg) selector, options(nomem, nostack, preserves_flags));
     }
+    // every TSS descriptor takes two GDT entries
+    let first = GDT.1.tssSelectors[0].index();
+    let cpu = (SegmentSelector(selector).index() - first) as usize / 2;
+    unsafe { &raw mut TSS[cpu] }
+}
+
+/// Sets the stack the calling CPU switches to when an interrupt or system call arrives in ring 3.
+///
+/// Called on every switch to a user thread, with interrupts disabled.
+pub fn setKernelStack(top: VirtAddr) {
+    unsafe { (*localTss()).privilege_stack_table[0] = top };
+    syscall::setEntryStack(top);
+}
+
+/// Top of the stack the calling CPU switches to for interrupts from ring 3, see `setKernelStack`.
+pub fn kernelStack() -> VirtAddr {
+    unsafe { (*localTss()).privilege_stack_table[0] }
 }
@@ -1,9 +1,8 @@
-use core::sync::atomic::AtomicU32;
 use lazy_static::lazy_static;
 // use pic8259::ChainedPics;
 use ps2::Controller;
-use spin::Mutex;
 use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{
     HandlerFuncType, HandlerFuncWithErrCode, InterruptDescriptorTable, InterruptStackFrame,
     PageFaultErrorCode,
@@ -12,36 +11,64 @@ use x86_64::{PrivilegeLevel, VirtAddr};
 
 use crate::kernel::kernelContext;
 use crate::kernel::{gdt, RTC};
-use crate::multitasking::preemptive::switchThread::timerInterruptEntry;
+use crate::util::lockdep::{self, Mutex};
+use crate::multitasking::preemptive::switchThread::{rescheduleInterruptEntry, rescheduleIpiEntry, timerInterruptEntry};
+use crate::multitasking::preemptive::fpu::deviceNotAvailableEntry;
+use crate::multitasking::preemptive::{current_pid, exit_process};
+use crate::kernel::watchdog::{nmiEntry, watchdogTimerEntry};
+use crate::kernel::syscall::legacySyscallEntry;
+use crate::mem::memory::{USER_SPACE_END, USER_SPACE_START};
+use crate::mem::{tlb, userAccess};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.non_maskable_interrupt
-            .set_handler_fn(nonMaskableInterruptHandler);
         idt.breakpoint.set_handler_fn(breakpointHandler);
+        idt.divide_error.set_handler_fn(divideErrorHandler);
         idt.invalid_opcode.set_handler_fn(invalidOpcodeHandler);
+        idt.x87_floating_point.set_handler_fn(floatingPointHandler);
+        idt.simd_floating_point.set_handler_fn(floatingPointHandler);
         idt.segment_not_present
             .set_handler_fn(segmentNotPresentHandler);
         idt.stack_segment_fault
             .set_handler_fn(stackSegmentFaultHandler);
         idt.invalid_tss.set_handler_fn(invalidTSSHandler);
-        idt[InterruptIndex::ProgIntTimer as u8].set_handler_fn(pitHandler);
         idt[InterruptIndex::Spurious as u8].set_handler_fn(spuriousInterruptHandler);
         
         unsafe {
             idt[InterruptIndex::LApicTimer as u8]
                 .set_handler_addr(VirtAddr::new(timerInterruptEntry as *const () as u64))
                 .set_stack_index(gdt::TIMER_INTERRUPT_IST_INDEX as u16);
+            idt[InterruptIndex::Reschedule as u8]
+                .set_handler_addr(VirtAddr::new(rescheduleInterruptEntry as *const () as u64))
+                .set_stack_index(gdt::TIMER_INTERRUPT_IST_INDEX as u16);
+            idt[InterruptIndex::RescheduleIpi as u8]
+                .set_handler_addr(VirtAddr::new(rescheduleIpiEntry as *const () as u64))
+                .set_stack_index(gdt::TIMER_INTERRUPT_IST_INDEX as u16);
+            idt.device_not_available
+                .set_handler_addr(VirtAddr::new(deviceNotAvailableEntry as *const () as u64));
+            idt.non_maskable_interrupt
+                .set_handler_addr(VirtAddr::new(nmiEntry as *const () as u64))
+                .set_stack_index(gdt::NMI_IST_INDEX as u16);
+            // like the NMI these can hit before `syscallEntry` has switched off the user stack
+            idt.debug
+                .set_handler_fn(debugHandler)
+                .set_stack_index(gdt::DEBUG_IST_INDEX as u16);
+            idt.machine_check
+                .set_handler_fn(machineCheckHandler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX as u16);
+            idt[InterruptIndex::ProgIntTimer as u8]
+                .set_handler_addr(VirtAddr::new(watchdogTimerEntry as *const () as u64));
+            idt[InterruptIndex::SystemCall as u8]
+                .set_handler_addr(VirtAddr::new(legacySyscallEntry as *const () as u64))
+                .disable_interrupts(false)
+                .set_privilege_level(PrivilegeLevel::Ring3);
         }
         
         idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboardInterruptHandler);
         idt[InterruptIndex::Floppy as u8].set_handler_fn(floppyInterruptHandler);
         idt[InterruptIndex::RealTimeClock as u8].set_handler_fn(realTimeClockInterruptHandler);
-        idt[InterruptIndex::SystemCall as u8]
-            .set_handler_fn(breakpointHandler)
-            .disable_interrupts(false)
-            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt[InterruptIndex::TlbShootdown as u8].set_handler_fn(tlb::tlbShootdownHandler);
         unsafe {
             idt.double_fault
                 .set_handler_addr(HandlerFuncType::to_virt_addr(
@@ -61,7 +88,7 @@ lazy_static! {
         idt
     };
     pub static ref CONTROLLER: Mutex<Controller> =
-        unsafe { Mutex::new(Controller::with_timeout(50000)) };
+        unsafe { Mutex::new("CONTROLLER", Controller::with_timeout(50000)) };
 }
 
 // pub const PIC_1_OFFSET: u8 = 32;
@@ -70,8 +97,6 @@ pub const APIC_BASE: u8 = 32;
 
 //pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-pub(crate) static TICK_COUNTER: AtomicU32 = AtomicU32::new(0);
-
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -81,28 +106,95 @@ pub enum InterruptIndex {
     LApicTimer = 34,
     Floppy = APIC_BASE + 6,
     RealTimeClock = APIC_BASE + 8,
-    SystemCall = 0xAA - APIC_BASE,
+    SystemCall = 0xAA,
+    Reschedule = 0xF0,
+    RescheduleIpi = 0xF1,
+    TlbShootdown = 0xF2,
 }
 
 pub fn initIDT() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn nonMaskableInterruptHandler(stackFrame: InterruptStackFrame) {
-    log::error!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}", stackFrame);
+/// Ends the current process if the exception was raised by ring 3 code, so a faulting
+/// program doesn't take the kernel down with it. Returns false if the kernel itself faulted.
+///
+/// The exit doesn't run here, the handler may be on a shared exception stack. Instead the
+/// handler returns into `userFaultExit` on the thread's kernel stack, which is unused while
+/// the thread is in ring 3.
+fn killIfUser(stackFrame: &mut InterruptStackFrame, exception: core::fmt::Arguments) -> bool {
+    if stackFrame.code_segment.rpl() != PrivilegeLevel::Ring3 {
+        return false;
+    }
+    log::warn!(
+        "{} in user code at {:?}, killing process {:?}",
+        exception,
+        stackFrame.instruction_pointer,
+        current_pid()
+    );
+
+    let selectors = &gdt::GDT.1;
+    unsafe {
+        stackFrame.as_mut().update(|frame| {
+            frame.code_segment = selectors.kernelCodeSelector;
+            frame.stack_segment = selectors.kernelDataSelector;
+            // entered like a call, so rsp + 8 must be 16 byte aligned
+            frame.stack_pointer = gdt::kernelStack() - 8u64;
+            frame.instruction_pointer = VirtAddr::new(userFaultExit as *const () as u64);
+            frame.cpu_flags.remove(RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+        });
+    }
+    true
+}
+
+extern "C" fn userFaultExit() -> ! {
+    exit_process();
+}
+
+extern "x86-interrupt" fn breakpointHandler(mut stackFrame: InterruptStackFrame) {
+    if killIfUser(&mut stackFrame, format_args!("Breakpoint")) {
+        return;
+    }
+    log::error!("EXCEPTION: BREAKPOINT\n{:#?}", stackFrame);
     loop {
         x86_64::instructions::hlt();
     }
 }
 
-extern "x86-interrupt" fn breakpointHandler(stackFrame: InterruptStackFrame) {
-    log::error!("EXCEPTION: BREAKPOINT\n{:#?}", stackFrame);
+extern "x86-interrupt" fn debugHandler(mut stackFrame: InterruptStackFrame) {
+    if killIfUser(&mut stackFrame, format_args!("Debug exception")) {
+        return;
+    }
+    log::error!("EXCEPTION: DEBUG\n{:#?}", stackFrame);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+extern "x86-interrupt" fn divideErrorHandler(mut stackFrame: InterruptStackFrame) {
+    if killIfUser(&mut stackFrame, format_args!("Divide error")) {
+        return;
+    }
+    log::error!("EXCEPTION: DIVIDE ERROR\n{:#?}", stackFrame);
     loop {
         x86_64::instructions::hlt();
     }
 }
 
-extern "x86-interrupt" fn invalidOpcodeHandler(stackFrame: InterruptStackFrame) {
+extern "x86-interrupt" fn floatingPointHandler(mut stackFrame: InterruptStackFrame) {
+    if killIfUser(&mut stackFrame, format_args!("Floating point exception")) {
+        return;
+    }
+    log::error!("EXCEPTION: FLOATING POINT\n{:#?}", stackFrame);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+extern "x86-interrupt" fn invalidOpcodeHandler(mut stackFrame: InterruptStackFrame) {
+    if killIfUser(&mut stackFrame, format_args!("Invalid opcode")) {
+        return;
+    }
     log::error!("EXCEPTION: INVALID OPCODE\n{:#?}", stackFrame);
     loop {
         x86_64::instructions::hlt();
@@ -110,9 +202,21 @@ extern "x86-interrupt" fn invalidOpcodeHandler(stackFrame: InterruptStackFrame)
 }
 
 extern "x86-interrupt" fn pageFaultHandler(
-    stackFrame: InterruptStackFrame,
+    mut stackFrame: InterruptStackFrame,
     errCode: PageFaultErrorCode,
 ) {
+    // a user copy hit memory that went away, it picks up at its fixup and returns EFAULT. A
+    // fault on a kernel address there is a kernel bug and isn't hidden
+    if stackFrame.code_segment.rpl() == PrivilegeLevel::Ring0
+        && (USER_SPACE_START..USER_SPACE_END).contains(&Cr2::read_raw())
+        && let Some(fixup) = userAccess::fixupFor(stackFrame.instruction_pointer)
+    {
+        unsafe { stackFrame.as_mut().update(|frame| frame.instruction_pointer = fixup) };
+        return;
+    }
+    if killIfUser(&mut stackFrame, format_args!("Page fault at {:?} ({:?})", Cr2::read(), errCode)) {
+        return;
+    }
     log::error!("EXCEPTION: PAGE FAULT");
     log::error!("Accessed Address: {:?}", Cr2::read());
     log::error!("{:#?}", stackFrame);
@@ -122,7 +226,10 @@ extern "x86-interrupt" fn pageFaultHandler(
     }
 }
 
-extern "x86-interrupt" fn GPFaultHandler(stackFrame: InterruptStackFrame, errCode: u64) {
+extern "x86-interrupt" fn GPFaultHandler(mut stackFrame: InterruptStackFrame, errCode: u64) {
+    if killIfUser(&mut stackFrame, format_args!("General protection fault ({})", errCode)) {
+        return;
+    }
     log::error!(
         "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nError Code: {}",
         stackFrame,
@@ -133,7 +240,10 @@ extern "x86-interrupt" fn GPFaultHandler(stackFrame: InterruptStackFrame, errCod
     }
 }
 
-extern "x86-interrupt" fn segmentNotPresentHandler(stackFrame: InterruptStackFrame, errCode: u64) {
+extern "x86-interrupt" fn segmentNotPresentHandler(mut stackFrame: InterruptStackFrame, errCode: u64) {
+    if killIfUser(&mut stackFrame, format_args!("Segment not present ({})", errCode)) {
+        return;
+    }
     log::error!(
         "EXCEPTION: SEGMENT NOT PRESENT\n{:#?}\nError Code: {}",
         stackFrame,
@@ -144,7 +254,10 @@ extern "x86-interrupt" fn segmentNotPresentHandler(stackFrame: InterruptStackFra
     }
 }
 
-extern "x86-interrupt" fn stackSegmentFaultHandler(stackFrame: InterruptStackFrame, errCode: u64) {
+extern "x86-interrupt" fn stackSegmentFaultHandler(mut stackFrame: InterruptStackFrame, errCode: u64) {
+    if killIfUser(&mut stackFrame, format_args!("Stack segment fault ({})", errCode)) {
+        return;
+    }
     log::error!(
         "EXCEPTION: STACK SEGMENT FAULT\n{:#?}\nError Code: {}",
         stackFrame,
@@ -162,16 +275,6 @@ extern "x86-interrupt" fn invalidTSSHandler(stackFrame: InterruptStackFrame, _er
     }
 }
 
-extern "x86-interrupt" fn pitHandler(_stackFrame: InterruptStackFrame) {
-  //  log::trace!("PIT INTERRUPT");
-
-    kernelContext()
-        .apic
-        .get()
-        .expect("APIC not initialized")
-        .notifyEOI();
-}
-
 extern "x86-interrupt" fn spuriousInterruptHandler(_stackFrame: InterruptStackFrame) {
  //   log::error!("EXCEPTION: Spurious Interrupt\n{:#?}", stackFrame);
     loop {
@@ -180,6 +283,7 @@ extern "x86-interrupt" fn spuriousInterruptHandler(_stackFrame: InterruptStackFr
 }
 
 extern "x86-interrupt" fn keyboardInterruptHandler(_stackFrame: InterruptStackFrame) {
+    let _irq = lockdep::irq_enter();
     let mut controller = unsafe {
         CONTROLLER.force_unlock();
         CONTROLLER.lock()
@@ -208,6 +312,7 @@ extern "x86-interrupt" fn floppyInterruptHandler(_stackFrame: InterruptStackFram
 }
 
 extern "x86-interrupt" fn realTimeClockInterruptHandler(_stackFrame: InterruptStackFrame) {
+    let _irq = lockdep::irq_enter();
     unsafe {
         RTC::handleInterrupt();
     }
@@ -219,6 +324,10 @@ extern "x86-interrupt" fn realTimeClockInterruptHandler(_stackFrame: InterruptSt
         .notifyEOI();
 }
 
+extern "x86-interrupt" fn machineCheckHandler(stackFrame: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stackFrame);
+}
+
 extern "x86-interrupt" fn doubleFaultHandler(stackFrame: InterruptStackFrame, _errCode: u64) {
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stackFrame);
 }
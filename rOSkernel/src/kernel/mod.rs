@@ -7,6 +7,10 @@ pub mod RTC;
 pub mod binIO;
 pub mod framebuffer;
 pub mod kacpi;
+pub mod watchdog;
+pub mod syscall;
+pub mod pci;
+pub mod smp;
 
 use crate::mem::allocator::HeapRegionAllocator;
 use crate::mem::memory::BootInfoFrameAllocator;
@@ -14,7 +18,7 @@ use crate::util::wrappers::XFeatures;
 use bootloader_x86_64_common::logger::LockedLogger;
 use core::fmt::Debug;
 use once_cell::sync::OnceCell;
-use spin::Mutex;
+use crate::util::lockdep::Mutex;
 use x86_64::structures::paging::OffsetPageTable;
 use crate::mem::heap::Heap;
 
@@ -26,9 +30,9 @@ pub struct KernelContext {
     pub mapper: OnceCell<Mutex<OffsetPageTable<'static>>>,
     pub frameAllocator: OnceCell<Mutex<BootInfoFrameAllocator>>,
     pub heapRegionAllocator: OnceCell<Mutex<HeapRegionAllocator>>,
-    pub frameBuffer: OnceCell<framebuffer::FrameBufferEditor>,
+    pub frameBuffer: OnceCell<Mutex<framebuffer::FrameBufferEditor>>,
     pub apic: OnceCell<AdvancedPic::AdvancedPic>,
-    pub timerQueue: OnceCell<timer::TimerQueue>,
+    pub timerQueue: OnceCell<Mutex<timer::TimerQueue>>,
     pub constants: KernelConstants,
 }
 
@@ -72,31 +76,31 @@ pub fn setKernelLogger(logger: LockedLogger) -> Option<&'static LockedLogger> {
     Some(kernelContext().logger.get().unwrap())
 }
 
-pub fn setKernelMapper(mapperMutex: Mutex<OffsetPageTable<'static>>) {
+pub fn setKernelMapper(mapper: OffsetPageTable<'static>) {
     kernelContext()
         .mapper
-        .set(mapperMutex)
+        .set(Mutex::new("mapper", mapper))
         .expect("Memory Mapper already initialized");
 }
 
-pub fn setKernelFrameAllocator(frameAllocatorMutex: Mutex<BootInfoFrameAllocator>) {
+pub fn setKernelFrameAllocator(frameAllocator: BootInfoFrameAllocator) {
     kernelContext()
         .frameAllocator
-        .set(frameAllocatorMutex)
+        .set(Mutex::new("frameAllocator", frameAllocator))
         .expect("Frame Allocator already initialized");
 }
 
 pub fn setKernelHeapManager(heap_manager: HeapRegionAllocator) {
     kernelContext()
         .heapRegionAllocator
-        .set(Mutex::new(heap_manager))
+        .set(Mutex::new("heapRegionAllocator", heap_manager))
         .expect("Heap Manager already initialized");
 }
 
 pub fn setKernelFrameBuffer(frameBuffer: framebuffer::FrameBufferEditor) {
     kernelContext()
         .frameBuffer
-        .set(frameBuffer)
+        .set(Mutex::new("frameBuffer", frameBuffer))
         .expect("Frame Buffer already initialized");
 }
 
@@ -110,10 +114,53 @@ pub fn setKernelAPIC(apic: AdvancedPic::AdvancedPic) {
 pub fn setKernelTimerQueue(timerQueue: timer::TimerQueue) {
     kernelContext()
         .timerQueue
-        .set(timerQueue)
+        .set(Mutex::new("timerQueue", timerQueue))
         .expect("Timer Queue already initialized");
 }
 
+/// Resets the machine through the keyboard controller, or with a triple fault if that does
+/// nothing.
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    use x86_64::structures::DescriptorTablePointer;
+
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        let mut status = Port::<u8>::new(0x64);
+        // wait for the controller's input buffer to drain, then pulse the reset line
+        for _ in 0..100_000 {
+            if status.read() & 0x02 == 0 {
+                break;
+            }
+        }
+        status.write(0xFE);
+
+        for _ in 0..1_000_000 {
+            core::hint::spin_loop();
+        }
+
+        // an empty IDT turns the next exception into a triple fault
+        let empty = DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::zero() };
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// CPUs the kernel runs on, numbered by local APIC ID. Per-CPU tables have this many slots
+/// and CPUs with a higher ID are left stopped.
+pub const MAX_CPUS: usize = 16;
+
+/// Returns the local APIC ID of the executing CPU, or 0 before the APIC is up.
+pub fn currentCpuID() -> u8 {
+    match KERNEL_CONTEXT.get().and_then(|ctx| ctx.apic.get()) {
+        Some(apic) => apic.lapicID(),
+        None => 0,
+    }
+}
+
 impl Debug for KernelContext {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("KernelContext")
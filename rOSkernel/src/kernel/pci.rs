@@ -0,0 +1,116 @@
+//! PCI devices, found through the legacy configuration mechanism on ports 0xCF8/0xCFC.
+
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const VENDOR_NONE: u16 = 0xFFFF;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+/// Reads the 32-bit configuration register at `offset` (rounded down to 4) of a function.
+pub fn readConfig(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = 1 << 31
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | (offset as u32 & 0xFC);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address);
+        Port::<u32>::new(CONFIG_DATA).read()
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendorID: u16,
+    pub deviceID: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub progIf: u8,
+    pub revision: u8,
+    pub headerType: u8,
+}
+
+impl PciDevice {
+    fn probe(bus: u8, device: u8, function: u8) -> Option<Self> {
+        let ids = readConfig(bus, device, function, 0x00);
+        if ids as u16 == VENDOR_NONE {
+            return None;
+        }
+        let classes = readConfig(bus, device, function, 0x08);
+        let header = readConfig(bus, device, function, 0x0C);
+
+        Some(Self {
+            bus,
+            device,
+            function,
+            vendorID: ids as u16,
+            deviceID: (ids >> 16) as u16,
+            class: (classes >> 24) as u8,
+            subclass: (classes >> 16) as u8,
+            progIf: (classes >> 8) as u8,
+            revision: classes as u8,
+            headerType: (header >> 16) as u8,
+        })
+    }
+
+    /// Rough name of the device class, from the class and subclass codes.
+    pub fn className(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, _) => "Network controller",
+            (0x03, _) => "Display controller",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x09, _) => "Input device controller",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "Serial bus controller",
+            _ => "Unknown device",
+        }
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} {} (class {:02x}{:02x}{:02x}, rev {:02x})",
+            self.bus, self.device, self.function, self.vendorID, self.deviceID, self.className(),
+            self.class, self.subclass, self.progIf, self.revision
+        )
+    }
+}
+
+/// Every function on every bus, found by trying each address.
+pub fn devices() -> Vec<PciDevice> {
+    let mut found = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32 {
+            let Some(first) = PciDevice::probe(bus, device, 0) else {
+                continue;
+            };
+            found.push(first);
+            if first.headerType & HEADER_MULTIFUNCTION != 0 {
+                found.extend((1..8).filter_map(|function| PciDevice::probe(bus, device, function)));
+            }
+        }
+    }
+    found
+}
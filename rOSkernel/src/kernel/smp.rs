@@ -0,0 +1,298 @@
+//! Starts the application processors (APs).
+//!
+//! Each AP listed in the MADT is woken with INIT and STARTUP IPIs into a trampoline copied
+//! below 1 MiB. The trampoline goes from real mode straight to long mode on the kernel's page
+//! tables and calls `apMain` on a stack of its own, which brings the CPU online for the
+//! scheduler. APs are started one at a time since they share the trampoline page, which is
+//! identity mapped only while they start.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use acpi::platform::ProcessorState;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::VirtAddr;
+use crate::kernel::{gdt, interrupts, kernelContext, syscall, timer, watchdog, MAX_CPUS};
+use crate::mem::{memory, stack};
+use crate::multitasking::preemptive;
+use crate::util::wrappers::{rdtsc, readCR0, readCR4, writeCR0, writeCR4, xgetbv0, xsetbv0};
+
+// The real mode part reads apCr3 and apGdtr through fixed offsets, `.org` keeps them there.
+// apLongJump and apGdtr hold offsets into the trampoline that `startApplicationProcessors`
+// turns into physical addresses once it knows where the copy lives.
+global_asm!(
+    ".pushsection .rodata.apTrampoline, \"a\"",
+    ".global apTrampolineStart",
+    ".global apTrampolineEnd",
+    ".global apLongJump",
+    ".global apGdtr",
+    ".global apCr3",
+    ".global apStack",
+    ".global apEntry",
+    ".code16",
+    "apTrampolineStart:",
+    "    jmp apRealMode",
+    ".org 8",
+    "apCr3:",
+    "    .quad 0",
+    ".org 16",
+    "apGdtr:",
+    "    .word apGdtEnd - apGdt - 1",
+    "    .long apGdt - apTrampolineStart",
+    ".org 24",
+    "apStack:",
+    "    .quad 0",
+    "apEntry:",
+    "    .quad 0",
+    "apGdt:",
+    "    .quad 0",
+    "    .quad 0x00AF9B000000FFFF", // 64-bit code
+    "    .quad 0x00CF93000000FFFF", // data
+    "apGdtEnd:",
+    "apRealMode:",
+    "    cli",
+    "    cld",
+    // the STARTUP IPI entered at offset 0 of the segment in CS
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    mov eax, 0x20", // CR4.PAE
+    "    mov cr4, eax",
+    "    mov eax, dword ptr [8]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080", // IA32_EFER.LME and NXE, the kernel maps pages NX
+    "    rdmsr",
+    "    or eax, 0x900",
+    "    wrmsr",
+    "    lgdt [16]",
+    "    mov eax, cr0",
+    "    or eax, 0x80000001", // PG and PE, which activates long mode
+    "    mov cr0, eax",
+    // jmp far dword 0x08:apLongMode
+    "    .byte 0x66, 0xEA",
+    "apLongJump:",
+    "    .long apLongMode - apTrampolineStart",
+    "    .word 0x08",
+    ".code64",
+    "apLongMode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    xor eax, eax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov rsp, qword ptr [rip + apStack]",
+    "    xor ebp, ebp",
+    "    call qword ptr [rip + apEntry]",
+    "    ud2",
+    "apTrampolineEnd:",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static apTrampolineStart: u8;
+    static apTrampolineEnd: u8;
+    static apLongJump: u8;
+    static apGdtr: u8;
+    static apCr3: u8;
+    static apStack: u8;
+    static apEntry: u8;
+}
+
+const AP_STACK_PAGES: u64 = 4;
+/// How long an AP gets to report in after its STARTUP IPIs.
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+/// Control registers of the boot CPU, copied by every AP.
+static BOOT_CR0: AtomicU64 = AtomicU64::new(0);
+static BOOT_CR4: AtomicU64 = AtomicU64::new(0);
+static BOOT_XCR0: AtomicU64 = AtomicU64::new(0);
+/// Set by the AP being started once it is online.
+static AP_ONLINE: AtomicBool = AtomicBool::new(false);
+
+const CR0_TS: u64 = 1 << 3;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// Starts every enabled AP listed in the MADT, returns how many came online.
+///
+/// Needs the local APIC, its calibrated timer and the scheduler's idle thread on the boot CPU.
+pub fn startApplicationProcessors() -> usize {
+    let Some(processorInfo) = kernelContext().constants.ACPI_PROCESSOR_INFO.get() else {
+        return 0;
+    };
+    let aps = processorInfo
+        .application_processors
+        .iter()
+        .filter(|ap| ap.state != ProcessorState::Disabled);
+    if aps.clone().next().is_none() {
+        return 0;
+    }
+
+    let Some(frame) = kernelContext().frameAllocator.get().unwrap().lock().realModeFrame() else {
+        log::warn!("SMP: no free memory below 1 MiB for the AP trampoline, running on one CPU");
+        return 0;
+    };
+    // the trampoline loads CR3 while still in 32-bit mode
+    let (pageTable, _) = Cr3::read();
+    if pageTable.start_address().as_u64() > u32::MAX as u64 {
+        log::warn!("SMP: kernel page table above 4 GiB, running on one CPU");
+        return 0;
+    }
+    if let Err(e) = identityMap(frame) {
+        log::warn!("SMP: can't identity map the AP trampoline ({:?}), running on one CPU", e);
+        return 0;
+    }
+
+    let base = frame.start_address().as_u64();
+    let trampoline = memory::physToVirt(base).as_mut_ptr::<u8>();
+    unsafe {
+        let start = &raw const apTrampolineStart;
+        let len = (&raw const apTrampolineEnd).offset_from(start) as usize;
+        core::ptr::copy_nonoverlapping(start, trampoline, len);
+
+        relocate(trampoline, &raw const apLongJump, base);
+        relocate(trampoline, (&raw const apGdtr).add(2), base);
+        field(trampoline, &raw const apCr3).write_unaligned(pageTable.start_address().as_u64());
+        field(trampoline, &raw const apEntry).write_unaligned(apMain as *const () as u64);
+
+        // lazy FPU switching may have CR0.TS set here, an AP starts without FPU state to protect
+        BOOT_CR0.store(readCR0() & !CR0_TS, Ordering::Relaxed);
+        BOOT_CR4.store(readCR4(), Ordering::Relaxed);
+        if readCR4() & CR4_OSXSAVE != 0 {
+            BOOT_XCR0.store(xgetbv0(), Ordering::Relaxed);
+        }
+    }
+
+    let apic = kernelContext().apic.get().expect("APIC not initialized");
+    let mut online = 0;
+    for ap in aps {
+        let Ok(apicID) = u8::try_from(ap.local_apic_id) else {
+            log::warn!("SMP: skipping CPU with x2APIC ID {}", ap.local_apic_id);
+            continue;
+        };
+        // per-CPU tables are indexed by APIC ID, a CPU past them would share another's slot
+        if apicID as usize >= MAX_CPUS {
+            log::warn!("SMP: skipping CPU {}, only IDs below {} are supported", apicID, MAX_CPUS);
+            continue;
+        }
+
+        let stack = {
+            let mut mapper = kernelContext().mapper.get().unwrap().lock();
+            let mut frameAllocator = kernelContext().frameAllocator.get().unwrap().lock();
+            stack::allocStack(AP_STACK_PAGES, &mut *mapper, &mut *frameAllocator)
+        };
+        let Ok(stack) = stack else {
+            log::warn!("SMP: no memory for the stack of CPU {}", apicID);
+            break;
+        };
+        unsafe { field(trampoline, &raw const apStack).write_unaligned(stack.end.as_u64()) };
+        AP_ONLINE.store(false, Ordering::Release);
+
+        // INIT, then STARTUP twice, as the MP specification asks
+        let page = (base >> 12) as u8;
+        apic.sendInit(apicID);
+        delayUs(10_000);
+        apic.sendStartup(apicID, page);
+        delayUs(200);
+        if !AP_ONLINE.load(Ordering::Acquire) {
+            apic.sendStartup(apicID, page);
+        }
+
+        let deadline = timer::now() + timer::msToCycles(AP_START_TIMEOUT_MS);
+        while !AP_ONLINE.load(Ordering::Acquire) && timer::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if AP_ONLINE.load(Ordering::Acquire) {
+            online += 1;
+        } else {
+            // its stack stays allocated, the CPU may still show up and use it
+            log::warn!("SMP: CPU {} did not start", apicID);
+        }
+    }
+
+    unmapIdentity(frame);
+    log::info!("SMP: {} application processor(s) online", online);
+    online
+}
+
+/// Address of trampoline field `symbol` in the copy at `trampoline`.
+unsafe fn field(trampoline: *mut u8, symbol: *const u8) -> *mut u64 {
+    unsafe {
+        let offset = symbol.offset_from(&raw const apTrampolineStart) as usize;
+        trampoline.add(offset) as *mut u64
+    }
+}
+
+/// Turns the 32-bit trampoline offset at `symbol` into a physical address.
+unsafe fn relocate(trampoline: *mut u8, symbol: *const u8, base: u64) {
+    unsafe {
+        let offset = field(trampoline, symbol) as *mut u32;
+        offset.write_unaligned(offset.read_unaligned() + base as u32);
+    }
+}
+
+/// Maps the trampoline page at its physical address, the code right after enabling paging
+/// still runs from there.
+fn identityMap(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = kernelContext().mapper.get().unwrap().lock();
+    let mut frameAllocator = kernelContext().frameAllocator.get().unwrap().lock();
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+
+    if let Some(mapped) = mapper.translate_addr(page.start_address()) {
+        return match mapped == frame.start_address() {
+            true => Ok(()),
+            false => Err(MapToError::PageAlreadyMapped(frame)),
+        };
+    }
+    unsafe {
+        mapper
+            .map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, &mut *frameAllocator)?
+            .flush();
+    }
+    Ok(())
+}
+
+fn unmapIdentity(frame: PhysFrame) {
+    let mut mapper = kernelContext().mapper.get().unwrap().lock();
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    if let Ok((_, flush)) = mapper.unmap(page) {
+        flush.flush();
+    }
+}
+
+fn delayUs(us: u64) {
+    let deadline = timer::now() + (timer::tscPerMs() * us).div_ceil(1000);
+    while timer::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// First Rust code on an AP, entered from the trampoline on the AP's own stack.
+extern "C" fn apMain() -> ! {
+    gdt::init();
+    interrupts::initIDT();
+
+    // same FPU, SSE and paging setup as the boot CPU
+    unsafe {
+        writeCR0(BOOT_CR0.load(Ordering::Relaxed));
+        writeCR4(BOOT_CR4.load(Ordering::Relaxed));
+        if BOOT_CR4.load(Ordering::Relaxed) & CR4_OSXSAVE != 0 {
+            xsetbv0(BOOT_XCR0.load(Ordering::Relaxed));
+        }
+    }
+
+    let apic = kernelContext().apic.get().expect("APIC not initialized");
+    apic.initLocalApic();
+    watchdog::initLocalWatchdog();
+    syscall::init();
+    preemptive::init_idle();
+    AP_ONLINE.store(true, Ordering::Release);
+
+    // enter the scheduler right away, it then pulls work from the other CPUs
+    apic.armTimer(Some(rdtsc()));
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,107 @@
+.extern syscall_dispatch
+
+// bytes saved: 15 registers * 8 bytes each
+.equ GPREG_SAVE_BYTES, 120
+
+// per-CPU scratch reached through the kernel GS base, see `SyscallCpu`
+.equ CPU_KERNEL_STACK, 0
+.equ CPU_USER_STACK, 8
+.equ CPU_USER_CODE, 16
+.equ CPU_USER_DATA, 24
+
+// Pushes the general-purpose registers in reverse order of the GPRegisters struct, on top of
+// an InterruptFrame, and runs the dispatcher on them.
+.macro SYSCALL_DISPATCH
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rbp
+    push rdi
+    push rsi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    // first arg: pointer to saved GP registers
+    mov rdi, rsp
+    // second arg: pointer to the InterruptFrame above them
+    lea rsi, [rsp + GPREG_SAVE_BYTES]
+
+    call syscall_dispatch
+.endm
+
+.macro POP_GPREGS
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rsi
+    pop rdi
+    pop rbp
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+.endm
+
+// SYSCALL leaves the user RIP in rcx, RFLAGS in r11 and doesn't switch stacks. FMASK has
+// cleared IF and TF, but an NMI, #DB or #MC can still arrive while rsp is the user's, so
+// those run on IST stacks of their own.
+.global syscallEntry
+syscallEntry:
+    swapgs
+    mov gs:[CPU_USER_STACK], rsp
+    mov rsp, gs:[CPU_KERNEL_STACK]
+
+    // build the frame an interrupt from ring 3 would have pushed, so the scheduler can
+    // switch away from a blocking call like from any other kernel code
+    push qword ptr gs:[CPU_USER_DATA]
+    push qword ptr gs:[CPU_USER_STACK]
+    push r11
+    push qword ptr gs:[CPU_USER_CODE]
+    push rcx
+    swapgs
+    sti
+
+    SYSCALL_DISPATCH
+
+    cli
+    // SYSRET faults in ring 0 on a non-canonical RIP, so anything outside the lower half
+    // (a frame rewritten by the kernel) takes the IRETQ path
+    mov rax, [rsp + GPREG_SAVE_BYTES]
+    shr rax, 47
+    jnz 1f
+
+    POP_GPREGS
+    mov rcx, [rsp]
+    mov r11, [rsp + 16]
+    mov rsp, [rsp + 24]
+    sysretq
+
+1:
+    POP_GPREGS
+    iretq
+
+// Legacy `int 0xAA` gate, same registers and table as SYSCALL. Unlike SYSCALL it leaves the
+// caller's DF and AC alone: DF would run string copies backwards and AC would lift SMAP for
+// the whole call, so both are cleared by hand. IRETQ restores them.
+.global legacySyscallEntry
+legacySyscallEntry:
+    cld
+    test byte ptr [rip + SMAP_ENABLED], 1
+    jz 2f
+    clac
+2:
+    SYSCALL_DISPATCH
+    POP_GPREGS
+    iretq
@@ -0,0 +1,289 @@
+//! System call interface.
+//!
+//! User code enters through SYSCALL or the legacy `int 0xAA` gate, both with the number in
+//! `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result comes
+//! back in `rax`, errors as `-errno`. Besides `rcx` and `r11`, which SYSCALL itself overwrites,
+//! the vector registers are clobbered like in a SysV function call.
+//!
+//! Numbers, flags and error values live in `rOSuser::abi`, which user programs build against.
+
+mod table;
+
+use alloc::string::String;
+use alloc::vec;
+use core::arch::global_asm;
+use core::marker::PhantomData;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+use crate::kernel::{currentCpuID, gdt, MAX_CPUS};
+use crate::mem::memory::{USER_SPACE_END, USER_SPACE_START};
+use crate::mem::userAccess::{self, checkUserRange, copy_from_user, copy_to_user, strncpy_from_user};
+use crate::multitasking::preemptive::thread::{GPRegisters, InterruptFrame};
+use crate::multitasking::preemptive::{self, ProcessID, ThreadID};
+pub use rOSuser::abi::{nr, Errno};
+
+global_asm!(include_str!("entry.asm"), options(raw));
+
+unsafe extern "C" {
+    pub fn syscallEntry();
+    pub fn legacySyscallEntry();
+}
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const EFER_SCE: u64 = 1 << 0;
+// TF, IF, DF, IOPL, NT and AC are cleared on entry
+const SYSCALL_RFLAGS_MASK: u64 = 0x4_7700;
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Per-CPU scratch the entry stub reaches through the kernel GS base, field offsets are
+/// mirrored in entry.asm.
+#[repr(C)]
+struct SyscallCpu {
+    kernelStack: u64,
+    userStack: u64,
+    userCode: u64,
+    userData: u64,
+}
+
+impl SyscallCpu {
+    const fn new() -> Self {
+        Self {
+            kernelStack: 0,
+            userStack: 0,
+            userCode: 0,
+            userData: 0,
+        }
+    }
+}
+
+static mut CPUS: [SyscallCpu; MAX_CPUS] = [const { SyscallCpu::new() }; MAX_CPUS];
+
+fn localCpu() -> *mut SyscallCpu {
+    unsafe { &raw mut CPUS[currentCpuID() as usize] }
+}
+
+/// Enables SYSCALL/SYSRET on the calling CPU, and SMAP so only the user copies can reach user
+/// memory. Needs the GDT and the local APIC.
+pub fn init() {
+    let selectors = &gdt::GDT.1;
+    let cpu = localCpu();
+
+    unsafe {
+        (*cpu).userCode = selectors.userCodeSelector.0 as u64;
+        (*cpu).userData = selectors.userDataSelector.0 as u64;
+
+        // SYSCALL loads CS from STAR[47:32] and SS 8 above it, SYSRET counts from
+        // STAR[63:48], see the GDT layout
+        let star = (selectors.userSegmentSelector.0 as u64) << 48
+            | (selectors.kernelCodeSelector.0 as u64) << 32;
+        Msr::new(IA32_STAR).write(star);
+        Msr::new(IA32_LSTAR).write(syscallEntry as *const () as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_RFLAGS_MASK);
+        Msr::new(IA32_KERNEL_GS_BASE).write(cpu as u64);
+
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SCE);
+    }
+    userAccess::enableSmap();
+
+    log::info!(
+        "System calls enabled ({} of {} implemented)",
+        table::SYSCALLS.iter().flatten().count(),
+        nr::COUNT
+    );
+}
+
+/// Sets the stack SYSCALL switches to on the calling CPU, kept equal to the TSS `rsp0`.
+pub(crate) fn setEntryStack(top: VirtAddr) {
+    unsafe { (*localCpu()).kernelStack = top.as_u64() };
+}
+
+/// Registers of a system call, with typed access to its arguments.
+pub struct SyscallArgs {
+    pub number: u64,
+    raw: [u64; 6],
+    frame: *mut InterruptFrame,
+}
+
+impl SyscallArgs {
+    /// Argument `n` decoded as `T`, EINVAL or EFAULT if it isn't a valid `T`.
+    pub fn get<T: SyscallArg>(&self, n: usize) -> Result<T, Errno> {
+        T::decode(self.raw[n])
+    }
+
+    pub fn raw(&self, n: usize) -> u64 {
+        self.raw[n]
+    }
+
+    /// Where the calling thread resumes in user mode.
+    pub fn frame(&mut self) -> &mut InterruptFrame {
+        unsafe { &mut *self.frame }
+    }
+}
+
+pub trait SyscallArg: Sized {
+    fn decode(raw: u64) -> Result<Self, Errno>;
+}
+
+impl SyscallArg for u64 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw)
+    }
+}
+
+impl SyscallArg for i64 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw as i64)
+    }
+}
+
+impl SyscallArg for usize {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw as usize)
+    }
+}
+
+impl SyscallArg for u32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        u32::try_from(raw).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl SyscallArg for i32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        i32::try_from(raw as i64).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl SyscallArg for bool {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        match raw {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+impl SyscallArg for ProcessID {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(ProcessID::from_raw(raw))
+    }
+}
+
+impl SyscallArg for ThreadID {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(ThreadID::from_raw(raw))
+    }
+}
+
+/// Pointer into the calling process's user range, checked to lie there but not that it's mapped.
+/// Memory behind it is only reached through the copies in `mem::userAccess`.
+#[derive(Debug, Clone, Copy)]
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*const T>,
+}
+
+impl<T> UserPtr<T> {
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Checks that `len` elements starting here stay inside the user range.
+    pub fn checkLen(&self, len: usize) -> Result<(), Errno> {
+        let bytes = (len as u64)
+            .checked_mul(size_of::<T>() as u64)
+            .ok_or(Errno::EFAULT)?;
+        let end = self.addr.checked_add(bytes).ok_or(Errno::EFAULT)?;
+        if end > USER_SPACE_END {
+            return Err(Errno::EFAULT);
+        }
+        Ok(())
+    }
+
+    /// Pointer `count` elements further on.
+    pub fn add(&self, count: usize) -> Result<UserPtr<T>, Errno> {
+        self.checkLen(count)?;
+        Ok(UserPtr { addr: self.addr + (count * size_of::<T>()) as u64, _marker: PhantomData })
+    }
+
+    /// Checks that `len` elements starting here are mapped for user code, writable if `write`
+    /// is set, without touching them.
+    pub fn checkMapped(&self, len: usize, write: bool) -> Result<(), Errno> {
+        self.checkLen(len)?;
+        checkUserRange(self.addr, len * size_of::<T>(), write)
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// Copies `data` to user memory starting here.
+    pub fn write(&self, data: &[T]) -> Result<(), Errno> {
+        self.checkLen(data.len())?;
+        let bytes = unsafe { core::slice::from_raw_parts(data.as_ptr().cast::<u8>(), size_of_val(data)) };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+impl UserPtr<u8> {
+    /// Fills `out` with the user bytes starting here.
+    pub fn read(&self, out: &mut [u8]) -> Result<(), Errno> {
+        copy_from_user(out, self.addr)
+    }
+
+    /// Copies a NUL-terminated UTF-8 string of at most `maxLen` bytes, not counting the NUL.
+    pub fn readCString(&self, maxLen: usize) -> Result<String, Errno> {
+        let mut bytes = vec![0; maxLen + 1];
+        let len = strncpy_from_user(&mut bytes, self.addr)?;
+        if len == bytes.len() {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        bytes.truncate(len);
+        String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl<T> SyscallArg for UserPtr<T> {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        if !(USER_SPACE_START..USER_SPACE_END).contains(&raw) || raw % align_of::<T>() as u64 != 0 {
+            return Err(Errno::EFAULT);
+        }
+        Ok(UserPtr { addr: raw, _marker: PhantomData })
+    }
+}
+
+/// Entered from both entry stubs with interrupts enabled, on the calling thread's kernel stack.
+#[unsafe(no_mangle)]
+extern "C" fn syscall_dispatch(savedRegs: *mut GPRegisters, frame: *mut InterruptFrame) {
+    let regs = unsafe { &mut *savedRegs };
+    let mut args = SyscallArgs {
+        number: regs.rax,
+        raw: [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+        frame,
+    };
+
+    let result = match table::SYSCALLS.get(args.number as usize).copied().flatten() {
+        Some((name, handler)) => {
+            log::trace!("syscall {}({:#x?})", name, args.raw);
+            handler(&mut args)
+        }
+        None => Err(Errno::ENOSYS),
+    };
+
+    regs.rax = match result {
+        Ok(value) => value,
+        Err(errno) => errno.as_return(),
+    };
+
+    // a killed process doesn't get back to user code
+    if preemptive::current_process().is_some_and(|process| process.is_killed()) {
+        preemptive::exit_thread();
+    }
+}
@@ -0,0 +1,475 @@
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use rOSuser::abi::mmap::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use rOSuser::abi::open::{O_ACCMODE, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+use rOSuser::abi::dirent::{self, DirentHeader, DT_BLK, DT_CHR, DT_DIR, DT_FIFO, DT_LNK, DT_REG};
+use rOSuser::abi::seek::{SEEK_CUR, SEEK_END, SEEK_SET};
+use rOSuser::abi::{NAME_MAX, PATH_MAX};
+use core::mem::offset_of;
+use crate::fs;
+use crate::fs::fd::OpenFile;
+use crate::fs::pipe::Pipe;
+use crate::fs::vfs::{DirEntry, FileType, OpenFlags, SeekFrom};
+use crate::kernel::{kernelContext, timer};
+use crate::mem::stack::StackBounds;
+use crate::mem::userAccess::MAX_CHECKED_RANGE;
+use crate::multitasking::preemptive::thread::{GPRegisters, JoinError, ProcessRef};
+use crate::multitasking::preemptive::{self, exit_process, exit_thread, ProcessID, ThreadID, DEFAULT_QUANTUM};
+use super::{nr, Errno, SyscallArgs, SyscallResult, UserPtr};
+
+pub type SyscallHandler = fn(&mut SyscallArgs) -> SyscallResult;
+
+/// Most bytes a read or write moves through a kernel buffer at once.
+const IO_CHUNK: usize = 64 * 1024;
+pub type SyscallEntry = Option<(&'static str, SyscallHandler)>;
+
+/// Indexed by number, calls without an entry fail with ENOSYS.
+pub static SYSCALLS: [SyscallEntry; nr::COUNT] = {
+    let mut table: [SyscallEntry; nr::COUNT] = [None; nr::COUNT];
+    table[nr::EXIT as usize] = Some(("exit", sys_exit));
+    table[nr::EXIT_THREAD as usize] = Some(("exit_thread", sys_exit_thread));
+    table[nr::YIELD as usize] = Some(("yield", sys_yield));
+    table[nr::SLEEP_MS as usize] = Some(("sleep_ms", sys_sleep_ms));
+    table[nr::GETPID as usize] = Some(("getpid", sys_getpid));
+    table[nr::GETTID as usize] = Some(("gettid", sys_gettid));
+    table[nr::WRITE as usize] = Some(("write", sys_write));
+    table[nr::UPTIME_MS as usize] = Some(("uptime_ms", sys_uptime_ms));
+    table[nr::READ as usize] = Some(("read", sys_read));
+    table[nr::OPEN as usize] = Some(("open", sys_open));
+    table[nr::CLOSE as usize] = Some(("close", sys_close));
+    table[nr::LSEEK as usize] = Some(("lseek", sys_lseek));
+    table[nr::DUP as usize] = Some(("dup", sys_dup));
+    table[nr::DUP2 as usize] = Some(("dup2", sys_dup2));
+    table[nr::PIPE as usize] = Some(("pipe", sys_pipe));
+    table[nr::CHDIR as usize] = Some(("chdir", sys_chdir));
+    table[nr::GETCWD as usize] = Some(("getcwd", sys_getcwd));
+    table[nr::FTRUNCATE as usize] = Some(("ftruncate", sys_ftruncate));
+    table[nr::FSYNC as usize] = Some(("fsync", sys_fsync));
+    table[nr::GETDENTS as usize] = Some(("getdents", sys_getdents));
+    table[nr::BRK as usize] = Some(("brk", sys_brk));
+    table[nr::MMAP as usize] = Some(("mmap", sys_mmap));
+    table[nr::MUNMAP as usize] = Some(("munmap", sys_munmap));
+    table[nr::THREAD_SPAWN as usize] = Some(("thread_spawn", sys_thread_spawn));
+    table[nr::THREAD_JOIN as usize] = Some(("thread_join", sys_thread_join));
+    table[nr::KILL as usize] = Some(("kill", sys_kill));
+    table
+};
+
+fn currentProcess() -> Result<ProcessRef, Errno> {
+    preemptive::current_process().ok_or(Errno::ESRCH)
+}
+
+fn sys_exit(args: &mut SyscallArgs) -> SyscallResult {
+    let status: i32 = args.get(0)?;
+    log::debug!("Process {:?} exited with status {}", preemptive::current_pid(), status);
+    exit_process()
+}
+
+fn sys_exit_thread(_args: &mut SyscallArgs) -> SyscallResult {
+    exit_thread()
+}
+
+fn sys_yield(_args: &mut SyscallArgs) -> SyscallResult {
+    preemptive::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep_ms(args: &mut SyscallArgs) -> SyscallResult {
+    let ms: u64 = args.get(0)?;
+    preemptive::sleep_ms(ms);
+    Ok(0)
+}
+
+fn sys_getpid(_args: &mut SyscallArgs) -> SyscallResult {
+    preemptive::current_pid()
+        .map(|pid| pid.as_u64())
+        .ok_or(Errno::ESRCH)
+}
+
+fn sys_gettid(_args: &mut SyscallArgs) -> SyscallResult {
+    preemptive::current_thread()
+        .map(|(_, tid)| tid.as_u64())
+        .ok_or(Errno::ESRCH)
+}
+
+fn sys_write(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    let buf: UserPtr<u8> = args.get(1)?;
+    let len: usize = args.get(2)?;
+
+    // the table lock isn't held while writing, a device may block. Each chunk is checked as
+    // it is copied
+    let file = currentProcess()?.files().lock().get(fd)?;
+
+    let mut chunk = vec![0; len.min(IO_CHUNK)];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(chunk.len());
+        // what was written before a fault or error still counts
+        let result = buf
+            .add(written)?
+            .read(&mut chunk[..n])
+            .and_then(|()| file.write(&chunk[..n]).map_err(Errno::from));
+        match result {
+            Ok(done) => {
+                written += done;
+                if done < n {
+                    break;
+                }
+            }
+            Err(_) if written > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(written as u64)
+}
+
+fn sys_read(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    let buf: UserPtr<u8> = args.get(1)?;
+    let len: usize = args.get(2)?;
+
+    let file = currentProcess()?.files().lock().get(fd)?;
+    // checked first so nothing is consumed from a pipe or the console just to be dropped
+    let len = len.min(IO_CHUNK);
+    buf.checkMapped(len, true)?;
+
+    let mut bytes = vec![0; len];
+    let n = file.read(&mut bytes)?;
+    buf.write(&bytes[..n])?;
+    Ok(n as u64)
+}
+
+/// Translates the ABI's `O_*` flags.
+fn openFlags(raw: u32) -> Result<OpenFlags, Errno> {
+    if raw & !(O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut flags = match raw & O_ACCMODE {
+        O_RDONLY => OpenFlags::READ,
+        O_WRONLY => OpenFlags::WRITE,
+        O_RDWR => OpenFlags::READ | OpenFlags::WRITE,
+        _ => return Err(Errno::EINVAL),
+    };
+    for (bit, flag) in [
+        (O_CREAT, OpenFlags::CREATE),
+        (O_EXCL, OpenFlags::EXCLUSIVE),
+        (O_TRUNC, OpenFlags::TRUNCATE),
+        (O_APPEND, OpenFlags::APPEND),
+    ] {
+        if raw & bit != 0 {
+            flags |= flag;
+        }
+    }
+    Ok(flags)
+}
+
+/// Relative paths start in the working directory of the process.
+fn sys_open(args: &mut SyscallArgs) -> SyscallResult {
+    let path: UserPtr<u8> = args.get(0)?;
+    let flags = openFlags(args.get(1)?)?;
+    let _mode: u32 = args.get(2)?;
+
+    let path = path.readCString(PATH_MAX - 1)?;
+    let process = currentProcess()?;
+    // opening a FIFO blocks, so the working directory isn't kept locked
+    let cwd = process.cwd().lock().clone();
+    let file = Arc::new(fs::path::open(&cwd, &path, flags)?);
+    let fd = process.files().lock().insert(file)?;
+    Ok(fd as u64)
+}
+
+fn sys_chdir(args: &mut SyscallArgs) -> SyscallResult {
+    let path: UserPtr<u8> = args.get(0)?;
+    let path = path.readCString(PATH_MAX - 1)?;
+
+    let process = currentProcess()?;
+    let cwd = process.cwd().lock().clone();
+    let dir = fs::path::resolve(&cwd, &path, true)?;
+    if !dir.isDirectory() {
+        return Err(Errno::ENOTDIR);
+    }
+    *process.cwd().lock() = dir.path();
+    Ok(0)
+}
+
+/// Writes the working directory with a NUL into `buf`, returns its length without the NUL.
+fn sys_getcwd(args: &mut SyscallArgs) -> SyscallResult {
+    let buf: UserPtr<u8> = args.get(0)?;
+    let len: usize = args.get(1)?;
+
+    let mut cwd = currentProcess()?.cwd().lock().clone().into_bytes();
+    if cwd.len() >= len {
+        return Err(Errno::ERANGE);
+    }
+    let pathLen = cwd.len();
+    cwd.push(0);
+    buf.write(&cwd)?;
+    Ok(pathLen as u64)
+}
+
+fn sys_close(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    currentProcess()?.files().lock().close(fd)?;
+    Ok(0)
+}
+
+fn sys_lseek(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    let offset: i64 = args.get(1)?;
+    let whence: u32 = args.get(2)?;
+
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| Errno::EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let file = currentProcess()?.files().lock().get(fd)?;
+    Ok(file.seek(pos)?)
+}
+
+fn sys_ftruncate(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    let size: u64 = args.get(1)?;
+
+    let file = currentProcess()?.files().lock().get(fd)?;
+    file.truncate(size)?;
+    Ok(0)
+}
+
+fn sys_fsync(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    let file = currentProcess()?.files().lock().get(fd)?;
+    file.sync()?;
+    Ok(0)
+}
+
+/// Appends the `getdents` record of `entry` to `out`.
+fn pushDirent(out: &mut Vec<u8>, entry: &DirEntry) {
+    let fileType = match entry.fileType {
+        FileType::RegularFile => DT_REG,
+        FileType::Directory => DT_DIR,
+        FileType::CharDevice => DT_CHR,
+        FileType::BlockDevice => DT_BLK,
+        FileType::Symlink => DT_LNK,
+        FileType::Fifo => DT_FIFO,
+    };
+    let start = out.len();
+    let reclen = dirent::recordLen(entry.name.len());
+    out.resize(start + reclen, 0);
+
+    let record = &mut out[start..];
+    let next = offset_of!(DirentHeader, next);
+    record[next..next + 8].copy_from_slice(&entry.cookie.to_ne_bytes());
+    let recordLen = offset_of!(DirentHeader, reclen);
+    record[recordLen..recordLen + 2].copy_from_slice(&(reclen as u16).to_ne_bytes());
+    record[offset_of!(DirentHeader, fileType)] = fileType;
+    record[offset_of!(DirentHeader, nameLen)] = entry.name.len() as u8;
+    let name = size_of::<DirentHeader>();
+    record[name..name + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+}
+
+/// Fills `buf` with as many entries of a directory as fit, returns the bytes used, 0 after the
+/// last entry.
+fn sys_getdents(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    let buf: UserPtr<u8> = args.get(1)?;
+    let len: usize = args.get(2)?;
+
+    let file = currentProcess()?.files().lock().get(fd)?;
+    let len = len.min(IO_CHUNK);
+    buf.checkMapped(len, true)?;
+
+    let mut records = Vec::new();
+    let mut tooSmall = false;
+    file.readdir(|entry| {
+        // the record only has a byte for the length, no program could open it anyway
+        if entry.name.len() > NAME_MAX {
+            log::warn!("getdents: skipping an entry with a {} byte name", entry.name.len());
+            return true;
+        }
+        if records.len() + dirent::recordLen(entry.name.len()) > len {
+            tooSmall = records.is_empty();
+            return false;
+        }
+        pushDirent(&mut records, entry);
+        true
+    })?;
+
+    if tooSmall {
+        return Err(Errno::EINVAL);
+    }
+    buf.write(&records)?;
+    Ok(records.len() as u64)
+}
+
+fn sys_dup(args: &mut SyscallArgs) -> SyscallResult {
+    let fd: u32 = args.get(0)?;
+    Ok(currentProcess()?.files().lock().dup(fd)? as u64)
+}
+
+fn sys_dup2(args: &mut SyscallArgs) -> SyscallResult {
+    let oldFd: u32 = args.get(0)?;
+    let newFd: u32 = args.get(1)?;
+    Ok(currentProcess()?.files().lock().dup2(oldFd, newFd)? as u64)
+}
+
+/// Stores the read and write descriptor of a new pipe in the two `u32`s at the argument.
+fn sys_pipe(args: &mut SyscallArgs) -> SyscallResult {
+    let fds: UserPtr<u32> = args.get(0)?;
+    fds.checkMapped(2, true)?;
+
+    let (readEnd, writeEnd) = Pipe::new().ends();
+    let readFile = Arc::new(OpenFile::new(readEnd, OpenFlags::READ)?);
+    let writeFile = Arc::new(OpenFile::new(writeEnd, OpenFlags::WRITE)?);
+
+    let process = currentProcess()?;
+    let mut files = process.files().lock();
+    let readFd = files.insert(readFile)?;
+    let writeFd = match files.insert(writeFile) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let _ = files.close(readFd);
+            return Err(e.into());
+        }
+    };
+    drop(files);
+
+    fds.write(&[readFd, writeFd])?;
+    Ok(0)
+}
+
+fn sys_uptime_ms(_args: &mut SyscallArgs) -> SyscallResult {
+    Ok(timer::cyclesToMs(timer::now()))
+}
+
+fn sys_brk(args: &mut SyscallArgs) -> SyscallResult {
+    let requested: u64 = args.get(0)?;
+    let process = currentProcess()?;
+
+    let mut memory = process.memory().lock();
+    let mut mapper = process.mapper();
+    let frameAllocator = kernelContext().frameAllocator.get().unwrap();
+    Ok(memory.brk(requested, &mut mapper, frameAllocator))
+}
+
+/// Only private anonymous mappings, the address hint, descriptor and offset are ignored.
+fn sys_mmap(args: &mut SyscallArgs) -> SyscallResult {
+    let len: u64 = args.get(1)?;
+    let prot: u32 = args.get(2)?;
+    let flags: u32 = args.get(3)?;
+
+    if flags != MAP_PRIVATE | MAP_ANONYMOUS {
+        return Err(Errno::EINVAL);
+    }
+    // a present page is always readable, so there is no PROT_NONE
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut pageFlags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        pageFlags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        pageFlags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let process = currentProcess()?;
+    let mut memory = process.memory().lock();
+    let mut mapper = process.mapper();
+    let frameAllocator = kernelContext().frameAllocator.get().unwrap();
+    memory
+        .mmap(len, pageFlags, &mut mapper, frameAllocator)
+        .ok_or(Errno::ENOMEM)
+}
+
+fn sys_munmap(args: &mut SyscallArgs) -> SyscallResult {
+    let addr: u64 = args.get(0)?;
+    let len: u64 = args.get(1)?;
+    let process = currentProcess()?;
+
+    let mut memory = process.memory().lock();
+    let mut mapper = process.mapper();
+    let frameAllocator = kernelContext().frameAllocator.get().unwrap();
+    if memory.munmap(addr, len, &mut mapper, frameAllocator) {
+        Ok(0)
+    } else {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// Starts a thread at `entry` with `arg` in `rdi` on a stack the caller mapped writable in its
+/// own address space, entered like a call so the runtime's start function sees an aligned stack.
+fn sys_thread_spawn(args: &mut SyscallArgs) -> SyscallResult {
+    let entry: UserPtr<u8> = args.get(0)?;
+    let arg: u64 = args.get(1)?;
+    let stack: UserPtr<u8> = args.get(2)?;
+    let stackSize: usize = args.get(3)?;
+
+    if stack.addr() % Size4KiB::SIZE != 0 || stackSize == 0 || stackSize as u64 % Size4KiB::SIZE != 0
+        || stackSize > MAX_CHECKED_RANGE
+    {
+        return Err(Errno::EINVAL);
+    }
+    stack.checkMapped(stackSize, true)?;
+
+    let stackBounds = StackBounds {
+        start: VirtAddr::new(stack.addr()),
+        end: VirtAddr::new(stack.addr() + stackSize as u64),
+    };
+    let gpRegisters = GPRegisters {
+        rdi: arg,
+        ..GPRegisters::default()
+    };
+
+    let process = currentProcess()?;
+    let tid = process
+        .create_user_thread_at(
+            VirtAddr::new(entry.addr()),
+            stackBounds,
+            stackBounds.end - 8u64,
+            gpRegisters,
+            DEFAULT_QUANTUM,
+        )
+        .map_err(|_| Errno::ENOMEM)?;
+    process.start_thread(tid).ok_or(Errno::ESRCH)?;
+    Ok(tid.as_u64())
+}
+
+/// Waits for another thread of the calling process.
+fn sys_thread_join(args: &mut SyscallArgs) -> SyscallResult {
+    let tid: ThreadID = args.get(0)?;
+    let (pid, current) = preemptive::current_thread().ok_or(Errno::ESRCH)?;
+    if tid == current {
+        return Err(Errno::EINVAL);
+    }
+
+    preemptive::join_thread(pid, tid).map_err(|e| match e {
+        JoinError::NoSuchThread => Errno::ESRCH,
+        JoinError::AlreadyJoined => Errno::EINVAL,
+    })?;
+    currentProcess()?.reap_thread(&tid);
+    Ok(0)
+}
+
+fn sys_kill(args: &mut SyscallArgs) -> SyscallResult {
+    let pid: ProcessID = args.get(0)?;
+    if Some(pid) == preemptive::current_pid() {
+        exit_process();
+    }
+
+    // kernel, idle and reaper threads can't be killed from user space
+    let target = preemptive::get_process(pid).ok_or(Errno::ESRCH)?;
+    if !target.is_user() {
+        return Err(Errno::EPERM);
+    }
+
+    preemptive::kill_process(pid).ok_or(Errno::ESRCH)?;
+    Ok(0)
+}
@@ -1,20 +1,68 @@
 use alloc::collections::BinaryHeap;
-use crate::multitasking::preemptive::ThreadID;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts;
+use crate::kernel::kernelContext;
+use crate::multitasking::preemptive::{ProcessID, ThreadID, SCHEDULER};
+use crate::util::wrappers::rdtsc;
 
+/// Length of one scheduling quantum tick, a thread's slice is `maxQuantum` of these.
+pub const TICK_MS: u64 = 10;
 
-#[derive(Debug, PartialEq, Eq)]
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn setTscPerMs(tscPerMs: u64) {
+    TSC_PER_MS.store(tscPerMs, Ordering::Relaxed);
+}
+
+pub fn tscPerMs() -> u64 {
+    TSC_PER_MS.load(Ordering::Relaxed)
+}
+
+/// Current time in TSC cycles, all timer deadlines use this clock.
+pub fn now() -> u64 {
+    rdtsc()
+}
+
+pub fn msToCycles(ms: u64) -> u64 {
+    ms.saturating_mul(tscPerMs())
+}
+
+pub fn cyclesToMs(cycles: u64) -> u64 {
+    match tscPerMs() {
+        0 => 0,
+        perMs => cycles / perMs,
+    }
+}
+
+#[derive(Debug)]
 struct Timer {
     deadline: u64,
     payload: TimerPayload,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum TimerPayload {
-    WakeThread(ThreadID),
+#[derive(Debug)]
+pub enum TimerPayload {
+    WakeThread(ProcessID, ThreadID),
     // DeferSignal { threadID: ThreadID, signal: Signal },
     DeferImportant(ThreadID),
+    /// Wakes an async task (or a thread in `block_on`) waiting in `sleepFor`, whichever
+    /// waker it polled with last.
+    WakeWaker(Arc<AtomicWaker>),
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
 }
 
+impl Eq for Timer {}
+
 impl Ord for Timer {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
         // Timers are ordered by earliest deadline
@@ -46,23 +94,87 @@ impl TimerQueue {
         self.heap.push(timer);
     }
 
-    pub fn popExpiredTimers(&mut self, now: u64) {
-        while let Some(timer) = self.heap.peek() {
-            if timer.deadline > now {
-                break;
-            }
+    /// Deadline of the earliest pending timer, in TSC cycles.
+    pub fn nextDeadline(&self) -> Option<u64> {
+        self.heap.peek().map(|timer| timer.deadline)
+    }
 
-            let expired_timer = self.heap.pop().unwrap();
-            match expired_timer.payload {
-                TimerPayload::WakeThread(_thread_id) => {
-                    // Wake the thread with thread_id
-                    // (Implementation depends on the rest of the kernel)
-                }
-                TimerPayload::DeferImportant(_thread_id) => {
-                    // Handle defer important for thread_id
-                    // (Implementation depends on the rest of the kernel)
-                }
+    /// Removes and returns the payload of the earliest timer if it expired by `now`.
+    pub fn popExpired(&mut self, now: u64) -> Option<TimerPayload> {
+        if self.heap.peek()?.deadline > now {
+            return None;
+        }
+        self.heap.pop().map(|timer| timer.payload)
+    }
+}
+
+/// Fires every timer that expired by `now`.
+///
+/// Neither the queue nor the scheduler is locked while a payload runs, so wakers are free
+/// to wake threads. Call with interrupts disabled.
+pub fn fireExpiredTimers(now: u64) {
+    let Some(queue) = kernelContext().timerQueue.get() else {
+        return;
+    };
+
+    loop {
+        let Some(payload) = queue.lock().popExpired(now) else {
+            break;
+        };
+
+        match payload {
+            TimerPayload::WakeThread(pid, tid) => {
+                SCHEDULER.lock().wake(pid, tid);
             }
+            TimerPayload::DeferImportant(tid) => {
+                SCHEDULER.lock().prioritize_thread(tid);
+            }
+            TimerPayload::WakeWaker(waker) => waker.wake(),
+        }
+    }
+}
+
+/// Future that completes once the TSC passes `deadline`.
+pub struct Sleep {
+    deadline: u64,
+    // shared with the timer, which is registered on the first pending poll
+    waker: Option<Arc<AtomicWaker>>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if let Some(waker) = &self.waker {
+            waker.register(cx.waker());
+            return Poll::Pending;
         }
+
+        let waker = Arc::new(AtomicWaker::new());
+        waker.register(cx.waker());
+        let deadline = self.deadline;
+        let payload = TimerPayload::WakeWaker(waker.clone());
+        self.waker = Some(waker);
+        interrupts::without_interrupts(|| {
+            kernelContext()
+                .timerQueue
+                .get()
+                .expect("Timer Queue not initialized")
+                .lock()
+                .addTimer(deadline, payload);
+        });
+        Poll::Pending
+    }
+}
+
+/// Completes after at least `ms` milliseconds without blocking the thread polling it.
+pub fn sleepFor(ms: u64) -> Sleep {
+    Sleep {
+        deadline: now() + msToCycles(ms),
+        waker: None,
     }
 }
@@ -0,0 +1,497 @@
+//! Soft-lockup watchdog.
+//!
+//! The scheduler reports every pass through a CPU's timer or reschedule trampoline. A check
+//! running either from the PIT interrupt or from an NMI raised by a LAPIC performance counter
+//! flags a CPU that has gone `thresholdMs` without scheduling while not idle. Only the NMI
+//! source catches threads spinning with interrupts disabled, the PIT one can't interrupt them.
+//!
+//! Reports go straight to serial, the log and scheduler locks may be held by the stuck code.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use cpuid::CPUID;
+use cpuid::pmu::PMonEvent;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::debug::serial::_print_emergency;
+use crate::kernel::{binIO, currentCpuID, gdt, kernelContext, timer, MAX_CPUS};
+use crate::multitasking::preemptive::thread::{GPRegisters, InterruptFrame};
+use crate::multitasking::preemptive::{exit_thread, ProcessID, ThreadID, SCHEDULER};
+use crate::util::wrappers::rdtsc;
+
+// Saves the interrupted registers for the report, plus the SSE state the Rust handler may
+// clobber. CR0.TS is cleared for the handler and restored on the way out.
+global_asm!(
+    ".macro WATCHDOG_ENTRY name, handler",
+    ".global \\name",
+    "\\name:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rbp",
+    "    push rdi",
+    "    push rsi",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    // rbx and r12 are callee-saved, so they survive the call
+    "    mov rbx, rsp",
+    "    mov r12, cr0",
+    "    clts",
+    // the interrupted code's DF and AC come back with IRETQ, Rust expects DF clear and AC
+    // would lift SMAP
+    "    cld",
+    "    test byte ptr [rip + SMAP_ENABLED], 1",
+    "    jz 2f",
+    "    clac",
+    "2:",
+    "    sub rsp, 512",
+    "    and rsp, -16",
+    "    fxsave64 [rsp]",
+    "    mov rdi, rbx",
+    "    lea rsi, [rbx + 120]",
+    "    call \\handler",
+    "    fxrstor64 [rsp]",
+    "    mov cr0, r12",
+    "    mov rsp, rbx",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rsi",
+    "    pop rdi",
+    "    pop rbp",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    iretq",
+    ".endm",
+    "WATCHDOG_ENTRY nmiEntry, nmi_trampoline",
+    "WATCHDOG_ENTRY watchdogTimerEntry, watchdog_timer_trampoline",
+);
+
+unsafe extern "C" {
+    pub fn nmiEntry();
+    pub fn watchdogTimerEntry();
+}
+
+pub const DEFAULT_THRESHOLD_MS: u64 = 2000;
+/// How often the PIT source checks for lockups.
+pub const WATCHDOG_HZ: u32 = 10;
+const MAX_BACKTRACE_DEPTH: usize = 16;
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+// unhalted core cycles in ring 0 and 3, interrupt on overflow
+const PERFEVTSEL_CORE_CYCLES: u64 = 0x3C | (1 << 16) | (1 << 17) | (1 << 20) | (1 << 22);
+
+const PIT_CH0_DATA: u16 = 0x40;
+const PIT_CMD: u16 = 0x43;
+const PIT_FREQ_HZ: u32 = 1_193_182;
+
+/// What to do with a CPU that stopped scheduling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LockupPolicy {
+    /// Dump the report and keep going.
+    Log = 0,
+    /// Dump the report and end the stuck thread. Locks it holds are never released.
+    KillThread = 1,
+    Panic = 2,
+}
+
+impl LockupPolicy {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => LockupPolicy::KillThread,
+            2 => LockupPolicy::Panic,
+            _ => LockupPolicy::Log,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogSource {
+    /// Checks from the PIT interrupt, which can't see CPUs running with interrupts disabled.
+    Timer,
+    /// Checks from an NMI raised by a performance counter every few hundred ms of busy CPU time.
+    Nmi,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogConfig {
+    pub source: WatchdogSource,
+    pub thresholdMs: u64,
+    pub policy: LockupPolicy,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            source: WatchdogSource::Nmi,
+            thresholdMs: DEFAULT_THRESHOLD_MS,
+            policy: LockupPolicy::Log,
+        }
+    }
+}
+
+/// What the scheduler last reported for one CPU.
+struct CpuWatch {
+    /// TSC time of the last pass through the scheduler, 0 until the first one.
+    lastProgress: AtomicU64,
+    pid: AtomicU64,
+    tid: AtomicU64,
+    idle: AtomicBool,
+    /// Set once a lockup was reported, so a stuck CPU is reported once per lockup.
+    reported: AtomicBool,
+}
+
+impl CpuWatch {
+    const fn new() -> Self {
+        Self {
+            lastProgress: AtomicU64::new(0),
+            pid: AtomicU64::new(0),
+            tid: AtomicU64::new(0),
+            idle: AtomicBool::new(false),
+            reported: AtomicBool::new(false),
+        }
+    }
+}
+
+static CPUS: [CpuWatch; MAX_CPUS] = [const { CpuWatch::new() }; MAX_CPUS];
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static THRESHOLD_CYCLES: AtomicU64 = AtomicU64::new(0);
+static POLICY: AtomicU8 = AtomicU8::new(LockupPolicy::Log as u8);
+/// Counter value loaded after each overflow, 0 while the NMI source is off.
+static PMC_RELOAD: AtomicU64 = AtomicU64::new(0);
+static PMC_TOP_BIT: AtomicU64 = AtomicU64::new(0);
+static PMC_GLOBAL_CTRL: AtomicBool = AtomicBool::new(false);
+// copied at init, the OnceInit holding it takes a lock
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the watchdog with `config`, falling back to the PIT when the CPU has no usable
+/// performance counter. Returns the source actually in use.
+pub fn initWatchdog(config: WatchdogConfig) -> WatchdogSource {
+    THRESHOLD_CYCLES.store(timer::msToCycles(config.thresholdMs), Ordering::Relaxed);
+    POLICY.store(config.policy as u8, Ordering::Relaxed);
+    if let Some(offset) = crate::mem::memory::PHYSICAL_MEMORY_OFFSET.get_copy() {
+        PHYS_OFFSET.store(offset, Ordering::Relaxed);
+    }
+
+    let source = match config.source {
+        WatchdogSource::Nmi if startPerfCounter(config.thresholdMs) => WatchdogSource::Nmi,
+        WatchdogSource::Nmi => {
+            log::warn!("Watchdog: no usable performance counter, checking from the PIT instead");
+            startPit();
+            WatchdogSource::Timer
+        }
+        WatchdogSource::Timer => {
+            startPit();
+            WatchdogSource::Timer
+        }
+    };
+
+    ENABLED.store(true, Ordering::Release);
+    log::info!(
+        "Watchdog: {:?} source, {} ms threshold, policy {:?}",
+        source, config.thresholdMs, config.policy
+    );
+    source
+}
+
+pub fn set_lockup_policy(policy: LockupPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Counts as a pass through the scheduler, for code that legitimately runs with interrupts
+/// disabled for longer than the threshold.
+pub fn touch_watchdog() {
+    let watch = &CPUS[cpuIndex()];
+    watch.lastProgress.store(rdtsc(), Ordering::Relaxed);
+    watch.reported.store(false, Ordering::Relaxed);
+}
+
+/// Called by the scheduler trampolines with the thread they are about to resume.
+pub fn reportScheduled(current: Option<(ProcessID, ThreadID)>, idle: bool) {
+    let watch = &CPUS[cpuIndex()];
+    let (pid, tid) = current.map_or((0, 0), |(pid, tid)| (pid.as_u64(), tid.as_u64()));
+    watch.pid.store(pid, Ordering::Relaxed);
+    watch.tid.store(tid, Ordering::Relaxed);
+    watch.idle.store(idle, Ordering::Relaxed);
+    watch.lastProgress.store(rdtsc(), Ordering::Relaxed);
+    watch.reported.store(false, Ordering::Relaxed);
+}
+
+fn cpuIndex() -> usize {
+    currentCpuID() as usize
+}
+
+/// Starts the NMI source on an AP, the PIT one already covers every CPU. Needs the AP's
+/// local APIC.
+pub fn initLocalWatchdog() {
+    if PMC_RELOAD.load(Ordering::Relaxed) != 0 {
+        armPerfCounter();
+    }
+}
+
+/// Sets up PMC0 to overflow after half the threshold worth of busy cycles and starts it on
+/// the calling CPU.
+fn startPerfCounter(thresholdMs: u64) -> bool {
+    let Some(info) = CPUID::pmonInfo() else {
+        return false;
+    };
+    if info.gpCountersPerProcessor() == 0 || !info.eventSupported(PMonEvent::CoreCycle) {
+        return false;
+    }
+    if kernelContext().apic.get().is_none() {
+        return false;
+    }
+
+    // writes through IA32_PMC0 sign-extend bit 31, which caps the period
+    let period = timer::msToCycles(thresholdMs / 2).clamp(1, i32::MAX as u64);
+    PMC_RELOAD.store(period.wrapping_neg(), Ordering::Relaxed);
+    PMC_TOP_BIT.store(1u64 << (info.gpCounterBitWidth().max(1) - 1), Ordering::Relaxed);
+    PMC_GLOBAL_CTRL.store(info.versionID() >= 2, Ordering::Relaxed);
+
+    armPerfCounter();
+    true
+}
+
+/// Programs the calling CPU's PMC0 and LVT entry with the period `startPerfCounter` chose.
+fn armPerfCounter() {
+    let Some(apic) = kernelContext().apic.get() else {
+        return;
+    };
+
+    apic.enablePerfCounterNmi();
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+        Msr::new(IA32_PMC0).write(PMC_RELOAD.load(Ordering::Relaxed));
+        if PMC_GLOBAL_CTRL.load(Ordering::Relaxed) {
+            let mut globalCtrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            let enabled = globalCtrl.read();
+            globalCtrl.write(enabled | 1);
+        }
+        Msr::new(IA32_PERFEVTSEL0).write(PERFEVTSEL_CORE_CYCLES);
+    }
+}
+
+/// Whether PMC0 overflowed, rearming it if so.
+fn takePerfCounterOverflow() -> bool {
+    let reload = PMC_RELOAD.load(Ordering::Relaxed);
+    if reload == 0 {
+        return false;
+    }
+
+    // the counter counts up from the negative reload value, so its top bit clears on overflow
+    let value = unsafe { Msr::new(IA32_PMC0).read() };
+    if value & PMC_TOP_BIT.load(Ordering::Relaxed) != 0 {
+        return false;
+    }
+
+    unsafe {
+        Msr::new(IA32_PMC0).write(reload);
+        if PMC_GLOBAL_CTRL.load(Ordering::Relaxed) {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+    }
+    if let Some(apic) = kernelContext().apic.get() {
+        apic.enablePerfCounterNmi();
+    }
+    true
+}
+
+/// Runs PIT channel 0 as a rate generator at `WATCHDOG_HZ`, IRQ0 is already routed to us.
+fn startPit() {
+    let divisor = (PIT_FREQ_HZ / WATCHDOG_HZ).min(u16::MAX as u32);
+    unsafe {
+        binIO::out8(PIT_CMD, 0b00110100); // channel 0, LSB/MSB, rate generator
+        binIO::out8(PIT_CH0_DATA, (divisor & 0xFF) as u8);
+        binIO::out8(PIT_CH0_DATA, ((divisor >> 8) & 0xFF) as u8);
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn nmi_trampoline(savedRegs: *mut GPRegisters, frame: *mut InterruptFrame) {
+    if !takePerfCounterOverflow() {
+        // the logger's lock may be held by the interrupted code
+        _print_emergency(format_args!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}\n", unsafe { &*frame }));
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    checkLocalCpu(savedRegs, frame);
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn watchdog_timer_trampoline(savedRegs: *mut GPRegisters, frame: *mut InterruptFrame) {
+    checkLocalCpu(savedRegs, frame);
+
+    // without local registers other CPUs can only be reported, not acted on
+    let now = rdtsc();
+    let local = cpuIndex();
+    for (index, watch) in CPUS.iter().enumerate() {
+        if index != local && isLockedUp(watch, now) {
+            _print_emergency(format_args!(
+                "watchdog: soft lockup on CPU {} for {} ms, PID {} TID {}\n",
+                index,
+                timer::cyclesToMs(now - watch.lastProgress.load(Ordering::Relaxed)),
+                watch.pid.load(Ordering::Relaxed),
+                watch.tid.load(Ordering::Relaxed),
+            ));
+        }
+    }
+
+    kernelContext()
+        .apic
+        .get()
+        .expect("APIC not initialized")
+        .notifyEOI();
+}
+
+/// Whether `watch` went past the threshold without scheduling, claiming the report if so.
+fn isLockedUp(watch: &CpuWatch, now: u64) -> bool {
+    let last = watch.lastProgress.load(Ordering::Relaxed);
+    if last == 0 || watch.idle.load(Ordering::Relaxed) {
+        return false;
+    }
+    if now.saturating_sub(last) < THRESHOLD_CYCLES.load(Ordering::Relaxed) {
+        return false;
+    }
+    !watch.reported.swap(true, Ordering::Relaxed)
+}
+
+fn checkLocalCpu(savedRegs: *mut GPRegisters, frame: *mut InterruptFrame) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let cpu = cpuIndex();
+    let watch = &CPUS[cpu];
+    let now = rdtsc();
+    if !isLockedUp(watch, now) {
+        return;
+    }
+
+    let regs = unsafe { &*savedRegs };
+    let frame = unsafe { &mut *frame };
+    let pid = watch.pid.load(Ordering::Relaxed);
+    let tid = watch.tid.load(Ordering::Relaxed);
+    let stuckMs = timer::cyclesToMs(now - watch.lastProgress.load(Ordering::Relaxed));
+    dumpLockup(cpu, stuckMs, pid, tid, regs, frame);
+
+    match LockupPolicy::from_u8(POLICY.load(Ordering::Relaxed)) {
+        LockupPolicy::Log => {}
+        LockupPolicy::KillThread => {
+            // exiting needs the scheduler, which the stuck code may be holding itself
+            if pid == 0 || SCHEDULER.is_locked() {
+                panic!("watchdog: CPU {} locked up and the stuck thread can't be killed", cpu);
+            }
+            _print_emergency(format_args!("watchdog: killing PID {} TID {}\n", pid, tid));
+            redirectToExit(frame);
+        }
+        LockupPolicy::Panic => {
+            panic!("watchdog: CPU {} locked up for {} ms in PID {} TID {}", cpu, stuckMs, pid, tid);
+        }
+    }
+}
+
+/// Makes the interrupted code return into `exit_thread` instead, as if it had called it.
+///
+/// Code interrupted in ring 3 returns to ring 0 on its thread's kernel stack, which is unused
+/// while the thread runs in user mode.
+fn redirectToExit(frame: &mut InterruptFrame) {
+    const RFLAGS_IF: u64 = 1 << 9;
+    if frame.cs & 0b11 == 3 {
+        let selectors = &gdt::GDT.1;
+        frame.cs = selectors.kernelCodeSelector.0 as u64;
+        frame.ss = selectors.kernelDataSelector.0 as u64;
+        frame.rsp = gdt::kernelStack().as_u64();
+    }
+    frame.rip = lockupExit as *const () as u64;
+    // a call leaves rsp 8 bytes below a 16 byte boundary
+    frame.rsp = (frame.rsp & !0xF) - 8;
+    frame.rflags |= RFLAGS_IF;
+}
+
+extern "C" fn lockupExit() -> ! {
+    exit_thread();
+}
+
+fn dumpLockup(cpu: usize, stuckMs: u64, pid: u64, tid: u64, regs: &GPRegisters, frame: &InterruptFrame) {
+    _print_emergency(format_args!(
+        "watchdog: soft lockup on CPU {}, no scheduling for {} ms\n\
+         PID {} TID {}\n\
+         RIP {:#018x} CS {:#06x} RFLAGS {:#010x} RSP {:#018x} SS {:#06x}\n\
+         RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}\n\
+         RSI {:#018x} RDI {:#018x} RBP {:#018x}\n\
+         R8  {:#018x} R9  {:#018x} R10 {:#018x} R11 {:#018x}\n\
+         R12 {:#018x} R13 {:#018x} R14 {:#018x} R15 {:#018x}\n",
+        cpu, stuckMs, pid, tid,
+        frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss,
+        regs.rax, regs.rbx, regs.rcx, regs.rdx,
+        regs.rsi, regs.rdi, regs.rbp,
+        regs.r8, regs.r9, regs.r10, regs.r11,
+        regs.r12, regs.r13, regs.r14, regs.r15,
+    ));
+
+    _print_emergency(format_args!("backtrace:\n  #0  {:#018x}\n", frame.rip));
+    let mut framePtr = regs.rbp;
+    for depth in 1..MAX_BACKTRACE_DEPTH {
+        // needs frame pointers, stops at the first link that doesn't look like one
+        if framePtr == 0 || framePtr % 8 != 0 || !isMapped(framePtr) || !isMapped(framePtr + 8) {
+            break;
+        }
+        let (next, returnAddr) = unsafe { (*(framePtr as *const u64), *((framePtr + 8) as *const u64)) };
+        if returnAddr == 0 {
+            break;
+        }
+        _print_emergency(format_args!("  #{:<2} {:#018x}\n", depth, returnAddr));
+        if next <= framePtr {
+            break;
+        }
+        framePtr = next;
+    }
+}
+
+/// Walks the active page tables by hand, the mapper is behind a lock the stuck code may hold.
+fn isMapped(addr: u64) -> bool {
+    let offset = PHYS_OFFSET.load(Ordering::Relaxed);
+    let Ok(virt) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    if offset == 0 {
+        return false;
+    }
+
+    let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+    let mut tableAddr = Cr3::read().0.start_address().as_u64();
+    for (level, index) in indices.into_iter().enumerate() {
+        let table = unsafe { &*((offset + tableAddr) as *const PageTable) };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        tableAddr = entry.addr().as_u64();
+    }
+    true
+}
@@ -12,20 +12,31 @@ use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, MemoryRegions};
 use bootloader_api::BootInfo;
 use bootloader_x86_64_common::logger::LockedLogger;
-use rOSkernel::multitasking::preemptive::{thread::Process, Parent};
+use rOSkernel::multitasking::preemptive::{fpu, programs, set_realtime, spawn, stats::topThread, thread::Process, wait_next_period, Parent};
+use rOSkernel::multitasking::preemptive::realtime::RealtimeParams;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::panic::PanicInfo;
-use spin::Mutex;
 use rOSkernel::kernel::framebuffer::FrameBufferEditor;
 use rOSkernel::kernel::AdvancedPic::AdvancedPic;
-use rOSkernel::kernel::{gdt, initKernelContext, interrupts, kernelContext, setKernelFrameAllocator, setKernelFrameBuffer, setKernelHeapManager, setKernelLogger, setKernelMapper};
+use rOSkernel::kernel::{gdt, initKernelContext, interrupts, syscall, kernelContext, setKernelFrameAllocator, setKernelFrameBuffer, setKernelHeapManager, setKernelLogger, setKernelMapper, setKernelTimerQueue};
+use rOSkernel::kernel::timer::TimerQueue;
+use rOSkernel::kernel::watchdog::{self, LockupPolicy, WatchdogConfig};
 use rOSkernel::mem::allocator::HeapRegionAllocator;
 use rOSkernel::mem::{memory, memory::BootInfoFrameAllocator, HEAP};
 use rOSkernel::tasks::keyboard;
+use rOSkernel::tasks::shell::shellThread;
+use rOSkernel::multitasking::cooperative::{executor::start_executor_thread, Task};
+use rOSkernel::fs::disk::floppy::detectFloppyDrives;
+use rOSkernel::fs::disk::{ramdisk::RamDisk, registry};
+use rOSkernel::fs::fd::OpenFile;
+use rOSkernel::fs::pipe::Pipe;
+use rOSkernel::fs::vfs::OpenFlags;
 use x86_64::VirtAddr;
 use rOSkernel::kernel::kacpi::ACPIHandler;
 use rOSkernel::mem::heap::Heap;
-use rOSkernel::util::wrappers::{CPUID, FPU_MECHANISM, FpuSaveMechanism, XFeatures, readCR0, readCR4, writeCR0, writeCR4};
+use rOSkernel::util::wrappers::{CPUID, FPU_MECHANISM, FpuSaveMechanism, XFeatures, readCR0, readCR4, writeCR0, writeCR4, xsetbv0};
 use core::sync::atomic::Ordering;
 
 
@@ -37,6 +48,12 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
 
 bootloader_api::entry_point!(kMain, config = &BOOTLOADER_CONFIG);
 
+/// Switch FPU state on first use (CR0.TS + #NM) instead of on every context switch.
+const LAZY_FPU_SWITCHING: bool = false;
+
+/// What the soft-lockup watchdog does with a CPU that stopped scheduling.
+const WATCHDOG_POLICY: LockupPolicy = LockupPolicy::Log;
+
 #[panic_handler]
 fn kPanic(info: &PanicInfo) -> ! {
     unsafe {
@@ -57,7 +74,7 @@ fn alloc_error_handler(layout: Layout) -> ! {
     panic!("Allocation error: {:?}", layout)
 }
 
-extern "C" fn threadFunc1() {
+extern "C" fn threadFunc1(logInterval: usize) {
     // calculate Fibonacci numbers
     let mut a: u64 = 0;
     let mut b: u64 = 1;
@@ -67,7 +84,7 @@ extern "C" fn threadFunc1() {
         a = b;
         b = c;
         i += 1;
-        if i % 10_000_000 == 0 {
+        if i % logInterval as u64 == 0 {
             log::info!("Hello from PID {:?}! Fibonacci number: {}", rOSkernel::multitasking::preemptive::current_pid().unwrap(), c);
         }
     }
@@ -110,12 +127,47 @@ extern "C" fn threadFunc3() {
     }
 }
 
+const PIPE_CHECK_MESSAGE: &[u8] = b"written by a process that exits right after";
+
+/// A pipe reader sees end of file once the process holding the write end has exited, since
+/// its last thread leaving closes its descriptors.
+fn checkPipeEofAfterWriterExit() {
+    let (readEnd, writeEnd) = Pipe::new().ends();
+    let reader = OpenFile::new(readEnd, OpenFlags::READ).expect("Pipe end has no metadata");
+    let writer = Arc::new(OpenFile::new(writeEnd, OpenFlags::WRITE).expect("Pipe end has no metadata"));
+
+    let writerProcess = Process::create(Parent::Inherit);
+    let fd = writerProcess.files().lock().insert(writer).expect("Writer process has no free descriptor");
+    let tid = writerProcess.create_thread_with(pipeWriter, fd as usize, 10);
+    let _ = writerProcess.start_thread(tid);
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 16];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) => panic!("Pipe read failed: {:?}", e),
+        }
+    }
+    assert_eq!(received, PIPE_CHECK_MESSAGE);
+    log::info!("Pipe reader got end of file after its writer process exited");
+}
+
+extern "C" fn pipeWriter(fd: usize) {
+    let process = rOSkernel::multitasking::preemptive::current_process().expect("Writer runs without a process");
+    let file = process.files().lock().get(fd as u32).expect("Writer lost its pipe end");
+    file.write(PIPE_CHECK_MESSAGE).expect("Pipe write failed");
+    drop((file, process));
+    rOSkernel::multitasking::preemptive::exit_process();
+}
+
 extern "C" fn kernelInit() {
     log::trace!("Kernel Init Thread started");
     
     // These processes will automatically inherit kernelInit as their parent
     let p1 = Process::create(Parent::Independent);
-    let tid1 = p1.create_thread(threadFunc1, 20);
+    let tid1 = p1.create_thread_with(threadFunc1, 10_000_000, 20);
     let _ = p1.start_thread(tid1);
 
     let p2 = Process::create(Parent::Inherit);
@@ -127,6 +179,55 @@ extern "C" fn kernelInit() {
     let tid3 = p3.create_thread(threadFunc3, 20);
     let _ = p3.add_thread_xfeatures(&tid3, XFeatures((1u64 << 5) | (1u64 << 6) | (1u64 << 7))); // enable AVX-512
     let _ = p3.start_thread(tid3);
+
+    // periodically dumps per-thread CPU accounting to serial
+    let top = Process::create(Parent::Inherit);
+    let topTid = top.create_thread(topThread, 5);
+    let _ = top.start_thread(topTid);
+
+    // async drivers run on their own executor thread, which sleeps in the scheduler when idle
+    let drivers = Process::create(Parent::Inherit);
+    let _spawner = start_executor_thread(&drivers, |executor| {
+        executor.spawn(Task::new(detectFloppyDrives()));
+    });
+
+    // the shell owns the keyboard and the framebuffer
+    let shell = Process::create(Parent::Inherit);
+    let shellTid = shell.create_thread(shellThread, 10);
+    let _ = shell.start_thread(shellTid);
+
+    // periodic producer with a guaranteed 2ms of CPU every 20ms
+    let producer = spawn(|| {
+        let mut periods: u64 = 0;
+        loop {
+            periods += 1;
+            if periods % 500 == 0 {
+                log::info!("Real-time producer completed {} periods", periods);
+            }
+            wait_next_period();
+        }
+    });
+    if let Err(e) = set_realtime(producer.pid(), producer.thread_id(), RealtimeParams::periodic(2, 20)) {
+        log::warn!("Real-time producer not admitted: {:?}", e);
+    }
+
+    // closures capture their data instead of going through a global function
+    let n = 20u64;
+    let factorial = spawn(move || (1..=n).product::<u64>());
+    match factorial.join() {
+        Some(result) => log::info!("{}! = {}", n, result),
+        None => log::warn!("Factorial thread was killed before returning"),
+    }
+
+    checkPipeEofAfterWriterExit();
+
+    // sample user programs the build put on the ramdisk
+    for (name, args) in [("hello", &["from", "kernelInit"][..]), ("threads", &[][..])] {
+        if let Err(e) = programs::run(name, args, &["TERM=serial"], Parent::Inherit) {
+            log::warn!("Failed to start {}: {:?}", name, e);
+        }
+    }
+
     loop {
         let mut i = 0;
         while i < 100_000_000 {
@@ -174,6 +275,9 @@ fn kMain(bootInfo: &'static mut BootInfo) -> ! {
         &bootInfo.memory_regions,
     );
 
+    fpu::benchmarkMechanisms();
+    fpu::set_lazy_fpu(LAZY_FPU_SWITCHING);
+
     let acpiTables = unsafe {
         AcpiTables::from_rsdp(
             ACPIHandler,
@@ -206,6 +310,8 @@ fn kMain(bootInfo: &'static mut BootInfo) -> ! {
         .ACPI_INTERRUPT_MODEL
         .get_or_init(|| madtInfo.0);
 
+    setKernelTimerQueue(TimerQueue::new());
+
     let apic = AdvancedPic::new();
     kernelContext()
         .apic
@@ -217,21 +323,40 @@ fn kMain(bootInfo: &'static mut BootInfo) -> ! {
         .unwrap()
         .initAPICTimer();
 
+    syscall::init();
+
+    // a disk image the build attached is the root file system
+    let rootDevice = bootInfo.ramdisk_addr.into_option().map(|addr| {
+        let ramdisk = unsafe { RamDisk::new(addr, bootInfo.ramdisk_len) };
+        registry::register("ram0", Arc::new(ramdisk)).expect("Failed to register the ramdisk");
+        "ram0"
+    });
+    rOSkernel::fs::init(rootDevice);
+
     if let Err(e) = keyboard::keyboardInitialize() {
         panic!("Failed to initialize keyboard: {:?}", e);
     }
 
+    watchdog::initWatchdog(WatchdogConfig {
+        policy: WATCHDOG_POLICY,
+        ..WatchdogConfig::default()
+    });
+
     log::trace!("Hello from kernel!");
 
     // kernel_process is the root process (PID 1), so it has no parent
     let kernel_process = Process::create(Parent::Independent);
+    rOSkernel::multitasking::preemptive::init_idle();
+    rOSkernel::multitasking::preemptive::init_reaper();
+    rOSkernel::kernel::smp::startApplicationProcessors();
     log::info!("Spawning kernel init thread");
     let tid = kernel_process.create_thread(kernelInit, 10);
     let _ = kernel_process.start_thread(tid);
     
     log::info!("Kernel initialization complete, starting scheduler");
 
-    // Enable interrupts to allow the APIC timer to fire and switch to the first thread
+    // Starting the init thread armed the (otherwise stopped) APIC timer,
+    // enabling interrupts lets it fire and switch to the first thread
     x86_64::instructions::interrupts::enable();
     
     rOSkernel::serial_println!("Interrupts enabled, entering HLT loop");
@@ -248,8 +373,8 @@ pub fn initMemory(
     let virtMemOffset = VirtAddr::new(physicalMemoryOffset);
     let mapper = unsafe { memory::init(virtMemOffset) };
     let frameAllocator = unsafe { BootInfoFrameAllocator::init(memoryRegions) };
-    setKernelMapper(Mutex::new(mapper));
-    setKernelFrameAllocator(Mutex::new(frameAllocator));
+    setKernelMapper(mapper);
+    setKernelFrameAllocator(frameAllocator);
 
     // initialize heap with desired size using a multi-heap allocator
     setKernelHeapManager(HeapRegionAllocator::new());
@@ -313,52 +438,40 @@ pub fn initXFeatures() {
         let has_xsave = (ecx1 & (1 << 26)) != 0;
         let has_fxsave = (edx1 & (1 << 24)) != 0;
 
-        // For now, we prioritize FXSAVE and keep XSAVE disabled as requested
-        if has_fxsave {
-            log::info!("FXSAVE supported. Enabling OSFXSR...");
-            
-            // Ensure CR0.EM is clear and CR0.MP is set
-            let mut cr0 = readCR0();
-            const CR0_EM_BIT: u64 = 1 << 2;
-            const CR0_MP_BIT: u64 = 1 << 1;
-            if (cr0 & CR0_EM_BIT) != 0 {
-                log::warn!("CR0.EM was set, clearing it.");
-                cr0 &= !CR0_EM_BIT;
-            }
-            cr0 |= CR0_MP_BIT;
-            writeCR0(cr0);
-
-            let mut cr4 = readCR4();
-            const CR4_OSFXSR_BIT: u64 = 1 << 9;
-            cr4 |= CR4_OSFXSR_BIT;
-            writeCR4(cr4);
-            
-            // Verify CR4 write
-            let cr4_verify = readCR4();
-            if (cr4_verify & CR4_OSFXSR_BIT) == 0 {
-                log::error!("Failed to set CR4.OSFXSR! CR4 is {:#x}", cr4_verify);
-            } else {
-                FPU_MECHANISM.store(FpuSaveMechanism::FXSave as u8, Ordering::Relaxed);
-                log::info!("Using FXSAVE mechanism");
-            }
-        } else if has_xsave {
-             log::warn!("XSAVE supported but disabled by policy. Falling back to None.");
-             // To enable XSAVE in future:
-             // 1. Check CPUID_ECX_OSXSAVE_BIT (bit 27) ? No, check bit 26 for support.
-             // 2. Set CR4_OSXSAVE_BIT (bit 18)
-             // 3. Init XCR0
-             // FPU_MECHANISM.store(FpuSaveMechanism::XSave as u8, Ordering::Relaxed);
-        } else {
+        if !has_fxsave {
             log::warn!("Neither XSAVE nor FXSAVE supported. FPU state will not be saved.");
+            return;
+        }
+
+        log::info!("FXSAVE supported. Enabling OSFXSR...");
+
+        // Ensure CR0.EM is clear and CR0.MP is set (MP makes WAIT/FWAIT honour CR0.TS)
+        let mut cr0 = readCR0();
+        const CR0_EM_BIT: u64 = 1 << 2;
+        const CR0_MP_BIT: u64 = 1 << 1;
+        if (cr0 & CR0_EM_BIT) != 0 {
+            log::warn!("CR0.EM was set, clearing it.");
+            cr0 &= !CR0_EM_BIT;
+        }
+        cr0 |= CR0_MP_BIT;
+        writeCR0(cr0);
+
+        let mut cr4 = readCR4();
+        const CR4_OSFXSR_BIT: u64 = 1 << 9;
+        cr4 |= CR4_OSFXSR_BIT;
+        writeCR4(cr4);
+        
+        // Verify CR4 write
+        let cr4_verify = readCR4();
+        if (cr4_verify & CR4_OSFXSR_BIT) == 0 {
+            log::error!("Failed to set CR4.OSFXSR! CR4 is {:#x}", cr4_verify);
+            return;
         }
+        FPU_MECHANISM.store(FpuSaveMechanism::FXSave as u8, Ordering::Relaxed);
 
-        /* Original XSAVE init code - kept for reference but disabled
-        // is XSAVE supported
-        let (_, _, features1, _features2) = CPUID(1, 0);
-        const CPUID_ECX_OSXSAVE_BIT: u32 = 1 << 27;
-        if (features1 & CPUID_ECX_OSXSAVE_BIT) == 0 {
-            log::warn!("CPUID_ECX_OSXSAVE_BIT is zero, XSAVE not supported");
-         //   return;
+        if !has_xsave {
+            log::info!("Using FXSAVE mechanism");
+            return;
         }
 
         // Enable XSAVE
@@ -377,7 +490,7 @@ pub fn initXFeatures() {
             .SUPPORTED_XFEATURES
             .get_or_init(|| XFeatures(supportedFeatures));
 
-        // build the "normal" mask
+        // build the "normal" mask, threads opt into anything larger with add_thread_xfeatures
         const X87_BIT: u64 = 1 << 0;
         const SSE_BIT: u64 = 1 << 1;
         const AVX_BIT: u64 = 1 << 2;
@@ -385,6 +498,14 @@ pub fn initXFeatures() {
         xsetbv0(supportedFeatures & desiredFeatures);
 
         log::info!("XCR0 after init: {:#?}", XFeatures::current());
-        */
+
+        // prefer the variants that skip unmodified (XSAVEOPT) or use compacted (XSAVES) state
+        let mechanism = match cpuid::CPUID::xsaveInfo() {
+            Some(info) if info.xss() => FpuSaveMechanism::XSaveS,
+            Some(info) if info.xsaveopt() => FpuSaveMechanism::XSaveOpt,
+            _ => FpuSaveMechanism::XSave,
+        };
+        FPU_MECHANISM.store(mechanism as u8, Ordering::Relaxed);
+        log::info!("Using {:?} mechanism", mechanism);
     }
 }
@@ -37,9 +37,7 @@ impl HeapRegionAllocator {
             let frame = (*frameAllocatorGuard)
                 .allocate_frame()
                 .ok_or(MapToError::FrameAllocationFailed)?;
-            let flags = PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::USER_ACCESSIBLE;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
             unsafe { (*mapperGuard).map_to(page, frame, flags, &mut *frameAllocatorGuard)?.flush() };
         }
         // advance next to the next aligned address after this heap
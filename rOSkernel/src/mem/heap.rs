@@ -240,6 +240,15 @@ impl Region {
     }
 }
 
+/// Heap usage in bytes. Freed blocks go back to per-region free lists rather than the bump
+/// pointer, so `reserved` is the high-water mark of what the regions handed out.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub regions: u64,
+    pub capacity: u64,
+    pub reserved: u64,
+}
+
 #[derive(Debug)]
 pub struct HeapInner {
     regions: [Region; MAX_HEAP_REGIONS as usize],
@@ -301,6 +310,16 @@ impl Heap {
         Ok(idx)
     }
 
+    pub fn stats(&self) -> HeapStats {
+        let count = self.inner().regionCount.load(Ordering::Acquire).min(MAX_HEAP_REGIONS);
+        let mut stats = HeapStats { regions: count, capacity: 0, reserved: 0 };
+        for region in &self.inner().regions[..count as usize] {
+            stats.capacity += region.end - region.base;
+            stats.reserved += region.bump.load(Ordering::Relaxed) - region.base;
+        }
+        stats
+    }
+
     pub fn allocSize(&self, size: u64, align: u64) -> Option<NonNull<u8>> {
         let count = self.inner().regionCount.load(Ordering::Acquire);
         if count == 0 { return None; }
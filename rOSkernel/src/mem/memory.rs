@@ -3,6 +3,7 @@ use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::{PhysAddr, VirtAddr};
 
 #[derive(Debug)]
@@ -16,6 +17,18 @@ unsafe impl Sync for BootInfoFrameAllocator {}
 
 pub static PHYSICAL_MEMORY_OFFSET: OnceInit<u64> = OnceInit::new();
 
+/// Lower half range reserved for user mappings. Its PML4 slots aren't copied into new
+/// address spaces, so whatever a process maps there stays private to it.
+pub const USER_SPACE_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+/// Memory below this is left to code that has to start in real mode, see `realModeFrame`.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+fn userL4Slots() -> core::ops::Range<usize> {
+    (USER_SPACE_START >> 39) as usize..(USER_SPACE_END >> 39) as usize
+}
+
 pub(crate) fn physToVirt(physAddr: u64) -> VirtAddr {
     let offset = PHYSICAL_MEMORY_OFFSET.get_copy().unwrap();
     let virtAddr = physAddr + offset;
@@ -25,6 +38,10 @@ pub(crate) fn physToVirt(physAddr: u64) -> VirtAddr {
 pub unsafe fn init(physicalMemoryOffset: VirtAddr) -> OffsetPageTable<'static> {
     unsafe {
         let level4Table = activeLevel4Table(physicalMemoryOffset);
+        // new address spaces would lose anything the bootloader put there
+        if userL4Slots().any(|i| !level4Table[i].is_unused()) {
+            panic!("Bootloader mappings overlap the user address range");
+        }
         OffsetPageTable::new(level4Table, physicalMemoryOffset)
     }
 }
@@ -50,14 +67,55 @@ pub fn newAddressSpace(
     unsafe {
         (*pageTablePtr).zero();
 
-        // copy higher half entries (kernel mappings)
-        for i in 0..512 {
+        // copy the kernel mappings, the user slots start out empty
+        for i in (0..512).filter(|i| !userL4Slots().contains(i)) {
             (&mut (*pageTablePtr))[i] = activeL4Table[i].clone();
         }
     }
     Some(newFrame)
 }
 
+/// Frees an address space made by `newAddressSpace`: every frame mapped in its user range, the
+/// page tables holding them and the level 4 table itself. No CPU may still run on it.
+pub fn freeAddressSpace(pageTable: PhysFrame, frameAllocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let l4 = tableAt(pageTable);
+    for l3 in mappedFrames(userL4Slots().map(|i| &l4[i])) {
+        for l2 in mappedFrames(tableAt(l3).iter()) {
+            for l1 in mappedFrames(tableAt(l2).iter()) {
+                for frame in mappedFrames(tableAt(l1).iter()) {
+                    unsafe { frameAllocator.deallocate_frame(frame) };
+                }
+                unsafe { frameAllocator.deallocate_frame(l1) };
+            }
+            unsafe { frameAllocator.deallocate_frame(l2) };
+        }
+        unsafe { frameAllocator.deallocate_frame(l3) };
+    }
+    unsafe { frameAllocator.deallocate_frame(pageTable) };
+}
+
+fn tableAt(frame: PhysFrame) -> &'static PageTable {
+    unsafe { &*physToVirt(frame.start_address().as_u64()).as_ptr::<PageTable>() }
+}
+
+/// Frames behind the present entries, user code only ever gets 4KiB pages.
+fn mappedFrames<'a>(entries: impl Iterator<Item = &'a PageTableEntry>) -> impl Iterator<Item = PhysFrame> {
+    entries.filter_map(|entry| entry.frame().ok())
+}
+
+/// Physical frame counts, 4KiB each.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+}
+
+impl FrameStats {
+    pub fn free(&self) -> usize {
+        self.total - self.used
+    }
+}
+
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memoryRegions: &'static MemoryRegions) -> Self {
         BootInfoFrameAllocator {
@@ -70,10 +128,34 @@ impl BootInfoFrameAllocator {
     fn usableFrames(&self) -> impl Iterator<Item = PhysFrame> {
         let regions = self.memoryRegions.iter();
         let usableRegions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        let addrRanges = usableRegions.map(|r| r.start..r.end);
+        let addrRanges = usableRegions.map(|r| r.start.max(LOW_MEMORY_END)..r.end);
         let frameAddresses = addrRanges.flat_map(|r| r.step_by(4096));
         frameAddresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// A usable frame below 1 MiB, where a STARTUP IPI can point. `allocate_frame` never
+    /// hands these out.
+    pub fn realModeFrame(&self) -> Option<PhysFrame> {
+        self.memoryRegions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .find_map(|r| {
+                // the first page holds the real mode interrupt table
+                let start = r.start.max(4096).next_multiple_of(4096);
+                (start + 4096 <= r.end.min(LOW_MEMORY_END))
+                    .then(|| PhysFrame::containing_address(PhysAddr::new(start)))
+            })
+    }
+
+    /// Counts the usable frames and how many are handed out, walking the memory map.
+    pub fn stats(&self) -> FrameStats {
+        let total = self.usableFrames().count();
+        let handedOut = self.next.min(total);
+        FrameStats {
+            total,
+            used: handedOut - self.freedFrames.len().min(handedOut),
+        }
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -2,6 +2,9 @@ pub mod allocator;
 pub mod memory;
 pub mod stack;
 pub mod heap;
+pub mod userMemory;
+pub mod userAccess;
+pub mod tlb;
 
 
 use heap::{Heap, HeapInner};
@@ -50,9 +50,38 @@ pub fn allocStack(
         let frame = frameAllocator
             .allocate_frame()
             .ok_or(mapper::MapToError::FrameAllocationFailed)?;
-        // TODO: only user accessible if process is user mode
-        // TODO: consider other flags
-        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frameAllocator)?.flush();
+        }
+    }
+
+    Ok(StackBounds {
+        start: stackStart.start_address(),
+        end: stackEnd.start_address(),
+    })
+}
+
+/// Maps a user stack of `pages` pages ending at `top`, with an unmapped guard page below it.
+///
+/// Must be called on the mapper of the address space the stack belongs to, `top` has to lie in
+/// the user range so the mapping stays private to that address space.
+pub fn allocUserStack(
+    top: VirtAddr,
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frameAllocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<StackBounds, mapper::MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let stackEnd = Page::<Size4KiB>::from_start_address(top).expect("User stack top is not page aligned");
+    let stackStart = stackEnd - pages;
+
+    for page in Page::range(stackStart, stackEnd) {
+        let frame = frameAllocator
+            .allocate_frame()
+            .ok_or(mapper::MapToError::FrameAllocationFailed)?;
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE | Flags::NO_EXECUTE;
         unsafe {
             mapper.map_to(page, frame, flags, frameAllocator)?.flush();
         }
@@ -0,0 +1,104 @@
+//! TLB shootdowns for user mappings that go away while other CPUs may still cache them.
+//!
+//! Every CPU publishes the page table it has loaded. Unmapping a range flushes it locally,
+//! then sends a shootdown IPI to each other CPU with the same page table loaded and waits for
+//! all of them to flush before the caller frees the frames. A CPU that loads the page table
+//! later walks the already cleared entries, so it needs no IPI.
+
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::tlb;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{OffsetPageTable, Page, PageSize, PageTable, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::kernel::interrupts::InterruptIndex;
+use crate::kernel::{currentCpuID, kernelContext, MAX_CPUS};
+use crate::util::lockdep::Mutex;
+
+/// Above this many pages a shootdown reloads CR3 instead of invalidating page by page.
+const FLUSH_ALL_PAGES: u64 = 32;
+
+// physical address of the page table each CPU has loaded, 0 if none was published yet
+static ACTIVE: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+// one shootdown at a time, the range and the CPUs yet to flush it are shared with the handler
+static SHOOTDOWN: Mutex<()> = Mutex::new("tlbShootdown", ());
+static RANGE_START: AtomicU64 = AtomicU64::new(0);
+static RANGE_END: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Records that the calling CPU now runs on `pageTable`, call before loading it into CR3.
+pub fn setActive(pageTable: PhysFrame) {
+    ACTIVE[currentCpuID() as usize].store(pageTable.start_address().as_u64(), Ordering::SeqCst);
+}
+
+/// Flushes `start..end` from the TLB of every CPU that has `pageTable` loaded, including the
+/// calling one, and returns once all of them did. The entries must already be unmapped.
+///
+/// Waits with interrupts enabled, so it must not be called with locks that are also taken
+/// with interrupts disabled.
+pub fn shootdown(pageTable: PhysFrame, start: u64, end: u64) {
+    if start >= end {
+        return;
+    }
+    // the cleared entries must be visible before the other CPUs' page tables are looked at
+    fence(Ordering::SeqCst);
+
+    let _guard = SHOOTDOWN.lock();
+    RANGE_START.store(start, Ordering::SeqCst);
+    RANGE_END.store(end, Ordering::SeqCst);
+
+    // flushing here and picking the targets on one CPU, a migration in between could skip one
+    interrupts::without_interrupts(|| {
+        flushRange(start, end);
+
+        let cpu = currentCpuID() as usize;
+        let table = pageTable.start_address().as_u64();
+        let targets = (0..MAX_CPUS)
+            .filter(|&other| other != cpu && ACTIVE[other].load(Ordering::SeqCst) == table)
+            .fold(0u64, |mask, other| mask | 1 << other);
+        if targets == 0 {
+            return;
+        }
+
+        PENDING.store(targets, Ordering::SeqCst);
+        let apic = kernelContext().apic.get().expect("APIC not initialized");
+        for other in (0..MAX_CPUS).filter(|other| targets & 1 << other != 0) {
+            apic.sendIPI(other as u8, InterruptIndex::TlbShootdown as u8);
+        }
+    });
+
+    // a target spinning on `SHOOTDOWN` itself still takes the IPI, callers keep interrupts on
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Physical frame of the level 4 table `mapper` edits.
+pub fn pageTableOf(mapper: &mut OffsetPageTable) -> PhysFrame {
+    let virt = VirtAddr::from_ptr(mapper.level_4_table() as *const PageTable);
+    PhysFrame::containing_address(PhysAddr::new((virt - mapper.phys_offset()) as u64))
+}
+
+fn flushRange(start: u64, end: u64) {
+    let pages = (end - start).div_ceil(Size4KiB::SIZE);
+    if pages > FLUSH_ALL_PAGES {
+        tlb::flush_all();
+        return;
+    }
+    for page in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + page * Size4KiB::SIZE));
+        tlb::flush(page.start_address());
+    }
+}
+
+pub extern "x86-interrupt" fn tlbShootdownHandler(_stackFrame: InterruptStackFrame) {
+    flushRange(RANGE_START.load(Ordering::SeqCst), RANGE_END.load(Ordering::SeqCst));
+    PENDING.fetch_and(!(1 << currentCpuID()), Ordering::SeqCst);
+
+    kernelContext()
+        .apic
+        .get()
+        .expect("APIC not initialized")
+        .notifyEOI();
+}
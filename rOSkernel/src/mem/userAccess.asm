@@ -0,0 +1,41 @@
+// Copies between kernel and user memory. Each routine labels the one instruction that touches
+// user memory and has a fixup label to continue at if it faults, see `userAccess::fixupFor`.
+// The caller opens the SMAP window.
+
+// userCopyBytes(dst: rdi, src: rsi, len: rdx) -> rax: bytes left uncopied
+.global userCopyBytes
+.global userCopyBytesFault
+.global userCopyBytesFixup
+userCopyBytes:
+    mov rcx, rdx
+userCopyBytesFault:
+    rep movsb
+    xor eax, eax
+    ret
+userCopyBytesFixup:
+    // a faulting rep movsb leaves rcx at the count still to go
+    mov rax, rcx
+    ret
+
+// userCopyString(dst: rdi, src: rsi, max: rdx) -> rax: length without the NUL, max if there
+// was none in the first max bytes, -1 on a fault
+.global userCopyString
+.global userCopyStringFault
+.global userCopyStringFixup
+userCopyString:
+    xor eax, eax
+.LcopyStringLoop:
+    cmp rax, rdx
+    je .LcopyStringDone
+userCopyStringFault:
+    movzx ecx, byte ptr [rsi + rax]
+    mov byte ptr [rdi + rax], cl
+    test cl, cl
+    jz .LcopyStringDone
+    inc rax
+    jmp .LcopyStringLoop
+.LcopyStringDone:
+    ret
+userCopyStringFixup:
+    mov rax, -1
+    ret
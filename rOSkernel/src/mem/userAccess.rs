@@ -0,0 +1,165 @@
+//! Copying to and from the calling process's user memory.
+//!
+//! Addresses from user code are checked to lie in the user range and be mapped user
+//! accessible in the caller's page tables, writable for `copy_to_user`. Another thread can
+//! still unmap them before the copy gets there, so the copies run in assembly routines whose
+//! faulting instructions are listed in a fixup table: `pageFaultHandler` resumes a kernel
+//! fault at one of them at its fixup, and the copy returns EFAULT instead of the kernel
+//! going down.
+//!
+//! With SMAP the kernel faults on any user access outside a STAC/CLAC window, which only these
+//! copies open.
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpuid::CPUID;
+use rOSuser::abi::Errno;
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::mem::memory::{USER_SPACE_END, USER_SPACE_START};
+use crate::multitasking::preemptive;
+use crate::util::wrappers::{readCR4, writeCR4};
+
+global_asm!(include_str!("userAccess.asm"), options(raw));
+
+unsafe extern "C" {
+    fn userCopyBytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn userCopyString(dst: *mut u8, src: *const u8, max: usize) -> isize;
+
+    static userCopyBytesFault: u8;
+    static userCopyBytesFixup: u8;
+    static userCopyStringFault: u8;
+    static userCopyStringFixup: u8;
+}
+
+const CR4_SMAP_BIT: u64 = 1 << 21;
+
+// the entry stubs read it to know whether they may `clac`
+#[unsafe(no_mangle)]
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on SMAP on the calling CPU if it has it.
+pub fn enableSmap() {
+    if !CPUID::extendedFeatures().is_some_and(|f| f.smap()) {
+        log::info!("SMAP not supported, the kernel can touch user memory anywhere");
+        return;
+    }
+    unsafe { writeCR4(readCR4() | CR4_SMAP_BIT) };
+    SMAP_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Runs `f` with user memory accessible to the kernel.
+#[inline(always)]
+fn withUserAccess<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+/// Faulting instruction and where to resume, for every instruction that touches user memory.
+fn fixups() -> [(u64, u64); 2] {
+    unsafe {
+        [
+            (&raw const userCopyBytesFault as u64, &raw const userCopyBytesFixup as u64),
+            (&raw const userCopyStringFault as u64, &raw const userCopyStringFixup as u64),
+        ]
+    }
+}
+
+/// Where a kernel page fault at `ip` continues, if it happened in a user copy.
+pub fn fixupFor(ip: VirtAddr) -> Option<VirtAddr> {
+    fixups()
+        .into_iter()
+        .find(|&(fault, _)| fault == ip.as_u64())
+        .map(|(_, fixup)| VirtAddr::new(fixup))
+}
+
+/// Longest range `checkUserRange` walks page by page, callers split longer transfers.
+pub const MAX_CHECKED_RANGE: usize = 16 * 1024 * 1024;
+
+/// Checks that `len` bytes at `addr` lie in the user range and are mapped for user code in the
+/// calling process, writable if `write` is set. Ranges over `MAX_CHECKED_RANGE` are refused.
+pub fn checkUserRange(addr: u64, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    if len > MAX_CHECKED_RANGE {
+        return Err(Errno::EFAULT);
+    }
+    let end = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+    if addr < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let process = preemptive::current_process().ok_or(Errno::EFAULT)?;
+    let mapper = process.mapper();
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let firstPage = addr & !(Size4KiB::SIZE - 1);
+    for page in (firstPage..end).step_by(Size4KiB::SIZE as usize) {
+        match mapper.translate(VirtAddr::new(page)) {
+            TranslateResult::Mapped { flags, .. } if flags.contains(required) => {}
+            _ => return Err(Errno::EFAULT),
+        }
+    }
+    Ok(())
+}
+
+/// Fills `dst` from user memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    checkUserRange(src, dst.len(), false)?;
+    let left = withUserAccess(|| unsafe { userCopyBytes(dst.as_mut_ptr(), src as *const u8, dst.len()) });
+    if left != 0 {
+        return Err(Errno::EFAULT);
+    }
+    Ok(())
+}
+
+/// Copies `src` to user memory at `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    checkUserRange(dst, src.len(), true)?;
+    let left = withUserAccess(|| unsafe { userCopyBytes(dst as *mut u8, src.as_ptr(), src.len()) });
+    if left != 0 {
+        return Err(Errno::EFAULT);
+    }
+    Ok(())
+}
+
+/// Copies a NUL-terminated string from user memory at `src` into `dst`, NUL included, and
+/// returns its length. Like in Linux, a string that doesn't end within `dst` comes back as
+/// `dst.len()` without a NUL.
+pub fn strncpy_from_user(dst: &mut [u8], src: u64) -> Result<usize, Errno> {
+    // the string may end well before `dst` does, only what's read has to be mapped
+    let max = dst.len().min(USER_SPACE_END.saturating_sub(src) as usize);
+    let mut copied = 0;
+    while copied < max {
+        let at = src + copied as u64;
+        let chunk = (max - copied).min((Size4KiB::SIZE - at % Size4KiB::SIZE) as usize);
+        checkUserRange(at, chunk, false)?;
+
+        let rest = &mut dst[copied..copied + chunk];
+        let len = withUserAccess(|| unsafe { userCopyString(rest.as_mut_ptr(), at as *const u8, chunk) });
+        if len < 0 {
+            return Err(Errno::EFAULT);
+        }
+        if (len as usize) < chunk {
+            return Ok(copied + len as usize);
+        }
+        copied += chunk;
+    }
+    if copied < dst.len() {
+        // the string ran into the end of the user range
+        return Err(Errno::EFAULT);
+    }
+    Ok(dst.len())
+}
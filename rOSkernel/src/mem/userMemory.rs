@@ -0,0 +1,204 @@
+//! Growable parts of a process's user range: the program break and anonymous mappings.
+//!
+//! The image sits at the bottom of the user range with the break right after it, mappings
+//! are handed out upwards from `MMAP_BASE` and stacks grow down from the top.
+
+use x86_64::structures::paging::mapper::{MapToError, Translate};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+use crate::mem::memory::{physToVirt, BootInfoFrameAllocator};
+use crate::mem::tlb;
+use crate::util::lockdep::Mutex;
+
+pub const MMAP_BASE: u64 = 0x0000_2000_0000_0000;
+pub const MMAP_END: u64 = 0x0000_3000_0000_0000;
+
+// pages unmapped before their frames are freed, so any range gets by without allocating
+const UNMAP_BATCH: usize = 64;
+
+fn pageUp(addr: u64) -> u64 {
+    (addr + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1)
+}
+
+fn pages(start: u64, end: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    (start..end)
+        .step_by(Size4KiB::SIZE as usize)
+        .map(|addr| Page::containing_address(VirtAddr::new(addr)))
+}
+
+#[derive(Debug)]
+pub struct UserMemory {
+    brkStart: u64,
+    brk: u64,
+    // next free address for `mmap`, mappings are never reused
+    mmapNext: u64,
+}
+
+impl UserMemory {
+    pub const fn new() -> Self {
+        Self {
+            brkStart: 0,
+            brk: 0,
+            mmapNext: MMAP_BASE,
+        }
+    }
+
+    /// Starts the break at the end of the loaded image.
+    pub fn setImageEnd(&mut self, end: VirtAddr) {
+        self.brkStart = pageUp(end.as_u64());
+        self.brk = self.brkStart;
+    }
+
+    /// Moves the break to `requested` and returns the new break. Like Linux, a break that can't
+    /// be moved is returned unchanged, and 0 only asks for the current one.
+    pub fn brk(
+        &mut self,
+        requested: u64,
+        mapper: &mut OffsetPageTable,
+        frameAllocator: &Mutex<BootInfoFrameAllocator>,
+    ) -> u64 {
+        if requested == 0 || self.brkStart == 0 || !(self.brkStart..=MMAP_BASE).contains(&requested) {
+            return self.brk;
+        }
+
+        let oldEnd = pageUp(self.brk);
+        let newEnd = pageUp(requested);
+        if newEnd > oldEnd {
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            if mapZeroed(oldEnd, newEnd, flags, mapper, frameAllocator).is_err() {
+                return self.brk;
+            }
+        } else {
+            unmapRange(newEnd, oldEnd, mapper, frameAllocator);
+        }
+
+        self.brk = requested;
+        self.brk
+    }
+
+    /// Maps `len` zeroed bytes rounded up to pages, followed by an unmapped guard page.
+    /// `flags` are added to PRESENT and USER_ACCESSIBLE.
+    pub fn mmap(
+        &mut self,
+        len: u64,
+        flags: PageTableFlags,
+        mapper: &mut OffsetPageTable,
+        frameAllocator: &Mutex<BootInfoFrameAllocator>,
+    ) -> Option<u64> {
+        if len == 0 {
+            return None;
+        }
+        let size = len.checked_add(Size4KiB::SIZE - 1)? & !(Size4KiB::SIZE - 1);
+        let start = self.mmapNext;
+        let end = start.checked_add(size)?;
+        if end + Size4KiB::SIZE > MMAP_END {
+            return None;
+        }
+
+        mapZeroed(start, end, flags, mapper, frameAllocator).ok()?;
+        self.mmapNext = end + Size4KiB::SIZE;
+        Some(start)
+    }
+
+    /// Unmaps whatever is mapped in the pages covering `len` bytes at `addr`, false if the
+    /// range isn't page aligned or lies outside the mapping area.
+    pub fn munmap(
+        &mut self,
+        addr: u64,
+        len: u64,
+        mapper: &mut OffsetPageTable,
+        frameAllocator: &Mutex<BootInfoFrameAllocator>,
+    ) -> bool {
+        let Some(end) = addr.checked_add(pageUp(len)) else {
+            return false;
+        };
+        if addr % Size4KiB::SIZE != 0 || addr < MMAP_BASE || end > self.mmapNext {
+            return false;
+        }
+
+        unmapRange(addr, end, mapper, frameAllocator);
+        true
+    }
+}
+
+/// Maps fresh zeroed frames over `start..end`, undoing its own mappings if it runs out of memory.
+fn mapZeroed(
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frameAllocator: &Mutex<BootInfoFrameAllocator>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    let mut frameAllocatorGuard = frameAllocator.lock();
+    for page in pages(start, end) {
+        let Some(frame) = frameAllocatorGuard.allocate_frame() else {
+            drop(frameAllocatorGuard);
+            unmapRange(start, page.start_address().as_u64(), mapper, frameAllocator);
+            return Err(MapToError::FrameAllocationFailed);
+        };
+        unsafe {
+            core::ptr::write_bytes(
+                physToVirt(frame.start_address().as_u64()).as_mut_ptr::<u8>(),
+                0,
+                Size4KiB::SIZE as usize,
+            );
+        }
+
+        if let Err(e) = unsafe { mapper.map_to(page, frame, flags, &mut *frameAllocatorGuard) }.map(|flush| flush.flush()) {
+            unsafe { frameAllocatorGuard.deallocate_frame(frame) };
+            drop(frameAllocatorGuard);
+            unmapRange(start, page.start_address().as_u64(), mapper, frameAllocator);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps the mapped pages in `start..end` and frees their frames once no CPU can reach them
+/// through its TLB anymore, in batches of `UNMAP_BATCH` pages.
+///
+/// `frameAllocator` is only locked to free a batch, the shootdown waits for other CPUs that
+/// may be spinning on it with interrupts off.
+fn unmapRange(
+    start: u64,
+    end: u64,
+    mapper: &mut OffsetPageTable,
+    frameAllocator: &Mutex<BootInfoFrameAllocator>,
+) {
+    let pageTable = tlb::pageTableOf(mapper);
+    let mut frames = [None; UNMAP_BATCH];
+
+    let mut batchStart = start;
+    while batchStart < end {
+        let mut count = 0;
+        let mut batchEnd = batchStart;
+        for page in pages(batchStart, end) {
+            batchEnd = page.start_address().as_u64() + Size4KiB::SIZE;
+            if mapper.translate_addr(page.start_address()).is_none() {
+                continue;
+            }
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                // flushed for every CPU at once below
+                flush.ignore();
+                frames[count] = Some(frame);
+                count += 1;
+                if count == UNMAP_BATCH {
+                    break;
+                }
+            }
+        }
+
+        if count > 0 {
+            tlb::shootdown(pageTable, batchStart, batchEnd);
+        }
+        let mut frameAllocatorGuard = frameAllocator.lock();
+        for frame in frames[..count].iter_mut().filter_map(Option::take) {
+            unsafe { frameAllocatorGuard.deallocate_frame(frame) };
+        }
+        batchStart = batchEnd;
+    }
+}
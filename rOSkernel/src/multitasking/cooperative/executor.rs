@@ -1,29 +1,62 @@
 use super::{Task, TaskId};
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use core::future::Future;
+use core::pin::{pin, Pin};
 use core::task::Context;
 use core::task::{Poll, Waker};
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::multitasking::preemptive::signal::ThreadSignal;
+use crate::multitasking::preemptive::thread::Process;
+
+/// Quantum of the threads started by `start_executor_thread`.
+pub const EXECUTOR_QUANTUM: u64 = 5;
+
+type SendFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// State shared between an executor, its wakers and its spawners.
+struct Shared {
+    taskQueue: ArrayQueue<TaskId>,
+    // futures spawned from other threads, picked up on the next run
+    injected: Mutex<VecDeque<SendFuture>>,
+    // wakes the executor's thread when it sleeps with nothing to poll
+    signal: ThreadSignal,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Shared {
+            taskQueue: ArrayQueue::new(100),
+            injected: Mutex::new(VecDeque::new()),
+            signal: ThreadSignal::new(),
+        }
+    }
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    taskQueue: Arc<ArrayQueue<TaskId>>,
+    shared: Arc<Shared>,
     wakerCache: BTreeMap<TaskId, Waker>,
 }
 
 struct TaskWaker {
     taskID: TaskId,
-    taskQueue: Arc<ArrayQueue<TaskId>>,
+    shared: Arc<Shared>,
 }
 
 impl TaskWaker {
-    pub fn new(taskID: TaskId, taskQueue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker { taskID, taskQueue }))
+    fn new(taskID: TaskId, shared: Arc<Shared>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { taskID, shared }))
     }
 
     fn wakeTask(&self) {
-        self.taskQueue.push(self.taskID).expect("TaskQueue full");
+        self.shared.taskQueue.push(self.taskID).expect("TaskQueue full");
+        self.shared.signal.notify();
     }
 }
 
@@ -37,11 +70,26 @@ impl Wake for TaskWaker {
     }
 }
 
+/// Handle for spawning tasks onto an executor from any thread.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        interrupts::without_interrupts(|| {
+            self.shared.injected.lock().push_back(Box::pin(future));
+        });
+        self.shared.signal.notify();
+    }
+}
+
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            taskQueue: Arc::new(ArrayQueue::new(100)),
+            shared: Arc::new(Shared::new()),
             wakerCache: BTreeMap::new(),
         }
     }
@@ -51,7 +99,13 @@ impl Executor {
         if self.tasks.insert(taskID, task).is_some() {
             panic!("Task with same ID already in tasks");
         }
-        self.taskQueue.push(taskID).expect("queue full");
+        self.shared.taskQueue.push(taskID).expect("queue full");
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
+        }
     }
 
     pub fn run(&mut self) -> ! {
@@ -61,31 +115,40 @@ impl Executor {
         }
     }
 
+    /// Sleeps the executor's thread until a task is woken or spawned.
     fn sleepIfIdle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-        interrupts::disable();
-        if self.taskQueue.is_empty() {
-            enable_and_hlt();
-        } else {
-            interrupts::enable();
+        let idle = interrupts::without_interrupts(|| {
+            self.shared.taskQueue.is_empty() && self.shared.injected.lock().is_empty()
+        });
+        if idle {
+            // a wake between the check and the wait leaves the signal set, so nothing is lost
+            self.shared.signal.wait();
+        }
+    }
+
+    fn spawnInjected(&mut self) {
+        while let Some(future) = interrupts::without_interrupts(|| self.shared.injected.lock().pop_front()) {
+            self.spawn(Task::new(future));
         }
     }
 
     fn runReadyTasks(&mut self) {
+        self.spawnInjected();
+
         let Self {
             tasks,
-            taskQueue,
+            shared,
             wakerCache,
         } = self;
 
-        while let Some(taskID) = taskQueue.pop() {
+        while let Some(taskID) = shared.taskQueue.pop() {
             let task = match tasks.get_mut(&taskID) {
                 Some(task) => task,
                 None => continue,
             };
             let waker = wakerCache
                 .entry(taskID)
-                .or_insert_with(|| TaskWaker::new(taskID, taskQueue.clone()));
+                .or_insert_with(|| TaskWaker::new(taskID, shared.clone()));
             let mut context = Context::from_waker(waker);
 
             match task.poll(&mut context) {
@@ -98,3 +161,46 @@ impl Executor {
         }
     }
 }
+
+/// Runs an executor on a new kernel thread of `process`.
+///
+/// `setup` runs on that thread before the executor starts, so it can spawn tasks that aren't
+/// `Send`; the returned spawner adds `Send` tasks later from anywhere.
+pub fn start_executor_thread<F>(process: &Process, setup: F) -> Spawner
+where
+    F: FnOnce(&mut Executor) + Send + 'static,
+{
+    let shared = Arc::new(Shared::new());
+    let spawner = Spawner {
+        shared: shared.clone(),
+    };
+
+    // the executor never returns, so the join handle is of no use
+    let _ = process.spawn(EXECUTOR_QUANTUM, move || {
+        let mut executor = Executor {
+            tasks: BTreeMap::new(),
+            shared,
+            wakerCache: BTreeMap::new(),
+        };
+        setup(&mut executor);
+        executor.run();
+    });
+
+    spawner
+}
+
+/// Drives `future` to completion on the calling thread, sleeping in the scheduler while it is
+/// pending. Interrupt handlers and other threads wake it through the future's waker.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let signal = Arc::new(ThreadSignal::new());
+    let waker = Waker::from(signal.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        signal.wait();
+    }
+}
@@ -0,0 +1,80 @@
+use core::fmt;
+
+/// Highest CPU (local APIC) ID a thread can be bound to, plus one.
+pub const MAX_CPUS: u8 = 64;
+
+/// Set of CPUs, indexed by local APIC ID, a thread is allowed to run on.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    pub const fn all() -> Self {
+        CpuMask(u64::MAX)
+    }
+
+    pub const fn empty() -> Self {
+        CpuMask(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        CpuMask(bits)
+    }
+
+    /// Mask with only `cpu` set, None if the ID is out of range.
+    pub fn single(cpu: u8) -> Option<Self> {
+        (cpu < MAX_CPUS).then(|| CpuMask(1 << cpu))
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, cpu: u8) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn insert(&mut self, cpu: u8) {
+        if cpu < MAX_CPUS {
+            self.0 |= 1 << cpu;
+        }
+    }
+
+    pub fn intersect(&self, other: CpuMask) -> CpuMask {
+        CpuMask(self.0 & other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> {
+        let bits = self.0;
+        (0..MAX_CPUS).filter(move |cpu| bits & (1 << cpu) != 0)
+    }
+}
+
+impl Default for CpuMask {
+    fn default() -> Self {
+        CpuMask::all()
+    }
+}
+
+impl fmt::Debug for CpuMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == CpuMask::all() {
+            return write!(f, "CpuMask(all)");
+        }
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityError {
+    NoSuchThread,
+    /// The mask is empty or names a CPU out of range.
+    InvalidMask,
+    /// None of the CPUs in the mask has joined the scheduler.
+    NoOnlineCpu,
+    /// The thread's affinity mask excludes the requested CPU.
+    NotAllowed,
+}
@@ -0,0 +1,567 @@
+//! ELF64 loader for user programs.
+//!
+//! Takes a complete image in memory, maps its `PT_LOAD` segments into a fresh process, applies
+//! `R_X86_64_RELATIVE` relocations for static PIEs and starts the entry thread on a stack laid
+//! out like the SysV ABI expects: `argc`, `argv`, `envp` and the auxiliary vector.
+//! Programs needing an interpreter aren't supported.
+
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::{MapToError, Translate, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+use crate::kernel::kernelContext;
+use crate::mem::memory::{physToVirt, USER_SPACE_END, USER_SPACE_START};
+use crate::multitasking::preemptive::thread::{GPRegisters, Process, ProcessRef, USER_STACK_PAGES};
+use crate::multitasking::preemptive::{Parent, SCHEDULER};
+use crate::util::wrappers::rdtsc;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const RELA_SIZE: usize = 24;
+
+/// Where position independent images are loaded.
+pub const PIE_LOAD_BASE: u64 = USER_SPACE_START + 0x40_0000;
+/// Upper bound for argv and envp including their strings.
+const MAX_ARG_BYTES: usize = 64 * 1024;
+const ENTRY_QUANTUM: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image ends before a header or segment it describes.
+    Truncated,
+    BadMagic,
+    /// Not a little-endian ELF64 image of the current version.
+    UnsupportedFormat,
+    WrongMachine,
+    /// Neither `ET_EXEC` nor `ET_DYN`.
+    NotExecutable,
+    /// Needs a dynamic linker (`PT_INTERP`).
+    Interpreter,
+    BadProgramHeader,
+    NoLoadableSegments,
+    /// A segment would land outside the user range.
+    SegmentOutOfRange,
+    BadDynamicSection,
+    UnsupportedRelocation(u32),
+    /// A relocation would patch memory outside the image's writable segments.
+    RelocationOutOfRange,
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        ElfError::OutOfMemory
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    fileSize: u64,
+    memSize: u64,
+    align: u64,
+}
+
+/// Validated headers of an image, addresses are still unbiased.
+struct ElfImage<'a> {
+    bytes: &'a [u8],
+    kind: u16,
+    entry: u64,
+    phoff: u64,
+    phentsize: u16,
+    programHeaders: Vec<ProgramHeader>,
+}
+
+/// Where an image ended up in its process.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    /// Offset added to every address in the image, 0 for `ET_EXEC`.
+    pub bias: u64,
+    /// Address of the program headers, for `AT_PHDR`.
+    pub programHeaders: VirtAddr,
+    pub programHeaderSize: u16,
+    pub programHeaderCount: u16,
+    /// First page after the highest segment, where a heap can start.
+    pub imageEnd: VirtAddr,
+}
+
+fn field<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N], ElfError> {
+    let end = at.checked_add(N).ok_or(ElfError::Truncated)?;
+    let raw = bytes.get(at..end).ok_or(ElfError::Truncated)?;
+    Ok(raw.try_into().unwrap())
+}
+
+fn readU16(bytes: &[u8], at: usize) -> Result<u16, ElfError> {
+    Ok(u16::from_le_bytes(field(bytes, at)?))
+}
+
+fn readU32(bytes: &[u8], at: usize) -> Result<u32, ElfError> {
+    Ok(u32::from_le_bytes(field(bytes, at)?))
+}
+
+fn readU64(bytes: &[u8], at: usize) -> Result<u64, ElfError> {
+    Ok(u64::from_le_bytes(field(bytes, at)?))
+}
+
+fn pageDown(addr: u64) -> u64 {
+    addr & !(Size4KiB::SIZE - 1)
+}
+
+fn pageUp(addr: u64) -> Option<u64> {
+    Some(addr.checked_add(Size4KiB::SIZE - 1)? & !(Size4KiB::SIZE - 1))
+}
+
+impl<'a> ElfImage<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != ELFCLASS64 || bytes[5] != ELFDATA2LSB || bytes[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+
+        let kind = readU16(bytes, 16)?;
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::NotExecutable);
+        }
+        if readU16(bytes, 18)? != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let entry = readU64(bytes, 24)?;
+        let phoff = readU64(bytes, 32)?;
+        let phentsize = readU16(bytes, 54)?;
+        let phnum = readU16(bytes, 56)? as usize;
+        if (phentsize as usize) < PHDR_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let mut programHeaders = Vec::with_capacity(phnum);
+        for i in 0..phnum {
+            let at = usize::try_from(phoff)
+                .ok()
+                .and_then(|phoff| phoff.checked_add(i * phentsize as usize))
+                .ok_or(ElfError::Truncated)?;
+            let header = ProgramHeader {
+                kind: readU32(bytes, at)?,
+                flags: readU32(bytes, at + 4)?,
+                offset: readU64(bytes, at + 8)?,
+                vaddr: readU64(bytes, at + 16)?,
+                fileSize: readU64(bytes, at + 32)?,
+                memSize: readU64(bytes, at + 40)?,
+                align: readU64(bytes, at + 48)?,
+            };
+
+            match header.kind {
+                PT_INTERP => return Err(ElfError::Interpreter),
+                PT_LOAD => header.validate(bytes.len())?,
+                _ => {}
+            }
+            programHeaders.push(header);
+        }
+
+        if !programHeaders.iter().any(|h| h.kind == PT_LOAD) {
+            return Err(ElfError::NoLoadableSegments);
+        }
+
+        Ok(ElfImage { bytes, kind, entry, phoff, phentsize, programHeaders })
+    }
+
+    fn loads(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.programHeaders.iter().filter(|h| h.kind == PT_LOAD)
+    }
+
+    /// Load bias that puts the lowest segment of a PIE at `PIE_LOAD_BASE`.
+    fn bias(&self) -> u64 {
+        if self.kind != ET_DYN {
+            return 0;
+        }
+        let lowest = self.loads().map(|h| pageDown(h.vaddr)).min().unwrap_or(0);
+        PIE_LOAD_BASE.wrapping_sub(lowest)
+    }
+
+    /// File offset of the unbiased address `vaddr`, if a segment maps it from the file.
+    fn fileOffset(&self, vaddr: u64) -> Option<usize> {
+        let h = self.loads().find(|h| vaddr >= h.vaddr && vaddr - h.vaddr < h.fileSize)?;
+        usize::try_from(h.offset + (vaddr - h.vaddr)).ok()
+    }
+
+    /// Whether `len` bytes at the unbiased address `vaddr` lie inside one writable segment.
+    fn isWritable(&self, vaddr: u64, len: u64) -> bool {
+        self.loads().any(|h| {
+            h.flags & PF_W != 0
+                && vaddr >= h.vaddr
+                && (vaddr - h.vaddr).checked_add(len).is_some_and(|end| end <= h.memSize)
+        })
+    }
+
+    /// Unbiased address of the program headers: `PT_PHDR` if present, else where the segment
+    /// covering file offset `phoff` maps them.
+    fn programHeaderAddress(&self) -> Option<u64> {
+        if let Some(h) = self.programHeaders.iter().find(|h| h.kind == PT_PHDR) {
+            return Some(h.vaddr);
+        }
+        self.loads()
+            .find(|h| self.phoff >= h.offset && self.phoff - h.offset < h.fileSize)
+            .map(|h| h.vaddr + (self.phoff - h.offset))
+    }
+}
+
+impl ProgramHeader {
+    fn validate(&self, imageLen: usize) -> Result<(), ElfError> {
+        if self.fileSize > self.memSize {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let fileEnd = self.offset.checked_add(self.fileSize).ok_or(ElfError::Truncated)?;
+        if fileEnd > imageLen as u64 {
+            return Err(ElfError::Truncated);
+        }
+        if self.align > 1 && !self.align.is_power_of_two() {
+            return Err(ElfError::BadProgramHeader);
+        }
+        // the file data has to start at the same offset within a page as its address
+        if self.vaddr % Size4KiB::SIZE != self.offset % Size4KiB::SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        self.vaddr.checked_add(self.memSize).ok_or(ElfError::SegmentOutOfRange)?;
+        Ok(())
+    }
+
+    fn pageFlags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Copies `data` to `addr` in the address space behind `mapper`, through the physical memory
+/// mapping since that address space usually isn't the active one.
+fn copyInto(mapper: &OffsetPageTable, addr: u64, data: &[u8]) -> Result<(), ElfError> {
+    let mut done = 0;
+    while done < data.len() {
+        let at = addr + done as u64;
+        let phys = mapper
+            .translate_addr(VirtAddr::new(at))
+            .ok_or(ElfError::SegmentOutOfRange)?;
+        let chunk = ((Size4KiB::SIZE - at % Size4KiB::SIZE) as usize).min(data.len() - done);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data[done..].as_ptr(),
+                physToVirt(phys.as_u64()).as_mut_ptr::<u8>(),
+                chunk,
+            );
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Maps every page any `PT_LOAD` segment touches, zeroed, with the union of the permissions of
+/// the segments sharing it, then copies in the file contents.
+///
+/// Fails with `OutOfMemory` before mapping anything if the segments need more frames than are
+/// free. Frames mapped before running out anyway are freed with the address space.
+fn mapSegments(image: &ElfImage, bias: u64, mapper: &mut OffsetPageTable) -> Result<u64, ElfError> {
+    let mut imageEnd = 0;
+    let mut totalPages = 0u64;
+
+    for h in image.loads() {
+        let start = h.vaddr.wrapping_add(bias);
+        let end = start.checked_add(h.memSize).ok_or(ElfError::SegmentOutOfRange)?;
+        if start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err(ElfError::SegmentOutOfRange);
+        }
+
+        let lastPage = pageUp(end).ok_or(ElfError::SegmentOutOfRange)?;
+        // pages shared by segments count twice, which only errs on the safe side
+        totalPages += (lastPage - pageDown(start)) / Size4KiB::SIZE;
+        imageEnd = imageEnd.max(lastPage);
+    }
+
+    let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+    if totalPages > frameAllocatorGuard.stats().free() as u64 {
+        return Err(ElfError::OutOfMemory);
+    }
+
+    for h in image.loads() {
+        let segmentFlags = h.pageFlags();
+        // checked above
+        let start = h.vaddr.wrapping_add(bias);
+        let end = pageUp(start + h.memSize).unwrap();
+
+        for addr in (pageDown(start)..end).step_by(Size4KiB::SIZE as usize) {
+            let page = Page::<Size4KiB>::from_start_address(VirtAddr::new(addr)).unwrap();
+
+            // a page shared with an earlier segment keeps its frame and gains this one's rights
+            if let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address()) {
+                let mut merged = flags | (segmentFlags & PageTableFlags::WRITABLE);
+                if !segmentFlags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe { mapper.update_flags(page, merged) }
+                    .map_err(|_| ElfError::SegmentOutOfRange)?
+                    .ignore();
+                continue;
+            }
+
+            let frame = frameAllocatorGuard
+                .allocate_frame()
+                .ok_or(ElfError::OutOfMemory)?;
+            unsafe {
+                core::ptr::write_bytes(physToVirt(frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+                let flags = segmentFlags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                if let Err(e) = mapper.map_to(page, frame, flags, &mut *frameAllocatorGuard) {
+                    frameAllocatorGuard.deallocate_frame(frame);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    drop(frameAllocatorGuard);
+
+    for h in image.loads() {
+        let data = &image.bytes[h.offset as usize..(h.offset + h.fileSize) as usize];
+        copyInto(mapper, h.vaddr.wrapping_add(bias), data)?;
+    }
+
+    Ok(imageEnd)
+}
+
+/// Applies the `PT_DYNAMIC` relocations of a static PIE, only relative ones are expected
+/// since there is no symbol resolution. Each one has to patch a writable segment of the image,
+/// which ends at `imageEnd`.
+fn relocate(image: &ElfImage, bias: u64, imageEnd: u64, mapper: &OffsetPageTable) -> Result<(), ElfError> {
+    let Some(dynamic) = image.programHeaders.iter().find(|h| h.kind == PT_DYNAMIC) else {
+        return Ok(());
+    };
+
+    let (mut rela, mut relaSize, mut relaEnt) = (None, 0, RELA_SIZE as u64);
+    let start = usize::try_from(dynamic.offset).map_err(|_| ElfError::BadDynamicSection)?;
+    let end = usize::try_from(dynamic.fileSize)
+        .ok()
+        .and_then(|size| start.checked_add(size))
+        .ok_or(ElfError::BadDynamicSection)?;
+    for at in (start..end).step_by(16).take_while(|at| end - at >= 16) {
+        let tag = readU64(image.bytes, at)?;
+        let value = readU64(image.bytes, at.checked_add(8).ok_or(ElfError::Truncated)?)?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => relaSize = value,
+            DT_RELAENT => relaEnt = value,
+            DT_REL => return Err(ElfError::BadDynamicSection),
+            _ => {}
+        }
+    }
+
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    if relaEnt < RELA_SIZE as u64 {
+        return Err(ElfError::BadDynamicSection);
+    }
+    let table = image.fileOffset(rela).ok_or(ElfError::BadDynamicSection)?;
+
+    for i in 0..relaSize / relaEnt {
+        let entry = i
+            .checked_mul(relaEnt)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .and_then(|offset| table.checked_add(offset))
+            .ok_or(ElfError::Truncated)?;
+        let offset = readU64(image.bytes, entry)?;
+        let info = readU64(image.bytes, entry.checked_add(8).ok_or(ElfError::Truncated)?)?;
+        let addend = readU64(image.bytes, entry.checked_add(16).ok_or(ElfError::Truncated)?)?;
+
+        match info as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let target = offset.wrapping_add(bias);
+                let inImage = target >= USER_SPACE_START && target.checked_add(8).is_some_and(|end| end <= imageEnd);
+                if !inImage || !image.isWritable(offset, 8) {
+                    return Err(ElfError::RelocationOutOfRange);
+                }
+                let value = bias.wrapping_add(addend);
+                copyInto(mapper, target, &value.to_le_bytes())?;
+            }
+            other => return Err(ElfError::UnsupportedRelocation(other)),
+        }
+    }
+    Ok(())
+}
+
+/// Maps `bytes` into `process`, which should have a fresh address space.
+pub fn load(process: &Process, bytes: &[u8]) -> Result<LoadedImage, ElfError> {
+    let image = ElfImage::parse(bytes)?;
+    let bias = image.bias();
+
+    let mut mapper = process.mapper();
+    let imageEnd = mapSegments(&image, bias, &mut mapper)?;
+    relocate(&image, bias, imageEnd, &mapper)?;
+
+    let entry = image.entry.wrapping_add(bias);
+    if !(USER_SPACE_START..imageEnd).contains(&entry) {
+        return Err(ElfError::SegmentOutOfRange);
+    }
+
+    Ok(LoadedImage {
+        entry: VirtAddr::new(entry),
+        bias,
+        programHeaders: VirtAddr::new(image.programHeaderAddress().unwrap_or(0).wrapping_add(bias)),
+        programHeaderSize: image.phentsize,
+        programHeaderCount: image.programHeaders.len() as u16,
+        imageEnd: VirtAddr::new(imageEnd),
+    })
+}
+
+/// Builds the initial stack contents for `_start`, ending at `top` and starting with `argc`,
+/// where the entry `rsp` points.
+fn buildInitialStack(top: u64, loaded: &LoadedImage, argv: &[&str], envp: &[&str]) -> Result<Vec<u8>, ElfError> {
+    // strings and the AT_RANDOM bytes go at the very top
+    let mut strings: Vec<u8> = Vec::new();
+    let mut offsets = Vec::with_capacity(argv.len() + envp.len());
+    for s in argv.iter().chain(envp) {
+        offsets.push(strings.len());
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let randomOffset = strings.len();
+    // seeds user space hashing, not meant to be cryptographically strong
+    let seed = rdtsc().rotate_left(29) ^ 0x9E37_79B9_7F4A_7C15;
+    strings.extend_from_slice(&seed.to_le_bytes());
+    strings.extend_from_slice(&rdtsc().wrapping_mul(seed | 1).to_le_bytes());
+    if strings.len() > MAX_ARG_BYTES {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let stringsBase = top - strings.len() as u64;
+    let auxv = [
+        (AT_PHDR, loaded.programHeaders.as_u64()),
+        (AT_PHENT, loaded.programHeaderSize as u64),
+        (AT_PHNUM, loaded.programHeaderCount as u64),
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, loaded.entry.as_u64()),
+        (AT_RANDOM, stringsBase + randomOffset as u64),
+        (AT_NULL, 0),
+    ];
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(offsets[..argv.len()].iter().map(|o| stringsBase + *o as u64));
+    words.push(0);
+    words.extend(offsets[argv.len()..].iter().map(|o| stringsBase + *o as u64));
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    // argc has to be 16 byte aligned, the gap goes between the vectors and the strings
+    let vectorBytes = words.len() * 8;
+    let rsp = (stringsBase - vectorBytes as u64) & !0xF;
+    let total = (top - rsp) as usize;
+
+    let mut stack = Vec::with_capacity(total);
+    for word in &words {
+        stack.extend_from_slice(&word.to_le_bytes());
+    }
+    stack.resize(total - strings.len(), 0);
+    stack.extend_from_slice(&strings);
+    Ok(stack)
+}
+
+/// Creates a process running the ELF executable in `bytes`, with `argv[0]` conventionally the
+/// program name. The process and its address space are freed again if loading fails.
+pub fn spawn(bytes: &[u8], argv: &[&str], envp: &[&str], parent: Parent) -> Result<ProcessRef, ElfError> {
+    // catch malformed images before there is a process to undo
+    ElfImage::parse(bytes)?;
+
+    let process = Process::create(parent);
+    match startProcess(&process, bytes, argv, envp) {
+        Ok(()) => Ok(process),
+        Err(e) => {
+            // the last reference frees the address space, which mustn't happen under the lock
+            let registered = x86_64::instructions::interrupts::without_interrupts(|| {
+                SCHEDULER.lock().unregister_process(process.pid())
+            });
+            drop(registered);
+            drop(process);
+            Err(e)
+        }
+    }
+}
+
+fn startProcess(process: &Process, bytes: &[u8], argv: &[&str], envp: &[&str]) -> Result<(), ElfError> {
+    let loaded = load(process, bytes)?;
+    process.memory().lock().setImageEnd(loaded.imageEnd);
+
+    let stack = process.alloc_user_stack(USER_STACK_PAGES)?;
+    let contents = buildInitialStack(stack.end.as_u64(), &loaded, argv, envp)?;
+    if contents.len() as u64 > stack.end - stack.start {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let base = stack.end.as_u64() - contents.len() as u64;
+    copyInto(&process.mapper(), base, &contents)?;
+
+    // rdx holds a termination function for `_start` to register, there is none
+    let rsp = VirtAddr::new(base);
+    let tid = process.create_user_thread_at(loaded.entry, stack, rsp, GPRegisters::default(), ENTRY_QUANTUM)?;
+    process.start_thread(tid).ok_or(ElfError::OutOfMemory)?;
+
+    log::info!(
+        "Started {:?} as process {:?}, entry {:?}",
+        argv.first().unwrap_or(&"<unnamed>"),
+        process.pid(),
+        loaded.entry
+    );
+    Ok(())
+}
@@ -0,0 +1,334 @@
+//! Saving and restoring the FPU/SSE/AVX state of threads.
+//!
+//! By default the scheduler saves the state of the thread it switches out and restores the
+//! next one's on every switch. In lazy mode it sets CR0.TS instead and the #NM that the
+//! thread's first FPU instruction raises restores its state. A thread's state is still saved
+//! as it leaves the CPU, but only if it used the FPU since its last save. So its area is up to
+//! date whenever it is off the CPU, any CPU can restore it and no CPU ever has to save state
+//! loaded on another. A CPU whose registers still hold what a thread saved last skips the
+//! restore entirely when that thread comes back.
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use cpuid::CPUID;
+use crate::kernel::{currentCpuID, MAX_CPUS};
+use crate::util::wrappers::{get_fpu_mechanism, rdtsc, readCR0, readCR4, writeCR0, xgetbv0, xsetbv0, FpuSaveMechanism, XFeatures};
+
+// The entry stub clears CR0.TS first, saving or restoring state with it set would raise #NM
+// again.
+global_asm!(
+    ".global deviceNotAvailableEntry",
+    "deviceNotAvailableEntry:",
+    "    clts",
+    "    push rax",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    // ring 3 may have left DF or AC set, IRETQ restores them
+    "    cld",
+    "    test byte ptr [rip + SMAP_ENABLED], 1",
+    "    jz 2f",
+    "    clac",
+    "2:",
+    "    call device_not_available_trampoline",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rax",
+    "    iretq",
+);
+
+unsafe extern "C" {
+    pub fn deviceNotAvailableEntry();
+}
+
+const CR0_TS_BIT: u64 = 1 << 3;
+const CR4_OSFXSR_BIT: u64 = 1 << 9;
+const CR4_OSXSAVE_BIT: u64 = 1 << 18;
+
+/// When set, FPU state is only restored when a thread first uses it after a switch.
+static LAZY_FPU: AtomicBool = AtomicBool::new(false);
+
+/// Next `FpuContext::stamp` to hand out, 0 stands for no state.
+static NEXT_STAMP: AtomicU64 = AtomicU64::new(1);
+
+/// A thread's saved extended state, as the scheduler passes it to the lazy switcher.
+#[derive(Debug, Clone, Copy)]
+pub struct FpuContext {
+    /// Identifies the contents of the save area, changes every time it is saved.
+    pub stamp: u64,
+    pub xAreaPtr: *mut u8,
+    pub xFeatures: XFeatures,
+}
+
+/// Lazy switching state of one CPU. Only that CPU touches it, from the scheduler or the #NM
+/// handler, so always with interrupts disabled.
+struct LazyCpu {
+    /// Stamp of the state loaded in the FPU registers, 0 if they hold nothing worth keeping.
+    owner: AtomicU64,
+    /// Whether the owner may have changed the registers since its state was saved.
+    dirty: AtomicBool,
+    /// The thread the scheduler last resumed here, restored by #NM on its first FPU use.
+    currentStamp: AtomicU64,
+    currentArea: AtomicPtr<u8>,
+    currentFeatures: AtomicU64,
+}
+
+impl LazyCpu {
+    const fn new() -> Self {
+        Self {
+            owner: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+            currentStamp: AtomicU64::new(0),
+            currentArea: AtomicPtr::new(core::ptr::null_mut()),
+            currentFeatures: AtomicU64::new(0),
+        }
+    }
+}
+
+static CPUS: [LazyCpu; MAX_CPUS] = [const { LazyCpu::new() }; MAX_CPUS];
+
+fn localCpu() -> &'static LazyCpu {
+    &CPUS[currentCpuID() as usize]
+}
+
+/// Switches between eager and lazy FPU switching. Only change this before the scheduler starts.
+pub fn set_lazy_fpu(enabled: bool) {
+    let enabled = enabled && get_fpu_mechanism() != FpuSaveMechanism::None;
+    LAZY_FPU.store(enabled, Ordering::Relaxed);
+}
+
+pub fn lazy_fpu() -> bool {
+    LAZY_FPU.load(Ordering::Relaxed)
+}
+
+/// A stamp no save area had before, for a new thread or freshly saved state.
+pub fn newStamp() -> u64 {
+    NEXT_STAMP.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Saves the live FPU state with `mechanism`, using the features enabled in XCR0.
+#[inline]
+pub unsafe fn saveWith(mechanism: FpuSaveMechanism, ptr: *mut u8) {
+    unsafe {
+        match mechanism {
+            FpuSaveMechanism::FXSave => core::arch::x86_64::_fxsave64(ptr),
+            FpuSaveMechanism::XSave => core::arch::x86_64::_xsave64(ptr, u64::MAX),
+            FpuSaveMechanism::XSaveOpt => core::arch::x86_64::_xsaveopt64(ptr, u64::MAX),
+            FpuSaveMechanism::XSaveS => core::arch::x86_64::_xsaves64(ptr, u64::MAX),
+            FpuSaveMechanism::None => {},
+        }
+    }
+}
+
+/// Loads FPU state saved by `saveWith` with the same mechanism, XCR0 must already match `features`.
+#[inline]
+pub unsafe fn restoreWith(mechanism: FpuSaveMechanism, ptr: *mut u8, features: XFeatures) {
+    unsafe {
+        match mechanism {
+            FpuSaveMechanism::FXSave => core::arch::x86_64::_fxrstor64(ptr),
+            FpuSaveMechanism::XSave | FpuSaveMechanism::XSaveOpt => {
+                core::arch::x86_64::_xrstor64(ptr, features.to_u64())
+            }
+            FpuSaveMechanism::XSaveS => core::arch::x86_64::_xrstors64(ptr, features.to_u64()),
+            FpuSaveMechanism::None => {},
+        }
+    }
+}
+
+pub unsafe fn saveState(ptr: *mut u8) {
+    unsafe { saveWith(get_fpu_mechanism(), ptr) }
+}
+
+pub unsafe fn restoreState(ptr: *mut u8, features: XFeatures) {
+    let mechanism = get_fpu_mechanism();
+    unsafe {
+        if mechanism.usesXSave() {
+            xsetbv0(features.to_u64());
+        }
+        restoreWith(mechanism, ptr, features);
+    }
+}
+
+/// Saves the state of a thread leaving this CPU if it used the FPU since its last save.
+/// Returns the stamp of the newly saved state, None if its area was already up to date.
+pub unsafe fn switchOut(state: FpuContext) -> Option<u64> {
+    let cpu = localCpu();
+    if cpu.owner.load(Ordering::Relaxed) != state.stamp || !cpu.dirty.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    unsafe { saveState(state.xAreaPtr) };
+    let stamp = newStamp();
+    // the registers still hold exactly what was saved
+    cpu.owner.store(stamp, Ordering::Relaxed);
+    cpu.dirty.store(false, Ordering::Relaxed);
+    // the scheduler may go back to the same thread without a new `switchedTo`
+    if cpu.currentStamp.load(Ordering::Relaxed) == state.stamp {
+        cpu.currentStamp.store(stamp, Ordering::Relaxed);
+    }
+    Some(stamp)
+}
+
+/// Records the thread the scheduler is resuming on this CPU, None for one without a save area.
+pub fn switchedTo(next: Option<FpuContext>) {
+    let cpu = localCpu();
+    let Some(next) = next else {
+        cpu.currentStamp.store(0, Ordering::Relaxed);
+        cpu.currentArea.store(core::ptr::null_mut(), Ordering::Relaxed);
+        return;
+    };
+
+    if cpu.owner.load(Ordering::Relaxed) == next.stamp {
+        // new features only become usable once XCR0 is reloaded, which a restore does
+        let featuresLoaded = !get_fpu_mechanism().usesXSave()
+            || unsafe { xgetbv0() } == next.xFeatures.to_u64();
+        if featuresLoaded {
+            // CR0.TS stays clear, so the thread may change the registers without an #NM
+            cpu.dirty.store(true, Ordering::Relaxed);
+        } else {
+            cpu.owner.store(0, Ordering::Relaxed);
+        }
+    }
+
+    cpu.currentStamp.store(next.stamp, Ordering::Relaxed);
+    cpu.currentArea.store(next.xAreaPtr, Ordering::Relaxed);
+    cpu.currentFeatures.store(next.xFeatures.to_u64(), Ordering::Relaxed);
+}
+
+/// Called last on the way out of the scheduler, whose entry stub cleared CR0.TS: sets it again
+/// unless the resumed thread's state is already loaded.
+pub fn prepareReturn() {
+    if !lazy_fpu() {
+        return;
+    }
+
+    let cpu = localCpu();
+    let current = cpu.currentStamp.load(Ordering::Relaxed);
+    if current == 0 || cpu.owner.load(Ordering::Relaxed) != current {
+        unsafe { writeCR0(readCR0() | CR0_TS_BIT) };
+    }
+}
+
+/// Forgets what this CPU's registers hold, for code that changed XCR0 under them, which
+/// discards the state of any component it disables.
+pub fn forgetLoadedState() {
+    localCpu().owner.store(0, Ordering::Relaxed);
+}
+
+/// Hands the FPU to the running thread on its first FPU instruction since being switched in.
+/// The previous owner's state was saved when it left the CPU, so it is simply overwritten.
+#[unsafe(no_mangle)]
+extern "C" fn device_not_available_trampoline() {
+    let cpu = localCpu();
+    let current = cpu.currentStamp.load(Ordering::Relaxed);
+    if current == 0 {
+        // nothing to restore, but the registers are no longer anyone's saved state
+        cpu.owner.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    if cpu.owner.load(Ordering::Relaxed) != current {
+        let features = XFeatures(cpu.currentFeatures.load(Ordering::Relaxed));
+        unsafe { restoreState(cpu.currentArea.load(Ordering::Relaxed), features) };
+        cpu.owner.store(current, Ordering::Relaxed);
+    }
+    cpu.dirty.store(true, Ordering::Relaxed);
+}
+
+/// Prepares a freshly allocated save area so its first restore loads the default FPU state.
+pub unsafe fn initArea(ptr: *mut u8, size: usize, features: XFeatures) {
+    unsafe {
+        core::ptr::write_bytes(ptr, 0, size);
+        // FCW and MXCSR defaults
+        *(ptr as *mut u16) = 0x037F;
+        *(ptr.add(24) as *mut u32) = 0x1F80;
+
+        if get_fpu_mechanism() == FpuSaveMechanism::XSaveS {
+            // XRSTORS only accepts the compacted format, flagged in XCOMP_BV
+            *(ptr.add(520) as *mut u64) = (1 << 63) | features.to_u64();
+        }
+    }
+}
+
+fn mechanismSupported(mechanism: FpuSaveMechanism) -> bool {
+    let cr4 = unsafe { readCR4() };
+    let osxsave = cr4 & CR4_OSXSAVE_BIT != 0;
+    let xsaveInfo = CPUID::xsaveInfo();
+
+    match mechanism {
+        FpuSaveMechanism::None => false,
+        FpuSaveMechanism::FXSave => CPUID::featureInfo().fxsr() && cr4 & CR4_OSFXSR_BIT != 0,
+        FpuSaveMechanism::XSave => osxsave,
+        FpuSaveMechanism::XSaveOpt => osxsave && xsaveInfo.is_some_and(|info| info.xsaveopt()),
+        FpuSaveMechanism::XSaveS => osxsave && xsaveInfo.is_some_and(|info| info.xss()),
+    }
+}
+
+/// Times a save/restore round trip with every mechanism this CPU supports and logs the cost.
+/// Must run before the scheduler starts, with CR0.TS clear.
+pub fn benchmarkMechanisms() {
+    const ITERATIONS: u64 = 1000;
+
+    let size = CPUID::xsaveInfo()
+        .map(|info| info.currentMaxSaveArea as usize)
+        .unwrap_or(0)
+        .max(512);
+    let Ok(layout) = Layout::from_size_align(size, 64) else {
+        return;
+    };
+
+    let ptr = unsafe { alloc(layout) };
+    if ptr.is_null() {
+        log::warn!("FPU benchmark: failed to allocate a {} byte save area", size);
+        return;
+    }
+
+    let mechanisms = [
+        FpuSaveMechanism::FXSave,
+        FpuSaveMechanism::XSave,
+        FpuSaveMechanism::XSaveOpt,
+        FpuSaveMechanism::XSaveS,
+    ];
+
+    interrupts::without_interrupts(|| {
+        let features = if mechanismSupported(FpuSaveMechanism::XSave) {
+            XFeatures(unsafe { xgetbv0() })
+        } else {
+            XFeatures::new(0)
+        };
+
+        for mechanism in mechanisms {
+            if !mechanismSupported(mechanism) {
+                log::info!("FPU benchmark: {:?} unsupported", mechanism);
+                continue;
+            }
+
+            unsafe {
+                // the first save puts the area in the right format for this mechanism
+                saveWith(mechanism, ptr);
+                let start = rdtsc();
+                for _ in 0..ITERATIONS {
+                    saveWith(mechanism, ptr);
+                    restoreWith(mechanism, ptr, features);
+                }
+                let cycles = (rdtsc() - start) / ITERATIONS;
+                log::info!("FPU benchmark: {:?} save+restore takes {} cycles", mechanism, cycles);
+            }
+        }
+    });
+
+    unsafe { dealloc(ptr, layout) };
+}
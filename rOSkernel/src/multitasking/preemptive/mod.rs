@@ -1,12 +1,29 @@
 use self::scheduler::Scheduler;
-use spin::Mutex;
+use self::stats::ProcessStats;
+use self::realtime::{AdmissionError, RealtimeParams};
+use self::affinity::{AffinityError, CpuMask};
+use crate::kernel::interrupts::InterruptIndex;
+use crate::kernel::kernelContext;
+use crate::kernel::timer::{self, TimerPayload};
+use crate::util::lockdep::Mutex;
 use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
 
+pub mod affinity;
+pub mod elf;
+pub mod fpu;
+pub mod programs;
+pub mod realtime;
 pub mod scheduler;
+pub mod signal;
+pub mod stats;
 pub mod switchThread;
 pub mod thread;
 
-pub static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+pub static SCHEDULER: Mutex<Scheduler> = Mutex::new("SCHEDULER", Scheduler::new());
+
+/// Quantum given to threads created by `spawn`.
+pub const DEFAULT_QUANTUM: u64 = 10;
 
 /// Specifies the parent process relationship when creating a new process.
 /// 
@@ -45,7 +62,278 @@ pub fn current_pid() -> Option<ProcessID> {
         SCHEDULER.lock().current_pid()
     })
 }
-pub static PROCESS_ID_ALLOCATOR: Mutex<IDAllocator> = Mutex::new(IDAllocator::new());
+
+/// Returns the process running on the calling CPU, if any.
+pub fn current_process() -> Option<thread::ProcessRef> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.current_pid().and_then(|pid| scheduler.get_process(pid))
+    })
+}
+
+/// Returns process `pid`, None if it doesn't exist.
+pub fn get_process(pid: ProcessID) -> Option<thread::ProcessRef> {
+    x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().get_process(pid))
+}
+
+/// Every process the scheduler knows about.
+pub fn processes() -> Vec<thread::ProcessRef> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.pids().into_iter().filter_map(|pid| scheduler.get_process(pid)).collect()
+    })
+}
+
+/// Returns the process and thread running on the calling CPU, if any.
+pub fn current_thread() -> Option<(ProcessID, ThreadID)> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().current()
+    })
+}
+
+/// Body of the idle thread, runs whenever no other thread is ready.
+pub extern "C" fn idle_loop() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Creates the idle process and registers its thread with the scheduler.
+///
+/// Without it, a CPU whose last runnable thread goes to sleep has nowhere to switch to.
+pub fn init_idle() {
+    let process = thread::Process::create(Parent::Independent);
+    let tid = process.create_thread(idle_loop, 1);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().set_idle(process.pid(), tid);
+    });
+}
+
+/// Body of the reaper thread, frees detached threads once they have exited and tears down
+/// processes whose last thread is gone.
+///
+/// The scheduler can't do that itself: it runs with interrupts off and its lock held, while
+/// freeing a thread or closing files takes other locks.
+extern "C" fn reaper_loop() {
+    loop {
+        let (zombies, finished) = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let zombies = scheduler.take_zombies();
+            let finished = scheduler.take_finished();
+
+            // checking and sleeping under one lock means no exit can slip in between
+            if zombies.is_empty() && finished.is_empty() && let Some((pid, tid)) = scheduler.current() {
+                scheduler.sleep(pid, tid);
+            }
+            (zombies, finished)
+        });
+
+        for (process, tid) in zombies {
+            process.reap_thread(&tid);
+        }
+        for process in finished {
+            process.close_files();
+            let registered = x86_64::instructions::interrupts::without_interrupts(|| {
+                SCHEDULER.lock().unregister_process(process.pid())
+            });
+            // the last reference frees the threads and the address space, not under the lock
+            drop(registered);
+        }
+        yield_now();
+    }
+}
+
+/// Creates the reaper process, whose thread frees detached threads and finished processes.
+pub fn init_reaper() {
+    let process = thread::Process::create(Parent::Independent);
+    let tid = process.create_thread(reaper_loop, 1);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().set_reaper(process.pid(), tid);
+    });
+    let _ = process.start_thread(tid);
+}
+
+/// Gives up the rest of the calling thread's time slice.
+pub fn yield_now() {
+    unsafe {
+        core::arch::asm!("int {vector}", vector = const InterruptIndex::Reschedule as u8);
+    }
+}
+
+/// Blocks the calling thread for at least `ms` milliseconds.
+///
+/// Before the scheduler is running there is no thread to put to sleep, so this busy-waits instead.
+pub fn sleep_ms(ms: u64) {
+    let deadline = timer::now() + timer::msToCycles(ms);
+
+    let current = x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().current()
+    });
+    let Some((pid, tid)) = current else {
+        while timer::now() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    };
+
+    // asleep before the timer is queued, another CPU could fire it right away and find the
+    // thread still running, which would make the wakeup a no-op
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.sleep(pid, tid);
+        kernelContext()
+            .timerQueue
+            .get()
+            .expect("Timer Queue not initialized")
+            .lock()
+            .addTimer(deadline, TimerPayload::WakeThread(pid, tid));
+    });
+
+    yield_now();
+}
+
+/// Ends the calling thread, waking any thread joining it.
+pub fn exit_thread() -> ! {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some((pid, tid)) = scheduler.current() {
+            scheduler.exit(pid, tid);
+        }
+    });
+
+    loop {
+        yield_now();
+    }
+}
+
+/// Ends the calling process, its other threads exit once they are out of the kernel or
+/// block, see `Scheduler::exit_process`.
+pub fn exit_process() -> ! {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(pid) = scheduler.current_pid() {
+            scheduler.exit_process(pid);
+        }
+    });
+
+    exit_thread();
+}
+
+/// Kills process `pid`, returns None if there is no such process. Its threads exit once they
+/// are out of the kernel or block, see `Scheduler::exit_process`.
+pub fn kill_process(pid: ProcessID) -> Option<()> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().exit_process(pid)
+    })
+}
+
+/// Blocks until thread `tid` of process `pid` exits. Fails if there is no such thread or
+/// another thread is already joining it.
+///
+/// Before the scheduler is running this busy-waits instead of sleeping.
+pub fn join_thread(pid: ProcessID, tid: ThreadID) -> Result<(), thread::JoinError> {
+    loop {
+        let finished = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler.current();
+            let finished = scheduler.join(pid, tid, current)?;
+
+            // registering and sleeping under one lock means the exit can't slip in between
+            if let (false, Some((currentPid, currentTid))) = (finished, current) {
+                scheduler.sleep(currentPid, currentTid);
+            }
+            Ok(finished)
+        })?;
+
+        if finished {
+            return Ok(());
+        }
+
+        if current_pid().is_some() {
+            yield_now();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Runs `f` on a new thread of the calling process.
+///
+/// Panics if called before the scheduler has a current process.
+pub fn spawn<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let process = current_process().expect("spawn called without a current process");
+
+    process.spawn(DEFAULT_QUANTUM, f)
+}
+
+/// Moves a thread into the EDF real-time class, see `realtime::RealtimeParams`.
+pub fn set_realtime(pid: ProcessID, tid: ThreadID, params: RealtimeParams) -> Result<(), AdmissionError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().set_realtime(pid, tid, params)
+    })
+}
+
+pub fn clear_realtime(pid: ProcessID, tid: ThreadID) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().clear_realtime(pid, tid)
+    })
+}
+
+/// Ends the calling real-time thread's job, returning at the start of its next period.
+/// For normal threads this only yields.
+pub fn wait_next_period() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some((pid, tid)) = scheduler.current() {
+            scheduler.finish_realtime_job(pid, tid);
+        }
+    });
+
+    yield_now();
+}
+
+/// Restricts the thread to the CPUs in `mask`, moving it off a CPU that is no longer allowed.
+pub fn set_affinity(pid: ProcessID, tid: ThreadID, mask: CpuMask) -> Result<(), AffinityError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().set_affinity(pid, tid, mask)
+    })
+}
+
+pub fn thread_affinity(pid: ProcessID, tid: ThreadID) -> Option<CpuMask> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().affinity(pid, tid)
+    })
+}
+
+/// Binds the thread to a single CPU.
+pub fn pin_thread(pid: ProcessID, tid: ThreadID, cpu: u8) -> Result<(), AffinityError> {
+    let mask = CpuMask::single(cpu).ok_or(AffinityError::InvalidMask)?;
+    set_affinity(pid, tid, mask)
+}
+
+pub fn unpin_thread(pid: ProcessID, tid: ThreadID) -> Result<(), AffinityError> {
+    set_affinity(pid, tid, CpuMask::all())
+}
+
+/// Moves the thread to `cpu` once, leaving its affinity mask unchanged.
+pub fn migrate_thread(pid: ProcessID, tid: ThreadID, cpu: u8) -> Result<(), AffinityError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().migrate(pid, tid, cpu)
+    })
+}
+
+/// Returns CPU time accounting for every thread of `pid`, or None if no such process exists.
+pub fn process_stats(pid: ProcessID) -> Option<ProcessStats> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER.lock().process_stats(pid)
+    })
+}
+
+pub static PROCESS_ID_ALLOCATOR: Mutex<IDAllocator> = Mutex::new("PROCESS_ID_ALLOCATOR", IDAllocator::new());
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(C)]
@@ -61,6 +349,11 @@ impl ThreadID {
         static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
         ThreadID(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// Refers to an ID handed out earlier, e.g. one passed in from user mode.
+    pub(crate) fn from_raw(id: u64) -> Self {
+        ThreadID(id)
+    }
 }
 
 
@@ -109,6 +402,11 @@ impl ProcessID {
         ProcessID(allocator.allocate())
     }
 
+    /// Refers to an ID handed out earlier, e.g. one passed in from user mode.
+    pub(crate) fn from_raw(id: u64) -> Self {
+        ProcessID(id)
+    }
+
     pub fn free(self) {
         let mut allocator = PROCESS_ID_ALLOCATOR.lock();
         allocator.deallocate(self.0);
@@ -0,0 +1,55 @@
+//! User programs on the root file system. The build puts the `userprogs` crate's binaries in
+//! `PROGRAM_DIR` of the ramdisk image.
+
+use alloc::format;
+use alloc::vec::Vec;
+use super::elf::{self, ElfError};
+use super::thread::ProcessRef;
+use super::Parent;
+use crate::fs::path;
+use crate::fs::vfs::{FsError, OpenFlags};
+
+/// Directory programs are looked up in by name.
+pub const PROGRAM_DIR: &str = "/bin";
+
+/// Largest image `run` reads into memory.
+const MAX_PROGRAM_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RunError {
+    /// The image couldn't be read, `NotFound` if there is no such program.
+    Fs(FsError),
+    Elf(ElfError),
+}
+
+/// Reads the image of program `name` from `PROGRAM_DIR`.
+pub fn find(name: &str) -> Result<Vec<u8>, FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::NotFound);
+    }
+    let file = path::open("/", &format!("{}/{}", PROGRAM_DIR, name), OpenFlags::READ)?;
+    let size = file.inode().stat()?.size;
+    if size > MAX_PROGRAM_SIZE {
+        return Err(FsError::FileTooLarge);
+    }
+
+    let mut image = alloc::vec![0u8; size as usize];
+    let mut done = 0;
+    while done < image.len() {
+        match file.read(&mut image[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    image.truncate(done);
+    Ok(image)
+}
+
+/// Starts program `name` with `argv[0]` set to its name.
+pub fn run(name: &str, args: &[&str], envp: &[&str], parent: Parent) -> Result<ProcessRef, RunError> {
+    let image = find(name).map_err(RunError::Fs)?;
+
+    let mut argv = alloc::vec![name];
+    argv.extend_from_slice(args);
+    elf::spawn(&image, &argv, envp, parent).map_err(RunError::Elf)
+}
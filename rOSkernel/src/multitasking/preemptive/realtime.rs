@@ -0,0 +1,251 @@
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::kernel::timer;
+use crate::multitasking::preemptive::{ProcessID, ThreadID};
+
+/// Share of each CPU the real-time class may reserve, in parts per million.
+/// The rest is kept for normal threads so they can't be starved completely.
+pub const RT_BANDWIDTH_LIMIT_PPM: u64 = 950_000;
+
+/// Timing contract of a real-time thread, in milliseconds.
+///
+/// Every `period` the thread is released with `runtime` of CPU time that must be consumed
+/// before `deadline` (relative to the release).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RealtimeParams {
+    pub runtime: u64,
+    pub period: u64,
+    pub deadline: u64,
+}
+
+impl RealtimeParams {
+    /// Implicit-deadline task, the deadline is the end of the period.
+    pub fn periodic(runtime: u64, period: u64) -> Self {
+        Self {
+            runtime,
+            period,
+            deadline: period,
+        }
+    }
+
+    /// Fraction of the CPU this task can demand within its deadline, in parts per million.
+    pub fn densityPpm(&self) -> u64 {
+        self.runtime.saturating_mul(1_000_000) / self.deadline.max(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionError {
+    /// runtime must be non-zero and runtime <= deadline <= period must hold.
+    InvalidParameters,
+    /// Admitting the thread would reserve more than `RT_BANDWIDTH_LIMIT_PPM` of the CPU.
+    Overcommitted { requestedPpm: u64, availablePpm: u64 },
+    NoSuchThread,
+}
+
+type ThreadKey = (ProcessID, ThreadID);
+
+/// Per-thread EDF bookkeeping, all times are in TSC cycles.
+#[derive(Debug)]
+struct RtEntity {
+    params: RealtimeParams,
+    runtime: u64,
+    period: u64,
+    deadline: u64,
+    absDeadline: u64,
+    budget: u64,
+    nextRelease: u64,
+    /// Budget ran out, the thread may not run again before `nextRelease`.
+    throttled: bool,
+    /// The thread finished its job and sleeps until `nextRelease`.
+    waitingForPeriod: bool,
+    /// When the thread was last charged while running, each CPU charges the thread it runs.
+    lastCharge: u64,
+}
+
+impl RtEntity {
+    fn replenish(&mut self, now: u64) {
+        // skip periods missed entirely, e.g. while the thread was blocked on something else
+        while self.nextRelease <= now {
+            self.absDeadline = self.nextRelease + self.deadline;
+            self.nextRelease += self.period;
+        }
+        self.budget = self.runtime;
+        self.throttled = false;
+        self.waitingForPeriod = false;
+    }
+}
+
+/// Earliest-deadline-first class, consulted by the scheduler before its round-robin queue.
+#[derive(Debug)]
+pub struct RealtimeClass {
+    entities: BTreeMap<ThreadKey, RtEntity>,
+    reservedPpm: u64,
+}
+
+impl RealtimeClass {
+    pub const fn new() -> Self {
+        Self {
+            entities: BTreeMap::new(),
+            reservedPpm: 0,
+        }
+    }
+
+    pub fn reservedPpm(&self) -> u64 {
+        self.reservedPpm
+    }
+
+    pub fn contains(&self, key: ThreadKey) -> bool {
+        self.entities.contains_key(&key)
+    }
+
+    pub fn params(&self, key: ThreadKey) -> Option<RealtimeParams> {
+        self.entities.get(&key).map(|e| e.params)
+    }
+
+    /// Admits a thread into the class if the total density stays under the bandwidth limit.
+    /// Changing the parameters of an admitted thread re-runs the test without its old share.
+    pub fn admit(&mut self, key: ThreadKey, params: RealtimeParams, now: u64) -> Result<(), AdmissionError> {
+        if params.runtime == 0 || params.runtime > params.deadline || params.deadline > params.period {
+            return Err(AdmissionError::InvalidParameters);
+        }
+
+        let previousPpm = self.entities.get(&key).map_or(0, |e| e.params.densityPpm());
+        let availablePpm = RT_BANDWIDTH_LIMIT_PPM - (self.reservedPpm - previousPpm);
+        let requestedPpm = params.densityPpm();
+        if requestedPpm > availablePpm {
+            return Err(AdmissionError::Overcommitted { requestedPpm, availablePpm });
+        }
+
+        let runtime = timer::msToCycles(params.runtime);
+        let period = timer::msToCycles(params.period);
+        let deadline = timer::msToCycles(params.deadline);
+        self.reservedPpm = self.reservedPpm - previousPpm + requestedPpm;
+        self.entities.insert(key, RtEntity {
+            params,
+            runtime,
+            period,
+            deadline,
+            absDeadline: now + deadline,
+            budget: runtime,
+            nextRelease: now + period,
+            throttled: false,
+            waitingForPeriod: false,
+            lastCharge: now,
+        });
+        Ok(())
+    }
+
+    /// Returns the thread to the normal class and releases its bandwidth.
+    pub fn remove(&mut self, key: ThreadKey) -> bool {
+        let Some(entity) = self.entities.remove(&key) else {
+            return false;
+        };
+        self.reservedPpm -= entity.params.densityPpm();
+        true
+    }
+
+    pub fn removeProcess(&mut self, pid: ProcessID) {
+        let keys: Vec<ThreadKey> = self.entities.keys().filter(|(p, _)| *p == pid).copied().collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    /// Whether the thread is a real-time thread that currently may not run.
+    pub fn isThrottled(&self, key: ThreadKey) -> bool {
+        self.entities.get(&key).is_some_and(|e| e.throttled || e.waitingForPeriod)
+    }
+
+    pub fn absDeadline(&self, key: ThreadKey) -> Option<u64> {
+        self.entities.get(&key).map(|e| e.absDeadline)
+    }
+
+    /// Marks the start of a real-time thread's run, budget is consumed from here.
+    pub fn startRunning(&mut self, key: ThreadKey, now: u64) {
+        if let Some(entity) = self.entities.get_mut(&key) {
+            entity.lastCharge = now;
+        }
+    }
+
+    /// Consumes the running thread's budget up to `now`, throttling it once it runs out.
+    pub fn charge(&mut self, key: ThreadKey, now: u64) {
+        if let Some(entity) = self.entities.get_mut(&key) {
+            let elapsed = now.saturating_sub(entity.lastCharge);
+            entity.lastCharge = now;
+            entity.budget = entity.budget.saturating_sub(elapsed);
+            if entity.budget == 0 {
+                entity.throttled = true;
+            }
+        }
+    }
+
+    /// When the running real-time thread's budget runs out if it keeps running.
+    pub fn budgetEnd(&self, key: ThreadKey, now: u64) -> Option<u64> {
+        self.entities.get(&key).map(|e| now + e.budget)
+    }
+
+    /// Ends the thread's current job early, it sleeps until its next release.
+    pub fn finishJob(&mut self, key: ThreadKey) -> bool {
+        let Some(entity) = self.entities.get_mut(&key) else {
+            return false;
+        };
+        entity.waitingForPeriod = true;
+        true
+    }
+
+    /// Refills every thread whose next period started by `now`.
+    ///
+    /// Returns the refilled threads and whether each was sleeping in `finishJob`,
+    /// so the scheduler can wake or requeue them.
+    pub fn replenish(&mut self, now: u64) -> Vec<(ThreadKey, bool)> {
+        let mut released = Vec::new();
+        for (key, entity) in self.entities.iter_mut() {
+            if (entity.throttled || entity.waitingForPeriod) && entity.nextRelease <= now {
+                let wasWaiting = entity.waitingForPeriod;
+                entity.replenish(now);
+                released.push((*key, wasWaiting));
+            }
+        }
+        released
+    }
+
+    /// Earliest release of a throttled or waiting thread, the timer must fire by then.
+    pub fn nextRelease(&self) -> Option<u64> {
+        self.entities
+            .values()
+            .filter(|e| e.throttled || e.waitingForPeriod)
+            .map(|e| e.nextRelease)
+            .min()
+    }
+
+    /// Index in `ready` of the eligible real-time thread with the earliest deadline.
+    pub fn pick(&self, ready: &VecDeque<ThreadKey>) -> Option<usize> {
+        ready
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| {
+                let entity = self.entities.get(key)?;
+                (!entity.throttled && !entity.waitingForPeriod).then_some((entity.absDeadline, i))
+            })
+            .min()
+            .map(|(_, i)| i)
+    }
+
+    /// Whether `candidate` should preempt `current`: any eligible real-time thread preempts a
+    /// normal one, between real-time threads the earlier deadline wins.
+    pub fn preempts(&self, candidate: ThreadKey, current: Option<ThreadKey>) -> bool {
+        let Some(candidateDeadline) = self.entities.get(&candidate)
+            .filter(|e| !e.throttled && !e.waitingForPeriod)
+            .map(|e| e.absDeadline)
+        else {
+            return false;
+        };
+
+        match current.and_then(|key| self.absDeadline(key)) {
+            Some(currentDeadline) => candidateDeadline < currentDeadline,
+            None => true,
+        }
+    }
+}
@@ -1,14 +1,32 @@
-use super::thread::{GPRegisters, InterruptFrame, ThreadStatus, ProcessRef};
+use super::thread::{GPRegisters, InterruptFrame, JoinError, ThreadStatus, ProcessRef};
+use crate::mem::stack::StackBounds;
 use alloc::collections::vec_deque::VecDeque;
 use alloc::collections::BTreeMap;
 use alloc::collections::BTreeSet;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::PhysFrame;
-use crate::kernel::kernelContext;
+use alloc::vec::Vec;
+use crate::kernel::{currentCpuID, kernelContext};
+use crate::kernel::gdt;
+use crate::kernel::interrupts::InterruptIndex;
+use crate::kernel::timer::{self, TICK_MS};
+use crate::kernel::watchdog;
 use crate::multitasking::preemptive::{ProcessID, ThreadID};
-use crate::util::wrappers::{xgetbv0, xsetbv0, CPUID, XFeatures, get_fpu_mechanism, FpuSaveMechanism};
+use crate::multitasking::preemptive::affinity::{AffinityError, CpuMask};
+use crate::multitasking::preemptive::stats::{CpuStats, ProcessStats};
+use crate::multitasking::preemptive::realtime::{AdmissionError, RealtimeClass, RealtimeParams};
+use crate::util::wrappers::{rdtsc, xgetbv0, xsetbv0, CPUID, XFeatures, get_fpu_mechanism};
+use crate::multitasking::preemptive::fpu::{self, FpuContext};
+use crate::mem::tlb;
+use crate::util::lockdep;
 use alloc::alloc::{alloc, dealloc, Layout};
 
+/// How often a busy CPU compares its load with the others. Idle CPUs don't poll, they are sent
+/// a reschedule IPI when another CPU queues more than it can run and pull work then.
+pub const BALANCE_INTERVAL_MS: u64 = 100;
+
+type ThreadKey = (ProcessID, ThreadID);
+
 /// Data extracted from a thread for context switching
 #[derive(Clone, Copy)]
 struct ThreadContext {
@@ -17,14 +35,57 @@ struct ThreadContext {
     iFrame: InterruptFrame,
     xAreaPtr: Option<*mut u8>,
     xFeatures: XFeatures,
+    fpuStamp: u64,
+    quantum: u64,
+    kernelStack: Option<StackBounds>,
+}
+
+/// Scheduling state of one CPU, created the first time the CPU enters the scheduler.
+#[derive(Debug)]
+struct CpuQueue {
+    current: Option<ThreadKey>,
+    ready: VecDeque<ThreadKey>,
+    idle: Option<ThreadKey>,
+    sliceEnd: u64,
+    lastBalance: u64,
+}
+
+impl CpuQueue {
+    fn new() -> Self {
+        Self {
+            current: None,
+            ready: VecDeque::new(),
+            idle: None,
+            sliceEnd: 0,
+            lastBalance: 0,
+        }
+    }
+
+    fn isIdle(&self) -> bool {
+        self.current.is_none() || self.current == self.idle
+    }
+
+    /// Threads queued here plus the running one, the idle thread doesn't count.
+    fn load(&self) -> usize {
+        self.ready.len() + !self.isIdle() as usize
+    }
 }
 
 pub struct Scheduler {
     processes: BTreeMap<ProcessID, ProcessRef>,
-    current: Option<(ProcessID, ThreadID)>,
-    ready: VecDeque<(ProcessID, ThreadID)>,
-    blocked: BTreeSet<(ProcessID, ThreadID)>,
-    idle: Option<(ProcessID, ThreadID)>,
+    /// Per-CPU run queues, keyed by local APIC ID.
+    cpus: BTreeMap<u8, CpuQueue>,
+    blocked: BTreeSet<ThreadKey>,
+    /// Threads asked to move by `migrate` while running, moved when they're next queued.
+    pendingMigrations: BTreeMap<ThreadKey, u8>,
+    cpuStats: BTreeMap<u8, CpuStats>,
+    ticks: u64,
+    rt: RealtimeClass,
+    /// Detached threads that exited and left their CPU, freed by the reaper thread.
+    zombies: Vec<ThreadKey>,
+    /// Processes whose last thread exited and left its CPU, torn down by the reaper thread.
+    finished: Vec<ProcessID>,
+    reaper: Option<ThreadKey>,
 }
 
 
@@ -32,15 +93,62 @@ impl Scheduler {
     pub const fn new() -> Self {
         Self {
             processes: BTreeMap::new(),
-            current: None,
-            ready: VecDeque::new(),
+            cpus: BTreeMap::new(),
             blocked: BTreeSet::new(),
-            idle: None,
+            pendingMigrations: BTreeMap::new(),
+            cpuStats: BTreeMap::new(),
+            ticks: 0,
+            rt: RealtimeClass::new(),
+            zombies: Vec::new(),
+            finished: Vec::new(),
+            reaper: None,
         }
     }
 
+    fn local(&mut self) -> &mut CpuQueue {
+        self.cpus.entry(currentCpuID()).or_insert_with(CpuQueue::new)
+    }
+
+    fn localRef(&self) -> Option<&CpuQueue> {
+        self.cpus.get(&currentCpuID())
+    }
+
+    /// Sets the idle thread of the calling CPU, which also brings the CPU online for scheduling.
     pub fn set_idle(&mut self, pid: ProcessID, tid: ThreadID) {
-        self.idle = Some((pid, tid));
+        self.local().idle = Some((pid, tid));
+    }
+
+    /// Sets the thread woken to free detached threads once they exited.
+    pub fn set_reaper(&mut self, pid: ProcessID, tid: ThreadID) {
+        self.reaper = Some((pid, tid));
+    }
+
+    /// Hands out the threads the reaper should free, see `Process::reap_thread`.
+    pub fn take_zombies(&mut self) -> Vec<(ProcessRef, ThreadID)> {
+        core::mem::take(&mut self.zombies)
+            .into_iter()
+            .filter_map(|(pid, tid)| self.processes.get(&pid).map(|process| (process.clone(), tid)))
+            .collect()
+    }
+
+    /// Hands out the processes the reaper should close and unregister.
+    pub fn take_finished(&mut self) -> Vec<ProcessRef> {
+        core::mem::take(&mut self.finished)
+            .into_iter()
+            .filter_map(|pid| self.processes.get(&pid).cloned())
+            .collect()
+    }
+
+    /// CPUs that have entered the scheduler through `set_idle`, only these can be picked for
+    /// a thread.
+    pub fn online_cpus(&self) -> CpuMask {
+        let mut mask = CpuMask::empty();
+        for (cpu, queue) in &self.cpus {
+            if queue.idle.is_some() {
+                mask.insert(*cpu);
+            }
+        }
+        mask
     }
 
     pub fn schedule(&mut self, pid: ProcessID, tid: ThreadID) -> Option<()> {
@@ -54,14 +162,18 @@ impl Scheduler {
             return None;
         }
         
-        if self.ready.contains(&(pid, tid)) {
+        if self.queuedOn((pid, tid)).is_some() {
             return None;
         }
         
         self.blocked.remove(&(pid, tid));
+        process.with_thread_mut(&tid, |thread| {
+            if let Some(t) = thread {
+                t.stats.enterReady(rdtsc());
+            }
+        });
         
-        self.ready.push_back((pid, tid));
-        Some(())
+        self.enqueue((pid, tid)).ok()
     }
 
     pub fn register_process(&mut self, process: ProcessRef) {
@@ -69,11 +181,15 @@ impl Scheduler {
     }
 
     pub fn unregister_process(&mut self, pid: ProcessID) -> Option<ProcessRef> {
-        self.ready.retain(|(p, _)| *p != pid);
-        self.blocked.retain(|(p, _)| *p != pid);
-        if self.current.map(|(p, _)| p) == Some(pid) {
-            self.current = None;
+        for queue in self.cpus.values_mut() {
+            queue.ready.retain(|(p, _)| *p != pid);
+            if queue.current.map(|(p, _)| p) == Some(pid) {
+                queue.current = None;
+            }
         }
+        self.blocked.retain(|(p, _)| *p != pid);
+        self.pendingMigrations.retain(|(p, _), _| *p != pid);
+        self.rt.removeProcess(pid);
         self.processes.remove(&pid)
     }
 
@@ -81,24 +197,83 @@ impl Scheduler {
         self.processes.get(&pid).cloned()
     }
 
+    /// Thread running on the calling CPU.
     pub fn current(&self) -> Option<(ProcessID, ThreadID)> {
-        self.current
+        self.localRef().and_then(|queue| queue.current)
+    }
+
+    /// Whether only the idle thread is running, or nothing at all before the first switch.
+    pub fn is_idle(&self) -> bool {
+        self.localRef().is_none_or(|queue| queue.isIdle())
     }
 
     pub fn current_pid(&self) -> Option<ProcessID> {
-        self.current.map(|(pid, _)| pid)
+        self.current().map(|(pid, _)| pid)
+    }
+
+    pub fn pids(&self) -> Vec<ProcessID> {
+        self.processes.keys().copied().collect()
+    }
+
+    /// Number of timer interrupts taken since boot, across all CPUs.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Counts a timer expiration on the calling CPU, the other ways into the scheduler only
+    /// account time.
+    fn countTimerTick(&mut self) {
+        self.ticks += 1;
+        self.cpuStats.entry(currentCpuID()).or_default().ticks += 1;
+    }
+
+    pub fn process_stats(&self, pid: ProcessID) -> Option<ProcessStats> {
+        let process = self.processes.get(&pid)?;
+        Some(ProcessStats::from_threads(pid, process.thread_stats(rdtsc())))
+    }
+
+    pub fn cpu_stats(&self) -> BTreeMap<u8, CpuStats> {
+        self.cpuStats.clone()
+    }
+
+    /// Charges the elapsed time since the previous tick to the running thread and this CPU.
+    fn accountTick(&mut self, now: u64, frame: &InterruptFrame) {
+        let idle = self.is_idle();
+        self.cpuStats
+            .entry(currentCpuID())
+            .or_default()
+            .account(now, idle);
+
+        let Some((pid, tid)) = self.current() else {
+            return;
+        };
+
+        if let Some(process) = self.processes.get(&pid) {
+            // RPL 3 in the interrupted CS means the tick landed in user code
+            let user = frame.cs & 0b11 == 3;
+            process.with_thread_mut(&tid, |thread| {
+                if let Some(t) = thread {
+                    t.stats.charge(now, user);
+                }
+            });
+        }
     }
 
     pub fn sleep(&mut self, pid: ProcessID, tid: ThreadID) -> Option<()> {
         let process = self.processes.get(&pid)?;
+        // a killed process's threads exit instead of blocking, the caller's yield is their last
+        if process.is_killed() {
+            return self.exit(pid, tid);
+        }
         
         process.with_thread_mut(&tid, |thread| {
             let t = thread?;
             t.status = ThreadStatus::Sleeping;
+            t.stats.enterSleeping(rdtsc());
             Some(())
         })?;
         
-        self.ready.retain(|&x| x != (pid, tid));
+        self.dequeue((pid, tid));
         self.blocked.insert((pid, tid));
         
         Some(())
@@ -106,14 +281,18 @@ impl Scheduler {
 
     pub fn sleep_no_disturb(&mut self, pid: ProcessID, tid: ThreadID) -> Option<()> {
         let process = self.processes.get(&pid)?;
+        if process.is_killed() {
+            return self.exit(pid, tid);
+        }
         
         process.with_thread_mut(&tid, |thread| {
             let t = thread?;
             t.status = ThreadStatus::SleepingNoDisturb;
+            t.stats.enterSleeping(rdtsc());
             Some(())
         })?;
         
-        self.ready.retain(|&x| x != (pid, tid));
+        self.dequeue((pid, tid));
         self.blocked.insert((pid, tid));
         
         Some(())
@@ -128,6 +307,7 @@ impl Scheduler {
             match t.status {
                 ThreadStatus::Sleeping => {
                     t.status = ThreadStatus::Waking;
+                    t.stats.enterReady(rdtsc());
                     Some(true)
                 }
                 ThreadStatus::Spawned => {
@@ -139,7 +319,7 @@ impl Scheduler {
         
         if should_enqueue {
             self.blocked.remove(&(pid, tid));
-            self.ready.push_back((pid, tid));
+            self.enqueue((pid, tid)).ok()?;
         }
         
         Some(())
@@ -154,6 +334,7 @@ impl Scheduler {
             match t.status {
                 ThreadStatus::Sleeping | ThreadStatus::SleepingNoDisturb => {
                     t.status = ThreadStatus::Waking;
+                    t.stats.enterReady(rdtsc());
                     Some(true)
                 }
                 ThreadStatus::Spawned => {
@@ -165,48 +346,542 @@ impl Scheduler {
         
         if should_enqueue {
             self.blocked.remove(&(pid, tid));
-            self.ready.push_back((pid, tid));
+            self.enqueue((pid, tid)).ok()?;
         }
         
         Some(())
     }
 
+    /// Marks a thread as finished. The thread joining it, if any, is woken once no CPU runs
+    /// the thread anymore.
+    pub fn exit(&mut self, pid: ProcessID, tid: ThreadID) -> Option<()> {
+        let process = self.processes.get(&pid)?;
+
+        let (wasDone, onCpu) = process.with_thread_mut(&tid, |thread| {
+            let t = thread?;
+            let wasDone = t.done;
+            t.status = ThreadStatus::Dead;
+            t.done = true;
+            if !wasDone {
+                t.stats.enterSleeping(rdtsc());
+            }
+            Some((wasDone, t.onCpu))
+        })?;
+
+        self.dequeue((pid, tid));
+        self.blocked.remove(&(pid, tid));
+        self.pendingMigrations.remove(&(pid, tid));
+        self.rt.remove((pid, tid));
+
+        if !wasDone && !onCpu {
+            self.retire((pid, tid));
+        }
+
+        Some(())
+    }
+
+    /// Called once a thread is no longer running anywhere, retires it if it has exited.
+    fn switchedOut(&mut self, key: ThreadKey) {
+        let Some(process) = self.processes.get(&key.0) else {
+            return;
+        };
+        let done = process.with_thread_mut(&key.1, |thread| {
+            thread.is_some_and(|t| {
+                t.onCpu = false;
+                t.done
+            })
+        });
+        if done {
+            self.retire(key);
+        }
+    }
+
+    /// Wakes the joiner of an exited thread that left its CPU, or hands it to the reaper if
+    /// it is detached. The reaper also gets the process once its last thread is gone.
+    fn retire(&mut self, key: ThreadKey) {
+        let Some(process) = self.processes.get(&key.0) else {
+            return;
+        };
+        let Some((joiner, detached)) = process.with_thread_mut(&key.1, |thread| {
+            thread.map(|t| (t.joiner.take(), t.detached))
+        }) else {
+            return;
+        };
+        let processFinished = process.threads_finished() && !self.finished.contains(&key.0);
+
+        if let Some((joinerPid, joinerTid)) = joiner {
+            self.wake(joinerPid, joinerTid);
+        }
+        if detached {
+            self.zombies.push(key);
+        }
+        if processFinished {
+            self.finished.push(key.0);
+        }
+        if (detached || processFinished) && let Some((reaperPid, reaperTid)) = self.reaper {
+            self.wake(reaperPid, reaperTid);
+        }
+    }
+
+    /// Marks a thread nobody will join, so the reaper frees it once it has exited.
+    pub fn detach(&mut self, pid: ProcessID, tid: ThreadID) -> Option<()> {
+        let process = self.processes.get(&pid)?;
+        let retired = process.with_thread_mut(&tid, |thread| {
+            let t = thread?;
+            t.detached = true;
+            Some(t.done && !t.onCpu)
+        })?;
+
+        if retired {
+            self.retire((pid, tid));
+        }
+        Some(())
+    }
+
+    /// Kills `pid`. A thread in kernel code may hold locks, so each thread only exits at its
+    /// next return to user mode or when it next blocks. Threads preempted in user mode or not
+    /// yet started exit right away, sleeping ones are woken and running ones preempted so they
+    /// get there.
+    pub fn exit_process(&mut self, pid: ProcessID) -> Option<()> {
+        let process = self.processes.get(&pid)?.clone();
+        process.mark_killed();
+
+        for tid in process.thread_ids() {
+            if let Some(cpu) = self.runningOn((pid, tid)) {
+                if cpu != currentCpuID() {
+                    self.reschedule(cpu);
+                }
+                continue;
+            }
+
+            let inUser = process.with_thread_mut(&tid, |thread| {
+                thread.is_some_and(|t| !t.initialised || t.iFrame.cs & 3 == 3)
+            });
+            if inUser {
+                self.exit(pid, tid);
+            } else {
+                self.wake_force(pid, tid);
+            }
+        }
+        Some(())
+    }
+
+    /// Registers `joiner` to be woken when the thread has exited and left its CPU.
+    /// Returns true if that already happened. A thread has at most one joiner, a second one
+    /// gets `AlreadyJoined` unless the first has exited in the meantime.
+    pub fn join(&mut self, pid: ProcessID, tid: ThreadID, joiner: Option<(ProcessID, ThreadID)>) -> Result<bool, JoinError> {
+        let process = self.processes.get(&pid).ok_or(JoinError::NoSuchThread)?.clone();
+
+        let (finished, registered) = process
+            .with_thread_mut(&tid, |thread| thread.map(|t| (t.done && !t.onCpu, t.joiner)))
+            .ok_or(JoinError::NoSuchThread)?;
+        if finished {
+            return Ok(true);
+        }
+        if let Some(other) = registered && Some(other) != joiner && self.isAlive(other) {
+            return Err(JoinError::AlreadyJoined);
+        }
+
+        if joiner.is_some() {
+            process.with_thread_mut(&tid, |thread| {
+                if let Some(t) = thread {
+                    t.joiner = joiner;
+                }
+            });
+        }
+        Ok(false)
+    }
+
+    /// Whether the thread exists and hasn't exited.
+    fn isAlive(&self, key: ThreadKey) -> bool {
+        self.processes.get(&key.0)
+            .is_some_and(|process| process.with_thread_mut(&key.1, |thread| thread.is_some_and(|t| !t.done)))
+    }
+
+    /// Moves a thread into the EDF real-time class, subject to admission control.
+    pub fn set_realtime(&mut self, pid: ProcessID, tid: ThreadID, params: RealtimeParams) -> Result<(), AdmissionError> {
+        let exists = self.processes.get(&pid)
+            .is_some_and(|process| process.with_thread_mut(&tid, |thread| thread.is_some()));
+        if !exists {
+            return Err(AdmissionError::NoSuchThread);
+        }
+
+        self.rt.admit((pid, tid), params, rdtsc())?;
+        if let Some(cpu) = self.queuedOn((pid, tid)) {
+            self.preemptForRealtime(cpu, (pid, tid));
+        }
+        Ok(())
+    }
+
+    /// Returns a real-time thread to round-robin scheduling.
+    pub fn clear_realtime(&mut self, pid: ProcessID, tid: ThreadID) -> bool {
+        let removed = self.rt.remove((pid, tid));
+        // it may have been parked while throttled
+        self.requeueIfRunnable((pid, tid));
+        removed
+    }
+
+    pub fn realtime_params(&self, pid: ProcessID, tid: ThreadID) -> Option<RealtimeParams> {
+        self.rt.params((pid, tid))
+    }
+
+    /// Ends the current job of a real-time thread, it sleeps until its next period.
+    /// Returns false for normal threads.
+    pub fn finish_realtime_job(&mut self, pid: ProcessID, tid: ThreadID) -> bool {
+        if !self.rt.finishJob((pid, tid)) {
+            return false;
+        }
+        self.sleep(pid, tid);
+        true
+    }
+
+    /// Restricts a thread to the CPUs in `mask`.
+    ///
+    /// A queued thread moves to an allowed CPU right away, a running one is preempted so it
+    /// moves on its next switch.
+    pub fn set_affinity(&mut self, pid: ProcessID, tid: ThreadID, mask: CpuMask) -> Result<(), AffinityError> {
+        if mask.is_empty() {
+            return Err(AffinityError::InvalidMask);
+        }
+        if mask.intersect(self.online_cpus()).is_empty() {
+            return Err(AffinityError::NoOnlineCpu);
+        }
+
+        let process = self.processes.get(&pid).ok_or(AffinityError::NoSuchThread)?;
+        process.with_thread_mut(&tid, |thread| {
+            thread.map(|t| t.affinity = mask).ok_or(AffinityError::NoSuchThread)
+        })?;
+
+        let key = (pid, tid);
+        if self.pendingMigrations.get(&key).is_some_and(|cpu| !mask.contains(*cpu)) {
+            self.pendingMigrations.remove(&key);
+        }
+        if self.queuedOn(key).is_some_and(|cpu| !mask.contains(cpu)) {
+            self.dequeue(key);
+            self.enqueue(key)?;
+        }
+        if let Some(cpu) = self.runningOn(key).filter(|cpu| !mask.contains(*cpu)) {
+            self.reschedule(cpu);
+        }
+        Ok(())
+    }
+
+    pub fn affinity(&self, pid: ProcessID, tid: ThreadID) -> Option<CpuMask> {
+        let process = self.processes.get(&pid)?;
+        process.with_thread_mut(&tid, |thread| thread.map(|t| t.affinity))
+    }
+
+    /// Moves a thread to `cpu`'s run queue. A running thread moves when it is next preempted,
+    /// a sleeping one when it wakes.
+    pub fn migrate(&mut self, pid: ProcessID, tid: ThreadID, cpu: u8) -> Result<(), AffinityError> {
+        let key = (pid, tid);
+        let affinity = self.affinity(pid, tid).ok_or(AffinityError::NoSuchThread)?;
+        if !affinity.contains(cpu) {
+            return Err(AffinityError::NotAllowed);
+        }
+        if !self.online_cpus().contains(cpu) {
+            return Err(AffinityError::NoOnlineCpu);
+        }
+
+        match self.queuedOn(key) {
+            Some(from) if from == cpu => {}
+            Some(_) => {
+                self.dequeue(key);
+                self.pushReady(cpu, key);
+            }
+            None if self.runningOn(key) == Some(cpu) => {}
+            None => {
+                self.pendingMigrations.insert(key, cpu);
+                if let Some(running) = self.runningOn(key) {
+                    self.reschedule(running);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// CPU whose ready queue holds the thread.
+    fn queuedOn(&self, key: ThreadKey) -> Option<u8> {
+        self.cpus.iter()
+            .find(|(_, queue)| queue.ready.contains(&key))
+            .map(|(cpu, _)| *cpu)
+    }
+
+    fn runningOn(&self, key: ThreadKey) -> Option<u8> {
+        self.cpus.iter()
+            .find(|(_, queue)| queue.current == Some(key))
+            .map(|(cpu, _)| *cpu)
+    }
+
+    fn dequeue(&mut self, key: ThreadKey) {
+        for queue in self.cpus.values_mut() {
+            queue.ready.retain(|&x| x != key);
+        }
+    }
+
+    fn loadOf(&self, cpu: u8) -> usize {
+        self.cpus.get(&cpu).map_or(0, |queue| queue.load())
+    }
+
+    /// Picks the CPU a thread that became ready should queue on: a pending migration target,
+    /// else the CPU it last ran on unless another allowed CPU is clearly less loaded.
+    ///
+    /// Only online CPUs are picked, a thread none of whose allowed CPUs is up can't be queued.
+    fn selectCpu(&self, key: ThreadKey) -> Result<u8, AffinityError> {
+        let local = currentCpuID();
+        let process = self.processes.get(&key.0).ok_or(AffinityError::NoSuchThread)?;
+        let (affinity, lastCpu) = process.with_thread_mut(&key.1, |thread| {
+            thread.map(|t| (t.affinity, t.lastCpu))
+        }).ok_or(AffinityError::NoSuchThread)?;
+
+        let allowed = affinity.intersect(self.online_cpus());
+        if allowed.is_empty() {
+            return Err(AffinityError::NoOnlineCpu);
+        }
+        if let Some(target) = self.pendingMigrations.get(&key).filter(|cpu| allowed.contains(**cpu)) {
+            return Ok(*target);
+        }
+
+        // prefer the local CPU among equally loaded ones
+        let leastLoaded = allowed.iter()
+            .min_by_key(|&cpu| (self.loadOf(cpu), cpu != local))
+            .unwrap_or(local);
+        Ok(match lastCpu.filter(|cpu| allowed.contains(*cpu)) {
+            Some(last) if self.loadOf(last) <= self.loadOf(leastLoaded) + 1 => last,
+            _ => leastLoaded,
+        })
+    }
+
+    fn pushReady(&mut self, cpu: u8, key: ThreadKey) {
+        self.pendingMigrations.remove(&key);
+        self.cpus.entry(cpu).or_insert_with(CpuQueue::new).ready.push_back(key);
+        self.kick(cpu);
+        self.preemptForRealtime(cpu, key);
+        self.kickIdleFor(cpu, key);
+    }
+
+    /// Queues a thread that became ready on the CPU picked by `selectCpu`.
+    fn enqueue(&mut self, key: ThreadKey) -> Result<(), AffinityError> {
+        let cpu = self.selectCpu(key)?;
+        self.pushReady(cpu, key);
+        Ok(())
+    }
+
+    /// Ends `cpu`'s current slice right away, through its timer if it is the calling CPU
+    /// and through a reschedule IPI otherwise.
+    fn reschedule(&mut self, cpu: u8) {
+        if let Some(queue) = self.cpus.get_mut(&cpu) {
+            queue.sliceEnd = 0;
+        }
+
+        let Some(apic) = kernelContext().apic.get() else {
+            return;
+        };
+        if cpu == currentCpuID() {
+            apic.armTimer(Some(rdtsc()));
+        } else {
+            apic.sendIPI(cpu, InterruptIndex::RescheduleIpi as u8);
+        }
+    }
+
+    /// Wakes `cpu` if it is idling with the tick stopped,
+    /// so a newly ready thread doesn't wait for an unrelated interrupt.
+    fn kick(&mut self, cpu: u8) {
+        if self.cpus.get(&cpu).is_some_and(|queue| queue.isIdle()) {
+            self.reschedule(cpu);
+        }
+    }
+
+    /// Once `cpu` has a thread waiting behind another, wakes an idle CPU `key` may run on so it
+    /// pulls work over, idle CPUs keep their tick stopped instead of polling for it.
+    fn kickIdleFor(&mut self, cpu: u8, key: ThreadKey) {
+        if self.loadOf(cpu) < 2 {
+            return;
+        }
+        let Some(affinity) = self.affinity(key.0, key.1) else {
+            return;
+        };
+
+        let idle = self.cpus.iter()
+            .find(|(other, queue)| {
+                **other != cpu
+                    && affinity.contains(**other)
+                    && queue.idle.is_some()
+                    && queue.isIdle()
+                    && queue.ready.is_empty()
+            })
+            .map(|(other, _)| *other);
+        if let Some(other) = idle {
+            self.reschedule(other);
+        }
+    }
+
+    /// Ends `cpu`'s slice right away if `key` is a real-time thread that beats the one running there.
+    fn preemptForRealtime(&mut self, cpu: u8, key: ThreadKey) {
+        let current = self.cpus.get(&cpu).and_then(|queue| queue.current);
+        if current == Some(key) || !self.rt.preempts(key, current) {
+            return;
+        }
+        self.reschedule(cpu);
+    }
+
+    /// Puts a thread back on a ready queue unless it is running, queued or not runnable.
+    fn requeueIfRunnable(&mut self, key: ThreadKey) {
+        if self.runningOn(key).is_some() || self.queuedOn(key).is_some() || self.blocked.contains(&key) {
+            return;
+        }
+
+        let runnable = self.processes.get(&key.0)
+            .is_some_and(|p| p.with_thread_mut(&key.1, |t| {
+                t.is_some_and(|t| matches!(t.status, ThreadStatus::Spawned | ThreadStatus::Waking))
+            }));
+        // it was queued before, so one of its CPUs is online and CPUs never go offline
+        if runnable {
+            let _ = self.enqueue(key);
+        }
+    }
+
+    /// Refills real-time threads whose period started, waking those that finished their last job.
+    fn releaseRealtime(&mut self, now: u64) {
+        for ((pid, tid), wasWaiting) in self.rt.replenish(now) {
+            if wasWaiting {
+                self.wake(pid, tid);
+            }
+            self.requeueIfRunnable((pid, tid));
+        }
+    }
+
+    /// Pulls ready threads from the busiest CPU onto `cpu` until their loads differ by at most one.
+    /// Without `force` this runs at most every `BALANCE_INTERVAL_MS`.
+    fn balance(&mut self, cpu: u8, now: u64, force: bool) {
+        let queue = self.cpus.entry(cpu).or_insert_with(CpuQueue::new);
+        if !force && now < queue.lastBalance + timer::msToCycles(BALANCE_INTERVAL_MS) {
+            return;
+        }
+        queue.lastBalance = now;
+
+        let localLoad = self.loadOf(cpu);
+        let Some((busiest, busiestLoad)) = self.cpus.iter()
+            .filter(|(other, _)| **other != cpu)
+            .map(|(other, queue)| (*other, queue.load()))
+            .max_by_key(|(_, load)| *load)
+        else {
+            return;
+        };
+        if busiestLoad < localLoad + 2 {
+            return;
+        }
+
+        // the most recently queued threads have waited least and lose the least by moving
+        let mut toMove = (busiestLoad - localLoad) / 2;
+        let mut i = self.cpus[&busiest].ready.len();
+        while toMove > 0 && i > 0 {
+            i -= 1;
+            let key = self.cpus[&busiest].ready[i];
+            let allowed = self.affinity(key.0, key.1).is_some_and(|mask| mask.contains(cpu));
+            if !allowed || self.pendingMigrations.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(queue) = self.cpus.get_mut(&busiest) {
+                queue.ready.remove(i);
+            }
+            self.local().ready.push_back(key);
+            toMove -= 1;
+        }
+    }
+
     pub fn prioritize(&mut self, pid: ProcessID, tid: ThreadID) -> bool {
-        if self.current == Some((pid, tid)) {
+        if self.runningOn((pid, tid)).is_some() {
             return true;
         }
         
-        if let Some(idx) = self.ready.iter().position(|&x| x == (pid, tid)) {
-            self.ready.remove(idx);
-            self.ready.push_front((pid, tid));
-            true
-        } else {
-            false
+        for queue in self.cpus.values_mut() {
+            if let Some(idx) = queue.ready.iter().position(|&x| x == (pid, tid)) {
+                queue.ready.remove(idx);
+                queue.ready.push_front((pid, tid));
+                return true;
+            }
         }
+        false
     }
     
     pub fn prioritize_thread(&mut self, tid: ThreadID) -> bool {
-        if let Some((_, current_tid)) = self.current {
-            if current_tid == tid {
-                return true;
-            }
+        if self.cpus.values().any(|queue| queue.current.is_some_and(|(_, t)| t == tid)) {
+            return true;
         }
         
-        for (pid, t) in self.ready.iter() {
-            if *t == tid {
-                let pid = *pid;
-                return self.prioritize(pid, tid);
-            }
+        let queued = self.cpus.values()
+            .flat_map(|queue| queue.ready.iter())
+            .find(|(_, t)| *t == tid)
+            .copied();
+        match queued {
+            Some((pid, tid)) => self.prioritize(pid, tid),
+            None => false,
+        }
+    }
+
+    /// Ends the current thread's time slice so the next `switchTask` picks another thread.
+    pub fn expireSlice(&mut self) {
+        self.local().sliceEnd = 0;
+    }
+
+    /// When the timer next needs to fire for scheduling purposes, in TSC cycles.
+    /// None means only the idle thread can run and no tick is needed.
+    pub fn nextSliceEnd(&self) -> Option<u64> {
+        let sliceEnd = match self.localRef() {
+            Some(queue) if !queue.isIdle() => Some(queue.sliceEnd),
+            Some(queue) if !queue.ready.is_empty() => Some(0),
+            _ => None,
+        };
+
+        // throttled real-time threads must be refilled on time even if nothing else is due
+        match (sliceEnd, self.rt.nextRelease()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
         }
-        
-        false
     }
 
-    fn tick(&mut self) -> bool {
-        let Some((pid, tid)) = self.current else {
-            return !self.ready.is_empty() || self.idle.is_some();
+    fn tick(&mut self, now: u64) -> bool {
+        let cpu = currentCpuID();
+        if let Some(key) = self.current().filter(|key| self.rt.contains(*key)) {
+            self.rt.charge(key, now);
+        }
+        self.releaseRealtime(now);
+        // an idle CPU only gets here when woken, usually by `kickIdleFor`, so it always looks
+        let idling = self.local().isIdle();
+        self.balance(cpu, now, idling);
+
+        let queue = self.local();
+        let (current, idle, sliceEnd, hasReady) = (queue.current, queue.idle, queue.sliceEnd, !queue.ready.is_empty());
+
+        let Some((pid, tid)) = current else {
+            return hasReady || idle.is_some();
         };
 
+        if current == idle {
+            return hasReady;
+        }
+
+        // EDF: real-time threads run before normal ones until their budget is used up,
+        // and a real-time thread with an earlier deadline preempts the running one
+        if self.rt.isThrottled((pid, tid)) {
+            return true;
+        }
+        let ready = &self.cpus[&cpu].ready;
+        if let Some(i) = self.rt.pick(ready) {
+            if self.rt.preempts(ready[i], current) {
+                return true;
+            }
+        }
+
+        // the affinity changed to exclude this CPU, or a migration is waiting for a switch
+        if self.pendingMigrations.contains_key(&(pid, tid))
+            || self.affinity(pid, tid).is_some_and(|mask| !mask.contains(cpu))
+        {
+            return true;
+        }
+
         let Some(process) = self.processes.get(&pid) else {
             return true;
         };
@@ -218,8 +893,7 @@ impl Scheduler {
                         || t.status == ThreadStatus::SleepingNoDisturb => {
                     true
                 }
-                Some(t) if t.initialised && t.quantum > 0 => {
-                    t.quantum -= 1;
+                Some(t) if t.initialised && now < sliceEnd => {
                     false
                 }
                 Some(t) => {
@@ -232,8 +906,12 @@ impl Scheduler {
     }
 
     fn switch_to_next(&mut self) -> Option<(ProcessID, ThreadID)> {
-        if let Some((pid, tid)) = self.current.take() {
-            if !self.blocked.contains(&(pid, tid)) {
+        let cpu = currentCpuID();
+        let idle = self.local().idle;
+
+        if let Some((pid, tid)) = self.local().current.take() {
+            // the idle thread only runs when nothing else can, it never queues
+            if !self.blocked.contains(&(pid, tid)) && Some((pid, tid)) != idle {
                 let is_runnable = self.processes.get(&pid)
                     .map(|p| p.with_thread_mut(&tid, |t| {
                         t.map(|t| !matches!(t.status, ThreadStatus::Dead | ThreadStatus::Sleeping | ThreadStatus::SleepingNoDisturb))
@@ -241,37 +919,69 @@ impl Scheduler {
                     }))
                     .unwrap_or(false);
                 
-                if is_runnable {
-                    self.ready.push_back((pid, tid));
+                // a throttled real-time thread is requeued when its budget is refilled
+                if is_runnable && !self.rt.isThrottled((pid, tid)) {
+                    let stays = !self.pendingMigrations.contains_key(&(pid, tid))
+                        && self.affinity(pid, tid).is_none_or(|mask| mask.contains(cpu));
+                    if stays {
+                        self.local().ready.push_back((pid, tid));
+                    } else {
+                        let _ = self.enqueue((pid, tid));
+                    }
                 }
             }
         }
 
-        while let Some((pid, tid)) = self.ready.pop_front() {
-            let Some(process) = self.processes.get(&pid) else {
-                continue;
-            };
+        if self.local().ready.is_empty() {
+            self.balance(cpu, rdtsc(), true);
+        }
 
-            let is_runnable = process.with_thread_mut(&tid, |thread| {
-                match thread {
-                    Some(t) => {
-                        if t.status == ThreadStatus::Waking {
-                            t.status = ThreadStatus::Spawned;
-                        }
-                        matches!(t.status, ThreadStatus::Spawned)
-                    }
-                    None => false,
-                }
-            });
+        // eligible real-time threads go first, earliest deadline first
+        while let Some(i) = self.rt.pick(&self.cpus[&cpu].ready) {
+            let key = self.local().ready.remove(i).unwrap();
+            if self.claimIfRunnable(key) {
+                self.local().current = Some(key);
+                return Some(key);
+            }
+        }
 
-            if is_runnable {
-                self.current = Some((pid, tid));
+        while let Some((pid, tid)) = self.local().ready.pop_front() {
+            if self.rt.isThrottled((pid, tid)) {
+                continue;
+            }
+
+            if self.claimIfRunnable((pid, tid)) {
+                self.local().current = Some((pid, tid));
                 return Some((pid, tid));
             }
         }
 
-        self.current = self.idle;
-        self.idle
+        self.local().current = idle;
+        idle
+    }
+
+    /// Checks that a dequeued thread can run, turning a waking thread into a running one.
+    fn claimIfRunnable(&self, (pid, tid): (ProcessID, ThreadID)) -> bool {
+        let Some(process) = self.processes.get(&pid) else {
+            return false;
+        };
+
+        process.with_thread_mut(&tid, |thread| {
+            match thread {
+                Some(t) => {
+                    if t.status == ThreadStatus::Waking {
+                        t.status = ThreadStatus::Spawned;
+                    }
+                    let runnable = matches!(t.status, ThreadStatus::Spawned);
+                    if runnable {
+                        t.lastCpu = Some(currentCpuID());
+                        t.onCpu = true;
+                    }
+                    runnable
+                }
+                None => false,
+            }
+        })
     }
 
     pub fn switchTask(
@@ -280,12 +990,16 @@ impl Scheduler {
         frame: *const InterruptFrame,
     ) -> *mut GPRegisters {
         let frame = unsafe { &*frame };
+        let now = rdtsc();
+
+        self.accountTick(now, frame);
 
-        if !self.tick() {
+        if !self.tick(now) {
             return saved_regs;
         }
 
-        if let Some((pid, tid)) = self.current {
+        let previous = self.current();
+        if let Some((pid, tid)) = previous {
             if let Some(process) = self.processes.get(&pid) {
                 process.with_thread_mut(&tid, |thread| {
                     if let Some(current) = thread {
@@ -293,17 +1007,26 @@ impl Scheduler {
                         current.gpRegisters = unsafe { *saved_regs };
                         current.iFrame = *frame;
 
-                        // Save extended state (FPU/SSE/AVX)
+                        // Save extended state (FPU/SSE/AVX), in lazy mode only if the thread
+                        // used it since its last save
                         if let Some(ptr) = current.xAreaPtr {
-                            match get_fpu_mechanism() {
-                                FpuSaveMechanism::FXSave => unsafe {
-                                    core::arch::x86_64::_fxsave64(ptr);
-                                },
-                                FpuSaveMechanism::XSave => unsafe {
-                                    current.xFeatures = XFeatures(xgetbv0());
-                                    core::arch::x86_64::_xsave64(ptr, u64::MAX);
-                                },
-                                FpuSaveMechanism::None => {},
+                            if fpu::lazy_fpu() {
+                                let state = FpuContext {
+                                    stamp: current.fpuStamp,
+                                    xAreaPtr: ptr,
+                                    xFeatures: current.xFeatures,
+                                };
+                                if let Some(stamp) = unsafe { fpu::switchOut(state) } {
+                                    current.fpuStamp = stamp;
+                                    if get_fpu_mechanism().usesXSave() {
+                                        current.xFeatures = XFeatures(unsafe { xgetbv0() });
+                                    }
+                                }
+                            } else {
+                                if get_fpu_mechanism().usesXSave() {
+                                    current.xFeatures = XFeatures(unsafe { xgetbv0() });
+                                }
+                                unsafe { fpu::saveState(ptr) };
                             }
                         }
                     }
@@ -311,10 +1034,38 @@ impl Scheduler {
             }
         }
 
+        // a killed thread interrupted in user mode holds no kernel locks, it ends here
+        if let Some((pid, tid)) = previous
+            && frame.cs & 3 == 3
+            && self.processes.get(&pid).is_some_and(|p| p.is_killed())
+        {
+            self.exit(pid, tid);
+        }
+
         let Some((next_pid, next_tid)) = self.switch_to_next() else {
             return saved_regs;
         };
 
+        if previous != Some((next_pid, next_tid)) {
+            if let Some((pid, tid)) = previous {
+                // the rest of the switch runs on the interrupt stack, nothing of the
+                // previous thread is used anymore
+                self.switchedOut((pid, tid));
+                let wasPreempted = self.queuedOn((pid, tid)).is_some();
+                if let Some(process) = self.processes.get(&pid) {
+                    process.with_thread_mut(&tid, |thread| {
+                        if let Some(t) = thread {
+                            t.stats.contextSwitches += 1;
+                            if wasPreempted {
+                                t.stats.preemptions += 1;
+                                t.stats.enterReady(now);
+                            }
+                        }
+                    });
+                }
+            }
+        }
+
         let Some(process) = self.processes.get(&next_pid) else {
             return saved_regs;
         };
@@ -326,9 +1077,13 @@ impl Scheduler {
             if !next.initialised {
                 next.initialised = true;
             }
+
+            if previous != Some((next_pid, next_tid)) {
+                next.stats.enterRunning(now);
+            }
             
             // Handle XSAVE resize if needed
-            if get_fpu_mechanism() == FpuSaveMechanism::XSave {
+            if get_fpu_mechanism().usesXSave() {
                 if let Some(newSize) = Self::getXAreaSize(next.xFeatures) {
                     if newSize != next.xAreaSize {
                         // Perform resize - allocate new, copy, deallocate old, update thread
@@ -349,9 +1104,7 @@ impl Scheduler {
                                     dealloc(old_ptr, old_layout);
                                 }
                             } else {
-                                // Initialize defaults if fresh
-                                *(new_ptr as *mut u16) = 0x037F;
-                                *(new_ptr.add(24) as *mut u32) = 0x1F80;
+                                fpu::initArea(new_ptr, newSize as usize, next.xFeatures);
                             }
                             new_ptr
                         };
@@ -367,19 +1120,42 @@ impl Scheduler {
                 iFrame: next.iFrame,
                 xAreaPtr: next.xAreaPtr,
                 xFeatures: next.xFeatures,
+                fpuStamp: next.fpuStamp,
+                quantum: next.quantum,
+                kernelStack: next.kernelStack,
             })
         });
 
         let Some(ctx) = ctx_result else {
             return saved_regs;
         };
+
+        // real-time threads get exactly their remaining budget
+        let sliceEnd = match self.rt.budgetEnd((next_pid, next_tid), now) {
+            Some(budgetEnd) => {
+                self.rt.startRunning((next_pid, next_tid), now);
+                budgetEnd
+            }
+            None => now + timer::msToCycles(TICK_MS * ctx.quantum.max(1)),
+        };
+        self.local().sliceEnd = sliceEnd;
         
+        tlb::setActive(ctx.cr3);
         let (currentCR3, flags) = Cr3::read();
         if currentCR3 != ctx.cr3 {
             unsafe { Cr3::write(ctx.cr3, flags); }
         }
 
-        let frame_ptr = (ctx.iFrame.rsp - size_of::<InterruptFrame>() as u64) as *mut InterruptFrame;
+        // ring 3 code is resumed from the top of the thread's kernel stack, which interrupts
+        // from user mode then switch to as well
+        let frameTop = match ctx.kernelStack {
+            Some(kernelStack) => {
+                gdt::setKernelStack(kernelStack.end);
+                if ctx.iFrame.cs & 0b11 == 3 { kernelStack.end.as_u64() } else { ctx.iFrame.rsp }
+            }
+            None => ctx.iFrame.rsp,
+        };
+        let frame_ptr = (frameTop - size_of::<InterruptFrame>() as u64) as *mut InterruptFrame;
         let regs_ptr = (frame_ptr as u64 - size_of::<GPRegisters>() as u64) as *mut GPRegisters;
 
         unsafe {
@@ -391,18 +1167,15 @@ impl Scheduler {
             (*frame_ptr).rip = ctx.iFrame.rip;
         }
 
-        // Restore extended state
-        if let Some(ptr) = ctx.xAreaPtr {
-            match get_fpu_mechanism() {
-                FpuSaveMechanism::FXSave => unsafe {
-                    core::arch::x86_64::_fxrstor64(ptr);
-                },
-                FpuSaveMechanism::XSave => unsafe {
-                    xsetbv0(ctx.xFeatures.to_u64());
-                    core::arch::x86_64::_xrstor64(ptr, ctx.xFeatures.to_u64());
-                },
-                FpuSaveMechanism::None => {},
-            }
+        // Restore extended state, or leave it to the #NM handler in lazy mode
+        if fpu::lazy_fpu() {
+            fpu::switchedTo(ctx.xAreaPtr.map(|ptr| FpuContext {
+                stamp: ctx.fpuStamp,
+                xAreaPtr: ptr,
+                xFeatures: ctx.xFeatures,
+            }));
+        } else if let Some(ptr) = ctx.xAreaPtr {
+            unsafe { fpu::restoreState(ptr, ctx.xFeatures) };
         }
 
         regs_ptr
@@ -420,6 +1193,11 @@ impl Scheduler {
             }
 
             let current_xcr0 = xgetbv0();
+            if current_xcr0 == xFeatures.to_u64() {
+                return Some(CPUID(0xD, 0).1);
+            }
+            // components XCR0 disables meanwhile lose their register state
+            fpu::forgetLoadedState();
             xsetbv0(xFeatures.to_u64());
             let size_needed = CPUID(0xD, 0).1;
             xsetbv0(current_xcr0);
@@ -429,15 +1207,41 @@ impl Scheduler {
     }
 }
 
+/// Programs the APIC timer for the earlier of the current slice end and the next
+/// `TimerQueue` deadline, stopping it when neither exists.
+fn rearmTimer(scheduler: &Scheduler) {
+    let timerDeadline = kernelContext()
+        .timerQueue
+        .get()
+        .and_then(|queue| queue.lock().nextDeadline());
+
+    let next = match (scheduler.nextSliceEnd(), timerDeadline) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    kernelContext()
+        .apic
+        .get()
+        .expect("APIC not initialized.")
+        .armTimer(next);
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn timer_interrupt_trampoline(
     savedRegs: *mut GPRegisters,
     frame: *const InterruptFrame,
 ) -> *mut GPRegisters {
     use crate::multitasking::preemptive::SCHEDULER;
-    let mut scheduler = SCHEDULER.lock();
+    let _irq = lockdep::irq_enter();
+    timer::fireExpiredTimers(rdtsc());
 
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.countTimerTick();
     let res = scheduler.switchTask(savedRegs, frame);
+    rearmTimer(&scheduler);
+    watchdog::reportScheduled(scheduler.current(), scheduler.is_idle());
+    fpu::prepareReturn();
 
     kernelContext()
         .apic
@@ -445,5 +1249,48 @@ pub extern "C" fn timer_interrupt_trampoline(
         .expect("APIC not initialized.")
         .notifyEOI();
     
+    res
+}
+
+/// Entered through an IPI from another CPU that made a thread ready here or changed where the
+/// running thread may run.
+#[unsafe(no_mangle)]
+pub extern "C" fn reschedule_ipi_trampoline(
+    savedRegs: *mut GPRegisters,
+    frame: *const InterruptFrame,
+) -> *mut GPRegisters {
+    use crate::multitasking::preemptive::SCHEDULER;
+    let _irq = lockdep::irq_enter();
+    let mut scheduler = SCHEDULER.lock();
+
+    let res = scheduler.switchTask(savedRegs, frame);
+    rearmTimer(&scheduler);
+    watchdog::reportScheduled(scheduler.current(), scheduler.is_idle());
+    fpu::prepareReturn();
+
+    kernelContext()
+        .apic
+        .get()
+        .expect("APIC not initialized.")
+        .notifyEOI();
+
+    res
+}
+
+/// Entered through `int` from a thread giving up the CPU, so there is no EOI to send.
+#[unsafe(no_mangle)]
+pub extern "C" fn reschedule_interrupt_trampoline(
+    savedRegs: *mut GPRegisters,
+    frame: *const InterruptFrame,
+) -> *mut GPRegisters {
+    use crate::multitasking::preemptive::SCHEDULER;
+    let mut scheduler = SCHEDULER.lock();
+
+    scheduler.expireSlice();
+    let res = scheduler.switchTask(savedRegs, frame);
+    rearmTimer(&scheduler);
+    watchdog::reportScheduled(scheduler.current(), scheduler.is_idle());
+    fpu::prepareReturn();
+
     res
 } 
\ No newline at end of file
@@ -0,0 +1,198 @@
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::multitasking::preemptive::{yield_now, ProcessID, ThreadID, SCHEDULER};
+
+/// One-shot wakeup flag for a kernel thread.
+///
+/// `notify` may be called from any thread or interrupt handler (but not while holding
+/// `SCHEDULER`); the waiting thread sleeps in the scheduler instead of spinning.
+/// As a `Waker` it lets futures wake the thread polling them.
+pub struct ThreadSignal {
+    thread: Mutex<Option<(ProcessID, ThreadID)>>,
+    notified: AtomicBool,
+}
+
+impl ThreadSignal {
+    pub const fn new() -> Self {
+        Self {
+            thread: Mutex::new(None),
+            notified: AtomicBool::new(false),
+        }
+    }
+
+    pub fn notify(&self) {
+        interrupts::without_interrupts(|| {
+            // under the scheduler lock like `wait`, which then either sees the flag or has
+            // registered its thread by the time it is read here
+            let mut scheduler = SCHEDULER.lock();
+            self.notified.store(true, Ordering::Release);
+            let thread = *self.thread.lock();
+            if let Some((pid, tid)) = thread {
+                scheduler.wake(pid, tid);
+            }
+        });
+    }
+
+    /// Blocks the calling thread until `notify` was called, consuming the notification.
+    ///
+    /// Before the scheduler is running there is no thread to put to sleep, so this polls the
+    /// flag instead, halting between interrupts when they are enabled.
+    pub fn wait(&self) {
+        loop {
+            let slept = interrupts::without_interrupts(|| {
+                let mut scheduler = SCHEDULER.lock();
+                // checked under the scheduler lock so a notify can't slip in before the sleep
+                if self.notified.swap(false, Ordering::Acquire) {
+                    return None;
+                }
+
+                let current = scheduler.current();
+                *self.thread.lock() = current;
+                if let Some((pid, tid)) = current {
+                    scheduler.sleep(pid, tid);
+                }
+                Some(current.is_some())
+            });
+
+            match slept {
+                None => return,
+                Some(true) => yield_now(),
+                Some(false) if interrupts::are_enabled() => x86_64::instructions::hlt(),
+                Some(false) => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+impl Wake for ThreadSignal {
+    fn wake(self: Arc<Self>) {
+        self.notify();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.notify();
+    }
+}
+
+/// Threads waiting for a condition guarded by some other lock, like a pipe's buffer.
+///
+/// A waiter calls `sleep` while holding that lock and only releases it afterwards, wakers
+/// change the condition under the lock and call `wake_all` once they let go of it, so no
+/// wakeup is lost in between. Woken threads recheck their condition.
+pub struct WaitQueue {
+    waiters: Mutex<Vec<(ProcessID, ThreadID)>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queues the calling thread and puts it to sleep in the scheduler, it stops running at
+    /// its next yield. Call with interrupts disabled. Returns false before the scheduler is
+    /// running, when there is no thread to put to sleep.
+    pub fn sleep(&self) -> bool {
+        let Some((pid, tid)) = SCHEDULER.lock().current() else {
+            return false;
+        };
+
+        {
+            let mut waiters = self.waiters.lock();
+            if !waiters.contains(&(pid, tid)) {
+                waiters.push((pid, tid));
+            }
+        }
+        SCHEDULER.lock().sleep(pid, tid);
+        true
+    }
+
+    /// Wakes every queued thread. Must not be called while holding `SCHEDULER`.
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        if waiters.is_empty() {
+            return;
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            for (pid, tid) in waiters {
+                scheduler.wake(pid, tid);
+            }
+        });
+    }
+}
+
+/// A lock for data that's held across I/O, like a mounted volume. Threads waiting for it
+/// sleep in a `WaitQueue` instead of spinning.
+///
+/// It doesn't disable interrupts, so interrupt handlers must not take it.
+pub struct SleepMutex<T> {
+    locked: Mutex<bool>,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SleepMutex<T> {}
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: Mutex::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SleepMutexGuard<'_, T> {
+        loop {
+            let slept = interrupts::without_interrupts(|| {
+                let mut locked = self.locked.lock();
+                if !*locked {
+                    *locked = true;
+                    return None;
+                }
+                Some(self.waiters.sleep())
+            });
+
+            match slept {
+                None => return SleepMutexGuard { mutex: self },
+                Some(true) => yield_now(),
+                Some(false) => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+pub struct SleepMutexGuard<'a, T> {
+    mutex: &'a SleepMutex<T>,
+}
+
+impl<T> Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| *self.mutex.locked.lock() = false);
+        self.mutex.waiters.wake_all();
+    }
+}
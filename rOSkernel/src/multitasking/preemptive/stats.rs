@@ -0,0 +1,230 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use crate::multitasking::preemptive::{sleep_ms, ProcessID, ThreadID, SCHEDULER};
+use crate::util::wrappers::rdtsc;
+use crate::serial_println;
+
+/// How often `topThread` dumps statistics.
+pub const TOP_INTERVAL_MS: u64 = 5000;
+
+/// Which bucket a thread's wall-clock time is currently being charged to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccountState {
+    Running,
+    Ready,
+    Sleeping,
+}
+
+/// Per-thread CPU time accounting, all times are in TSC cycles.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadStats {
+    pub userCycles: u64,
+    pub kernelCycles: u64,
+    pub readyCycles: u64,
+    pub sleepCycles: u64,
+    pub contextSwitches: u64,
+    pub preemptions: u64,
+    state: AccountState,
+    since: u64,
+}
+
+impl ThreadStats {
+    pub fn new() -> Self {
+        Self {
+            userCycles: 0,
+            kernelCycles: 0,
+            readyCycles: 0,
+            sleepCycles: 0,
+            contextSwitches: 0,
+            preemptions: 0,
+            state: AccountState::Sleeping,
+            since: rdtsc(),
+        }
+    }
+
+    pub fn runCycles(&self) -> u64 {
+        self.userCycles + self.kernelCycles
+    }
+
+    fn flush(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.since);
+        match self.state {
+            // running time that wasn't charged by a tick happened in the kernel (e.g. a sleep call)
+            AccountState::Running => self.kernelCycles += elapsed,
+            AccountState::Ready => self.readyCycles += elapsed,
+            AccountState::Sleeping => self.sleepCycles += elapsed,
+        }
+        self.since = now;
+    }
+
+    fn enter(&mut self, state: AccountState, now: u64) {
+        self.flush(now);
+        self.state = state;
+    }
+
+    pub(super) fn enterRunning(&mut self, now: u64) {
+        self.enter(AccountState::Running, now);
+    }
+
+    pub(super) fn enterReady(&mut self, now: u64) {
+        self.enter(AccountState::Ready, now);
+    }
+
+    pub(super) fn enterSleeping(&mut self, now: u64) {
+        self.enter(AccountState::Sleeping, now);
+    }
+
+    /// Charges the time since the last accounting point to user or kernel time.
+    pub(super) fn charge(&mut self, now: u64, user: bool) {
+        if self.state != AccountState::Running {
+            return;
+        }
+
+        let elapsed = now.saturating_sub(self.since);
+        if user {
+            self.userCycles += elapsed;
+        } else {
+            self.kernelCycles += elapsed;
+        }
+        self.since = now;
+    }
+
+    /// Returns a copy with the in-progress interval folded in.
+    pub fn snapshot(&self, now: u64) -> Self {
+        let mut copy = *self;
+        copy.flush(now);
+        copy
+    }
+}
+
+/// Per-CPU idle/busy accounting, in TSC cycles.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    pub idleCycles: u64,
+    pub busyCycles: u64,
+    /// Timer interrupts taken, yields and reschedule IPIs don't count.
+    pub ticks: u64,
+    pub(super) lastTick: u64,
+}
+
+impl CpuStats {
+    pub(super) fn account(&mut self, now: u64, idle: bool) {
+        if self.lastTick != 0 {
+            let elapsed = now.saturating_sub(self.lastTick);
+            if idle {
+                self.idleCycles += elapsed;
+            } else {
+                self.busyCycles += elapsed;
+            }
+        }
+        self.lastTick = now;
+    }
+}
+
+/// Aggregated statistics for all threads of a process.
+#[derive(Debug, Clone)]
+pub struct ProcessStats {
+    pub pid: ProcessID,
+    pub userCycles: u64,
+    pub kernelCycles: u64,
+    pub readyCycles: u64,
+    pub sleepCycles: u64,
+    pub contextSwitches: u64,
+    pub preemptions: u64,
+    pub threads: Vec<(ThreadID, ThreadStats)>,
+}
+
+impl ProcessStats {
+    pub fn from_threads(pid: ProcessID, threads: Vec<(ThreadID, ThreadStats)>) -> Self {
+        let mut stats = Self {
+            pid,
+            userCycles: 0,
+            kernelCycles: 0,
+            readyCycles: 0,
+            sleepCycles: 0,
+            contextSwitches: 0,
+            preemptions: 0,
+            threads,
+        };
+
+        for (_, t) in stats.threads.iter() {
+            stats.userCycles += t.userCycles;
+            stats.kernelCycles += t.kernelCycles;
+            stats.readyCycles += t.readyCycles;
+            stats.sleepCycles += t.sleepCycles;
+            stats.contextSwitches += t.contextSwitches;
+            stats.preemptions += t.preemptions;
+        }
+
+        stats
+    }
+
+    pub fn runCycles(&self) -> u64 {
+        self.userCycles + self.kernelCycles
+    }
+}
+
+/// Returns the statistics of every registered process, gathered under a single lock.
+pub fn all_process_stats() -> Vec<ProcessStats> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler
+            .pids()
+            .into_iter()
+            .filter_map(|pid| scheduler.process_stats(pid))
+            .collect()
+    })
+}
+
+/// Returns the accounting of every CPU that has taken a scheduler tick.
+pub fn cpu_stats() -> BTreeMap<u8, CpuStats> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().cpu_stats())
+}
+
+fn percent(part: u64, whole: u64) -> u64 {
+    if whole == 0 { 0 } else { part.saturating_mul(100) / whole }
+}
+
+/// Prints a `top`-like table to serial.
+///
+/// `previous` holds the run time of each thread at the last dump and is updated in place,
+/// so the CPU column shows usage over the interval rather than since boot.
+pub fn dumpTop(previous: &mut BTreeMap<(ProcessID, ThreadID), u64>, intervalCycles: u64) {
+    let processes = all_process_stats();
+    let cpus = cpu_stats();
+
+    serial_println!("---- top ----");
+    for (cpu, stats) in cpus.iter() {
+        let total = stats.idleCycles + stats.busyCycles;
+        serial_println!(
+            "cpu{}: {}% idle, {} ticks, idle={} busy={}",
+            cpu, percent(stats.idleCycles, total), stats.ticks, stats.idleCycles, stats.busyCycles
+        );
+    }
+
+    serial_println!("{:>5} {:>5} {:>4} {:>14} {:>14} {:>14} {:>14} {:>8} {:>8}",
+        "PID", "TID", "CPU%", "USER", "KERNEL", "READY", "SLEEP", "SWITCH", "PREEMPT");
+    for process in processes.iter() {
+        for (tid, t) in process.threads.iter() {
+            let key = (process.pid, *tid);
+            let run = t.runCycles();
+            let last = previous.insert(key, run).unwrap_or(0);
+
+            serial_println!("{:>5} {:>5} {:>4} {:>14} {:>14} {:>14} {:>14} {:>8} {:>8}",
+                process.pid.as_u64(), tid.as_u64(), percent(run.saturating_sub(last), intervalCycles),
+                t.userCycles, t.kernelCycles, t.readyCycles, t.sleepCycles,
+                t.contextSwitches, t.preemptions);
+        }
+    }
+}
+
+/// Thread body that periodically dumps scheduler statistics to serial.
+pub extern "C" fn topThread() {
+    let mut previous = BTreeMap::new();
+    loop {
+        let startTsc = rdtsc();
+        sleep_ms(TOP_INTERVAL_MS);
+        dumpTop(&mut previous, rdtsc() - startTsc);
+    }
+}
@@ -1,10 +1,25 @@
-.global timerInterruptEntry
 .extern timer_interrupt_trampoline
+.extern reschedule_interrupt_trampoline
+.extern reschedule_ipi_trampoline
 
 // bytes saved: 15 registers * 8 bytes each
 .equ GPREG_SAVE_BYTES, 120
 
-timerInterruptEntry:
+// Saves the interrupted thread's registers, lets `handler` pick the registers to resume
+// (possibly on another thread's stack) and returns into them.
+.macro SWITCH_ENTRY name, handler
+.global \name
+\name:
+    // the scheduler saves FPU state, lazy FPU switching sets CR0.TS again in `fpu::prepareReturn`
+    clts
+    // ring 3 may have left DF or AC set, Rust expects DF clear and AC would lift SMAP. IRETQ
+    // restores both
+    cld
+    test byte ptr [rip + SMAP_ENABLED], 1
+    jz 2f
+    clac
+2:
+
     // push general-purpose registers in reverse order of GPRegisters struct
     push rax
     push rbx
@@ -27,9 +42,9 @@ timerInterruptEntry:
     // second arg: pointer to InterruptStackFrame (above GP register save area)
     lea rsi, [rsp + GPREG_SAVE_BYTES]
 
-    call timer_interrupt_trampoline
+    call \handler
 
-    // trampoline returns pointer to GP registers to restore in RAX
+    // handler returns pointer to GP registers to restore in RAX
     mov rsp, rax
 
     // restore in reverse order
@@ -49,4 +64,9 @@ timerInterruptEntry:
     pop rbx
     pop rax
 
-    iretq
\ No newline at end of file
+    iretq
+.endm
+
+SWITCH_ENTRY timerInterruptEntry, timer_interrupt_trampoline
+SWITCH_ENTRY rescheduleInterruptEntry, reschedule_interrupt_trampoline
+SWITCH_ENTRY rescheduleIpiEntry, reschedule_ipi_trampoline
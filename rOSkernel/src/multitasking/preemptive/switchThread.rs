@@ -4,4 +4,6 @@ global_asm!(include_str!("switchThread.asm"), options(raw));
 
 unsafe extern "C" {
     pub fn timerInterruptEntry();
+    pub fn rescheduleInterruptEntry();
+    pub fn rescheduleIpiEntry();
 }
\ No newline at end of file
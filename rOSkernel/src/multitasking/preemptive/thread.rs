@@ -1,20 +1,33 @@
 use crate::mem::stack::{self, StackBounds};
 use crate::util::wrappers::{XFeatures, xgetbv0, xsetbv0, get_fpu_mechanism, FpuSaveMechanism};
 use crate::multitasking::preemptive::{ProcessID, ThreadID};
+use crate::multitasking::preemptive::stats::ThreadStats;
+use crate::multitasking::preemptive::fpu;
+use crate::multitasking::preemptive::affinity::CpuMask;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use x86_64::VirtAddr;
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::{PhysFrame, OffsetPageTable, PageTable};
-use crate::kernel::kernelContext;
-use super::{SCHEDULER, Parent, current_pid};
+use x86_64::structures::paging::{PhysFrame, OffsetPageTable, PageTable, PageSize, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use crate::kernel::{gdt, kernelContext};
+use super::{SCHEDULER, Parent, current_pid, exit_thread, join_thread};
 use alloc::alloc::{alloc, dealloc, Layout};
-use crate::mem::memory::{newAddressSpace, PHYSICAL_MEMORY_OFFSET};
+use crate::mem::memory::{freeAddressSpace, newAddressSpace, PHYSICAL_MEMORY_OFFSET, USER_SPACE_END};
+use crate::mem::userMemory::UserMemory;
+use crate::fs::fd::FdTable;
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
 use cpuid::CPUID;
 
 pub type ProcessRef = Arc<Process>;
 
+const KERNEL_STACK_PAGES: u64 = 4;
+pub const USER_STACK_PAGES: u64 = 16;
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(C)]
 pub struct GPRegisters {
@@ -63,6 +76,14 @@ pub enum ThreadStatus {
     Dead,
 }
 
+/// Body of a thread, the thread itself starts in one of the entry shims.
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadFunction {
+    Kernel(extern "C" fn()),
+    KernelWithArg(extern "C" fn(usize)),
+    User(VirtAddr),
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct Thread {
@@ -71,9 +92,15 @@ pub struct Thread {
     pub(super) maxQuantum: u64,
     pub(super) quantum: u64,
     pub(super) done: bool,
+    // set while a CPU runs the thread, its stacks and state can only be freed once this is clear
+    pub(super) onCpu: bool,
+    // nobody joins the thread, the reaper frees it once it exits
+    pub(super) detached: bool,
     pub status: ThreadStatus,
     pub initialised: bool,
     pub stackBounds: StackBounds,
+    /// Stack for interrupts and system calls taken in ring 3, None for kernel threads.
+    pub kernelStack: Option<StackBounds>,
 
     // registers
     pub cr3: PhysFrame,
@@ -85,8 +112,19 @@ pub struct Thread {
     pub xAreaSize: u32,
     pub xAreaAlign: u32,
     pub xFeatures: XFeatures,
+    // identifies what the area holds, see `fpu::FpuContext`
+    pub(super) fpuStamp: u64,
 
-    pub(super) function: extern "C" fn(),
+    // accounting
+    pub stats: ThreadStats,
+
+    pub(super) function: ThreadFunction,
+    // woken when this thread exits
+    pub(super) joiner: Option<(ProcessID, ThreadID)>,
+
+    // placement
+    pub(super) affinity: CpuMask,
+    pub(super) lastCpu: Option<u8>,
 }
 
 #[derive(Debug)]
@@ -95,12 +133,24 @@ pub struct Process {
     parentPID: Option<ProcessID>,
     pageTable: PhysFrame,
     threads: Mutex<BTreeMap<ThreadID, Thread>>,
-    // TODO: file descriptors, etc.
+    // top of the next user stack
+    nextUserStack: AtomicU64,
+    // program break and anonymous mappings
+    memory: Mutex<UserMemory>,
+    files: Mutex<FdTable>,
+    // absolute path of the working directory
+    cwd: Mutex<String>,
+    // set once the process is killed, its threads exit when it's safe, see `Scheduler::exit_process`
+    killed: AtomicBool,
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        // threads are dropped automatically when the BTreeMap is dropped
+        // threads free their kernel stacks through the page table, so they go first
+        core::mem::take(self.threads.get_mut());
+        let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+        freeAddressSpace(self.pageTable, &mut *frameAllocatorGuard);
+        drop(frameAllocatorGuard);
         self.pid.free();
     }
 }
@@ -118,11 +168,14 @@ impl Drop for Thread {
             }
             self.xAreaPtr = None;
         }
-        
-        // Deallocate stack pages
-        // Note: This requires unmapping the pages from the page table
-        // For now we track the bounds; full deallocation requires mapper access
-        // TODO: Implement stack::deallocStack when page table access is available
+
+        // kernel stacks are ours to free, user stacks go with the address space
+        let kernelStack = self.kernelStack.unwrap_or(self.stackBounds);
+        let mut mapper = mapperFor(self.cr3);
+        let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+        if stack::deallocStack(kernelStack, KERNEL_STACK_PAGES, &mut mapper, &mut *frameAllocatorGuard).is_err() {
+            log::warn!("Failed to free the kernel stack of thread {:?}", self.id);
+        }
     }
 }
 
@@ -134,12 +187,22 @@ impl Process {
             Parent::Explicit(pid) => Some(pid),
         };
 
-        let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+        // descriptors and the working directory are inherited, processes without a parent
+        // start out on the console in the root directory
+        let parentProcess = parent_pid.and_then(|pid| interrupts::without_interrupts(|| SCHEDULER.lock().get_process(pid)));
+        let (files, cwd) = match parentProcess {
+            Some(parent) => (parent.files.lock().clone(), parent.cwd.lock().clone()),
+            None => (FdTable::withStdio(), String::from("/")),
+        };
+
         let phys_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.get_copy().unwrap());
-        
-        // Create new address space
-        let new_cr3 = newAddressSpace(&mut *frameAllocatorGuard, phys_offset)
-            .expect("Failed to allocate new address space");
+
+        // Create new address space, the allocator is released before the scheduler is locked
+        let new_cr3 = {
+            let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+            newAddressSpace(&mut *frameAllocatorGuard, phys_offset)
+                .expect("Failed to allocate new address space")
+        };
 
         let pid = ProcessID::new();
         let process = Process {
@@ -147,6 +210,11 @@ impl Process {
             parentPID: parent_pid,
             pageTable: new_cr3,
             threads: Mutex::new(BTreeMap::new()),
+            nextUserStack: AtomicU64::new(USER_SPACE_END),
+            memory: Mutex::new(UserMemory::new()),
+            files: Mutex::new(files),
+            cwd: Mutex::new(cwd),
+            killed: AtomicBool::new(false),
         };
         
         let process_arc = Arc::new(process);
@@ -163,6 +231,14 @@ impl Process {
         self.pid
     }
 
+    pub fn mark_killed(&self) {
+        self.killed.store(true, Ordering::Release);
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
     pub fn with_thread_mut<F, R>(&self, tid: &ThreadID, f: F) -> R
     where
         F: FnOnce(Option<&mut Thread>) -> R,
@@ -174,18 +250,107 @@ impl Process {
         })
     }
 
+    pub fn memory(&self) -> &Mutex<UserMemory> {
+        &self.memory
+    }
+
+    pub fn files(&self) -> &Mutex<FdTable> {
+        &self.files
+    }
+
+    pub fn cwd(&self) -> &Mutex<String> {
+        &self.cwd
+    }
+
+    /// Whether the process runs ring 3 code, which the kernel, idle and reaper processes don't.
+    pub fn is_user(&self) -> bool {
+        interrupts::without_interrupts(|| self.threads.lock().values().any(|t| t.kernelStack.is_some()))
+    }
+
+    /// Whether every thread has exited and left its CPU.
+    pub fn threads_finished(&self) -> bool {
+        interrupts::without_interrupts(|| self.threads.lock().values().all(|t| t.done && !t.onCpu))
+    }
+
+    /// Closes every descriptor of a process that has finished.
+    pub fn close_files(&self) {
+        let files = core::mem::replace(&mut *self.files.lock(), FdTable::new());
+        // closing can wake threads waiting on a pipe, so not under the table's lock
+        drop(files);
+    }
+
+    pub fn thread_ids(&self) -> Vec<ThreadID> {
+        interrupts::without_interrupts(|| self.threads.lock().keys().copied().collect())
+    }
+
+    /// Snapshots the accounting of every thread in this process at TSC time `now`.
+    pub fn thread_stats(&self, now: u64) -> Vec<(ThreadID, ThreadStats)> {
+        interrupts::without_interrupts(|| {
+            self.threads
+                .lock()
+                .iter()
+                .map(|(tid, thread)| (*tid, thread.stats.snapshot(now)))
+                .collect()
+        })
+    }
+
     pub fn create_thread(&self, func: extern "C" fn(), maxQuantum: u64) -> ThreadID {
-        let phys_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.get_copy().unwrap());
+        self.create_thread_at(threadEntryNoArg as *const () as u64, ThreadFunction::Kernel(func), func as *const () as u64, 0, maxQuantum)
+    }
 
-        let l4_table_ptr = (phys_offset + self.pageTable.start_address().as_u64()).as_mut_ptr::<PageTable>();
-        let l4_table = unsafe { &mut *l4_table_ptr };
-        let mut mapper = unsafe { OffsetPageTable::new(l4_table, phys_offset) };
-        
+    /// Like `create_thread`, but `func` receives `arg` as its first argument (in `rdi`).
+    pub fn create_thread_with(&self, func: extern "C" fn(usize), arg: usize, maxQuantum: u64) -> ThreadID {
+        self.create_thread_at(threadEntry as *const () as u64, ThreadFunction::KernelWithArg(func), func as *const () as u64, arg, maxQuantum)
+    }
+
+    /// Runs `f` on a new thread of this process, its return value is handed out by the join handle.
+    pub fn spawn<F, T>(&self, maxQuantum: u64, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let result = Arc::new(Mutex::new(None));
+        let threadResult = result.clone();
+        let body: Box<dyn FnOnce() + Send> = Box::new(move || {
+            let value = f();
+            *threadResult.lock() = Some(value);
+        });
+
+        // double boxed so the trampoline receives a thin pointer
+        let arg = Box::into_raw(Box::new(body)) as usize;
+        let tid = self.create_thread_with(closureTrampoline, arg, maxQuantum);
+        if self.start_thread(tid).is_none() {
+            panic!("Failed to start spawned thread {:?}", tid);
+        }
+
+        JoinHandle {
+            pid: self.pid,
+            tid,
+            result,
+        }
+    }
+
+    /// Removes a thread that has exited and been switched away from, returns false if it is
+    /// unknown or still running.
+    pub fn reap_thread(&self, tid: &ThreadID) -> bool {
+        let removed = interrupts::without_interrupts(|| {
+            let mut threads_lock = self.threads.lock();
+            match threads_lock.get(tid) {
+                Some(thread) if thread.done && !thread.onCpu => threads_lock.remove(tid),
+                _ => None,
+            }
+        });
+        // dropped here, freeing the thread takes other locks
+        removed.is_some()
+    }
+
+    /// Creates a thread that starts at `entry` with `rdi = arg` and `rsi = funcAddr`.
+    fn create_thread_at(&self, entry: u64, function: ThreadFunction, funcAddr: u64, arg: usize, maxQuantum: u64) -> ThreadID {
+        let mut mapper = self.mapper();
         let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
         
-        // Allocate 4 pages (16KB) for stack
-        let stackPageCount = 4u64;
-        let sb = stack::allocStack(stackPageCount, &mut mapper, &mut *frameAllocatorGuard).ok();
+        // 16KB stack
+        let sb = stack::allocStack(KERNEL_STACK_PAGES, &mut mapper, &mut *frameAllocatorGuard).ok();
         if sb.is_none() {
             panic!("Failed to allocate stack");
         }
@@ -197,7 +362,89 @@ impl Process {
             core::arch::asm!("mov {0:x}, ss", out(reg) ss, options(nomem, nostack, preserves_flags));
         }
 
-        let xFeatures = if get_fpu_mechanism() == FpuSaveMechanism::XSave {
+        let gpRegisters = GPRegisters {
+            rdi: arg as u64,
+            rsi: funcAddr,
+            ..GPRegisters::default()
+        };
+        let iFrame = InterruptFrame {
+            rip: entry,
+            cs: cs as u64,
+            rflags: 0x202,
+            // entered like a call, so rsp + 8 must be 16 byte aligned
+            rsp: stackBounds.end.as_u64() - 8,
+            ss: ss as u64,
+        };
+
+        self.insertThread(stackBounds, None, gpRegisters, iFrame, function, maxQuantum)
+    }
+
+    /// Creates a ring 3 thread that starts at `entry` with `rdi = arg`, on a fresh user stack
+    /// mapped only in this process's address space.
+    pub fn create_user_thread(&self, entry: VirtAddr, arg: u64, maxQuantum: u64) -> Result<ThreadID, MapToError<Size4KiB>> {
+        let stackBounds = self.alloc_user_stack(USER_STACK_PAGES)?;
+        let gpRegisters = GPRegisters {
+            rdi: arg,
+            ..GPRegisters::default()
+        };
+        // entered like a call, so rsp + 8 must be 16 byte aligned
+        let rsp = stackBounds.end - 8u64;
+        self.create_user_thread_at(entry, stackBounds, rsp, gpRegisters, maxQuantum)
+    }
+
+    /// Creates a ring 3 thread resuming at `entry` with the given registers, on a user stack
+    /// from `alloc_user_stack` that the caller may already have filled in up to `rsp`.
+    pub fn create_user_thread_at(
+        &self,
+        entry: VirtAddr,
+        stackBounds: StackBounds,
+        rsp: VirtAddr,
+        gpRegisters: GPRegisters,
+        maxQuantum: u64,
+    ) -> Result<ThreadID, MapToError<Size4KiB>> {
+        // interrupts and system calls from ring 3 run on this one
+        let kernelStack = {
+            let mut mapper = self.mapper();
+            let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+            stack::allocStack(KERNEL_STACK_PAGES, &mut mapper, &mut *frameAllocatorGuard)?
+        };
+
+        let selectors = &gdt::GDT.1;
+        let iFrame = InterruptFrame {
+            rip: entry.as_u64(),
+            cs: selectors.userCodeSelector.0 as u64,
+            rflags: 0x202,
+            rsp: rsp.as_u64(),
+            ss: selectors.userDataSelector.0 as u64,
+        };
+
+        Ok(self.insertThread(stackBounds, Some(kernelStack), gpRegisters, iFrame, ThreadFunction::User(entry), maxQuantum))
+    }
+
+    /// Maps a user stack of `pages` pages below the previous one, one guard page apart.
+    pub fn alloc_user_stack(&self, pages: u64) -> Result<StackBounds, MapToError<Size4KiB>> {
+        let mut mapper = self.mapper();
+        let mut frameAllocatorGuard = kernelContext().frameAllocator.get().unwrap().lock();
+
+        let stackTop = self.nextUserStack.fetch_sub((pages + 1) * Size4KiB::SIZE, Ordering::Relaxed);
+        stack::allocUserStack(VirtAddr::new(stackTop), pages, &mut mapper, &mut *frameAllocatorGuard)
+    }
+
+    /// Page table mapper for this process's address space.
+    pub(crate) fn mapper(&self) -> OffsetPageTable<'static> {
+        mapperFor(self.pageTable)
+    }
+
+    fn insertThread(
+        &self,
+        stackBounds: StackBounds,
+        kernelStack: Option<StackBounds>,
+        gpRegisters: GPRegisters,
+        iFrame: InterruptFrame,
+        function: ThreadFunction,
+        maxQuantum: u64,
+    ) -> ThreadID {
+        let xFeatures = if get_fpu_mechanism().usesXSave() {
             XFeatures::current()
         } else {
             XFeatures::new(0)
@@ -212,15 +459,10 @@ impl Process {
                 if ptr.is_null() {
                     panic!("Failed to allocate FX save area");
                 }
-                core::ptr::write_bytes(ptr, 0, size);
-                // Set FCW to 0x037F
-                *(ptr as *mut u16) = 0x037F;
-                // Set MXCSR to 0x1F80 (offset 24)
-                *(ptr.add(24) as *mut u32) = 0x1F80;
+                fpu::initArea(ptr, size, xFeatures);
                 (Some(ptr), size as u32, align as u32)
             },
-            FpuSaveMechanism::XSave => unsafe {
-                // XSAVE logic (currently disabled via initXFeatures, but kept here for completeness)
+            FpuSaveMechanism::XSave | FpuSaveMechanism::XSaveOpt | FpuSaveMechanism::XSaveS => unsafe {
                 let size_ebx = x86_64::instructions::interrupts::without_interrupts(|| {
                     let current_xcr0 = xgetbv0();
                     xsetbv0(xFeatures.to_u64());
@@ -235,9 +477,7 @@ impl Process {
                 if ptr.is_null() {
                     panic!("Failed to allocate XSAVE area");
                 }
-                core::ptr::write_bytes(ptr, 0, size_ebx as usize);
-                *(ptr as *mut u16) = 0x037F;
-                *(ptr.add(24) as *mut u32) = 0x1F80;
+                fpu::initArea(ptr, size_ebx as usize, xFeatures);
                 (Some(ptr), size_ebx, align as u32)
             },
             FpuSaveMechanism::None => (None, 0, 1),
@@ -250,23 +490,25 @@ impl Process {
             maxQuantum,
             quantum: maxQuantum,
             done: false,
+            onCpu: false,
+            detached: false,
             initialised: false,
             status: ThreadStatus::Spawned,
             cr3: self.pageTable,
-            gpRegisters: GPRegisters::default(),
-            iFrame: InterruptFrame {
-                rip: func as *const () as u64,
-                cs: cs as u64,
-                rflags: 0x202,
-                rsp: stackBounds.end.as_u64(),
-                ss: ss as u64,
-            },
+            gpRegisters,
+            iFrame,
             xAreaPtr: fx_ptr,
             xAreaSize: fx_size,
             xAreaAlign: fx_align,
             xFeatures,
-            function: func,
+            fpuStamp: fpu::newStamp(),
+            stats: ThreadStats::new(),
+            function,
+            joiner: None,
+            affinity: CpuMask::all(),
+            lastCpu: None,
             stackBounds,
+            kernelStack,
         };
 
         interrupts::without_interrupts(|| {
@@ -281,7 +523,9 @@ impl Process {
         interrupts::without_interrupts(|| {
             let mut threads_lock = self.threads.lock();
             if let Some(thread) = threads_lock.get_mut(tid) {
-                thread.xFeatures.0 |= features.to_u64();
+                // features the CPU or the kernel policy doesn't allow would make XSETBV fault
+                let supported = kernelContext().constants.SUPPORTED_XFEATURES.get().map_or(0, |f| f.to_u64());
+                thread.xFeatures.0 |= features.to_u64() & supported;
                 Ok(())
             } else {
                 Err(())
@@ -303,3 +547,78 @@ impl Process {
 }
 
 unsafe impl Send for Thread {}
+
+/// Page table mapper for the address space with top level table `pageTable`.
+fn mapperFor(pageTable: PhysFrame) -> OffsetPageTable<'static> {
+    let phys_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.get_copy().unwrap());
+
+    let l4_table_ptr = (phys_offset + pageTable.start_address().as_u64()).as_mut_ptr::<PageTable>();
+    let l4_table = unsafe { &mut *l4_table_ptr };
+    unsafe { OffsetPageTable::new(l4_table, phys_offset) }
+}
+
+/// First code run by threads from `create_thread_with`, ends the thread once `func` returns.
+extern "C" fn threadEntry(arg: usize, func: extern "C" fn(usize)) -> ! {
+    func(arg);
+    exit_thread()
+}
+
+extern "C" fn threadEntryNoArg(_arg: usize, func: extern "C" fn()) -> ! {
+    func();
+    exit_thread()
+}
+
+extern "C" fn closureTrampoline(arg: usize) {
+    let body = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+    body();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    NoSuchThread,
+    /// Another thread is already waiting for it to exit.
+    AlreadyJoined,
+}
+
+/// Owned permission to wait for a spawned thread and take its result.
+pub struct JoinHandle<T> {
+    pid: ProcessID,
+    tid: ThreadID,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn pid(&self) -> ProcessID {
+        self.pid
+    }
+
+    pub fn thread_id(&self) -> ThreadID {
+        self.tid
+    }
+
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| self.result.lock().is_some())
+    }
+
+    /// Blocks until the thread exits and returns what its closure returned, `None` if it was
+    /// killed before the closure returned or another thread is already joining it.
+    pub fn join(self) -> Option<T> {
+        join_thread(self.pid, self.tid).ok()?;
+
+        let process = interrupts::without_interrupts(|| SCHEDULER.lock().get_process(self.pid));
+        if let Some(process) = process {
+            process.reap_thread(&self.tid);
+        }
+
+        interrupts::without_interrupts(|| self.result.lock().take())
+    }
+}
+
+/// Dropping the handle detaches the thread, the reaper frees it once it exits.
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            SCHEDULER.lock().detach(self.pid, self.tid);
+        });
+    }
+}
@@ -4,7 +4,6 @@ use core::task::{Context, Poll};
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::Stream;
 use futures_util::task::AtomicWaker;
-use futures_util::StreamExt;
 use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
 use pc_keyboard::KeyCode::{CapsLock, NumpadLock, ScrollLock};
@@ -12,7 +11,8 @@ use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyState, Keyboard, Scanco
 use ps2::error::ControllerError;
 use ps2::flags::{ControllerConfigFlags, KeyboardLedFlags};
 use ps2::Controller;
-use spin::{Mutex, MutexGuard};
+use spin::Mutex;
+use crate::util::lockdep;
 
 lazy_static! {
     static ref KEYBOARD: Mutex<Keyboard<layouts::Uk105Key, ScancodeSet1>> =
@@ -74,6 +74,13 @@ impl Stream for ScancodeStream {
     }
 }
 
+impl ScancodeStream {
+    /// Takes the next scancode without waiting, for readers that poll instead of awaiting.
+    pub fn pop(&mut self) -> Option<u8> {
+        SCANCODE_QUEUE.get().expect("Not initialized").pop()
+    }
+}
+
 pub(crate) fn addScancode(scancode: u8) {
     if let Some(queue) = SCANCODE_QUEUE.get() {
         if let Err(_) = queue.push(scancode) {
@@ -86,34 +93,27 @@ pub(crate) fn addScancode(scancode: u8) {
     }
 }
 
-pub async fn printKeypresses() {
-    let mut scancodes = ScancodeStream::new();
+/// Feeds a scancode to the keyboard state machine and returns the key it completes, if any.
+/// The lock keys toggle their LEDs on the way.
+pub fn decodeScancode(scancode: u8) -> Option<DecodedKey> {
     let mut keyboard = KEYBOARD.lock();
-    let mut controller = unsafe {
-        CONTROLLER.force_unlock();
-        CONTROLLER.lock()
-    };
-
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(keyEvent)) = keyboard.add_byte(scancode) {
-            log::info!("keyEvent: {:?})", keyEvent);
-            if keyEvent.state == KeyState::Down {
-                match keyEvent.code {
-                    CapsLock => handleLed(&mut controller, KeyboardLedFlags::CAPS_LOCK),
-                    NumpadLock => handleLed(&mut controller, KeyboardLedFlags::NUM_LOCK),
-                    ScrollLock => handleLed(&mut controller, KeyboardLedFlags::SCROLL_LOCK),
-                    _ => {}
-                };
-            }
-
-            if let Some(key) = keyboard.process_keyevent(keyEvent) {
-                match key {
-                    DecodedKey::Unicode(character) => log::info!("{}", character),
-                    DecodedKey::RawKey(key) => log::info!("{:?}", key),
-                }
-            }
+    let keyEvent = keyboard.add_byte(scancode).ok()??;
+    log::trace!("keyEvent: {:?}", keyEvent);
+
+    if keyEvent.state == KeyState::Down {
+        let led = match keyEvent.code {
+            CapsLock => Some(KeyboardLedFlags::CAPS_LOCK),
+            NumpadLock => Some(KeyboardLedFlags::NUM_LOCK),
+            ScrollLock => Some(KeyboardLedFlags::SCROLL_LOCK),
+            _ => None,
+        };
+        if let Some(led) = led {
+            // the interrupt handler takes the controller too
+            x86_64::instructions::interrupts::without_interrupts(|| handleLed(&mut CONTROLLER.lock(), led));
         }
     }
+
+    keyboard.process_keyevent(keyEvent)
 }
 
 pub fn keyboardInitialize() -> Result<(), ControllerError> {
@@ -192,7 +192,7 @@ pub fn keyboardInitialize() -> Result<(), ControllerError> {
     Ok(())
 }
 
-fn handleLed(ctrl: &mut MutexGuard<Controller>, key: KeyboardLedFlags) {
+fn handleLed(ctrl: &mut lockdep::MutexGuard<Controller>, key: KeyboardLedFlags) {
     let mut state = KEYBOARD_STATE.lock();
 
     match key {
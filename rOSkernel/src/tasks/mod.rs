@@ -1 +1,2 @@
 pub mod keyboard;
+pub mod shell;
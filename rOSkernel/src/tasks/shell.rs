@@ -0,0 +1,413 @@
+//! Interactive command shell on the console.
+//!
+//! Lines are typed on the PS/2 keyboard or the first serial port, whichever is used, and the
+//! output is drawn on the framebuffer and mirrored to serial. Serial input is shared with
+//! `/dev/console`, so a user program reading standard input competes with the shell for it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use cpuid::features::ExtendedFeatures;
+use cpuid::CPUID;
+use pc_keyboard::DecodedKey;
+use crate::debug::serial::SERIAL1;
+use crate::fs::vfs::{FileType, FsError, OpenFlags};
+use crate::fs::disk::registry;
+use crate::fs::{self, mount, path, simplefs};
+use crate::kernel::{kernelContext, pci, reboot, timer, RTC};
+use crate::mem::HEAP;
+use crate::multitasking::preemptive::stats::all_process_stats;
+use crate::multitasking::preemptive::{current_pid, current_process, kill_process, sleep_ms, ProcessID};
+use crate::tasks::keyboard::{decodeScancode, ScancodeStream};
+
+const PROMPT: &str = "rOS> ";
+/// How long the shell sleeps when neither the keyboard nor serial had input.
+const POLL_MS: u64 = 10;
+const MAX_LINE: usize = 256;
+/// Most bytes `cat` prints from one file.
+const CAT_LIMIT: usize = 64 * 1024;
+
+type Builtin = fn(&mut Console, &[&str]) -> fmt::Result;
+
+/// Name, usage and handler of every command.
+const BUILTINS: [(&str, &str, Builtin); 16] = [
+    ("help", "list the commands", help),
+    ("ps", "list processes and their threads", ps),
+    ("kill", "kill <pid>: end every thread of a process", kill),
+    ("mem", "physical memory usage", mem),
+    ("heap", "kernel heap usage", heap),
+    ("cpuid", "processor identification and features", cpuid),
+    ("ls", "ls [dir]: list a directory", ls),
+    ("cat", "cat <file>...: print files", cat),
+    ("cd", "cd [dir]: change the working directory, / by default", cd),
+    ("pwd", "print the working directory", pwd),
+    ("mount", "mount [<device> <dir>]: list file systems, or mount the one on device at dir", mountCommand),
+    ("umount", "umount <dir>: detach the file system mounted at dir", umount),
+    ("fsck", "fsck <device>: repair the unmounted SFS volume on device", fsck),
+    ("date", "current date and time from the RTC", date),
+    ("reboot", "reset the machine", rebootCommand),
+    ("lspci", "list PCI devices", lspci),
+];
+
+/// Framebuffer plus serial, where the shell writes.
+struct Console;
+
+impl Console {
+    fn backspace(&mut self) {
+        if let Some(fb) = kernelContext().frameBuffer.get() {
+            let _ = fb.lock().write_char('\x08');
+        }
+        crate::serial_print!("\x08 \x08");
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(fb) = kernelContext().frameBuffer.get() {
+            fb.lock().write_str(s)?;
+        }
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Waits for the next typed character from either input.
+fn nextChar(scancodes: &mut ScancodeStream) -> char {
+    loop {
+        while let Some(scancode) = scancodes.pop() {
+            if let Some(DecodedKey::Unicode(c)) = decodeScancode(scancode) {
+                return c;
+            }
+        }
+
+        let byte = x86_64::instructions::interrupts::without_interrupts(|| SERIAL1.lock().try_receive().ok());
+        if let Some(byte) = byte {
+            return byte as char;
+        }
+        sleep_ms(POLL_MS);
+    }
+}
+
+/// Reads a line, echoing what's typed and handling backspace.
+fn readLine(console: &mut Console, scancodes: &mut ScancodeStream) -> String {
+    let mut line = String::new();
+    loop {
+        match nextChar(scancodes) {
+            '\n' | '\r' => {
+                let _ = console.write_char('\n');
+                return line;
+            }
+            '\x08' | '\x7f' => {
+                if line.pop().is_some() {
+                    console.backspace();
+                }
+            }
+            c if !c.is_control() && line.len() + c.len_utf8() <= MAX_LINE => {
+                line.push(c);
+                let _ = console.write_char(c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn execute(console: &mut Console, line: &str) -> fmt::Result {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return Ok(());
+    };
+
+    match BUILTINS.iter().find(|(builtin, _, _)| *builtin == name) {
+        Some((_, _, handler)) => handler(console, args),
+        None => writeln!(console, "{}: command not found, try help", name),
+    }
+}
+
+/// Thread body of the shell. Takes over the keyboard, so nothing else may read scancodes.
+pub extern "C" fn shellThread() {
+    let mut scancodes = ScancodeStream::new();
+    let mut console = Console;
+
+    if let Some(fb) = kernelContext().frameBuffer.get() {
+        fb.lock().clear();
+    }
+    let _ = writeln!(console, "rOS kernel shell, type help for the commands");
+
+    loop {
+        let _ = console.write_str(PROMPT);
+        let line = readLine(&mut console, &mut scancodes);
+        let _ = execute(&mut console, &line);
+    }
+}
+
+fn help(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    for (name, usage, _) in BUILTINS.iter() {
+        writeln!(out, "  {:<8} {}", name, usage)?;
+    }
+    Ok(())
+}
+
+fn ps(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    writeln!(out, "{:>5} {:>5} {:>10} {:>10} {:>8}", "PID", "TID", "USER ms", "KERNEL ms", "SWITCH")?;
+    for process in all_process_stats() {
+        writeln!(
+            out,
+            "{:>5} {:>5} {:>10} {:>10} {:>8}",
+            process.pid.as_u64(), "-", timer::cyclesToMs(process.userCycles),
+            timer::cyclesToMs(process.kernelCycles), process.contextSwitches
+        )?;
+        for (tid, thread) in process.threads.iter() {
+            writeln!(
+                out,
+                "{:>5} {:>5} {:>10} {:>10} {:>8}",
+                "", tid.as_u64(), timer::cyclesToMs(thread.userCycles),
+                timer::cyclesToMs(thread.kernelCycles), thread.contextSwitches
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn kill(out: &mut Console, args: &[&str]) -> fmt::Result {
+    let Some(pid) = args.first().and_then(|arg| arg.parse::<u64>().ok()) else {
+        return writeln!(out, "usage: kill <pid>");
+    };
+    let pid = ProcessID::from_raw(pid);
+    if Some(pid) == current_pid() {
+        return writeln!(out, "kill: that's the shell");
+    }
+
+    match kill_process(pid) {
+        // its threads exit once they are out of the kernel, see `kill_process`
+        Some(()) => writeln!(out, "process {} is exiting", pid.as_u64()),
+        None => writeln!(out, "kill: no process {}", pid.as_u64()),
+    }
+}
+
+fn mem(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    let Some(allocator) = kernelContext().frameAllocator.get() else {
+        return writeln!(out, "mem: no frame allocator yet");
+    };
+    let stats = allocator.lock().stats();
+    let kib = |frames: usize| frames * 4;
+    writeln!(out, "frames: {} total, {} used, {} free", stats.total, stats.used, stats.free())?;
+    writeln!(out, "memory: {} KiB total, {} KiB used, {} KiB free", kib(stats.total), kib(stats.used), kib(stats.free()))
+}
+
+fn heap(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    let stats = HEAP.stats();
+    writeln!(out, "regions:  {}", stats.regions)?;
+    writeln!(out, "capacity: {} KiB", stats.capacity / 1024)?;
+    writeln!(out, "reserved: {} KiB (freed blocks are reused, not returned)", stats.reserved / 1024)
+}
+
+fn cpuid(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    let info = CPUID::featureInfo();
+    let family = match info.familyID() {
+        0xF => info.familyID() as u32 + info.extendedFamilyID() as u32,
+        family => family as u32,
+    };
+    let model = match info.familyID() {
+        0x6 | 0xF => (info.extendedModelID() << 4) | info.model(),
+        _ => info.model(),
+    };
+
+    writeln!(out, "vendor:   {}", CPUID::vendorInfo().as_str())?;
+    if let Some(brand) = CPUID::processorBrand() {
+        writeln!(out, "brand:    {}", brand.as_str().trim())?;
+    }
+    writeln!(out, "family {:#x}, model {:#x}, stepping {}", family, model, info.steppingID())?;
+
+    let extended = CPUID::extendedFeatures();
+    let has = |f: fn(&ExtendedFeatures) -> bool| extended.as_ref().is_some_and(f);
+    let features = [
+        ("sse3", info.sse3()),
+        ("ssse3", info.ssse3()),
+        ("sse4.1", info.sse4_1()),
+        ("sse4.2", info.sse4_2()),
+        ("popcnt", info.popcnt()),
+        ("aes", info.aes()),
+        ("xsave", info.xsave()),
+        ("avx", info.avx()),
+        ("x2apic", info.x2apic()),
+        ("avx2", has(|f| f.avx2())),
+        ("avx512f", has(|f| f.avx512f())),
+        ("smep", has(|f| f.smep())),
+        ("smap", has(|f| f.smap())),
+        ("fsgsbase", has(|f| f.fsgsbase())),
+    ];
+    write!(out, "features:")?;
+    for (name, _) in features.iter().filter(|(_, present)| *present) {
+        write!(out, " {}", name)?;
+    }
+    writeln!(out)
+}
+
+/// Working directory of the shell process.
+fn cwd() -> String {
+    current_process().map_or_else(|| String::from("/"), |process| process.cwd().lock().clone())
+}
+
+/// Directories are marked with a slash, symlinks with an @.
+fn ls(out: &mut Console, args: &[&str]) -> fmt::Result {
+    let target = args.first().copied().unwrap_or(".");
+    let mut entries = Vec::new();
+    let listed = path::open(&cwd(), target, OpenFlags::READ).and_then(|dir| {
+        dir.readdir(|entry| {
+            entries.push(entry.clone());
+            true
+        })
+    });
+    match listed {
+        Ok(()) => {}
+        // a file lists as itself
+        Err(FsError::NotADirectory) => return writeln!(out, "{}", target),
+        Err(e) => return writeln!(out, "ls: {}: {:?}", target, e),
+    }
+
+    for entry in entries {
+        let mark = match entry.fileType {
+            FileType::Directory => "/",
+            FileType::Symlink => "@",
+            _ => "",
+        };
+        writeln!(out, "{}{}", entry.name, mark)?;
+    }
+    Ok(())
+}
+
+fn cat(out: &mut Console, args: &[&str]) -> fmt::Result {
+    if args.is_empty() {
+        return writeln!(out, "usage: cat <file>...");
+    }
+
+    let cwd = cwd();
+    for arg in args {
+        let file = match path::open(&cwd, arg, OpenFlags::READ) {
+            Ok(file) => file,
+            Err(e) => {
+                writeln!(out, "cat: {}: {:?}", arg, e)?;
+                continue;
+            }
+        };
+
+        let mut buf = [0u8; 512];
+        let mut total = 0;
+        while total < CAT_LIMIT {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    total += n;
+                    for chunk in buf[..n].utf8_chunks() {
+                        out.write_str(chunk.valid())?;
+                        if !chunk.invalid().is_empty() {
+                            out.write_char('\u{FFFD}')?;
+                        }
+                    }
+                }
+                Err(e) => {
+                    writeln!(out, "cat: {}: {:?}", arg, e)?;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cd(out: &mut Console, args: &[&str]) -> fmt::Result {
+    let target = args.first().copied().unwrap_or("/");
+    let Some(process) = current_process() else {
+        return writeln!(out, "cd: no process");
+    };
+
+    let cwd = process.cwd().lock().clone();
+    match path::resolve(&cwd, target, true) {
+        Ok(dir) if dir.isDirectory() => {
+            *process.cwd().lock() = dir.path();
+            Ok(())
+        }
+        Ok(_) => writeln!(out, "cd: {}: not a directory", target),
+        Err(e) => writeln!(out, "cd: {}: {:?}", target, e),
+    }
+}
+
+fn pwd(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    writeln!(out, "{}", cwd())
+}
+
+fn mountCommand(out: &mut Console, args: &[&str]) -> fmt::Result {
+    let [device, target] = args else {
+        for mount in mount::mounts() {
+            let device = mount.device().unwrap_or("none");
+            writeln!(out, "{} on {} type {} ({} open files)", device, mount.path(), mount.fsName(), mount.users())?;
+        }
+        return Ok(());
+    };
+
+    let name = device.strip_prefix("/dev/").unwrap_or(device);
+    let Some(blockDevice) = registry::get(name) else {
+        return writeln!(out, "mount: {}: no such block device", device);
+    };
+    // checked again when mounting, but probing shouldn't open a second volume either
+    if mount::isDeviceMounted(name) {
+        return writeln!(out, "mount: {}: already mounted", device);
+    }
+    let result = path::canonicalize(&cwd(), target).and_then(|target| {
+        let (fsName, root) = fs::probe(blockDevice)?;
+        mount::mount(&target, fsName, Some(name), root)
+    });
+    if let Err(e) = result {
+        writeln!(out, "mount: {} on {}: {:?}", device, target, e)?;
+    }
+    Ok(())
+}
+
+fn umount(out: &mut Console, args: &[&str]) -> fmt::Result {
+    let Some(target) = args.first() else {
+        return writeln!(out, "usage: umount <dir>");
+    };
+    let target = match path::canonicalize(&cwd(), target) {
+        Ok(target) => target,
+        Err(e) => return writeln!(out, "umount: {}: {:?}", target, e),
+    };
+    if let Err(e) = mount::umount(&target) {
+        writeln!(out, "umount: {}: {:?}", target, e)?;
+    }
+    Ok(())
+}
+
+fn fsck(out: &mut Console, args: &[&str]) -> fmt::Result {
+    let Some(device) = args.first() else {
+        return writeln!(out, "usage: fsck <device>");
+    };
+    let name = device.strip_prefix("/dev/").unwrap_or(device);
+    let Some(blockDevice) = registry::get(name) else {
+        return writeln!(out, "fsck: {}: no such block device", device);
+    };
+    // the mounted volume keeps its own copy of the index
+    if mount::isDeviceMounted(name) {
+        return writeln!(out, "fsck: {}: mounted, unmount it first", device);
+    }
+    match simplefs::repair(blockDevice) {
+        Ok(0) => writeln!(out, "{}: clean", device),
+        Ok(problems) => writeln!(out, "{}: repaired {} problems", device, problems),
+        Err(e) => writeln!(out, "fsck: {}: {:?}", device, e),
+    }
+}
+
+fn date(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    writeln!(out, "{}", RTC::now())
+}
+
+fn rebootCommand(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    writeln!(out, "rebooting...")?;
+    reboot()
+}
+
+fn lspci(out: &mut Console, _args: &[&str]) -> fmt::Result {
+    for device in pci::devices() {
+        writeln!(out, "{}", device)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,356 @@
+//! Opt-in lock dependency validator, enabled with the `lockdep` cargo feature.
+//!
+//! Locks are grouped into classes by name. Whenever a lock is taken while others are held,
+//! the order is recorded; taking two classes in both orders (ABBA), taking a class
+//! recursively, or taking a class both in interrupt context and with interrupts enabled is
+//! reported once on the log. Without the feature the wrapper is a plain `spin::Mutex`.
+//!
+//! The validator uses fixed-size tables only, so it works before the heap exists and
+//! can guard the allocator's own locks. Held locks are tracked per CPU rather than per
+//! thread, so a thread preempted while holding a lock can produce false orderings.
+
+use core::fmt::{self, Debug};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(feature = "lockdep")]
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize};
+
+/// Most distinct lock classes the validator can track, further classes go unchecked.
+pub const MAX_CLASSES: usize = 64;
+/// Deepest nesting of held locks tracked per CPU.
+pub const MAX_HELD: usize = 16;
+
+const NO_CLASS: u8 = u8::MAX;
+
+/// Spinlock that reports its acquisitions to the validator.
+pub struct Mutex<T: ?Sized> {
+    name: &'static str,
+    // index into the class table, assigned on first lock
+    class: AtomicU8,
+    inner: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    class: u8,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    /// Locks with the same `name` share a class, so give every distinct role its own name.
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            class: AtomicU8::new(NO_CLASS),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let class = self.classIndex();
+        validator::acquire(class);
+        MutexGuard {
+            class,
+            inner: self.inner.lock(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        let class = self.classIndex();
+        // a successful try_lock can't deadlock, but it still orders later acquisitions
+        validator::acquireTry(class);
+        Some(MutexGuard { class, inner })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Releases the lock without a guard, see `spin::Mutex::force_unlock`.
+    pub unsafe fn force_unlock(&self) {
+        if self.inner.is_locked() {
+            validator::release(self.classIndex());
+        }
+        unsafe { self.inner.force_unlock() };
+    }
+
+    fn classIndex(&self) -> u8 {
+        let class = self.class.load(Ordering::Relaxed);
+        if class != NO_CLASS {
+            return class;
+        }
+
+        let class = validator::classFor(self.name);
+        self.class.store(class, Ordering::Relaxed);
+        class
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new("<anonymous>", T::default())
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("name", &self.name)
+            .field("inner", &&self.inner)
+            .finish()
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.inner, f)
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        validator::release(self.class);
+    }
+}
+
+/// Marks the current CPU as running an interrupt handler until the guard is dropped.
+pub struct IrqContextGuard {
+    _private: (),
+}
+
+/// Call at the top of interrupt handlers that take locks.
+pub fn irq_enter() -> IrqContextGuard {
+    validator::irqEnter();
+    IrqContextGuard { _private: () }
+}
+
+impl Drop for IrqContextGuard {
+    fn drop(&mut self) {
+        validator::irqExit();
+    }
+}
+
+#[cfg(not(feature = "lockdep"))]
+mod validator {
+    use super::NO_CLASS;
+
+    #[inline(always)]
+    pub fn classFor(_name: &'static str) -> u8 {
+        NO_CLASS - 1
+    }
+
+    #[inline(always)]
+    pub fn acquire(_class: u8) {}
+
+    #[inline(always)]
+    pub fn acquireTry(_class: u8) {}
+
+    #[inline(always)]
+    pub fn release(_class: u8) {}
+
+    #[inline(always)]
+    pub fn irqEnter() {}
+
+    #[inline(always)]
+    pub fn irqExit() {}
+}
+
+#[cfg(feature = "lockdep")]
+mod validator {
+    use super::*;
+    use crate::kernel::{currentCpuID, MAX_CPUS};
+    use x86_64::instructions::interrupts;
+
+    const UNTRACKED: u8 = NO_CLASS - 1;
+
+    const USED_IN_IRQ: u8 = 1 << 0;
+    const USED_IRQS_ENABLED: u8 = 1 << 1;
+    const IRQ_REPORTED: u8 = 1 << 2;
+
+    struct PerCpu {
+        held: [AtomicU8; MAX_HELD],
+        depth: AtomicUsize,
+        irqDepth: AtomicU32,
+    }
+
+    impl PerCpu {
+        const fn new() -> Self {
+            Self {
+                held: [const { AtomicU8::new(NO_CLASS) }; MAX_HELD],
+                depth: AtomicUsize::new(0),
+                irqDepth: AtomicU32::new(0),
+            }
+        }
+    }
+
+    static CLASS_NAMES: [spin::Once<&'static str>; MAX_CLASSES] = [const { spin::Once::new() }; MAX_CLASSES];
+    static CLASS_FLAGS: [AtomicU8; MAX_CLASSES] = [const { AtomicU8::new(0) }; MAX_CLASSES];
+    /// Bit `b` of `AFTER[a]`: class b has been taken while holding class a.
+    static AFTER: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+    /// Bit `b` of `REPORTED[a]`: the inversion between a and b was already reported.
+    static REPORTED: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+    static CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+    fn cpu() -> &'static PerCpu {
+        &CPUS[currentCpuID() as usize]
+    }
+
+    fn className(class: u8) -> &'static str {
+        CLASS_NAMES[class as usize].get().copied().unwrap_or("<unknown>")
+    }
+
+    pub fn classFor(name: &'static str) -> u8 {
+        for (i, slot) in CLASS_NAMES.iter().enumerate() {
+            // claims the first free slot, unless another CPU claimed it for a different name first
+            if *slot.call_once(|| name) == name {
+                return i as u8;
+            }
+        }
+
+        UNTRACKED
+    }
+
+    /// Whether `to` can be reached from `from` through recorded orderings.
+    fn reachable(from: u8, to: u8) -> bool {
+        let mut visited: u64 = 1 << from;
+        let mut frontier: u64 = 1 << from;
+
+        while frontier != 0 {
+            let class = frontier.trailing_zeros() as usize;
+            frontier &= frontier - 1;
+
+            let next = AFTER[class].load(Ordering::Relaxed) & !visited;
+            if next & (1 << to) != 0 {
+                return true;
+            }
+            visited |= next;
+            frontier |= next;
+        }
+        false
+    }
+
+    fn reportOnce(a: u8, b: u8) -> bool {
+        let bit = 1u64 << b;
+        REPORTED[a as usize].fetch_or(bit, Ordering::Relaxed) & bit == 0
+    }
+
+    fn checkIrqUsage(class: u8, cpu: &PerCpu) {
+        let flag = if cpu.irqDepth.load(Ordering::Relaxed) > 0 {
+            USED_IN_IRQ
+        } else if interrupts::are_enabled() {
+            USED_IRQS_ENABLED
+        } else {
+            return;
+        };
+
+        let flags = CLASS_FLAGS[class as usize].fetch_or(flag, Ordering::Relaxed) | flag;
+        let both = USED_IN_IRQ | USED_IRQS_ENABLED;
+        if flags & both == both
+            && CLASS_FLAGS[class as usize].fetch_or(IRQ_REPORTED, Ordering::Relaxed) & IRQ_REPORTED == 0
+        {
+            log::warn!(
+                "lockdep: {} is taken in interrupt context and with interrupts enabled, \
+                 an interrupt arriving while it is held deadlocks",
+                className(class)
+            );
+        }
+    }
+
+    fn record(class: u8, checkOrder: bool) {
+        if class == UNTRACKED {
+            return;
+        }
+
+        let cpu = cpu();
+        checkIrqUsage(class, cpu);
+
+        let depth = cpu.depth.load(Ordering::Relaxed).min(MAX_HELD);
+        for slot in cpu.held[..depth].iter() {
+            let held = slot.load(Ordering::Relaxed);
+            if held == NO_CLASS || held == UNTRACKED {
+                continue;
+            }
+
+            if held == class {
+                if checkOrder && reportOnce(class, class) {
+                    log::warn!("lockdep: recursive acquisition of {}", className(class));
+                }
+                continue;
+            }
+
+            if checkOrder && reachable(class, held) && reportOnce(held, class) {
+                log::warn!(
+                    "lockdep: possible ABBA deadlock, {} taken while holding {}, \
+                     but {} has been taken while holding {} before",
+                    className(class), className(held), className(held), className(class)
+                );
+            }
+            AFTER[held as usize].fetch_or(1 << class, Ordering::Relaxed);
+        }
+
+        let slot = cpu.depth.fetch_add(1, Ordering::Relaxed);
+        if slot < MAX_HELD {
+            cpu.held[slot].store(class, Ordering::Relaxed);
+        }
+    }
+
+    pub fn acquire(class: u8) {
+        record(class, true);
+    }
+
+    pub fn acquireTry(class: u8) {
+        record(class, false);
+    }
+
+    pub fn release(class: u8) {
+        if class == UNTRACKED {
+            return;
+        }
+
+        let cpu = cpu();
+        let depth = cpu.depth.load(Ordering::Relaxed);
+        if depth == 0 {
+            return;
+        }
+
+        // guards usually drop in reverse order, but not necessarily
+        let tracked = depth.min(MAX_HELD);
+        if let Some(index) = (0..tracked).rev().find(|&i| cpu.held[i].load(Ordering::Relaxed) == class) {
+            for i in index..tracked - 1 {
+                let next = cpu.held[i + 1].load(Ordering::Relaxed);
+                cpu.held[i].store(next, Ordering::Relaxed);
+            }
+            cpu.held[tracked - 1].store(NO_CLASS, Ordering::Relaxed);
+        }
+        cpu.depth.store(depth - 1, Ordering::Relaxed);
+    }
+
+    pub fn irqEnter() {
+        cpu().irqDepth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn irqExit() {
+        cpu().irqDepth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod AtomicLazy;
 pub mod AtomicSingleton;
+pub mod lockdep;
 pub mod OnceInit;
 pub mod wrappers;
@@ -8,6 +8,15 @@ pub enum FpuSaveMechanism {
     None = 0,
     FXSave = 1,
     XSave = 2,
+    XSaveOpt = 3,
+    XSaveS = 4,
+}
+
+impl FpuSaveMechanism {
+    /// Whether this mechanism uses an XSAVE-format area governed by XCR0.
+    pub fn usesXSave(&self) -> bool {
+        matches!(self, Self::XSave | Self::XSaveOpt | Self::XSaveS)
+    }
 }
 
 pub static FPU_MECHANISM: AtomicU8 = AtomicU8::new(FpuSaveMechanism::None as u8);
@@ -16,6 +25,8 @@ pub fn get_fpu_mechanism() -> FpuSaveMechanism {
     match FPU_MECHANISM.load(Ordering::Relaxed) {
         1 => FpuSaveMechanism::FXSave,
         2 => FpuSaveMechanism::XSave,
+        3 => FpuSaveMechanism::XSaveOpt,
+        4 => FpuSaveMechanism::XSaveS,
         _ => FpuSaveMechanism::None,
     }
 }
@@ -92,6 +103,11 @@ pub unsafe fn CPUID(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
     (result.eax, result.ebx, result.ecx, result.edx)
 }
 
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
 #[inline]
 pub unsafe fn readCR0() -> u64 {
     let mut cr0: u64;
@@ -0,0 +1,190 @@
+//! System call ABI shared by the kernel and user programs.
+//!
+//! A call puts its number in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8`
+//! and `r9`, then executes SYSCALL (or the legacy `int 0xAA`). The result comes back in `rax`,
+//! errors as `-errno`, so values in `-4095..0` are errors. Besides `rcx` and `r11`, which
+//! SYSCALL itself overwrites, the vector registers are clobbered like in a SysV function call;
+//! all other registers are preserved.
+//!
+//! Strings passed to the kernel, like paths, are NUL-terminated.
+
+use core::fmt;
+
+/// System call numbers.
+pub mod nr {
+    pub const EXIT: u64 = 0;
+    pub const EXIT_THREAD: u64 = 1;
+    pub const YIELD: u64 = 2;
+    pub const SLEEP_MS: u64 = 3;
+    pub const GETPID: u64 = 4;
+    pub const GETTID: u64 = 5;
+    pub const WRITE: u64 = 6;
+    pub const UPTIME_MS: u64 = 7;
+    pub const READ: u64 = 8;
+    pub const OPEN: u64 = 9;
+    pub const CLOSE: u64 = 10;
+    pub const LSEEK: u64 = 11;
+    pub const DUP: u64 = 12;
+    pub const DUP2: u64 = 13;
+    pub const BRK: u64 = 14;
+    pub const MMAP: u64 = 15;
+    pub const MUNMAP: u64 = 16;
+    pub const THREAD_SPAWN: u64 = 17;
+    pub const THREAD_JOIN: u64 = 18;
+    pub const KILL: u64 = 19;
+    pub const PIPE: u64 = 20;
+    pub const CHDIR: u64 = 21;
+    pub const GETCWD: u64 = 22;
+    pub const FTRUNCATE: u64 = 23;
+    pub const FSYNC: u64 = 24;
+    pub const GETDENTS: u64 = 25;
+
+    /// One past the highest number.
+    pub const COUNT: usize = 26;
+}
+
+pub const STDIN: u32 = 0;
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+/// Longest path accepted by `open` or returned by `getcwd`, including the terminating NUL.
+pub const PATH_MAX: usize = 4096;
+
+/// Longest name of a single directory entry.
+pub const NAME_MAX: usize = 255;
+
+/// `open` flags, the values follow Linux.
+pub mod open {
+    pub const O_RDONLY: u32 = 0;
+    pub const O_WRONLY: u32 = 1;
+    pub const O_RDWR: u32 = 2;
+    pub const O_ACCMODE: u32 = 3;
+    pub const O_CREAT: u32 = 0o100;
+    pub const O_EXCL: u32 = 0o200;
+    pub const O_TRUNC: u32 = 0o1000;
+    pub const O_APPEND: u32 = 0o2000;
+}
+
+/// `lseek` origins.
+pub mod seek {
+    pub const SEEK_SET: u32 = 0;
+    pub const SEEK_CUR: u32 = 1;
+    pub const SEEK_END: u32 = 2;
+}
+
+/// Directory entries as `getdents` lays them out: each record is a `DirentHeader`, the name
+/// and a NUL, padded to a multiple of 8 bytes.
+pub mod dirent {
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct DirentHeader {
+        /// Offset of the directory after this entry.
+        pub next: u64,
+        /// Length of the whole record.
+        pub reclen: u16,
+        pub fileType: u8,
+        pub nameLen: u8,
+    }
+
+    /// Record of a name `nameLen` bytes long.
+    pub const fn recordLen(nameLen: usize) -> usize {
+        (size_of::<DirentHeader>() + nameLen + 1).next_multiple_of(8)
+    }
+
+    // file types, the values follow Linux
+    pub const DT_UNKNOWN: u8 = 0;
+    pub const DT_FIFO: u8 = 1;
+    pub const DT_CHR: u8 = 2;
+    pub const DT_DIR: u8 = 4;
+    pub const DT_BLK: u8 = 6;
+    pub const DT_REG: u8 = 8;
+    pub const DT_LNK: u8 = 10;
+}
+
+/// `mmap` protection and flags. Only private anonymous mappings exist.
+pub mod mmap {
+    pub const PROT_NONE: u32 = 0;
+    pub const PROT_READ: u32 = 1;
+    pub const PROT_WRITE: u32 = 2;
+    pub const PROT_EXEC: u32 = 4;
+
+    pub const MAP_PRIVATE: u32 = 0x02;
+    pub const MAP_ANONYMOUS: u32 = 0x20;
+}
+
+/// Error numbers returned by system calls, negated in `rax`. The values follow Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    ENFILE = 23,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EMLINK = 31,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+}
+
+impl Errno {
+    const ALL: [Errno; 34] = [
+        Errno::EPERM, Errno::ENOENT, Errno::ESRCH, Errno::EINTR, Errno::EIO, Errno::E2BIG,
+        Errno::ENOEXEC, Errno::EBADF, Errno::ECHILD, Errno::EAGAIN, Errno::ENOMEM, Errno::EACCES,
+        Errno::EFAULT, Errno::EBUSY, Errno::EEXIST, Errno::EXDEV, Errno::ENODEV, Errno::ENOTDIR,
+        Errno::EISDIR, Errno::EINVAL, Errno::ENFILE, Errno::EMFILE, Errno::ENOTTY, Errno::EFBIG,
+        Errno::ENOSPC, Errno::ESPIPE, Errno::EROFS, Errno::EMLINK, Errno::EPIPE, Errno::ERANGE,
+        Errno::ENAMETOOLONG, Errno::ENOSYS, Errno::ENOTEMPTY, Errno::ELOOP,
+    ];
+
+    /// The value handed back to user code, `-errno` as an unsigned register.
+    pub fn as_return(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+
+    pub fn from_raw(value: i64) -> Option<Errno> {
+        Self::ALL.iter().copied().find(|e| *e as i64 == value)
+    }
+
+    /// Splits a raw system call return value into a result. Error numbers the ABI doesn't
+    /// know come back as EINVAL.
+    pub fn from_return(value: u64) -> Result<u64, Errno> {
+        let signed = value as i64;
+        if (-4095..0).contains(&signed) {
+            Err(Self::from_raw(-signed).unwrap_or(Errno::EINVAL))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({})", self, *self as i64)
+    }
+}
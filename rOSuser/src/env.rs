@@ -0,0 +1,40 @@
+//! Arguments and environment the program was started with.
+
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Called once by `_start` with the arrays from the initial stack.
+pub(crate) unsafe fn init(argc: usize, argv: *const *const u8, envp: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+    ENVP.store(envp as *mut _, Ordering::Relaxed);
+}
+
+/// Strings of a NULL-terminated pointer array. The loader places them on the initial stack,
+/// which lives as long as the main thread, so they are handed out as `'static`. Strings that
+/// aren't UTF-8 come back empty.
+fn strings(array: *const *const u8, limit: usize) -> impl Iterator<Item = &'static str> {
+    (0..limit)
+        .map(move |i| unsafe { if array.is_null() { core::ptr::null() } else { *array.add(i) } })
+        .take_while(|ptr| !ptr.is_null())
+        .map(|ptr| unsafe { CStr::from_ptr(ptr.cast()) }.to_str().unwrap_or(""))
+}
+
+/// The program's arguments, the first one is usually its name.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    strings(ARGV.load(Ordering::Relaxed), ARGC.load(Ordering::Relaxed))
+}
+
+/// The environment as `(name, value)` pairs.
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    strings(ENVP.load(Ordering::Relaxed), usize::MAX)
+        .map(|entry| entry.split_once('=').unwrap_or((entry, "")))
+}
+
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find(|(key, _)| *key == name).map(|(_, value)| value)
+}
@@ -0,0 +1,230 @@
+//! File descriptors and the file system calls.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::abi::dirent::{self, DirentHeader};
+use crate::abi::{nr, open, seek, Errno, PATH_MAX};
+use crate::syscall::{syscall1, syscall2, syscall3};
+
+pub use crate::abi::open::{O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+pub use crate::abi::{STDERR, STDIN, STDOUT};
+
+/// Where `lseek` counts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Copies `path` into a NUL-terminated buffer for the kernel.
+fn cPath(path: &str) -> Result<Vec<u8>, Errno> {
+    if path.len() >= PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if path.as_bytes().contains(&0) {
+        return Err(Errno::EINVAL);
+    }
+    let mut buf = Vec::with_capacity(path.len() + 1);
+    buf.extend_from_slice(path.as_bytes());
+    buf.push(0);
+    Ok(buf)
+}
+
+/// Opens `path` with the `O_*` flags, `mode` only matters when creating.
+pub fn open(path: &str, flags: u32, mode: u32) -> Result<u32, Errno> {
+    let path = cPath(path)?;
+    let ret = unsafe { syscall3(nr::OPEN, path.as_ptr() as u64, flags as u64, mode as u64) };
+    Errno::from_return(ret).map(|fd| fd as u32)
+}
+
+/// Changes the working directory relative paths start from.
+pub fn chdir(path: &str) -> Result<(), Errno> {
+    let path = cPath(path)?;
+    Errno::from_return(unsafe { syscall1(nr::CHDIR, path.as_ptr() as u64) }).map(|_| ())
+}
+
+/// Absolute path of the working directory.
+pub fn getcwd() -> Result<String, Errno> {
+    let mut buf = vec![0u8; PATH_MAX];
+    let len = Errno::from_return(unsafe { syscall2(nr::GETCWD, buf.as_mut_ptr() as u64, buf.len() as u64) })?;
+    buf.truncate(len as usize);
+    String::from_utf8(buf).map_err(|_| Errno::EINVAL)
+}
+
+pub fn read(fd: u32, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(nr::READ, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    Errno::from_return(ret).map(|n| n as usize)
+}
+
+pub fn write(fd: u32, buf: &[u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(nr::WRITE, fd as u64, buf.as_ptr() as u64, buf.len() as u64) };
+    Errno::from_return(ret).map(|n| n as usize)
+}
+
+pub fn close(fd: u32) -> Result<(), Errno> {
+    Errno::from_return(unsafe { syscall1(nr::CLOSE, fd as u64) }).map(|_| ())
+}
+
+/// Moves the file offset and returns the new one.
+pub fn lseek(fd: u32, pos: SeekFrom) -> Result<u64, Errno> {
+    let (offset, whence) = match pos {
+        SeekFrom::Start(offset) => (offset as i64, seek::SEEK_SET),
+        SeekFrom::Current(offset) => (offset, seek::SEEK_CUR),
+        SeekFrom::End(offset) => (offset, seek::SEEK_END),
+    };
+    Errno::from_return(unsafe { syscall3(nr::LSEEK, fd as u64, offset as u64, whence as u64) })
+}
+
+/// Duplicates `fd` onto the lowest free descriptor.
+pub fn dup(fd: u32) -> Result<u32, Errno> {
+    Errno::from_return(unsafe { syscall1(nr::DUP, fd as u64) }).map(|fd| fd as u32)
+}
+
+/// Makes `newFd` refer to the same file as `oldFd`, closing whatever it was before.
+pub fn dup2(oldFd: u32, newFd: u32) -> Result<u32, Errno> {
+    Errno::from_return(unsafe { syscall2(nr::DUP2, oldFd as u64, newFd as u64) }).map(|fd| fd as u32)
+}
+
+/// Sets the size of the file open on `fd`, cutting it off or growing it with zeroes.
+pub fn ftruncate(fd: u32, size: u64) -> Result<(), Errno> {
+    Errno::from_return(unsafe { syscall2(nr::FTRUNCATE, fd as u64, size) }).map(|_| ())
+}
+
+/// Waits until what was written to `fd` is on the device.
+pub fn fsync(fd: u32) -> Result<(), Errno> {
+    Errno::from_return(unsafe { syscall1(nr::FSYNC, fd as u64) }).map(|_| ())
+}
+
+/// Fills `buf` with directory entry records, see `abi::dirent`. Returns the bytes used, 0 once
+/// the whole directory was listed.
+pub fn getdents(fd: u32, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(nr::GETDENTS, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    Errno::from_return(ret).map(|n| n as usize)
+}
+
+/// An entry of `read_dir`.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    /// One of the `abi::dirent::DT_*` values.
+    pub fileType: u8,
+}
+
+/// Lists the directory at `path`.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Errno> {
+    let dir = File::open(path)?;
+    let mut buf = vec![0u8; 4096];
+    let mut entries = Vec::new();
+    loop {
+        let n = getdents(dir.fd, &mut buf)?;
+        if n == 0 {
+            return Ok(entries);
+        }
+
+        let mut records = &buf[..n];
+        while records.len() >= size_of::<DirentHeader>() {
+            let header = unsafe { records.as_ptr().cast::<DirentHeader>().read_unaligned() };
+            let name = &records[size_of::<DirentHeader>()..][..header.nameLen as usize];
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                fileType: header.fileType,
+            });
+            records = &records[(header.reclen as usize).max(dirent::recordLen(0))..];
+        }
+    }
+}
+
+/// Creates a pipe and returns its read and write end. Reads block until data arrives and
+/// return 0 once every write end is closed, writes fail with EPIPE once every read end is.
+pub fn pipe() -> Result<(File, File), Errno> {
+    let mut fds = [0u32; 2];
+    Errno::from_return(unsafe { syscall1(nr::PIPE, fds.as_mut_ptr() as u64) })?;
+    Ok((File { fd: fds[0] }, File { fd: fds[1] }))
+}
+
+/// An open file descriptor, closed when dropped.
+#[derive(Debug)]
+pub struct File {
+    fd: u32,
+}
+
+impl File {
+    /// Opens an existing file for reading.
+    pub fn open(path: &str) -> Result<File, Errno> {
+        open(path, open::O_RDONLY, 0).map(|fd| File { fd })
+    }
+
+    /// Opens a file for writing, creating it or truncating what's there.
+    pub fn create(path: &str) -> Result<File, Errno> {
+        open(path, open::O_WRONLY | open::O_CREAT | open::O_TRUNC, 0o644).map(|fd| File { fd })
+    }
+
+    pub fn with_flags(path: &str, flags: u32, mode: u32) -> Result<File, Errno> {
+        open(path, flags, mode).map(|fd| File { fd })
+    }
+
+    pub fn fd(&self) -> u32 {
+        self.fd
+    }
+
+    /// Gives up ownership of the descriptor without closing it.
+    pub fn into_fd(self) -> u32 {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        read(self.fd, buf)
+    }
+
+    /// Reads until end of file, appending to `buf`.
+    pub fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, Errno> {
+        let mut chunk = [0u8; 512];
+        let mut total = 0;
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(total),
+                n => {
+                    buf.extend_from_slice(&chunk[..n]);
+                    total += n;
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        write(self.fd, buf)
+    }
+
+    pub fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Errno> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(Errno::EIO),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, Errno> {
+        lseek(self.fd, pos)
+    }
+
+    pub fn set_len(&self, size: u64) -> Result<(), Errno> {
+        ftruncate(self.fd, size)
+    }
+
+    pub fn sync_all(&self) -> Result<(), Errno> {
+        fsync(self.fd)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
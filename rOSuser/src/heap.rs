@@ -0,0 +1,188 @@
+//! Global allocator: a first-fit free list on memory from `brk`, large blocks come straight
+//! from `mmap` so freeing them gives the pages back.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+use crate::mem::{self, PAGE_SIZE, PROT_READ, PROT_WRITE};
+use crate::sync::Mutex;
+
+/// Allocations at least this large get their own mapping.
+const MMAP_THRESHOLD: usize = 128 * 1024;
+/// The break is moved at least this far at once.
+const GROW_MIN: usize = 64 * 1024;
+
+/// Header written into every free block.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+const BLOCK_ALIGN: usize = align_of::<FreeBlock>();
+
+fn alignUp(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Size actually taken from the free list, so every block can hold a header once freed.
+fn blockSize(layout: Layout) -> usize {
+    alignUp(layout.size().max(MIN_BLOCK), BLOCK_ALIGN)
+}
+
+fn isMapped(layout: Layout) -> bool {
+    layout.size() >= MMAP_THRESHOLD && layout.align() <= PAGE_SIZE
+}
+
+/// Free blocks sorted by address.
+struct FreeList {
+    head: *mut FreeBlock,
+}
+
+impl FreeList {
+    /// Returns `size` bytes at `addr` to the list, merging them with adjacent free blocks.
+    unsafe fn insert(&mut self, addr: usize, mut size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        unsafe {
+            while !cur.is_null() && (cur as usize) < addr {
+                prev = cur;
+                cur = (*cur).next;
+            }
+
+            let mut next = cur;
+            if !cur.is_null() && addr + size == cur as usize {
+                size += (*cur).size;
+                next = (*cur).next;
+            }
+
+            if !prev.is_null() && prev as usize + (*prev).size == addr {
+                (*prev).size += size;
+                (*prev).next = next;
+                return;
+            }
+
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            if prev.is_null() {
+                self.head = block;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+
+    /// Takes the first block that fits, putting back what's left on either side. Blocks that
+    /// would leave a remainder too small for a header are skipped.
+    unsafe fn take(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        unsafe {
+            while !cur.is_null() {
+                let start = cur as usize;
+                let end = start + (*cur).size;
+
+                let mut addr = alignUp(start, align);
+                if addr != start && addr - start < MIN_BLOCK {
+                    addr = alignUp(start + MIN_BLOCK, align);
+                }
+                let fits = addr
+                    .checked_add(size)
+                    .filter(|&allocEnd| allocEnd <= end && (allocEnd == end || end - allocEnd >= MIN_BLOCK));
+
+                if let Some(allocEnd) = fits {
+                    let next = (*cur).next;
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if addr > start {
+                        self.insert(start, addr - start);
+                    }
+                    if end > allocEnd {
+                        self.insert(allocEnd, end - allocEnd);
+                    }
+                    return Some(addr);
+                }
+
+                prev = cur;
+                cur = (*cur).next;
+            }
+        }
+        None
+    }
+}
+
+struct Heap {
+    free: FreeList,
+    // current program break, 0 until the first allocation
+    top: usize,
+}
+
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Moves the break far enough for `size` bytes at `align`, false if the kernel refuses.
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        if self.top == 0 {
+            match mem::brk(0) {
+                Ok(top) => self.top = top,
+                Err(_) => return false,
+            }
+        }
+
+        let amount = alignUp(size + align + MIN_BLOCK, PAGE_SIZE).max(GROW_MIN);
+        let Some(wanted) = self.top.checked_add(amount) else {
+            return false;
+        };
+        match mem::brk(wanted) {
+            Ok(newTop) if newTop >= wanted => {
+                unsafe { self.free.insert(self.top, newTop - self.top) };
+                self.top = newTop;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    free: FreeList { head: null_mut() },
+    top: 0,
+});
+
+struct Allocator;
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if isMapped(layout) {
+            return mem::map_anonymous(layout.size(), PROT_READ | PROT_WRITE).unwrap_or(null_mut());
+        }
+
+        let size = blockSize(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+        let mut heap = HEAP.lock();
+        loop {
+            if let Some(addr) = unsafe { heap.free.take(size, align) } {
+                return addr as *mut u8;
+            }
+            if !unsafe { heap.grow(size, align) } {
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if isMapped(layout) {
+            let _ = unsafe { mem::unmap(ptr, alignUp(layout.size(), PAGE_SIZE)) };
+            return;
+        }
+
+        unsafe { HEAP.lock().free.insert(ptr as usize, blockSize(layout)) };
+    }
+}
@@ -0,0 +1,99 @@
+//! Standard streams and the `print!` family.
+
+use core::fmt::{self, Write};
+
+use crate::abi::{STDERR, STDOUT};
+use crate::fs;
+use crate::sync::Mutex;
+
+/// Formats into a small buffer and writes it out in as few system calls as possible.
+struct BufferedFd {
+    fd: u32,
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl BufferedFd {
+    const fn new(fd: u32) -> Self {
+        Self { fd, buf: [0; 256], len: 0 }
+    }
+
+    fn flush(&mut self) -> fmt::Result {
+        let mut written = 0;
+        while written < self.len {
+            match fs::write(self.fd, &self.buf[written..self.len]) {
+                Ok(0) | Err(_) => {
+                    self.len = 0;
+                    return Err(fmt::Error);
+                }
+                Ok(n) => written += n,
+            }
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl Write for BufferedFd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == self.buf.len() {
+                self.flush()?;
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// held while formatting so lines from different threads don't interleave
+static STDOUT_LOCK: Mutex<()> = Mutex::new(());
+static STDERR_LOCK: Mutex<()> = Mutex::new(());
+
+fn writeFmt(fd: u32, args: fmt::Arguments) {
+    let mut out = BufferedFd::new(fd);
+    let _ = out.write_fmt(args);
+    let _ = out.flush();
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _guard = STDOUT_LOCK.lock();
+    writeFmt(STDOUT, args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    // a panic while printing must still get its message out, so don't wait for the lock
+    let _guard = STDERR_LOCK.try_lock();
+    writeFmt(STDERR, args);
+}
+
+/// Prints to standard output.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+/// Prints to standard output, appending a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Prints to standard error.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_eprint(format_args!($($arg)*)));
+}
+
+/// Prints to standard error, appending a newline.
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($fmt:expr) => ($crate::eprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::eprint!(concat!($fmt, "\n"), $($arg)*));
+}
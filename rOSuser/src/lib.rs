@@ -0,0 +1,66 @@
+//! Runtime for rOS user programs.
+//!
+//! `abi` holds the system call numbers, flags and error values shared with the kernel. With
+//! the `runtime` feature the crate also provides what a `#![no_std]`, `#![no_main]` program
+//! needs to run: `_start`, a panic handler, a heap on top of `brk`/`mmap` and wrappers for the
+//! file, process, thread and time system calls.
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use rOSuser::println;
+//!
+//! rOSuser::entry!(main);
+//!
+//! fn main() {
+//!     println!("Hello from user mode");
+//! }
+//! ```
+
+#![allow(non_snake_case)]
+#![no_std]
+
+#[cfg(feature = "runtime")]
+extern crate alloc;
+
+pub mod abi;
+
+#[cfg(feature = "runtime")]
+pub mod env;
+#[cfg(feature = "runtime")]
+pub mod fs;
+#[cfg(feature = "runtime")]
+pub mod io;
+#[cfg(feature = "runtime")]
+pub mod mem;
+#[cfg(feature = "runtime")]
+pub mod process;
+#[cfg(feature = "runtime")]
+pub mod sync;
+#[cfg(feature = "runtime")]
+pub mod syscall;
+#[cfg(feature = "runtime")]
+pub mod thread;
+#[cfg(feature = "runtime")]
+pub mod time;
+
+#[cfg(feature = "runtime")]
+mod heap;
+#[cfg(feature = "runtime")]
+mod start;
+
+pub use abi::Errno;
+
+/// Declares the program's entry point, `main` may return `()`, an `i32` exit status or a
+/// `Result` whose error is printed before exiting with status 1.
+#[cfg(feature = "runtime")]
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[unsafe(no_mangle)]
+        fn __rOSuser_main() -> i32 {
+            $crate::process::Termination::report($main())
+        }
+    };
+}
@@ -0,0 +1,38 @@
+//! Address space management: the program break and anonymous mappings.
+
+use crate::abi::{mmap, nr, Errno};
+use crate::syscall::{syscall1, syscall2, syscall6};
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub use crate::abi::mmap::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+
+/// Moves the program break to `addr` and returns the new break, `brk(0)` returns the current
+/// one. The break starts right after the loaded image and only ever covers whole pages.
+pub fn brk(addr: usize) -> Result<usize, Errno> {
+    Errno::from_return(unsafe { syscall1(nr::BRK, addr as u64) }).map(|v| v as usize)
+}
+
+/// Maps `len` bytes of zeroed memory, rounded up to whole pages, somewhere in the address space.
+pub fn map_anonymous(len: usize, prot: u32) -> Result<*mut u8, Errno> {
+    let ret = unsafe {
+        syscall6(
+            nr::MMAP,
+            0,
+            len as u64,
+            prot as u64,
+            (mmap::MAP_PRIVATE | mmap::MAP_ANONYMOUS) as u64,
+            u64::MAX,
+            0,
+        )
+    };
+    Errno::from_return(ret).map(|addr| addr as *mut u8)
+}
+
+/// Unmaps the pages covering `len` bytes at `addr`, which must be page aligned.
+///
+/// # Safety
+/// Nothing may use the range afterwards.
+pub unsafe fn unmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    Errno::from_return(unsafe { syscall2(nr::MUNMAP, addr as u64, len as u64) }).map(|_| ())
+}
@@ -0,0 +1,52 @@
+//! The calling process.
+
+use core::fmt::Debug;
+
+use crate::abi::{nr, Errno};
+use crate::eprintln;
+use crate::syscall::{syscall0, syscall1};
+
+/// Ends every thread of the process.
+pub fn exit(status: i32) -> ! {
+    unsafe { syscall1(nr::EXIT, status as i64 as u64) };
+    unreachable!("exit returned");
+}
+
+/// The process ID.
+pub fn id() -> u64 {
+    unsafe { syscall0(nr::GETPID) }
+}
+
+/// Ends every thread of process `pid`, which must be a user process.
+pub fn kill(pid: u64) -> Result<(), Errno> {
+    Errno::from_return(unsafe { syscall1(nr::KILL, pid) }).map(|_| ())
+}
+
+/// What `main` can return, turned into the exit status.
+pub trait Termination {
+    fn report(self) -> i32;
+}
+
+impl Termination for () {
+    fn report(self) -> i32 {
+        0
+    }
+}
+
+impl Termination for i32 {
+    fn report(self) -> i32 {
+        self
+    }
+}
+
+impl<T: Termination, E: Debug> Termination for Result<T, E> {
+    fn report(self) -> i32 {
+        match self {
+            Ok(value) => value.report(),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                1
+            }
+        }
+    }
+}
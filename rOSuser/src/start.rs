@@ -0,0 +1,44 @@
+//! Program entry and panic handling.
+
+use core::panic::PanicInfo;
+
+use crate::{env, eprintln, process};
+
+unsafe extern "Rust" {
+    // defined by `entry!`
+    fn __rOSuser_main() -> i32;
+}
+
+/// The loader jumps here with `rsp` pointing at `argc`, followed by the `argv` and `envp`
+/// arrays and the auxiliary vector. Not a call, so the stack still needs aligning.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn _start() -> ! {
+    core::arch::naked_asm!(
+        "xor ebp, ebp",
+        "mov rdi, rsp",
+        "and rsp, -16",
+        "call {start}",
+        "ud2",
+        start = sym startRust,
+    )
+}
+
+unsafe extern "C" fn startRust(initialStack: *const u64) -> ! {
+    unsafe {
+        let argc = *initialStack as usize;
+        let argv = initialStack.add(1) as *const *const u8;
+        let envp = argv.add(argc + 1);
+        env::init(argc, argv, envp);
+    }
+
+    let status = unsafe { __rOSuser_main() };
+    process::exit(status)
+}
+
+/// Panics end the whole process, like an abort.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    process::exit(101)
+}
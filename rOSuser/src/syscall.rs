@@ -0,0 +1,87 @@
+//! Raw system call instructions, see `abi` for the convention.
+//!
+//! These return the register as is, `Errno::from_return` splits off errors. All of them are
+//! unsafe for the same reason: the kernel may read or write memory through the arguments, so
+//! they must be valid for whatever call `n` is.
+
+#![allow(clippy::missing_safety_doc)]
+
+use core::arch::asm;
+
+#[inline(always)]
+pub unsafe fn syscall0(n: u64) -> u64 {
+    let ret;
+    unsafe {
+        asm!("syscall", inlateout("rax") n => ret, clobber_abi("C"), options(nostack));
+    }
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall1(n: u64, a0: u64) -> u64 {
+    let ret;
+    unsafe {
+        asm!("syscall", inlateout("rax") n => ret, in("rdi") a0, clobber_abi("C"), options(nostack));
+    }
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(n: u64, a0: u64, a1: u64) -> u64 {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") n => ret,
+            in("rdi") a0, in("rsi") a1,
+            clobber_abi("C"),
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(n: u64, a0: u64, a1: u64, a2: u64) -> u64 {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") n => ret,
+            in("rdi") a0, in("rsi") a1, in("rdx") a2,
+            clobber_abi("C"),
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(n: u64, a0: u64, a1: u64, a2: u64, a3: u64) -> u64 {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") n => ret,
+            in("rdi") a0, in("rsi") a1, in("rdx") a2, in("r10") a3,
+            clobber_abi("C"),
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(n: u64, a0: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> u64 {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") n => ret,
+            in("rdi") a0, in("rsi") a1, in("rdx") a2, in("r10") a3, in("r8") a4, in("r9") a5,
+            clobber_abi("C"),
+            options(nostack),
+        );
+    }
+    ret
+}
@@ -0,0 +1,111 @@
+//! Threads of the calling process.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::time::Duration;
+
+use crate::abi::{nr, Errno};
+use crate::mem::{self, PROT_READ, PROT_WRITE};
+use crate::syscall::{syscall0, syscall1, syscall4};
+
+/// Stack size of threads started by `spawn`.
+pub const STACK_SIZE: usize = 64 * 1024;
+
+type Main = Box<dyn FnOnce() + Send>;
+
+/// Gives up the rest of the time slice.
+pub fn yield_now() {
+    unsafe { syscall0(nr::YIELD) };
+}
+
+/// Blocks the calling thread for at least `duration`, rounded up to whole milliseconds.
+pub fn sleep(duration: Duration) {
+    let ms = duration.as_millis() + u128::from(!duration.subsec_nanos().is_multiple_of(1_000_000));
+    unsafe { syscall1(nr::SLEEP_MS, ms.min(u64::MAX as u128) as u64) };
+}
+
+/// ID of the calling thread.
+pub fn id() -> u64 {
+    unsafe { syscall0(nr::GETTID) }
+}
+
+/// Ends the calling thread. The process keeps running until its last thread exits.
+pub fn exit() -> ! {
+    unsafe { syscall0(nr::EXIT_THREAD) };
+    unreachable!("exit_thread returned");
+}
+
+// where a thread leaves its result for `join`
+struct Packet<T> {
+    result: UnsafeCell<Option<T>>,
+}
+
+// written once by the thread before it exits, read by `join` after the kernel saw it exit
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+pub struct JoinHandle<T> {
+    tid: u64,
+    stack: *mut u8,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> u64 {
+        self.tid
+    }
+
+    /// Waits for the thread to finish and returns what it returned.
+    pub fn join(self) -> T {
+        let ret = unsafe { syscall1(nr::THREAD_JOIN, self.tid) };
+        if let Err(e) = Errno::from_return(ret) {
+            panic!("Failed to join thread {}: {}", self.tid, e);
+        }
+        // the thread is gone, nothing runs on its stack anymore
+        let _ = unsafe { mem::unmap(self.stack, STACK_SIZE) };
+        unsafe { (*self.packet.result.get()).take() }.expect("Thread exited without a result")
+    }
+}
+
+/// First code of a new thread: `rdi` holds the boxed closure, the stack is aligned like
+/// after a call.
+extern "C" fn threadStart(main: *mut Main) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit()
+}
+
+/// Runs `f` on a new thread with its own `STACK_SIZE` stack.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, Errno>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet { result: UnsafeCell::new(None) });
+    let theirs = packet.clone();
+    let main: Main = Box::new(move || {
+        let result = f();
+        unsafe { *theirs.result.get() = Some(result) };
+    });
+
+    let stack = mem::map_anonymous(STACK_SIZE, PROT_READ | PROT_WRITE)?;
+    let main = Box::into_raw(Box::new(main));
+    let ret = unsafe {
+        syscall4(
+            nr::THREAD_SPAWN,
+            threadStart as *const () as u64,
+            main as u64,
+            stack as u64,
+            STACK_SIZE as u64,
+        )
+    };
+
+    match Errno::from_return(ret) {
+        Ok(tid) => Ok(JoinHandle { tid, stack, packet }),
+        Err(e) => {
+            drop(unsafe { Box::from_raw(main) });
+            let _ = unsafe { mem::unmap(stack, STACK_SIZE) };
+            Err(e)
+        }
+    }
+}
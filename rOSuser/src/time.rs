@@ -0,0 +1,29 @@
+//! Clocks.
+
+use core::time::Duration;
+
+use crate::abi::nr;
+use crate::syscall::syscall0;
+
+/// Time since the kernel started its timers, in milliseconds.
+pub fn uptime() -> Duration {
+    Duration::from_millis(unsafe { syscall0(nr::UPTIME_MS) })
+}
+
+/// A point on the monotonic uptime clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(uptime())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
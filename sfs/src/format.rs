@@ -0,0 +1,473 @@
+//! On-disk layout: the superblock and index entries.
+
+use crate::{Error, Result};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const SUPER_OFFSET: u64 = 0x18E;
+pub const SUPER_SIZE: usize = 42;
+pub const MAGIC: [u8; 3] = *b"SFS";
+pub const VERSION: u8 = 0x1A;
+
+/// Block size of new volumes, 512 bytes.
+pub const DEFAULT_BLOCK_SIZE_LOG: u8 = 2;
+
+pub const ENTRY_SIZE: usize = 64;
+
+// entry types
+pub const ENTRY_VOLUME_ID: u8 = 0x01;
+pub const ENTRY_START: u8 = 0x02;
+pub const ENTRY_UNUSED: u8 = 0x10;
+pub const ENTRY_DIR: u8 = 0x11;
+pub const ENTRY_FILE: u8 = 0x12;
+pub const ENTRY_UNUSABLE: u8 = 0x18;
+pub const ENTRY_DIR_DELETED: u8 = 0x19;
+pub const ENTRY_FILE_DELETED: u8 = 0x1A;
+
+// where the name starts in the first entry and how much of it fits there
+const DIR_NAME: usize = 11;
+const DIR_NAME_LEN: usize = 53;
+const FILE_NAME: usize = 35;
+const FILE_NAME_LEN: usize = 29;
+const VOLUME_NAME: usize = 12;
+pub const VOLUME_NAME_LEN: usize = 52;
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// The CRC byte for `data`, whose own CRC byte must still be 0.
+pub fn crc(data: &[u8]) -> u8 {
+    0u8.wrapping_sub(checksum(data))
+}
+
+pub(crate) fn u64At(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Entries that hold a name and continuations after them.
+pub fn hasContinuations(entryType: u8) -> bool {
+    matches!(entryType, ENTRY_DIR | ENTRY_FILE | ENTRY_DIR_DELETED | ENTRY_FILE_DELETED)
+}
+
+pub fn isEntryType(entryType: u8) -> bool {
+    matches!(
+        entryType,
+        ENTRY_VOLUME_ID
+            | ENTRY_START
+            | ENTRY_UNUSED
+            | ENTRY_DIR
+            | ENTRY_FILE
+            | ENTRY_UNUSABLE
+            | ENTRY_DIR_DELETED
+            | ENTRY_FILE_DELETED
+    )
+}
+
+/// Where the name of an entry starts and how much of it fits in the first entry.
+pub(crate) fn nameField(entryType: u8) -> Option<(usize, usize)> {
+    match entryType {
+        ENTRY_DIR | ENTRY_DIR_DELETED => Some((DIR_NAME, DIR_NAME_LEN)),
+        ENTRY_FILE | ENTRY_FILE_DELETED => Some((FILE_NAME, FILE_NAME_LEN)),
+        ENTRY_VOLUME_ID => Some((VOLUME_NAME, VOLUME_NAME_LEN)),
+        _ => None,
+    }
+}
+
+/// The name in an entry and its continuations, `None` if it isn't UTF-8.
+pub(crate) fn decodeName(bytes: &[u8], at: usize, len: usize) -> Option<String> {
+    let mut name = Vec::from(&bytes[at..at + len]);
+    name.extend_from_slice(&bytes[ENTRY_SIZE..]);
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    name.truncate(end);
+    String::from_utf8(name).ok()
+}
+
+/// Continuations a name of `len` bytes needs after `firstLen` bytes in the first entry. The name
+/// is NUL-terminated unless it fills the entries exactly, then another continuation follows for
+/// the NUL.
+pub(crate) fn continuationsFor(len: usize, firstLen: usize) -> usize {
+    (len + 1).saturating_sub(firstLen).div_ceil(ENTRY_SIZE)
+}
+
+#[derive(Debug, Clone)]
+pub struct SuperBlock {
+    pub timeStamp: i64,
+    /// Blocks from the end of the reserved area up to the last one in use.
+    pub dataSize: u64,
+    /// Bytes.
+    pub indexSize: u64,
+    pub totalBlocks: u64,
+    pub reservedBlocks: u32,
+    /// log2 of the block size minus 7.
+    pub blockSizeLog: u8,
+}
+
+impl SuperBlock {
+    /// `NotSfs` without the magic, `Corrupted` for a bad CRC or sizes that don't add up.
+    pub fn parse(raw: &[u8; SUPER_SIZE]) -> Result<Self> {
+        let superBlock = Self::decode(raw)?;
+        if checksum(raw) != 0 || superBlock.minimumBlocks()? > superBlock.totalBlocks {
+            return Err(Error::Corrupted);
+        }
+        Ok(superBlock)
+    }
+
+    /// Like `parse`, but only fails if the index can't be found. The CRC and the data area are
+    /// left for `fsck` to check.
+    pub fn decode(raw: &[u8; SUPER_SIZE]) -> Result<Self> {
+        if raw[24..27] != MAGIC {
+            return Err(Error::NotSfs);
+        }
+
+        let superBlock = Self {
+            timeStamp: u64At(raw, 0) as i64,
+            dataSize: u64At(raw, 8),
+            indexSize: u64At(raw, 16),
+            totalBlocks: u64At(raw, 28),
+            reservedBlocks: u32::from_le_bytes(raw[36..40].try_into().unwrap()),
+            blockSizeLog: raw[40],
+        };
+        if superBlock.blockSizeLog > 9
+            || superBlock.reservedBlocks == 0
+            || superBlock.indexSize == 0
+            || !superBlock.indexSize.is_multiple_of(ENTRY_SIZE as u64)
+        {
+            return Err(Error::Corrupted);
+        }
+        let blocks = superBlock.reservedBlocks as u64 + superBlock.indexBlocks();
+        if blocks > superBlock.totalBlocks {
+            return Err(Error::Corrupted);
+        }
+        Ok(superBlock)
+    }
+
+    pub fn encode(&self) -> [u8; SUPER_SIZE] {
+        let mut raw = [0u8; SUPER_SIZE];
+        raw[0..8].copy_from_slice(&self.timeStamp.to_le_bytes());
+        raw[8..16].copy_from_slice(&self.dataSize.to_le_bytes());
+        raw[16..24].copy_from_slice(&self.indexSize.to_le_bytes());
+        raw[24..27].copy_from_slice(&MAGIC);
+        raw[27] = VERSION;
+        raw[28..36].copy_from_slice(&self.totalBlocks.to_le_bytes());
+        raw[36..40].copy_from_slice(&self.reservedBlocks.to_le_bytes());
+        raw[40] = self.blockSizeLog;
+        raw[41] = crc(&raw);
+        raw
+    }
+
+    pub fn blockSize(&self) -> u64 {
+        1 << (self.blockSizeLog + 7)
+    }
+
+    pub fn indexBlocks(&self) -> u64 {
+        self.indexSize.div_ceil(self.blockSize())
+    }
+
+    /// First block of the index area, where the data area has to end.
+    pub fn indexStartBlock(&self) -> u64 {
+        self.totalBlocks - self.indexBlocks()
+    }
+
+    /// Byte offset of the first index entry.
+    pub fn indexStart(&self) -> u64 {
+        self.totalBlocks * self.blockSize() - self.indexSize
+    }
+
+    /// Size of the volume in bytes, `Corrupted` if that doesn't fit in a `u64`.
+    pub fn volumeSize(&self) -> Result<u64> {
+        self.totalBlocks.checked_mul(self.blockSize()).ok_or(Error::Corrupted)
+    }
+
+    /// Smallest the volume can get without moving data, `Corrupted` for a data area too large
+    /// to count.
+    pub fn minimumBlocks(&self) -> Result<u64> {
+        (self.reservedBlocks as u64 + self.indexBlocks())
+            .checked_add(self.dataSize)
+            .ok_or(Error::Corrupted)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Dir,
+    /// Blocks `start..=end` hold `length` bytes, an empty file has all three 0.
+    File { start: u64, end: u64, length: u64 },
+}
+
+/// A live file or directory in the index.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Full path, without a leading slash.
+    pub name: String,
+    pub timeStamp: i64,
+    pub kind: EntryKind,
+    /// Position in the index, in entries.
+    pub slot: usize,
+    /// The entry and its continuations.
+    pub slots: usize,
+}
+
+impl Entry {
+    /// The entry and its continuations as they go on disk.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let (entryType, nameAt, firstLen) = match self.kind {
+            EntryKind::Dir => (ENTRY_DIR, DIR_NAME, DIR_NAME_LEN),
+            EntryKind::File { .. } => (ENTRY_FILE, FILE_NAME, FILE_NAME_LEN),
+        };
+        let name = self.name.as_bytes();
+        let continuations = continuationsFor(name.len(), firstLen);
+        let continuationCount = u8::try_from(continuations).map_err(|_| Error::NameTooLong)?;
+
+        let mut raw = vec![0u8; ENTRY_SIZE * (1 + continuations)];
+        raw[0] = entryType;
+        raw[2] = continuationCount;
+        raw[3..11].copy_from_slice(&self.timeStamp.to_le_bytes());
+        if let EntryKind::File { start, end, length } = self.kind {
+            raw[11..19].copy_from_slice(&start.to_le_bytes());
+            raw[19..27].copy_from_slice(&end.to_le_bytes());
+            raw[27..35].copy_from_slice(&length.to_le_bytes());
+        }
+        let first = name.len().min(firstLen);
+        raw[nameAt..nameAt + first].copy_from_slice(&name[..first]);
+        raw[ENTRY_SIZE..ENTRY_SIZE + name.len() - first].copy_from_slice(&name[first..]);
+        raw[1] = crc(&raw);
+        Ok(raw)
+    }
+
+    /// The blocks the file has, also while it's being filled.
+    pub fn extent(&self) -> Option<(u64, u64)> {
+        match self.kind {
+            EntryKind::File { start, end, .. } if start > 0 => Some((start, end)),
+            _ => None,
+        }
+    }
+}
+
+/// An entry with nothing in it besides its type.
+pub fn markerEntry(entryType: u8) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[0] = entryType;
+    raw[1] = crc(&raw);
+    raw
+}
+
+/// `count` unused entries.
+pub fn unusedEntries(count: usize) -> Vec<u8> {
+    markerEntry(ENTRY_UNUSED).iter().copied().cycle().take(count * ENTRY_SIZE).collect()
+}
+
+pub fn volumeIdEntry(name: &str, timeStamp: i64) -> Result<[u8; ENTRY_SIZE]> {
+    if name.len() > VOLUME_NAME_LEN {
+        return Err(Error::NameTooLong);
+    }
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[0] = ENTRY_VOLUME_ID;
+    raw[3..11].copy_from_slice(&timeStamp.to_le_bytes());
+    raw[VOLUME_NAME..VOLUME_NAME + name.len()].copy_from_slice(name.as_bytes());
+    raw[1] = crc(&raw);
+    Ok(raw)
+}
+
+/// Blocks `start..=end` marked bad.
+pub fn unusableEntry(start: u64, end: u64) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[0] = ENTRY_UNUSABLE;
+    raw[10..18].copy_from_slice(&start.to_le_bytes());
+    raw[18..26].copy_from_slice(&end.to_le_bytes());
+    raw[1] = crc(&raw);
+    raw
+}
+
+/// What the index holds besides free slots.
+#[derive(Debug, Default)]
+pub struct Index {
+    pub entries: Vec<Entry>,
+    /// (slot, slots) of deleted entries.
+    pub deleted: Vec<(usize, usize)>,
+    /// Blocks marked bad.
+    pub unusable: Vec<(u64, u64)>,
+    pub volumeName: Option<String>,
+    /// Slots of entries skipped for a bad CRC, a name that isn't UTF-8 or continuations running
+    /// past the end.
+    pub bad: Vec<usize>,
+}
+
+pub fn parseIndex(raw: &[u8]) -> Index {
+    let mut index = Index::default();
+    let count = raw.len() / ENTRY_SIZE;
+    let mut slot = 0;
+    while slot < count {
+        let first = &raw[slot * ENTRY_SIZE..(slot + 1) * ENTRY_SIZE];
+        let slots = if hasContinuations(first[0]) { 1 + first[2] as usize } else { 1 };
+        let Some(bytes) = raw.get(slot * ENTRY_SIZE..(slot + slots) * ENTRY_SIZE) else {
+            index.bad.push(slot);
+            break;
+        };
+        // sfsTool.py leaves the CRC of unused entries out when it grows the index
+        if first[0] != ENTRY_UNUSED && checksum(bytes) != 0 {
+            index.bad.push(slot);
+            slot += 1;
+            continue;
+        }
+
+        let name = || nameField(first[0]).and_then(|(at, len)| decodeName(bytes, at, len));
+        let timeStamp = u64At(bytes, 3) as i64;
+        let live = match first[0] {
+            ENTRY_DIR => Some(name().map(|name| (name, EntryKind::Dir))),
+            ENTRY_FILE => Some(name().map(|name| {
+                let kind = EntryKind::File {
+                    start: u64At(bytes, 11),
+                    end: u64At(bytes, 19),
+                    length: u64At(bytes, 27),
+                };
+                (name, kind)
+            })),
+            ENTRY_DIR_DELETED | ENTRY_FILE_DELETED => {
+                index.deleted.push((slot, slots));
+                None
+            }
+            ENTRY_UNUSABLE => {
+                index.unusable.push((u64At(bytes, 10), u64At(bytes, 18)));
+                None
+            }
+            ENTRY_VOLUME_ID => {
+                index.volumeName = name();
+                None
+            }
+            _ => None,
+        };
+        match live {
+            Some(Some((name, kind))) => index.entries.push(Entry {
+                name,
+                timeStamp,
+                kind,
+                slot,
+                slots,
+            }),
+            Some(None) => index.bad.push(slot),
+            None => {}
+        }
+        slot += slots;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn entry(name: &str, kind: EntryKind) -> Entry {
+        Entry { name: name.to_string(), timeStamp: 0x1234_5678, kind, slot: 0, slots: 0 }
+    }
+
+    /// `entries` one after another behind a start marker, then a free slot.
+    fn index(entries: &[Entry]) -> Vec<u8> {
+        let mut raw = Vec::from(markerEntry(ENTRY_START));
+        for entry in entries {
+            raw.extend_from_slice(&entry.encode().unwrap());
+        }
+        raw.extend_from_slice(&unusedEntries(1));
+        raw
+    }
+
+    #[test]
+    fn continuationCounts() {
+        // the NUL has to fit too
+        assert_eq!(continuationsFor(0, FILE_NAME_LEN), 0);
+        assert_eq!(continuationsFor(FILE_NAME_LEN - 1, FILE_NAME_LEN), 0);
+        assert_eq!(continuationsFor(FILE_NAME_LEN, FILE_NAME_LEN), 1);
+        assert_eq!(continuationsFor(FILE_NAME_LEN + ENTRY_SIZE - 1, FILE_NAME_LEN), 1);
+        assert_eq!(continuationsFor(FILE_NAME_LEN + ENTRY_SIZE, FILE_NAME_LEN), 2);
+        assert_eq!(continuationsFor(DIR_NAME_LEN - 1, DIR_NAME_LEN), 0);
+        assert_eq!(continuationsFor(DIR_NAME_LEN, DIR_NAME_LEN), 1);
+    }
+
+    #[test]
+    fn encodedEntriesSumToZero() {
+        for len in [1, FILE_NAME_LEN - 1, FILE_NAME_LEN, 100, 300] {
+            let name = "n".repeat(len);
+            let raw = entry(&name, EntryKind::File { start: 3, end: 4, length: 700 }).encode().unwrap();
+            assert_eq!(raw.len(), ENTRY_SIZE * (1 + continuationsFor(len, FILE_NAME_LEN)));
+            assert_eq!(raw[2] as usize, raw.len() / ENTRY_SIZE - 1);
+            assert_eq!(checksum(&raw), 0);
+        }
+    }
+
+    #[test]
+    fn entriesRoundTrip() {
+        let entries = [
+            entry("a", EntryKind::Dir),
+            entry("a/file", EntryKind::File { start: 1, end: 2, length: 1000 }),
+            entry("empty", EntryKind::File { start: 0, end: 0, length: 0 }),
+            entry(&"d".repeat(DIR_NAME_LEN), EntryKind::Dir),
+            entry(&"long/".repeat(40), EntryKind::File { start: 7, end: 7, length: 1 }),
+        ];
+        let parsed = parseIndex(&index(&entries));
+        assert!(parsed.bad.is_empty());
+        assert_eq!(parsed.entries.len(), entries.len());
+
+        let mut slot = 1;
+        for (parsed, entry) in parsed.entries.iter().zip(&entries) {
+            assert_eq!(parsed.name, entry.name);
+            assert_eq!(parsed.kind, entry.kind);
+            assert_eq!(parsed.timeStamp, entry.timeStamp);
+            assert_eq!(parsed.slot, slot);
+            assert_eq!(parsed.slots, entry.encode().unwrap().len() / ENTRY_SIZE);
+            slot += parsed.slots;
+        }
+    }
+
+    #[test]
+    fn namesTooLongForTheContinuationCount() {
+        let name = "n".repeat(FILE_NAME_LEN + 255 * ENTRY_SIZE);
+        let result = entry(&name, EntryKind::File { start: 0, end: 0, length: 0 }).encode();
+        assert_eq!(result.unwrap_err(), Error::NameTooLong);
+    }
+
+    #[test]
+    fn damagedEntriesAreSkipped() {
+        let entries = [entry("first", EntryKind::Dir), entry("second", EntryKind::Dir)];
+        let mut raw = index(&entries);
+        raw[ENTRY_SIZE + DIR_NAME] ^= 1;
+        let parsed = parseIndex(&raw);
+        assert_eq!(parsed.bad, [1]);
+        assert_eq!(parsed.entries.len(), 1);
+        assert_eq!(parsed.entries[0].name, "second");
+    }
+
+    #[test]
+    fn superBlockRoundTrip() {
+        let superBlock = SuperBlock {
+            timeStamp: 42,
+            dataSize: 10,
+            indexSize: 1024,
+            totalBlocks: 100,
+            reservedBlocks: 1,
+            blockSizeLog: DEFAULT_BLOCK_SIZE_LOG,
+        };
+        let parsed = SuperBlock::parse(&superBlock.encode()).unwrap();
+        assert_eq!(parsed.dataSize, 10);
+        assert_eq!(parsed.indexSize, 1024);
+        assert_eq!(parsed.totalBlocks, 100);
+        assert_eq!(parsed.indexStartBlock(), 98);
+        assert_eq!(parsed.minimumBlocks(), Ok(13));
+    }
+
+    #[test]
+    fn superBlockSizesDontOverflow() {
+        let superBlock = SuperBlock {
+            timeStamp: 0,
+            dataSize: u64::MAX,
+            indexSize: 512,
+            totalBlocks: u64::MAX,
+            reservedBlocks: 1,
+            blockSizeLog: DEFAULT_BLOCK_SIZE_LOG,
+        };
+        assert_eq!(superBlock.minimumBlocks().unwrap_err(), Error::Corrupted);
+        assert_eq!(superBlock.volumeSize().unwrap_err(), Error::Corrupted);
+        assert_eq!(SuperBlock::parse(&superBlock.encode()).unwrap_err(), Error::Corrupted);
+    }
+}
@@ -0,0 +1,408 @@
+//! Checking a volume as a whole, and repairing what can be.
+//!
+//! `check` reads the superblock and the raw index without trusting either, `repair` marks the
+//! entries it found bad unused and writes the superblock again with a data area that holds
+//! what's left.
+
+use crate::format::*;
+use crate::{Device, Error, Result};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// The superblock doesn't sum to 0.
+    SuperBlockCrc,
+    /// The data area the superblock records runs into the index area.
+    DataOverlapsIndex { dataEnd: u64, indexStart: u64 },
+    /// Files reach past the end of the data area the superblock records.
+    DataSizeTooSmall { recorded: u64, needed: u64 },
+    /// The entry and its continuations don't sum to 0.
+    BadCrc { slot: usize },
+    /// More continuations than the index has room for, or a different number than the name
+    /// needs.
+    BadContinuations { slot: usize },
+    /// The name isn't UTF-8.
+    BadName { slot: usize },
+    /// Blocks in the reserved area or the index area, or fewer than the length needs.
+    OutOfRange { slot: usize, start: u64, end: u64 },
+    /// Blocks the entry at `other`, earlier on the volume, has too.
+    Overlap { slot: usize, other: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Problem::SuperBlockCrc => write!(f, "superblock: bad CRC"),
+            Problem::DataOverlapsIndex { dataEnd, indexStart } => write!(
+                f,
+                "superblock: data area ends at block {}, after the index starts at {}",
+                dataEnd, indexStart
+            ),
+            Problem::DataSizeTooSmall { recorded, needed } => write!(
+                f,
+                "superblock: data area is {} blocks, files need {}",
+                recorded, needed
+            ),
+            Problem::BadCrc { slot } => write!(f, "entry {}: bad CRC", slot),
+            Problem::BadContinuations { slot } => write!(f, "entry {}: bad continuation count", slot),
+            Problem::BadName { slot } => write!(f, "entry {}: name isn't UTF-8", slot),
+            Problem::OutOfRange { slot, start, end } => {
+                write!(f, "entry {}: blocks {}..={} out of range", slot, start, end)
+            }
+            Problem::Overlap { slot, other } => {
+                write!(f, "entry {}: blocks overlap those of entry {}", slot, other)
+            }
+        }
+    }
+}
+
+/// What `check` found.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub superBlock: SuperBlock,
+    pub problems: Vec<Problem>,
+    /// (slot, slots) of the bad entries, what `repair` marks unused.
+    bad: Vec<(usize, usize)>,
+    /// The data area size once the bad entries are gone.
+    dataSize: u64,
+}
+
+impl Report {
+    pub fn isClean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Slots an entry at `slot` takes that failed its CRC. Its continuation count can't be trusted,
+/// so continuations end at the first slot that looks like an entry of its own.
+fn badSpan(raw: &[u8], slot: usize) -> usize {
+    let count = raw.len() / ENTRY_SIZE;
+    let claimed = if hasContinuations(raw[slot * ENTRY_SIZE]) { raw[slot * ENTRY_SIZE + 2] as usize } else { 0 };
+    1 + (slot + 1..count.min(slot + 1 + claimed))
+        .take_while(|&next| !isEntryType(raw[next * ENTRY_SIZE]))
+        .count()
+}
+
+/// Checks the volume on `device`. Fails only if there's no superblock to find the index by.
+pub fn check<D: Device>(device: &mut D) -> Result<Report> {
+    let mut raw = [0u8; SUPER_SIZE];
+    device.read(SUPER_OFFSET, &mut raw)?;
+    let superBlock = SuperBlock::decode(&raw)?;
+    if superBlock.volumeSize()? > device.size() {
+        return Err(Error::Corrupted);
+    }
+    let mut problems = Vec::new();
+    if checksum(&raw) != 0 {
+        problems.push(Problem::SuperBlockCrc);
+    }
+
+    let mut raw = vec![0u8; superBlock.indexSize as usize];
+    device.read(superBlock.indexStart(), &mut raw)?;
+    let blockSize = superBlock.blockSize();
+    let dataStart = superBlock.reservedBlocks as u64;
+    let indexStart = superBlock.indexStartBlock();
+
+    let mut bad = Vec::new();
+    // (start, end, slot, slots) of the blocks in use
+    let mut extents = Vec::new();
+    let count = raw.len() / ENTRY_SIZE;
+    let mut slot = 0;
+    while slot < count {
+        let entryType = raw[slot * ENTRY_SIZE];
+        let slots = if hasContinuations(entryType) { 1 + raw[slot * ENTRY_SIZE + 2] as usize } else { 1 };
+        let Some(bytes) = raw.get(slot * ENTRY_SIZE..(slot + slots) * ENTRY_SIZE) else {
+            problems.push(Problem::BadContinuations { slot });
+            bad.push((slot, badSpan(&raw, slot)));
+            slot += 1;
+            continue;
+        };
+        // sfsTool.py leaves the CRC of unused entries out when it grows the index
+        if entryType != ENTRY_UNUSED && checksum(bytes) != 0 {
+            problems.push(Problem::BadCrc { slot });
+            let span = badSpan(&raw, slot);
+            bad.push((slot, span));
+            slot += span;
+            continue;
+        }
+
+        if let Some((at, firstLen)) = nameField(entryType) {
+            match decodeName(bytes, at, firstLen) {
+                None => {
+                    problems.push(Problem::BadName { slot });
+                    bad.push((slot, slots));
+                }
+                Some(name) if hasContinuations(entryType) && continuationsFor(name.len(), firstLen) != slots - 1 => {
+                    problems.push(Problem::BadContinuations { slot });
+                    bad.push((slot, slots));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let extent = match entryType {
+            ENTRY_FILE => {
+                let (start, end, length) = (u64At(bytes, 11), u64At(bytes, 19), u64At(bytes, 27));
+                let fits = if start == 0 {
+                    length == 0
+                } else {
+                    start >= dataStart && end >= start && end < indexStart && length <= (end - start + 1) * blockSize
+                };
+                if !fits {
+                    problems.push(Problem::OutOfRange { slot, start, end });
+                    bad.push((slot, slots));
+                }
+                (fits && start > 0).then_some((start, end))
+            }
+            ENTRY_UNUSABLE => {
+                let (start, end) = (u64At(bytes, 10), u64At(bytes, 18));
+                let fits = start >= dataStart && end >= start && end < indexStart;
+                if !fits {
+                    problems.push(Problem::OutOfRange { slot, start, end });
+                    bad.push((slot, slots));
+                }
+                fits.then_some((start, end))
+            }
+            _ => None,
+        };
+        if let Some((start, end)) = extent {
+            extents.push((start, end, slot, slots));
+        }
+        slot += slots;
+    }
+
+    // the extent that starts later gives way
+    extents.sort_unstable();
+    let mut last: Option<(u64, usize)> = None;
+    for &(start, end, slot, slots) in &extents {
+        if bad.iter().any(|&(badSlot, _)| badSlot == slot) {
+            continue;
+        }
+        match last {
+            Some((lastEnd, other)) if start <= lastEnd => {
+                problems.push(Problem::Overlap { slot, other });
+                bad.push((slot, slots));
+            }
+            _ => last = Some((end, slot)),
+        }
+    }
+
+    let used = extents
+        .iter()
+        .filter(|&&(_, _, slot, _)| !bad.iter().any(|&(badSlot, _)| badSlot == slot))
+        .map(|&(_, end, _, _)| end + 1)
+        .max()
+        .unwrap_or(dataStart);
+    let dataSize = used - dataStart;
+    let dataEnd = dataStart.checked_add(superBlock.dataSize).ok_or(Error::Corrupted)?;
+    if dataEnd > indexStart {
+        problems.push(Problem::DataOverlapsIndex { dataEnd, indexStart });
+    } else if superBlock.dataSize < dataSize {
+        problems.push(Problem::DataSizeTooSmall { recorded: superBlock.dataSize, needed: dataSize });
+    }
+
+    Ok(Report { superBlock, problems, bad, dataSize })
+}
+
+/// Marks the bad entries `check` found unused and writes the superblock again.
+pub fn repair<D: Device>(device: &mut D, report: &Report) -> Result<()> {
+    let indexStart = report.superBlock.indexStart();
+    for &(slot, slots) in &report.bad {
+        device.write(indexStart + (slot * ENTRY_SIZE) as u64, &unusedEntries(slots))?;
+    }
+
+    let mut superBlock = report.superBlock.clone();
+    superBlock.dataSize = report.dataSize;
+    superBlock.timeStamp = device.timeStamp();
+    device.write(SUPER_OFFSET, &superBlock.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryDevice, NodeKind, Volume};
+
+    const BLOCK: usize = 512;
+
+    /// A volume with the files `a` and `b` of two blocks each and the directory `dir`.
+    fn image() -> Volume<MemoryDevice> {
+        let mut volume = Volume::format(MemoryDevice(vec![0; 64 * BLOCK]), "test").unwrap();
+        for (name, byte) in [("a", 1), ("b", 2)] {
+            volume.create(name, NodeKind::File).unwrap();
+            volume.write(name, 0, &[byte; BLOCK + 1]).unwrap();
+        }
+        volume.create("dir", NodeKind::Directory).unwrap();
+        volume
+    }
+
+    fn superBlock(device: &mut MemoryDevice) -> SuperBlock {
+        let mut raw = [0u8; SUPER_SIZE];
+        device.read(SUPER_OFFSET, &mut raw).unwrap();
+        SuperBlock::decode(&raw).unwrap()
+    }
+
+    /// The device of `volume` after `corrupt` changed the entry named `name` and its
+    /// continuations, with its CRC fixed again if `fixCrc`.
+    fn corruptEntry(volume: Volume<MemoryDevice>, name: &str, fixCrc: bool, corrupt: impl FnOnce(&mut [u8])) -> MemoryDevice {
+        let entry = volume.find(name).unwrap().clone();
+        let mut device = volume.device().clone();
+        let at = (superBlock(&mut device).indexStart() as usize) + entry.slot * ENTRY_SIZE;
+        let bytes = &mut device.0[at..at + entry.slots * ENTRY_SIZE];
+        corrupt(bytes);
+        if fixCrc {
+            bytes[1] = 0;
+            bytes[1] = crc(bytes);
+        }
+        device
+    }
+
+    fn corruptSuperBlock(volume: Volume<MemoryDevice>, corrupt: impl FnOnce(&mut SuperBlock)) -> MemoryDevice {
+        let mut device = volume.device().clone();
+        let mut superBlock = superBlock(&mut device);
+        corrupt(&mut superBlock);
+        device.write(SUPER_OFFSET, &superBlock.encode()).unwrap();
+        device
+    }
+
+    /// Checks `device`, expecting `problems`, then repairs it and checks it's clean.
+    fn checkAndRepair(mut device: MemoryDevice, problems: &[Problem]) -> Volume<MemoryDevice> {
+        let report = check(&mut device).unwrap();
+        assert_eq!(report.problems, problems);
+        repair(&mut device, &report).unwrap();
+        assert!(check(&mut device).unwrap().isClean());
+        Volume::open(device).unwrap()
+    }
+
+    fn names(volume: &Volume<MemoryDevice>) -> Vec<&str> {
+        volume.entries().iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn cleanVolume() {
+        let volume = image();
+        let report = check(&mut volume.device().clone()).unwrap();
+        assert!(report.isClean());
+        assert_eq!(report.dataSize, 4);
+    }
+
+    #[test]
+    fn superBlockCrc() {
+        let mut device = image().device().clone();
+        device.0[SUPER_OFFSET as usize + SUPER_SIZE - 1] ^= 0xFF;
+        assert_eq!(Volume::open(device.clone()).err(), Some(crate::Error::Corrupted));
+        let volume = checkAndRepair(device, &[Problem::SuperBlockCrc]);
+        assert_eq!(names(&volume), ["a", "b", "dir"]);
+    }
+
+    #[test]
+    fn dataOverlapsIndex() {
+        let device = corruptSuperBlock(image(), |superBlock| superBlock.dataSize = 63);
+        let volume = checkAndRepair(device, &[Problem::DataOverlapsIndex { dataEnd: 64, indexStart: 63 }]);
+        assert_eq!(volume.superBlock().dataSize, 4);
+    }
+
+    #[test]
+    fn dataSizeTooSmall() {
+        let device = corruptSuperBlock(image(), |superBlock| superBlock.dataSize = 1);
+        let volume = checkAndRepair(device, &[Problem::DataSizeTooSmall { recorded: 1, needed: 4 }]);
+        assert_eq!(volume.superBlock().dataSize, 4);
+    }
+
+    #[test]
+    fn dataSizePastU64() {
+        let mut device = corruptSuperBlock(image(), |superBlock| superBlock.dataSize = u64::MAX);
+        assert_eq!(check(&mut device).unwrap_err(), crate::Error::Corrupted);
+    }
+
+    #[test]
+    fn badCrc() {
+        let volume = image();
+        let slot = volume.find("b").unwrap().slot;
+        let device = corruptEntry(volume, "b", false, |bytes| bytes[3] ^= 1);
+        let volume = checkAndRepair(device, &[Problem::BadCrc { slot }]);
+        assert_eq!(names(&volume), ["a", "dir"]);
+        // the blocks of `b` are free again
+        assert_eq!(volume.superBlock().dataSize, 2);
+    }
+
+    #[test]
+    fn continuationsPastTheEnd() {
+        let volume = image();
+        let slot = volume.find("dir").unwrap().slot;
+        let device = corruptEntry(volume, "dir", false, |bytes| bytes[2] = 255);
+        let volume = checkAndRepair(device, &[Problem::BadContinuations { slot }]);
+        assert_eq!(names(&volume), ["a", "b"]);
+    }
+
+    #[test]
+    fn continuationsTheNameDoesntNeed() {
+        let volume = image();
+        let slot = volume.find("dir").unwrap().slot;
+        // swallows the unused entry after it, which sums to 0 on its own
+        let device = corruptEntry(volume, "dir", true, |bytes| bytes[2] = 1);
+        let volume = checkAndRepair(device, &[Problem::BadContinuations { slot }]);
+        assert_eq!(names(&volume), ["a", "b"]);
+    }
+
+    #[test]
+    fn badName() {
+        let volume = image();
+        let slot = volume.find("a").unwrap().slot;
+        let (nameAt, _) = nameField(ENTRY_FILE).unwrap();
+        let device = corruptEntry(volume, "a", true, |bytes| bytes[nameAt] = 0xFF);
+        let volume = checkAndRepair(device, &[Problem::BadName { slot }]);
+        assert_eq!(names(&volume), ["b", "dir"]);
+    }
+
+    #[test]
+    fn outOfRange() {
+        let volume = image();
+        let slot = volume.find("b").unwrap().slot;
+        let device = corruptEntry(volume, "b", true, |bytes| bytes[19..27].copy_from_slice(&63u64.to_le_bytes()));
+        let volume = checkAndRepair(device, &[Problem::OutOfRange { slot, start: 3, end: 63 }]);
+        assert_eq!(names(&volume), ["a", "dir"]);
+        assert_eq!(volume.superBlock().dataSize, 2);
+    }
+
+    #[test]
+    fn lengthPastTheBlocks() {
+        let volume = image();
+        let slot = volume.find("a").unwrap().slot;
+        let length = (2 * BLOCK + 1) as u64;
+        let device = corruptEntry(volume, "a", true, |bytes| bytes[27..35].copy_from_slice(&length.to_le_bytes()));
+        checkAndRepair(device, &[Problem::OutOfRange { slot, start: 1, end: 2 }]);
+    }
+
+    #[test]
+    fn overlap() {
+        let volume = image();
+        let (a, b) = (volume.find("a").unwrap().slot, volume.find("b").unwrap().slot);
+        let device = corruptEntry(volume, "b", true, |bytes| {
+            bytes[11..19].copy_from_slice(&2u64.to_le_bytes());
+            bytes[19..27].copy_from_slice(&3u64.to_le_bytes());
+        });
+        let mut volume = checkAndRepair(device, &[Problem::Overlap { slot: b, other: a }]);
+        assert_eq!(names(&volume), ["a", "dir"]);
+        let mut data = [0; BLOCK + 1];
+        volume.read("a", 0, &mut data).unwrap();
+        assert_eq!(data, [1; BLOCK + 1]);
+    }
+
+    #[test]
+    fn severalProblems() {
+        let volume = image();
+        let (a, b) = (volume.find("a").unwrap().slot, volume.find("b").unwrap().slot);
+        let mut device = corruptEntry(volume, "a", false, |bytes| bytes[3] ^= 1);
+        device.0[SUPER_OFFSET as usize + SUPER_SIZE - 1] ^= 0xFF;
+        assert!(Volume::open(device.clone()).is_err());
+
+        let mut volume = checkAndRepair(device, &[Problem::SuperBlockCrc, Problem::BadCrc { slot: a }]);
+        assert_eq!(names(&volume), ["b", "dir"]);
+        assert_eq!(volume.find("b").unwrap().slot, b);
+        // what's left works as usual
+        volume.create("c", NodeKind::File).unwrap();
+        volume.write("c", 0, &[3; 10]).unwrap();
+        assert_eq!(volume.find("c").unwrap().extent(), Some((1, 1)));
+    }
+}
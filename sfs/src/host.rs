@@ -0,0 +1,151 @@
+//! Volumes in image files, and building one from a host directory.
+
+use crate::volume::{NodeKind, Volume};
+use crate::{Device, Error, Result};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+/// Smallest image `createImage` makes.
+const MIN_IMAGE_SIZE: u64 = 64 * 1024;
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Error::NoSpace => io::ErrorKind::StorageFull,
+            Error::NotSfs | Error::Corrupted => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// An image file.
+pub struct FileDevice {
+    file: File,
+    size: u64,
+}
+
+impl FileDevice {
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+
+    pub fn open(path: &Path, writable: bool) -> io::Result<Self> {
+        Self::new(File::options().read(true).write(writable).open(path)?)
+    }
+
+    /// A new image file of `size` bytes, replacing whatever was at `path`.
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let file = File::create(path)?;
+        file.set_len(size)?;
+        Self::new(file)
+    }
+}
+
+impl Device for FileDevice {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).map_err(|_| Error::Io)?;
+        self.file.read_exact(buf).map_err(|_| Error::Io)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).map_err(|_| Error::Io)?;
+        self.file.write_all(buf).map_err(|_| Error::Io)
+    }
+
+    fn setSize(&mut self, size: u64) -> Result<()> {
+        self.file.set_len(size).map_err(|_| Error::Io)?;
+        self.size = size;
+        Ok(())
+    }
+
+    fn timeStamp(&self) -> i64 {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        (seconds as i64) << 16
+    }
+}
+
+/// Copies the host file `from` to `path` on the volume, creating the directories above it.
+pub fn addFile(volume: &mut Volume<FileDevice>, from: &Path, path: &str) -> io::Result<()> {
+    let data = fs::read(from)?;
+    volume.createParents(path)?;
+    volume.create(path, NodeKind::File)?;
+    volume.write(path, 0, &data)?;
+    Ok(())
+}
+
+/// Files under `dir` to put in an image, as (host path, volume path), directories without
+/// files included with no host path. Names in `ignore` are left out wherever they are.
+pub fn scanDir(dir: &Path, ignore: &[String]) -> io::Result<Vec<(Option<PathBuf>, String)>> {
+    fn walk(
+        dir: &Path,
+        prefix: &str,
+        ignore: &[String],
+        found: &mut Vec<(Option<PathBuf>, String)>,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if ignore.contains(&name) {
+                continue;
+            }
+            let path = if prefix.is_empty() { name } else { std::format!("{}/{}", prefix, name) };
+            if entry.file_type()?.is_dir() {
+                found.push((None, path.clone()));
+                walk(&entry.path(), &path, ignore, found)?;
+            } else {
+                found.push((Some(entry.path()), path));
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = Vec::new();
+    walk(dir, "", ignore, &mut found)?;
+    Ok(found)
+}
+
+/// Makes an image at `image` holding everything under `dir`, a power of two bytes large and at
+/// least 64 KiB. Calls `added` with every volume path put in it.
+pub fn createImage(image: &Path, dir: &Path, ignore: &[String], mut added: impl FnMut(&str)) -> io::Result<()> {
+    let files = scanDir(dir, ignore)?;
+    let blockSize = 512u64;
+    let mut dataBlocks = 0;
+    let mut indexEntries = 2;
+    for (host, path) in &files {
+        if let Some(host) = host {
+            dataBlocks += fs::metadata(host)?.len().div_ceil(blockSize);
+        }
+        // room for a continuation per 64 bytes of name
+        indexEntries += 2 + path.len() as u64 / 64;
+    }
+    let total = 1 + dataBlocks + (indexEntries * 64).div_ceil(blockSize);
+    let size = (total * blockSize).max(MIN_IMAGE_SIZE).next_power_of_two();
+
+    let mut volume = Volume::format(FileDevice::create(image, size)?, "SFS_VOLUME")?;
+    for (host, path) in &files {
+        match host {
+            Some(host) => addFile(&mut volume, host, path)?,
+            None => {
+                volume.createParents(path)?;
+                volume.create(path, NodeKind::Directory)?;
+            }
+        }
+        added(path);
+    }
+    Ok(())
+}
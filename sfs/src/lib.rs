@@ -0,0 +1,120 @@
+//! SimpleFS (SFS) 1.10 volumes, shared by the kernel driver and the host image tool.
+//!
+//! Block 0 holds the superblock at 0x18E, the data area follows the reserved blocks and the
+//! index area takes the end of the volume, growing towards the data a block at a time. The
+//! index is a list of 64-byte entries naming every file and directory by its full path, a name
+//! too long for its entry goes on in continuation entries right after it. A CRC byte makes the
+//! bytes of an entry and its continuations sum to 0. Every file is one contiguous run of
+//! blocks.
+//!
+//! `Volume` works on anything implementing `Device`, the kernel's block devices and image files
+//! on the host (`host`, with the `std` feature). `fsck` checks and repairs a volume before it's
+//! opened.
+
+#![allow(non_snake_case)]
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod format;
+pub mod fsck;
+#[cfg(feature = "std")]
+pub mod host;
+mod volume;
+
+pub use format::{Entry, EntryKind, SuperBlock};
+pub use volume::{NodeKind, Volume};
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No SFS magic in the superblock.
+    NotSfs,
+    /// On-disk structures that don't make sense.
+    Corrupted,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    NameTooLong,
+    /// No room left in the data area, or for the index to grow.
+    NoSpace,
+    NotSupported,
+    /// The device failed.
+    Io,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::NotSfs => "not an SFS volume",
+            Error::Corrupted => "volume is corrupted",
+            Error::NotFound => "no such file or directory",
+            Error::AlreadyExists => "already exists",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::NotEmpty => "directory not empty",
+            Error::NameTooLong => "name too long",
+            Error::NoSpace => "no space left on the volume",
+            Error::NotSupported => "not supported",
+            Error::Io => "I/O error",
+        })
+    }
+}
+
+/// Where a volume is stored, addressed in bytes.
+pub trait Device {
+    /// Size in bytes.
+    fn size(&self) -> u64;
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()>;
+
+    /// Grows or shrinks the device, only image files can.
+    fn setSize(&mut self, _size: u64) -> Result<()> {
+        Err(Error::NotSupported)
+    }
+
+    /// The time as an SFS time stamp, in 1/65536 seconds since 1970.
+    fn timeStamp(&self) -> i64 {
+        0
+    }
+}
+
+/// A volume held in memory, for the tests.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct MemoryDevice(pub alloc::vec::Vec<u8>);
+
+#[cfg(test)]
+impl Device for MemoryDevice {
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let start = offset as usize;
+        let bytes = self.0.get(start..start + buf.len()).ok_or(Error::Io)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let start = offset as usize;
+        let bytes = self.0.get_mut(start..start + buf.len()).ok_or(Error::Io)?;
+        bytes.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn setSize(&mut self, size: u64) -> Result<()> {
+        self.0.resize(size as usize, 0);
+        Ok(())
+    }
+}
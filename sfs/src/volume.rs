@@ -0,0 +1,820 @@
+//! Files and directories on an SFS volume, by path.
+//!
+//! The index is kept in memory and every change is written straight through. Deleted entries
+//! keep their 0x19/0x1A type until their slots are needed for new entries. Directories needn't
+//! have an entry of their own, any path with entries below it is one.
+
+use crate::format::*;
+use crate::{Device, Error, Result};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Largest piece of file data moved at once.
+const COPY_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+pub struct Volume<D: Device> {
+    device: D,
+    superBlock: SuperBlock,
+    // the index area as on disk
+    raw: Vec<u8>,
+    index: Index,
+}
+
+/// Whether `name` is `dir` or somewhere below it, the root being "".
+fn isBelow(name: &str, dir: &str) -> bool {
+    dir.is_empty() || name.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl<D: Device> Volume<D> {
+    /// Reads the superblock and index of the volume on `device`. Entries with a bad CRC are
+    /// skipped, `badEntries` tells where they are.
+    pub fn open(mut device: D) -> Result<Self> {
+        let mut raw = [0u8; SUPER_SIZE];
+        device.read(SUPER_OFFSET, &mut raw)?;
+        let superBlock = SuperBlock::parse(&raw)?;
+        if superBlock.volumeSize()? > device.size() {
+            return Err(Error::Corrupted);
+        }
+
+        let mut raw = vec![0u8; superBlock.indexSize as usize];
+        device.read(superBlock.indexStart(), &mut raw)?;
+        let index = parseIndex(&raw);
+        Ok(Self { device, superBlock, raw, index })
+    }
+
+    /// Makes an empty volume filling `device`, with 512-byte blocks and a one block index.
+    pub fn format(mut device: D, volumeName: &str) -> Result<Self> {
+        let blockSize = 1u64 << (DEFAULT_BLOCK_SIZE_LOG + 7);
+        let totalBlocks = device.size() / blockSize;
+        if totalBlocks < 3 {
+            return Err(Error::NoSpace);
+        }
+        let timeStamp = device.timeStamp();
+        let superBlock = SuperBlock {
+            timeStamp,
+            dataSize: 0,
+            indexSize: blockSize,
+            totalBlocks,
+            reservedBlocks: 1,
+            blockSizeLog: DEFAULT_BLOCK_SIZE_LOG,
+        };
+
+        // the start marker first and the volume name last
+        let mut raw = unusedEntries(blockSize as usize / ENTRY_SIZE);
+        raw[..ENTRY_SIZE].copy_from_slice(&markerEntry(ENTRY_START));
+        let last = raw.len() - ENTRY_SIZE;
+        raw[last..].copy_from_slice(&volumeIdEntry(volumeName, timeStamp)?);
+
+        device.write(0, &vec![0u8; blockSize as usize])?;
+        device.write(SUPER_OFFSET, &superBlock.encode())?;
+        device.write(superBlock.indexStart(), &raw)?;
+        let index = parseIndex(&raw);
+        Ok(Self { device, superBlock, raw, index })
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn superBlock(&self) -> &SuperBlock {
+        &self.superBlock
+    }
+
+    pub fn volumeName(&self) -> Option<&str> {
+        self.index.volumeName.as_deref()
+    }
+
+    /// Every live file and directory entry, in index order.
+    pub fn entries(&self) -> &[Entry] {
+        &self.index.entries
+    }
+
+    /// Index slots of the entries that couldn't be read.
+    pub fn badEntries(&self) -> &[usize] {
+        &self.index.bad
+    }
+
+    /// Blocks marked bad, as (first, last).
+    pub fn unusable(&self) -> &[(u64, u64)] {
+        &self.index.unusable
+    }
+
+    /// The index area as on disk.
+    pub fn rawIndex(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn find(&self, path: &str) -> Option<&Entry> {
+        self.index.entries.iter().find(|entry| entry.name == path)
+    }
+
+    pub fn kind(&self, path: &str) -> Option<NodeKind> {
+        match self.find(path).map(|entry| entry.kind) {
+            Some(EntryKind::File { .. }) => Some(NodeKind::File),
+            _ if self.isDirectory(path) => Some(NodeKind::Directory),
+            _ => None,
+        }
+    }
+
+    pub fn isDirectory(&self, path: &str) -> bool {
+        path.is_empty()
+            || self.index.entries.iter().any(|entry| {
+                entry.name == path && entry.kind == EntryKind::Dir || isBelow(&entry.name, path)
+            })
+    }
+
+    /// The names directly in the directory at `path`.
+    pub fn children(&self, path: &str) -> BTreeMap<String, NodeKind> {
+        let mut children = BTreeMap::new();
+        for entry in &self.index.entries {
+            let rest = if path.is_empty() {
+                Some(entry.name.as_str())
+            } else {
+                entry.name.strip_prefix(path).and_then(|rest| rest.strip_prefix('/'))
+            };
+            let Some(rest) = rest.filter(|rest| !rest.is_empty()) else {
+                continue;
+            };
+            match rest.split_once('/') {
+                Some((dir, _)) => children.insert(String::from(dir), NodeKind::Directory),
+                None if entry.kind == EntryKind::Dir => children.insert(String::from(rest), NodeKind::Directory),
+                None => children.insert(String::from(rest), NodeKind::File),
+            };
+        }
+        children
+    }
+
+    /// Adds an empty file or a directory at `path`. The directory it's in isn't checked.
+    pub fn create(&mut self, path: &str, kind: NodeKind) -> Result<()> {
+        if path.is_empty() || self.kind(path).is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        let entry = Entry {
+            name: String::from(path),
+            timeStamp: self.device.timeStamp(),
+            kind: match kind {
+                NodeKind::File => EntryKind::File { start: 0, end: 0, length: 0 },
+                NodeKind::Directory => EntryKind::Dir,
+            },
+            slot: 0,
+            slots: 0,
+        };
+        self.insert(entry)
+    }
+
+    /// Gives every directory above `path` an entry of its own, like `sfsTool.py` does.
+    pub fn createParents(&mut self, path: &str) -> Result<()> {
+        for (end, _) in path.match_indices('/') {
+            let dir = &path[..end];
+            match self.find(dir).map(|entry| entry.kind) {
+                Some(EntryKind::Dir) => {}
+                Some(EntryKind::File { .. }) => return Err(Error::NotADirectory),
+                None => self.insert(Entry {
+                    name: String::from(dir),
+                    timeStamp: self.device.timeStamp(),
+                    kind: EntryKind::Dir,
+                    slot: 0,
+                    slots: 0,
+                })?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the file at `path`.
+    pub fn unlink(&mut self, path: &str) -> Result<()> {
+        match self.kind(path) {
+            Some(NodeKind::File) => self.delete(path),
+            Some(NodeKind::Directory) => Err(Error::IsADirectory),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Removes the empty directory at `path`.
+    pub fn rmdir(&mut self, path: &str) -> Result<()> {
+        match self.kind(path) {
+            Some(NodeKind::Directory) if path.is_empty() => Err(Error::NotSupported),
+            Some(NodeKind::Directory) if !self.children(path).is_empty() => Err(Error::NotEmpty),
+            Some(NodeKind::Directory) => self.delete(path),
+            Some(NodeKind::File) => Err(Error::NotADirectory),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Renaming a directory renames everything in it, as every entry holds its full path.
+    pub fn rename(&mut self, oldPath: &str, newPath: &str) -> Result<()> {
+        if self.kind(newPath).is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        let renamed: Vec<String> = self
+            .index
+            .entries
+            .iter()
+            .filter(|entry| entry.name == oldPath || isBelow(&entry.name, oldPath))
+            .map(|entry| entry.name.clone())
+            .collect();
+        if oldPath.is_empty() || renamed.is_empty() {
+            return Err(Error::NotFound);
+        }
+        for name in renamed {
+            let newName = alloc::format!("{}{}", newPath, &name[oldPath.len()..]);
+            self.update(&name, |entry| entry.name = newName)?;
+        }
+        Ok(())
+    }
+
+    /// Length of the file at `path`.
+    pub fn length(&self, path: &str) -> Result<u64> {
+        match self.find(path).map(|entry| entry.kind) {
+            Some(EntryKind::File { length, .. }) => Ok(length),
+            Some(EntryKind::Dir) => Err(Error::IsADirectory),
+            None if self.isDirectory(path) => Err(Error::IsADirectory),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Reads from the file at `path`, no further than its end.
+    pub fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let length = self.length(path)?;
+        let Some((start, _)) = self.find(path).and_then(Entry::extent) else {
+            return Ok(0);
+        };
+        if offset >= length {
+            return Ok(0);
+        }
+
+        let n = buf.len().min((length - offset) as usize);
+        self.device.read(start * self.superBlock.blockSize() + offset, &mut buf[..n])?;
+        Ok(n)
+    }
+
+    /// Writes to the file at `path`, moving it if it has to grow and the blocks after it are
+    /// taken. Writing past the end fills the gap with zeroes.
+    pub fn write(&mut self, path: &str, offset: u64, buf: &[u8]) -> Result<usize> {
+        let length = self.length(path)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::NoSpace)?;
+
+        let newLength = length.max(end);
+        let start = self.reserve(path, newLength)?;
+        let base = start * self.superBlock.blockSize();
+        if offset > length {
+            self.zero(base + length, offset - length)?;
+        }
+        self.device.write(base + offset, buf)?;
+        self.setLength(path, newLength)?;
+        Ok(buf.len())
+    }
+
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<()> {
+        let length = self.length(path)?;
+        if size > length {
+            let start = self.reserve(path, size)?;
+            self.zero(start * self.superBlock.blockSize() + length, size - length)?;
+        }
+        self.setLength(path, size)
+    }
+
+    /// Makes the volume `totalBlocks` long, moving the index to the new end. Image files grow
+    /// and shrink with it, other devices have to be large enough already.
+    pub fn resize(&mut self, totalBlocks: u64) -> Result<()> {
+        if totalBlocks < self.superBlock.minimumBlocks()? {
+            return Err(Error::NoSpace);
+        }
+        let blockSize = self.superBlock.blockSize();
+        let newSize = totalBlocks.checked_mul(blockSize).ok_or(Error::NoSpace)?;
+        if newSize > self.device.size() {
+            self.device.setSize(newSize)?;
+        }
+
+        let oldStart = self.superBlock.indexStart();
+        self.superBlock.totalBlocks = totalBlocks;
+        let newStart = self.superBlock.indexStart();
+        self.device.write(newStart, &self.raw)?;
+        if newStart > oldStart {
+            // what's left of the old index would look like data
+            let stale = (newStart - oldStart).min(self.superBlock.indexSize);
+            self.zero(oldStart, stale)?;
+        }
+        self.writeSuper()?;
+
+        if newSize < self.device.size() {
+            match self.device.setSize(newSize) {
+                Ok(()) | Err(Error::NotSupported) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Cuts the volume down to the reserved blocks, data area and index.
+    pub fn shrink(&mut self) -> Result<()> {
+        self.resize(self.superBlock.minimumBlocks()?)
+    }
+
+    /// Moves every file down to close the gaps between them, and rewrites the index without
+    /// deleted entries.
+    pub fn defrag(&mut self) -> Result<()> {
+        let mut files: Vec<(u64, u64, String)> = self
+            .index
+            .entries
+            .iter()
+            .filter_map(|entry| entry.extent().map(|(start, end)| (start, end, entry.name.clone())))
+            .collect();
+        files.sort_unstable();
+
+        let mut next = self.superBlock.reservedBlocks as u64;
+        for (start, end, name) in files {
+            let blocks = end - start + 1;
+            // bad blocks stay where they are, files go around them
+            while let Some(&(_, bad)) = self.index.unusable.iter().find(|&&(first, last)| first < next + blocks && last >= next) {
+                next = bad + 1;
+            }
+            if next != start {
+                self.copyBlocks(start, next, blocks)?;
+                self.update(&name, |entry| {
+                    if let EntryKind::File { length, .. } = entry.kind {
+                        entry.kind = EntryKind::File { start: next, end: next + blocks - 1, length };
+                    }
+                })?;
+            }
+            next += blocks;
+        }
+        self.shrinkDataArea()?;
+        self.compactIndex()
+    }
+
+    /// Writes the index again with the live entries packed after the start marker.
+    pub fn compactIndex(&mut self) -> Result<()> {
+        let mut raw = Vec::with_capacity(self.raw.len());
+        raw.extend_from_slice(&markerEntry(ENTRY_START));
+        for &(start, end) in &self.index.unusable {
+            raw.extend_from_slice(&unusableEntry(start, end));
+        }
+        for entry in &self.index.entries {
+            raw.extend_from_slice(&entry.encode()?);
+        }
+        let volumeId = volumeIdEntry(self.index.volumeName.as_deref().unwrap_or(""), self.device.timeStamp())?;
+        let free = (self.raw.len() / ENTRY_SIZE).checked_sub(raw.len() / ENTRY_SIZE + 1).ok_or(Error::NoSpace)?;
+        raw.extend_from_slice(&unusedEntries(free));
+        raw.extend_from_slice(&volumeId);
+
+        self.device.write(self.superBlock.indexStart(), &raw)?;
+        self.raw = raw;
+        self.index = parseIndex(&self.raw);
+        self.writeSuper()
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> Result<()> {
+        let zeroes = vec![0u8; (len as usize).min(COPY_CHUNK)];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(zeroes.len() as u64) as usize;
+            self.device.write(offset + done, &zeroes[..n])?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies `blocks` blocks from `from` to `to`, front to back, so `to` may overlap the source
+    /// from below.
+    fn copyBlocks(&mut self, from: u64, to: u64, blocks: u64) -> Result<()> {
+        let blockSize = self.superBlock.blockSize();
+        let mut chunk = vec![0u8; COPY_CHUNK.max(blockSize as usize)];
+        let chunkBlocks = chunk.len() as u64 / blockSize;
+        let mut done = 0;
+        while done < blocks {
+            let n = (blocks - done).min(chunkBlocks.min(to.abs_diff(from)).max(1));
+            let bytes = &mut chunk[..(n * blockSize) as usize];
+            self.device.read((from + done) * blockSize, bytes)?;
+            self.device.write((to + done) * blockSize, bytes)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn writeSuper(&mut self) -> Result<()> {
+        self.superBlock.timeStamp = self.device.timeStamp();
+        self.device.write(SUPER_OFFSET, &self.superBlock.encode())
+    }
+
+    fn writeSlots(&mut self, slot: usize, bytes: &[u8]) -> Result<()> {
+        let at = slot * ENTRY_SIZE;
+        self.raw[at..at + bytes.len()].copy_from_slice(bytes);
+        self.device.write(self.superBlock.indexStart() + at as u64, bytes)
+    }
+
+    fn freeSlots(&mut self, slot: usize, slots: usize) -> Result<()> {
+        self.writeSlots(slot, &unusedEntries(slots))
+    }
+
+    fn freeRun(&self, slots: usize) -> Option<usize> {
+        let count = self.raw.len() / ENTRY_SIZE;
+        (0..=count.checked_sub(slots)?).find(|&slot| {
+            (slot..slot + slots).all(|slot| self.raw[slot * ENTRY_SIZE] == ENTRY_UNUSED)
+        })
+    }
+
+    /// Adds a block of unused entries in front of the index.
+    fn growIndex(&mut self) -> Result<()> {
+        let blockSize = self.superBlock.blockSize();
+        let newStart = self.superBlock.indexStartBlock() - 1;
+        if newStart < self.superBlock.reservedBlocks as u64 + self.superBlock.dataSize {
+            return Err(Error::NoSpace);
+        }
+
+        let mut raw = unusedEntries(blockSize as usize / ENTRY_SIZE);
+        // the start marker stays the first entry
+        if self.raw[0] == ENTRY_START {
+            raw[..ENTRY_SIZE].copy_from_slice(&self.raw[..ENTRY_SIZE]);
+            self.raw[..ENTRY_SIZE].copy_from_slice(&markerEntry(ENTRY_UNUSED));
+        }
+        raw.extend_from_slice(&self.raw);
+
+        self.superBlock.indexSize += blockSize;
+        self.device.write(self.superBlock.indexStart(), &raw)?;
+        self.raw = raw;
+        self.index = parseIndex(&self.raw);
+        self.writeSuper()
+    }
+
+    /// Writes `entry` to free slots, making room if there are none.
+    fn insert(&mut self, mut entry: Entry) -> Result<()> {
+        let raw = entry.encode()?;
+        let slots = raw.len() / ENTRY_SIZE;
+        let slot = match self.freeRun(slots) {
+            Some(slot) => slot,
+            None => {
+                // deleted entries go for good before the index grows
+                for (slot, slots) in core::mem::take(&mut self.index.deleted) {
+                    self.freeSlots(slot, slots)?;
+                }
+                loop {
+                    if let Some(slot) = self.freeRun(slots) {
+                        break slot;
+                    }
+                    self.growIndex()?;
+                }
+            }
+        };
+
+        self.writeSlots(slot, &raw)?;
+        entry.slot = slot;
+        entry.slots = slots;
+        self.index.entries.push(entry);
+        Ok(())
+    }
+
+    /// Rewrites the entry named `path` after `update` changed it.
+    fn update(&mut self, path: &str, update: impl FnOnce(&mut Entry)) -> Result<()> {
+        let position = self.index.entries.iter().position(|entry| entry.name == path).ok_or(Error::NotFound)?;
+        let mut entry = self.index.entries[position].clone();
+        update(&mut entry);
+        entry.timeStamp = self.device.timeStamp();
+
+        let raw = entry.encode()?;
+        if raw.len() / ENTRY_SIZE == entry.slots {
+            self.writeSlots(entry.slot, &raw)?;
+            self.index.entries[position] = entry;
+            return Ok(());
+        }
+        // a new name that needs a different number of continuations moves the entry. The old
+        // one stays until the new one is written, growing the index moves its slots meanwhile.
+        let oldName = self.index.entries[position].name.clone();
+        self.insert(entry)?;
+        let inserted = self.index.entries.len() - 1;
+        let position = self.index.entries[..inserted]
+            .iter()
+            .position(|entry| entry.name == oldName)
+            .ok_or(Error::NotFound)?;
+        let old = self.index.entries.remove(position);
+        self.freeSlots(old.slot, old.slots)
+    }
+
+    /// Marks the entry named `path` deleted, if there is one.
+    fn delete(&mut self, path: &str) -> Result<()> {
+        let Some(position) = self.index.entries.iter().position(|entry| entry.name == path) else {
+            // a directory only there because of what was in it
+            return Ok(());
+        };
+        let entry = self.index.entries.remove(position);
+        let at = entry.slot * ENTRY_SIZE;
+        let mut first: [u8; ENTRY_SIZE] = self.raw[at..at + ENTRY_SIZE].try_into().unwrap();
+        let deleted = match entry.kind {
+            EntryKind::Dir => ENTRY_DIR_DELETED,
+            EntryKind::File { .. } => ENTRY_FILE_DELETED,
+        };
+        // keeps the sum over the continuations at 0
+        first[1] = first[1].wrapping_add(first[0]).wrapping_sub(deleted);
+        first[0] = deleted;
+        self.writeSlots(entry.slot, &first)?;
+        self.index.deleted.push((entry.slot, entry.slots));
+        self.shrinkDataArea()
+    }
+
+    fn extents(&self) -> Vec<(u64, u64)> {
+        let mut extents: Vec<(u64, u64)> = self.index.entries.iter().filter_map(Entry::extent).collect();
+        extents.extend_from_slice(&self.index.unusable);
+        extents.sort_unstable();
+        extents
+    }
+
+    /// Finds `blocks` free blocks in a row in the data area or past its end.
+    fn allocate(&mut self, blocks: u64) -> Result<u64> {
+        let dataStart = self.superBlock.reservedBlocks as u64;
+        let mut start = dataStart;
+        for (extentStart, extentEnd) in self.extents() {
+            if extentStart >= start + blocks {
+                break;
+            }
+            start = start.max(extentEnd + 1);
+        }
+        if start + blocks > self.superBlock.indexStartBlock() {
+            return Err(Error::NoSpace);
+        }
+        self.growDataArea(start + blocks)?;
+        Ok(start)
+    }
+
+    /// Whether blocks `start..end` are free and inside the data area's room.
+    fn isFree(&self, start: u64, end: u64) -> bool {
+        end <= self.superBlock.indexStartBlock()
+            && self.extents().iter().all(|&(extentStart, extentEnd)| extentEnd < start || extentStart >= end)
+    }
+
+    /// Makes the data area reach up to block `end`, exclusive.
+    fn growDataArea(&mut self, end: u64) -> Result<()> {
+        let used = end - self.superBlock.reservedBlocks as u64;
+        if used > self.superBlock.dataSize {
+            self.superBlock.dataSize = used;
+            self.writeSuper()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the data area after the last block still in use.
+    fn shrinkDataArea(&mut self) -> Result<()> {
+        let dataStart = self.superBlock.reservedBlocks as u64;
+        let used = self.extents().iter().map(|&(_, end)| end + 1).max().unwrap_or(dataStart);
+        let dataSize = used.saturating_sub(dataStart);
+        if dataSize < self.superBlock.dataSize {
+            self.superBlock.dataSize = dataSize;
+            self.writeSuper()?;
+        }
+        Ok(())
+    }
+
+    /// Makes room for `length` bytes in the file `path`, moving it if the blocks after it are
+    /// taken. Returns its first block.
+    fn reserve(&mut self, path: &str, length: u64) -> Result<u64> {
+        let Some(EntryKind::File { start, end, length: oldLength }) = self.find(path).map(|entry| entry.kind) else {
+            return Err(Error::NotFound);
+        };
+        let blockSize = self.superBlock.blockSize();
+        let needed = length.div_ceil(blockSize);
+        let have = if start > 0 { end - start + 1 } else { 0 };
+        if needed <= have {
+            return Ok(start);
+        }
+
+        let newStart = if have > 0 && self.isFree(end + 1, start + needed) {
+            self.growDataArea(start + needed)?;
+            start
+        } else {
+            let newStart = self.allocate(needed)?;
+            self.copyBlocks(start, newStart, oldLength.div_ceil(blockSize))?;
+            newStart
+        };
+
+        self.update(path, |entry| {
+            entry.kind = EntryKind::File { start: newStart, end: newStart + needed - 1, length: oldLength };
+        })?;
+        self.shrinkDataArea()?;
+        Ok(newStart)
+    }
+
+    fn setLength(&mut self, path: &str, length: u64) -> Result<()> {
+        let blockSize = self.superBlock.blockSize();
+        self.update(path, |entry| {
+            if let EntryKind::File { start, .. } = entry.kind {
+                entry.kind = match length {
+                    0 => EntryKind::File { start: 0, end: 0, length: 0 },
+                    _ => EntryKind::File { start, end: start + length.div_ceil(blockSize) - 1, length },
+                };
+            }
+        })?;
+        self.shrinkDataArea()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsck, MemoryDevice};
+
+    const BLOCK: usize = 512;
+
+    fn volume(blocks: usize) -> Volume<MemoryDevice> {
+        Volume::format(MemoryDevice(vec![0; blocks * BLOCK]), "test").unwrap()
+    }
+
+    fn extent(volume: &Volume<MemoryDevice>, path: &str) -> Option<(u64, u64)> {
+        volume.find(path).and_then(Entry::extent)
+    }
+
+    fn contents(volume: &mut Volume<MemoryDevice>, path: &str) -> Vec<u8> {
+        let mut buf = vec![0; volume.length(path).unwrap() as usize];
+        assert_eq!(volume.read(path, 0, &mut buf).unwrap(), buf.len());
+        buf
+    }
+
+    /// Opens what `volume` wrote to its device again and checks it.
+    fn reopen(volume: &Volume<MemoryDevice>) -> Volume<MemoryDevice> {
+        let mut device = volume.device().clone();
+        assert!(fsck::check(&mut device).unwrap().isClean());
+        Volume::open(device).unwrap()
+    }
+
+    fn file(volume: &mut Volume<MemoryDevice>, path: &str, data: &[u8]) {
+        volume.create(path, NodeKind::File).unwrap();
+        volume.write(path, 0, data).unwrap();
+    }
+
+    #[test]
+    fn filesGoOneAfterAnother() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; BLOCK + 1]);
+        file(&mut volume, "b", &[2; BLOCK]);
+        assert_eq!(extent(&volume, "a"), Some((1, 2)));
+        assert_eq!(extent(&volume, "b"), Some((3, 3)));
+        assert_eq!(volume.superBlock().dataSize, 3);
+
+        let mut volume = reopen(&volume);
+        assert_eq!(contents(&mut volume, "a"), [1; BLOCK + 1]);
+        assert_eq!(contents(&mut volume, "b"), [2; BLOCK]);
+    }
+
+    #[test]
+    fn filesGrowInPlace() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; 10]);
+        volume.write("a", 10, &[2; BLOCK * 2]).unwrap();
+        assert_eq!(extent(&volume, "a"), Some((1, 3)));
+
+        let mut expected = vec![1; 10];
+        expected.extend_from_slice(&[2; BLOCK * 2]);
+        assert_eq!(contents(&mut reopen(&volume), "a"), expected);
+    }
+
+    #[test]
+    fn filesMoveWhenTheNextBlockIsTaken() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; BLOCK]);
+        file(&mut volume, "b", &[2; BLOCK]);
+        volume.write("a", BLOCK as u64, &[3; BLOCK]).unwrap();
+        assert_eq!(extent(&volume, "a"), Some((3, 4)));
+        assert_eq!(extent(&volume, "b"), Some((2, 2)));
+
+        // the first block is free again and a one block file fits there
+        file(&mut volume, "c", &[4; BLOCK]);
+        assert_eq!(extent(&volume, "c"), Some((1, 1)));
+
+        let mut volume = reopen(&volume);
+        assert_eq!(contents(&mut volume, "a")[..BLOCK], [1; BLOCK]);
+        assert_eq!(contents(&mut volume, "a")[BLOCK..], [3; BLOCK]);
+        assert_eq!(contents(&mut volume, "b"), [2; BLOCK]);
+    }
+
+    #[test]
+    fn writingPastTheEndZeroesTheGap() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; 4]);
+        volume.write("a", 8, &[2; 4]).unwrap();
+        assert_eq!(contents(&mut volume, "a"), [1, 1, 1, 1, 0, 0, 0, 0, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn truncateFreesAndZeroes() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; BLOCK * 3]);
+        volume.truncate("a", 10).unwrap();
+        assert_eq!(extent(&volume, "a"), Some((1, 1)));
+        assert_eq!(volume.superBlock().dataSize, 1);
+
+        volume.truncate("a", 20).unwrap();
+        let mut expected = vec![1; 10];
+        expected.resize(20, 0);
+        assert_eq!(contents(&mut volume, "a"), expected);
+
+        volume.truncate("a", 0).unwrap();
+        assert_eq!(extent(&volume, "a"), None);
+        assert_eq!(volume.superBlock().dataSize, 0);
+        reopen(&volume);
+    }
+
+    #[test]
+    fn fullVolumes() {
+        let mut volume = volume(8);
+        volume.create("a", NodeKind::File).unwrap();
+        assert_eq!(volume.write("a", 0, &[1; BLOCK * 7]).unwrap_err(), Error::NoSpace);
+        volume.write("a", 0, &[1; BLOCK * 6]).unwrap();
+        // the index can't grow into the data area either
+        let names = (0..8).map(|i| alloc::format!("{}", i));
+        let created = names.map(|name| volume.create(&name, NodeKind::Directory)).collect::<Vec<_>>();
+        assert_eq!(created.last().unwrap().unwrap_err(), Error::NoSpace);
+    }
+
+    #[test]
+    fn indexGrowsForMoreEntries() {
+        let mut volume = volume(64);
+        file(&mut volume, "data", &[9; 100]);
+        for i in 0..40 {
+            volume.create(&alloc::format!("dir{}", i), NodeKind::Directory).unwrap();
+        }
+        assert!(volume.superBlock().indexSize > BLOCK as u64);
+        assert_eq!(volume.rawIndex()[0], ENTRY_START);
+
+        let mut volume = reopen(&volume);
+        assert_eq!(volume.entries().len(), 41);
+        assert_eq!(volume.volumeName(), Some("test"));
+        assert_eq!(contents(&mut volume, "data"), [9; 100]);
+    }
+
+    #[test]
+    fn renamesNeedingMoreSlotsMoveTheEntry() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; 100]);
+        // leave 3 free slots in the two block index, too few for the new name
+        for i in 0..10 {
+            volume.create(&alloc::format!("d{}", i), NodeKind::Directory).unwrap();
+        }
+        assert_eq!(volume.superBlock().indexSize, 2 * BLOCK as u64);
+        let long = "l".repeat(200);
+        volume.rename("a", &long).unwrap();
+        assert_eq!(volume.superBlock().indexSize, 3 * BLOCK as u64);
+        assert!(volume.find("a").is_none());
+
+        let mut volume = reopen(&volume);
+        assert!(volume.find("a").is_none());
+        assert_eq!(volume.find(&long).unwrap().slots, 1 + continuationsFor(200, 29));
+        assert_eq!(contents(&mut volume, &long), [1; 100]);
+    }
+
+    #[test]
+    fn defragClosesGaps() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; BLOCK]);
+        file(&mut volume, "b", &[2; BLOCK * 2]);
+        file(&mut volume, "c", &[3; BLOCK + 5]);
+        volume.unlink("b").unwrap();
+        volume.defrag().unwrap();
+
+        assert_eq!(extent(&volume, "a"), Some((1, 1)));
+        assert_eq!(extent(&volume, "c"), Some((2, 3)));
+        assert_eq!(volume.superBlock().dataSize, 3);
+        let mut volume = reopen(&volume);
+        assert_eq!(contents(&mut volume, "c"), [3; BLOCK + 5]);
+        // deleted entries are gone too
+        let mut types = volume.rawIndex().iter().step_by(ENTRY_SIZE);
+        assert!(types.all(|&entryType| entryType != ENTRY_FILE_DELETED));
+    }
+
+    #[test]
+    fn defragGoesAroundBadBlocks() {
+        let mut volume = volume(64);
+        volume.index.unusable.push((2, 2));
+        file(&mut volume, "a", &[1; BLOCK]);
+        file(&mut volume, "b", &[2; BLOCK * 2]);
+        assert_eq!(extent(&volume, "b"), Some((3, 4)));
+        volume.unlink("a").unwrap();
+        volume.defrag().unwrap();
+        assert_eq!(extent(&volume, "b"), Some((3, 4)));
+        assert_eq!(volume.unusable(), [(2, 2)]);
+        assert_eq!(contents(&mut reopen(&volume), "b"), [2; BLOCK * 2]);
+    }
+
+    #[test]
+    fn shrinkAndResize() {
+        let mut volume = volume(64);
+        file(&mut volume, "a", &[1; BLOCK * 2]);
+        volume.shrink().unwrap();
+        assert_eq!(volume.superBlock().totalBlocks, 4);
+        assert_eq!(volume.device().size(), 4 * BLOCK as u64);
+
+        volume.resize(16).unwrap();
+        file(&mut volume, "b", &[2; BLOCK * 4]);
+        let mut volume = reopen(&volume);
+        assert_eq!(contents(&mut volume, "a"), [1; BLOCK * 2]);
+        assert_eq!(contents(&mut volume, "b"), [2; BLOCK * 4]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Builds and edits SFS images on the host.
+//!
+//! Paths on the volume are given from its root, a leading slash is optional.
+
+#![allow(non_snake_case)]
+
+use sfs::host::{self, FileDevice};
+use sfs::{EntryKind, NodeKind, Volume};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+const USAGE: &str = "usage: sfstool <command> <image> [args]
+
+commands:
+    create <image> <folder> [-v] [-ignore <name>]...
+    add <image> <file> [path]
+    list <image>
+    cat <image> <path>
+    extract <image> <path> <dest>
+    mkdir <image> <path>
+    rm <image> <path>
+    resize <image> <MB>
+    shrink <image>
+    defrag <image>
+    fsck <image> [-r]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn volumePath(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn open(image: &str, writable: bool) -> io::Result<Volume<FileDevice>> {
+    Ok(Volume::open(FileDevice::open(Path::new(image), writable)?)?)
+}
+
+fn create(image: &str, args: &[String]) -> io::Result<()> {
+    let mut folder = None;
+    let mut verbose = false;
+    let mut ignore = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            "-ignore" => ignore.push(args.next().unwrap_or_else(|| usage()).clone()),
+            _ if folder.is_none() => folder = Some(arg),
+            _ => usage(),
+        }
+    }
+    let folder = folder.unwrap_or_else(|| usage());
+
+    let mut count = 0;
+    host::createImage(Path::new(image), Path::new(folder), &ignore, |path| {
+        count += 1;
+        if verbose {
+            println!("Added: {}", path);
+        }
+    })?;
+    let size = fs::metadata(image)?.len();
+    println!(
+        "{} entries, volume size {:.2} MB",
+        count,
+        size as f64 / (1024.0 * 1024.0)
+    );
+    Ok(())
+}
+
+fn add(image: &str, file: &str, path: Option<&String>) -> io::Result<()> {
+    let from = Path::new(file);
+    let path = match path {
+        Some(path) => volumePath(path).to_string(),
+        None => from
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    host::addFile(&mut open(image, true)?, from, &path)?;
+    println!("File added.");
+    Ok(())
+}
+
+fn list(image: &str) -> io::Result<()> {
+    let volume = open(image, false)?;
+    if let Some(name) = volume.volumeName() {
+        println!("VOLID: {}", name);
+    }
+    println!("{:<6} {:<10} Name", "Type", "Size");
+    println!("{}", "-".repeat(60));
+    for entry in volume.entries() {
+        match entry.kind {
+            EntryKind::File { length, .. } => {
+                println!("{:<6} {:<10} {}", "FILE", length, entry.name)
+            }
+            EntryKind::Dir => println!("{:<6} {:<10} {}", "DIR", "-", entry.name),
+        }
+    }
+    Ok(())
+}
+
+fn readFile(volume: &mut Volume<FileDevice>, path: &str) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; volume.length(path)? as usize];
+    volume.read(path, 0, &mut data)?;
+    Ok(data)
+}
+
+fn cat(image: &str, path: &str) -> io::Result<()> {
+    let data = readFile(&mut open(image, false)?, volumePath(path))?;
+    io::stdout().write_all(&data)
+}
+
+/// Copies the file or directory at `path` to `dest` on the host.
+fn extractTo(volume: &mut Volume<FileDevice>, path: &str, dest: &Path) -> io::Result<()> {
+    match volume.kind(path) {
+        Some(NodeKind::File) => fs::write(dest, readFile(volume, path)?),
+        Some(NodeKind::Directory) => {
+            fs::create_dir_all(dest)?;
+            for (name, _) in volume.children(path) {
+                let child = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}/{}", path, name)
+                };
+                extractTo(volume, &child, &dest.join(&name))?;
+            }
+            Ok(())
+        }
+        None => Err(sfs::Error::NotFound.into()),
+    }
+}
+
+fn extract(image: &str, path: &str, dest: &str) -> io::Result<()> {
+    extractTo(
+        &mut open(image, false)?,
+        volumePath(path),
+        &PathBuf::from(dest),
+    )
+}
+
+fn mkdir(image: &str, path: &str) -> io::Result<()> {
+    let path = volumePath(path);
+    let mut volume = open(image, true)?;
+    volume.createParents(path)?;
+    Ok(volume.create(path, NodeKind::Directory)?)
+}
+
+/// Removes a file or an empty directory.
+fn rm(image: &str, path: &str) -> io::Result<()> {
+    let path = volumePath(path);
+    let mut volume = open(image, true)?;
+    match volume.kind(path) {
+        Some(NodeKind::Directory) => Ok(volume.rmdir(path)?),
+        _ => Ok(volume.unlink(path)?),
+    }
+}
+
+fn resize(image: &str, size: &str) -> io::Result<()> {
+    let megabytes: f64 = size
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("bad size {:?}", size)))?;
+    let mut volume = open(image, true)?;
+    let blocks = (megabytes * 1024.0 * 1024.0) as u64 / volume.superBlock().blockSize();
+    if blocks <= volume.superBlock().totalBlocks {
+        println!("Size unchanged or too small. Use 'shrink' to reduce.");
+        return Ok(());
+    }
+    volume.resize(blocks)?;
+    println!("Resized to {} MB.", size);
+    Ok(())
+}
+
+fn shrink(image: &str) -> io::Result<()> {
+    let mut volume = open(image, true)?;
+    let blocks = volume.superBlock().minimumBlocks()?;
+    if blocks == volume.superBlock().totalBlocks {
+        println!("Volume is already at minimum size.");
+        return Ok(());
+    }
+    println!("Shrinking to {} blocks...", blocks);
+    volume.shrink()?;
+    println!("Shrink complete.");
+    Ok(())
+}
+
+fn defrag(image: &str) -> io::Result<()> {
+    let mut volume = open(image, true)?;
+    let files = volume
+        .entries()
+        .iter()
+        .filter(|entry| entry.extent().is_some())
+        .count();
+    println!("Defragmenting {} files...", files);
+    volume.defrag()?;
+    println!("Defragmentation complete.");
+    Ok(())
+}
+
+/// Checks the volume and repairs it with `-r`. Returns whether it's clean afterwards.
+fn fsck(image: &str, repair: bool) -> io::Result<bool> {
+    let mut device = FileDevice::open(Path::new(image), repair)?;
+    let report = sfs::fsck::check(&mut device)?;
+    let superBlock = &report.superBlock;
+    println!(
+        "{}: {} blocks of {} bytes, {} reserved, {} data, {} index entries",
+        image,
+        superBlock.totalBlocks,
+        superBlock.blockSize(),
+        superBlock.reservedBlocks,
+        superBlock.dataSize,
+        superBlock.indexSize / sfs::format::ENTRY_SIZE as u64
+    );
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if report.isClean() {
+        return Ok(true);
+    }
+    if !repair {
+        println!("{} problems, run with -r to repair", report.problems.len());
+        return Ok(false);
+    }
+    sfs::fsck::repair(&mut device, &report)?;
+    println!("Repaired.");
+    Ok(true)
+}
+
+fn run(args: &[String]) -> io::Result<bool> {
+    let [command, image, rest @ ..] = args else {
+        usage()
+    };
+    let (image, rest) = (image.as_str(), rest);
+    match (command.as_str(), rest) {
+        ("create", _) => create(image, rest)?,
+        ("add", [file]) => add(image, file, None)?,
+        ("add", [file, path]) => add(image, file, Some(path))?,
+        ("list", []) => list(image)?,
+        ("cat", [path]) => cat(image, path)?,
+        ("extract", [path, dest]) => extract(image, path, dest)?,
+        ("mkdir", [path]) => mkdir(image, path)?,
+        ("rm", [path]) => rm(image, path)?,
+        ("resize", [size]) => resize(image, size)?,
+        ("shrink", []) => shrink(image)?,
+        ("defrag", []) => defrag(image)?,
+        ("fsck", []) => return fsck(image, false),
+        ("fsck", [flag]) if flag == "-r" => return fsck(image, true),
+        _ => usage(),
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("sfstool: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Prints its arguments and environment, then exercises the heap.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use rOSuser::{env, println, process, time};
+
+rOSuser::entry!(main);
+
+fn main() -> i32 {
+    println!("Hello from user mode! PID {}, uptime {:?}", process::id(), time::uptime());
+
+    for (i, arg) in env::args().enumerate() {
+        println!("argv[{}] = {:?}", i, arg);
+    }
+    for (name, value) in env::vars() {
+        println!("{}={}", name, value);
+    }
+
+    // small blocks come from the break, the large one from its own mapping
+    let squares: Vec<u64> = (0..1000u64).map(|n| n * n).collect();
+    let big = alloc::vec![0xA5u8; 256 * 1024];
+    let mut text = String::new();
+    for word in ["heap", "works"] {
+        text.push_str(word);
+        text.push(' ');
+    }
+    println!(
+        "sum of squares {}, big block checksum {}, {}",
+        squares.iter().sum::<u64>(),
+        big.iter().map(|&b| b as u64).sum::<u64>(),
+        text.trim_end()
+    );
+
+    0
+}
@@ -0,0 +1,56 @@
+//! Spawns a few threads that each sum a range and sleep, then joins them. Afterwards one
+//! thread streams through a pipe to the main thread until it closes its end.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::time::Duration;
+use rOSuser::fs;
+use rOSuser::time::Instant;
+use rOSuser::{println, thread, Errno};
+
+rOSuser::entry!(main);
+
+const THREADS: u64 = 4;
+const PER_THREAD: u64 = 1_000_000;
+
+fn main() -> Result<(), Errno> {
+    let start = Instant::now();
+
+    let handles = (0..THREADS)
+        .map(|i| {
+            thread::spawn(move || {
+                let sum: u64 = (i * PER_THREAD..(i + 1) * PER_THREAD).sum();
+                thread::sleep(Duration::from_millis(100 * (i + 1)));
+                println!("thread {} (tid {}) done: {}", i, thread::id(), sum);
+                sum
+            })
+        })
+        .collect::<Result<Vec<_>, Errno>>()?;
+
+    let total: u64 = handles.into_iter().map(|h| h.join()).sum();
+    let n = THREADS * PER_THREAD;
+    assert_eq!(total, n * (n - 1) / 2);
+    println!("all threads joined after {:?}, total {}", start.elapsed(), total);
+
+    // more than the pipe buffers, so the writer has to wait for the reader
+    let (mut reader, mut writer) = fs::pipe()?;
+    let producer = thread::spawn(move || {
+        let chunk = [b'x'; 1000];
+        for _ in 0..20 {
+            writer.write_all(&chunk)?;
+        }
+        Ok::<(), Errno>(())
+        // dropping the writer here is what ends the reader's loop
+    })?;
+
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received)?;
+    producer.join()?;
+    println!("read {} bytes through a pipe", received.len());
+
+    Ok(())
+}